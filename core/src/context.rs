@@ -7,13 +7,16 @@ pub struct RemoteContext {
     pub remote_file: PathBuf,
     /// Path to the remote's directory
     pub remote_dir: PathBuf,
+    /// Path to the remote's private data directory
+    pub data_dir: PathBuf,
 }
 
 impl RemoteContext {
-    pub fn new(remote_file: PathBuf, remote_dir: PathBuf) -> Self {
+    pub fn new(remote_file: PathBuf, remote_dir: PathBuf, data_dir: PathBuf) -> Self {
         Self {
             remote_file,
             remote_dir,
+            data_dir,
         }
     }
 }
@@ -3,18 +3,23 @@ pub mod id;
 pub mod layout;
 pub mod message;
 pub mod meta;
+pub mod permission;
+pub mod schema;
 
 use std::path::PathBuf;
 
 pub use context::RemoteContext;
 pub use id::{ActionId, RemoteId};
 pub use layout::Layout;
-pub use message::{CallActionRequest, ClientMessage, ServerMessage};
+pub use message::{CallActionRequest, ClientMessage, ConfirmResponse, ServerMessage};
 pub use meta::{PLATFORM, Platform, RemoteMeta};
+pub use permission::{Permission, Permissions};
+pub use schema::{SettingSpec, SettingType, SettingsSchema};
 
 #[derive(Debug)]
 pub struct Remote {
     pub path: PathBuf,
     pub meta: RemoteMeta,
     pub layout: Layout,
+    pub schema: SettingsSchema,
 }
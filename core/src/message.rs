@@ -9,6 +9,13 @@ pub struct CallActionRequest {
     pub args: Option<Vec<serde_json::Value>>,
 }
 
+/// Answer of a client to a [`ServerMessage::Confirm`] request
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfirmResponse {
+    pub nonce: String,
+    pub confirmed: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
@@ -19,6 +26,20 @@ pub enum ServerMessage {
     },
     #[serde(rename = "error")]
     Error { message: String },
+    /// Desktop notification mirrored from the host
+    #[serde(rename = "notification")]
+    Notification {
+        app: String,
+        summary: String,
+        body: String,
+        icon: Option<String>,
+    },
+    /// Request to confirm an action, answered with [`ClientMessage::Confirm`]
+    #[serde(rename = "confirm")]
+    Confirm { nonce: String, message: String },
+    /// Text content of the desktop clipboard, sent on [`ClientMessage::ClipboardGet`]
+    #[serde(rename = "clipboard")]
+    Clipboard { text: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +47,14 @@ pub enum ServerMessage {
 pub enum ClientMessage {
     #[serde(rename = "call")]
     CallAction(CallActionRequest),
+    #[serde(rename = "confirm")]
+    Confirm(ConfirmResponse),
+    /// Replace the text on the desktop clipboard
+    #[serde(rename = "clipboard_set")]
+    ClipboardSet { text: String },
+    /// Request the text on the desktop clipboard
+    #[serde(rename = "clipboard_get")]
+    ClipboardGet,
 }
 
 #[cfg(test)]
@@ -44,6 +73,60 @@ mod tests {
         assert!(json.contains(r#""action":"info""#));
     }
 
+    #[test]
+    fn test_notification_serialization() {
+        let msg = ServerMessage::Notification {
+            app: "mail".to_string(),
+            summary: "New message".to_string(),
+            body: "Hello".to_string(),
+            icon: None,
+        };
+
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "notification",
+                "app": "mail",
+                "summary": "New message",
+                "body": "Hello",
+                "icon": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_confirm_response_deserialization() {
+        let json = r#"{"type":"confirm","nonce":"abc","confirmed":true}"#;
+        let msg: ClientMessage = serde_json::from_str(json).unwrap();
+
+        match msg {
+            ClientMessage::Confirm(ConfirmResponse { nonce, confirmed }) => {
+                assert_eq!(nonce, "abc");
+                assert!(confirmed);
+            }
+            _ => panic!("Expected Confirm variant"),
+        }
+    }
+
+    #[test]
+    fn test_clipboard_messages() {
+        let msg: ClientMessage =
+            serde_json::from_str(r#"{"type":"clipboard_set","text":"hello"}"#).unwrap();
+        assert!(matches!(msg, ClientMessage::ClipboardSet { text } if text == "hello"));
+
+        let msg: ClientMessage = serde_json::from_str(r#"{"type":"clipboard_get"}"#).unwrap();
+        assert!(matches!(msg, ClientMessage::ClipboardGet));
+
+        let msg = ServerMessage::Clipboard {
+            text: "hello".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap(),
+            serde_json::json!({"type": "clipboard", "text": "hello"})
+        );
+    }
+
     #[test]
     fn test_server_message_deserialization() {
         let json = r#"{"type":"update","action":"btn","args":{"id":"btn"}}"#;
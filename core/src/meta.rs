@@ -2,6 +2,8 @@ use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
+use crate::Permissions;
+
 pub const PLATFORM: Platform = if cfg!(target_os = "linux") {
     Platform::Linux
 } else if cfg!(target_os = "windows") {
@@ -50,6 +52,14 @@ pub struct RemoteMeta {
     pub instance: Instance,
     #[serde(default, rename = "meta.autostart")]
     pub autostart: Autostart,
+    #[serde(default, rename = "meta.permissions")]
+    pub permissions: Permissions,
+    #[serde(
+        default,
+        rename = "meta.fsroots",
+        deserialize_with = "deserialize_path_list"
+    )]
+    pub fs_roots: Option<Vec<PathBuf>>,
 }
 
 impl RemoteMeta {
@@ -166,6 +176,20 @@ where
     Ok(platforms)
 }
 
+fn deserialize_path_list<'de, D>(deserializer: D) -> Result<Option<Vec<PathBuf>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = Option::deserialize(deserializer)?;
+    Ok(s.map(|s| {
+        s.split(':')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(PathBuf::from)
+            .collect()
+    }))
+}
+
 fn default_version() -> String {
     "0.0.0".into()
 }
@@ -0,0 +1,248 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("unknown permission")]
+pub struct UnknownPermission;
+
+/// Capability a remote has to declare in `meta.permissions` to get access to
+/// the corresponding `libs.*` module
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Keyboard,
+    Mouse,
+    Script,
+    Fs,
+    Http,
+    Ps,
+    Sensors,
+    Dbus,
+    Media,
+    Audio,
+    Notify,
+    Power,
+    Clipboard,
+    Window,
+    Screen,
+    Net,
+    Websocket,
+    Osc,
+    Midi,
+}
+
+impl Permission {
+    /// Name of the permission, which is also the name of the gated module
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Keyboard => "keyboard",
+            Permission::Mouse => "mouse",
+            Permission::Script => "script",
+            Permission::Fs => "fs",
+            Permission::Http => "http",
+            Permission::Ps => "ps",
+            Permission::Sensors => "sensors",
+            Permission::Dbus => "dbus",
+            Permission::Media => "media",
+            Permission::Audio => "audio",
+            Permission::Notify => "notify",
+            Permission::Power => "power",
+            Permission::Clipboard => "clipboard",
+            Permission::Window => "window",
+            Permission::Screen => "screen",
+            Permission::Net => "net",
+            Permission::Websocket => "websocket",
+            Permission::Osc => "osc",
+            Permission::Midi => "midi",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = UnknownPermission;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keyboard" => Ok(Permission::Keyboard),
+            "mouse" => Ok(Permission::Mouse),
+            "script" => Ok(Permission::Script),
+            "fs" => Ok(Permission::Fs),
+            "http" => Ok(Permission::Http),
+            "ps" => Ok(Permission::Ps),
+            "sensors" => Ok(Permission::Sensors),
+            "dbus" => Ok(Permission::Dbus),
+            "media" => Ok(Permission::Media),
+            "audio" => Ok(Permission::Audio),
+            "notify" => Ok(Permission::Notify),
+            "power" => Ok(Permission::Power),
+            "clipboard" => Ok(Permission::Clipboard),
+            "window" => Ok(Permission::Window),
+            "screen" => Ok(Permission::Screen),
+            "net" => Ok(Permission::Net),
+            "websocket" => Ok(Permission::Websocket),
+            "osc" => Ok(Permission::Osc),
+            "midi" => Ok(Permission::Midi),
+            _ => Err(UnknownPermission),
+        }
+    }
+}
+
+/// Set of permissions granted to a remote.
+///
+/// Remotes without a `meta.permissions` entry only get keyboard and mouse
+/// input; everything else has to be declared, or granted with an explicit
+/// `*`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Permissions {
+    All,
+    Only(Vec<Permission>),
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions::Only(vec![Permission::Keyboard, Permission::Mouse])
+    }
+}
+
+impl Permissions {
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Permissions::All => true,
+            Permissions::Only(permissions) => permissions.contains(&permission),
+        }
+    }
+
+    /// Whether the permission is listed by name, for capabilities that `*`
+    /// alone must not grant
+    pub fn declares(&self, permission: Permission) -> bool {
+        match self {
+            Permissions::All => false,
+            Permissions::Only(permissions) => permissions.contains(&permission),
+        }
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Permissions::All => f.write_str("unrestricted"),
+            Permissions::Only(permissions) if permissions.is_empty() => f.write_str("none"),
+            Permissions::Only(permissions) => {
+                for (index, permission) in permissions.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_str(permission.as_str())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        if s.trim() == "*" {
+            return Ok(Permissions::All);
+        }
+
+        let mut permissions = s
+            .split_whitespace()
+            .map(|part| {
+                part.parse::<Permission>()
+                    .map_err(|_| serde::de::Error::custom(format!("unknown permission '{part}'")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        permissions.sort();
+        permissions.dedup();
+
+        Ok(Permissions::Only(permissions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Meta {
+        #[serde(default, rename = "meta.permissions")]
+        permissions: Permissions,
+    }
+
+    #[test]
+    fn test_permissions_missing_grants_input() {
+        let meta: Meta = serde_json::from_str("{}").unwrap();
+        assert!(meta.permissions.allows(Permission::Keyboard));
+        assert!(meta.permissions.allows(Permission::Mouse));
+        assert!(!meta.permissions.allows(Permission::Script));
+        assert!(!meta.permissions.allows(Permission::Fs));
+    }
+
+    #[test]
+    fn test_permissions_wildcard_grants_all() {
+        let meta: Meta = serde_json::from_str(r#"{"meta.permissions": " * "}"#).unwrap();
+        assert_eq!(meta.permissions, Permissions::All);
+        assert!(meta.permissions.allows(Permission::Script));
+        assert!(!meta.permissions.declares(Permission::Screen));
+
+        let result = serde_json::from_str::<Meta>(r#"{"meta.permissions": "* keyboard"}"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_permissions_parse() {
+        let meta: Meta =
+            serde_json::from_str(r#"{"meta.permissions": "mouse keyboard  http mouse"}"#).unwrap();
+
+        assert_eq!(
+            meta.permissions,
+            Permissions::Only(vec![
+                Permission::Keyboard,
+                Permission::Mouse,
+                Permission::Http
+            ])
+        );
+        assert!(meta.permissions.allows(Permission::Http));
+        assert!(!meta.permissions.allows(Permission::Fs));
+        assert!(meta.permissions.declares(Permission::Http));
+        assert_eq!(meta.permissions.to_string(), "keyboard, mouse, http");
+    }
+
+    #[test]
+    fn test_permissions_empty() {
+        let meta: Meta = serde_json::from_str(r#"{"meta.permissions": ""}"#).unwrap();
+        assert_eq!(meta.permissions, Permissions::Only(vec![]));
+        assert_eq!(meta.permissions.to_string(), "none");
+    }
+
+    #[test]
+    fn test_permissions_unknown() {
+        let result = serde_json::from_str::<Meta>(r#"{"meta.permissions": "keyboard root"}"#);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum SchemaError {
+    #[error("unknown setting type '{0}'")]
+    UnknownType(String),
+    #[error("unknown schema attribute '{0}'")]
+    UnknownAttribute(String),
+    #[error("enum setting '{0}' has no options")]
+    MissingOptions(String),
+    #[error("invalid default for setting '{key}': {error}")]
+    InvalidDefault { key: String, error: InvalidSetting },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidSetting {
+    #[error("expected an integer")]
+    NotAnInteger,
+    #[error("expected true or false")]
+    NotABool,
+    #[error("expected one of: {}", .0.join(", "))]
+    NotAnOption(Vec<String>),
+}
+
+/// Type of a setting value, declared as `<key>.type` in the schema
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SettingType {
+    #[default]
+    String,
+    Int,
+    Bool,
+    Enum,
+    Path,
+    Secret,
+}
+
+impl SettingType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SettingType::String => "string",
+            SettingType::Int => "int",
+            SettingType::Bool => "bool",
+            SettingType::Enum => "enum",
+            SettingType::Path => "path",
+            SettingType::Secret => "secret",
+        }
+    }
+}
+
+impl fmt::Display for SettingType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SettingType {
+    type Err = SchemaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "string" => Ok(SettingType::String),
+            "int" => Ok(SettingType::Int),
+            "bool" => Ok(SettingType::Bool),
+            "enum" => Ok(SettingType::Enum),
+            "path" => Ok(SettingType::Path),
+            "secret" => Ok(SettingType::Secret),
+            _ => Err(SchemaError::UnknownType(s.to_string())),
+        }
+    }
+}
+
+/// Description of a single setting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SettingSpec {
+    pub key: String,
+    pub kind: SettingType,
+    pub default: Option<String>,
+    pub label: String,
+    pub help: Option<String>,
+    /// Allowed values of an enum setting
+    pub options: Vec<String>,
+}
+
+impl SettingSpec {
+    /// Parse a raw settings file value into a typed value
+    pub fn parse(&self, raw: &str) -> Result<Value, InvalidSetting> {
+        match self.kind {
+            SettingType::String | SettingType::Path | SettingType::Secret => Ok(Value::from(raw)),
+            SettingType::Int => raw
+                .trim()
+                .parse::<i64>()
+                .map(Value::from)
+                .map_err(|_| InvalidSetting::NotAnInteger),
+            SettingType::Bool => match raw.trim().to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(Value::Bool(true)),
+                "false" | "no" | "off" | "0" => Ok(Value::Bool(false)),
+                _ => Err(InvalidSetting::NotABool),
+            },
+            SettingType::Enum => {
+                if self.options.iter().any(|option| option == raw) {
+                    Ok(Value::from(raw))
+                } else {
+                    Err(InvalidSetting::NotAnOption(self.options.clone()))
+                }
+            }
+        }
+    }
+
+    /// Typed default value, `None` if the schema doesn't declare one
+    pub fn default_value(&self) -> Option<Value> {
+        self.default
+            .as_deref()
+            .and_then(|default| self.parse(default).ok())
+    }
+}
+
+/// Typed settings schema of a remote.
+///
+/// The schema is a properties file with `<key>.<attribute>` entries, where
+/// the attribute is one of `type`, `default`, `label`, `help` or `options`
+/// (whitespace-separated enum values).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SettingsSchema {
+    pub settings: Vec<SettingSpec>,
+}
+
+impl SettingsSchema {
+    pub fn from_properties(properties: HashMap<String, String>) -> Result<Self, SchemaError> {
+        let mut specs: HashMap<String, SettingSpec> = HashMap::new();
+
+        for (name, value) in properties {
+            let Some((key, attribute)) = name.rsplit_once('.') else {
+                return Err(SchemaError::UnknownAttribute(name));
+            };
+
+            let spec = specs.entry(key.to_string()).or_insert_with(|| SettingSpec {
+                key: key.to_string(),
+                ..SettingSpec::default()
+            });
+
+            match attribute {
+                "type" => spec.kind = value.parse()?,
+                "default" => spec.default = Some(value),
+                "label" => spec.label = value,
+                "help" => spec.help = Some(value),
+                "options" => spec.options = value.split_whitespace().map(String::from).collect(),
+                _ => return Err(SchemaError::UnknownAttribute(name)),
+            }
+        }
+
+        let mut settings = specs.into_values().collect::<Vec<_>>();
+        settings.sort_by(|a, b| a.key.cmp(&b.key));
+
+        for spec in &mut settings {
+            if spec.label.is_empty() {
+                spec.label = spec.key.clone();
+            }
+
+            if spec.kind == SettingType::Enum && spec.options.is_empty() {
+                return Err(SchemaError::MissingOptions(spec.key.clone()));
+            }
+
+            if let Some(default) = &spec.default {
+                spec.parse(default)
+                    .map_err(|error| SchemaError::InvalidDefault {
+                        key: spec.key.clone(),
+                        error,
+                    })?;
+            }
+        }
+
+        Ok(Self { settings })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&SettingSpec> {
+        self.settings.iter().find(|spec| spec.key == key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.settings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema(pairs: &[(&str, &str)]) -> Result<SettingsSchema, SchemaError> {
+        SettingsSchema::from_properties(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_schema_from_properties() {
+        let schema = schema_ok();
+        assert_eq!(
+            schema
+                .settings
+                .iter()
+                .map(|spec| spec.key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["host", "mode", "muted", "port"]
+        );
+
+        let port = schema.get("port").unwrap();
+        assert_eq!(port.kind, SettingType::Int);
+        assert_eq!(port.label, "API port");
+        assert_eq!(port.default_value(), Some(Value::from(8080)));
+
+        // The label defaults to the key
+        assert_eq!(schema.get("host").unwrap().label, "host");
+        assert_eq!(schema.get("mode").unwrap().options, vec!["fast", "slow"]);
+    }
+
+    fn schema_ok() -> SettingsSchema {
+        schema(&[
+            ("host.type", "string"),
+            ("port.type", "int"),
+            ("port.default", "8080"),
+            ("port.label", "API port"),
+            ("mode.type", "enum"),
+            ("mode.options", "fast  slow"),
+            ("muted.type", "bool"),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_setting_parse() {
+        let schema = schema_ok();
+
+        let port = schema.get("port").unwrap();
+        assert_eq!(port.parse(" 9090 "), Ok(Value::from(9090)));
+        assert_eq!(port.parse("ninety"), Err(InvalidSetting::NotAnInteger));
+
+        let muted = schema.get("muted").unwrap();
+        assert_eq!(muted.parse("yes"), Ok(Value::Bool(true)));
+        assert_eq!(muted.parse("off"), Ok(Value::Bool(false)));
+        assert_eq!(muted.parse("maybe"), Err(InvalidSetting::NotABool));
+
+        let mode = schema.get("mode").unwrap();
+        assert_eq!(mode.parse("slow"), Ok(Value::from("slow")));
+        assert_eq!(
+            mode.parse("medium").unwrap_err().to_string(),
+            "expected one of: fast, slow"
+        );
+    }
+
+    #[test]
+    fn test_schema_invalid() {
+        assert!(matches!(
+            schema(&[("port.type", "float")]),
+            Err(SchemaError::UnknownType(_))
+        ));
+        assert!(matches!(
+            schema(&[("player.url", "http://localhost")]),
+            Err(SchemaError::UnknownAttribute(_))
+        ));
+        assert!(matches!(
+            schema(&[("mode.type", "enum")]),
+            Err(SchemaError::MissingOptions(_))
+        ));
+        assert!(matches!(
+            schema(&[("port.type", "int"), ("port.default", "high")]),
+            Err(SchemaError::InvalidDefault { .. })
+        ));
+    }
+}
@@ -6,26 +6,56 @@ use std::{
 };
 
 use anyhow::{Context, Result};
-use uniremote_core::{Layout, PLATFORM, Platform, Remote, RemoteContext, RemoteId, RemoteMeta};
+use uniremote_core::{
+    Layout, PLATFORM, Permissions, Platform, Remote, RemoteContext, RemoteId, RemoteMeta,
+    SettingType, SettingsSchema,
+};
 use uniremote_input::UInputBackend;
-pub use uniremote_lua::LuaLimits;
-use uniremote_lua::LuaState;
+pub use uniremote_lua::{LuaLimits, secrets::SecretStore};
+use uniremote_lua::{
+    LuaState,
+    data::{DATA_FILE, DEFAULT_DATA_QUOTA, DataStore},
+    fs::FsRoots,
+    media::MediaArt,
+    secrets::Secrets,
+    settings::SettingsFile,
+};
 use uniremote_worker::LuaWorker;
 
+/// File in the remote directory describing the types of its settings
+const SETTINGS_SCHEMA_FILE: &str = "schema.prop";
+
 pub struct LoadedRemote {
     pub remote: Remote,
     pub worker: LuaWorker,
+    pub data: DataStore,
+    pub secrets: Secrets,
+    pub media_art: MediaArt,
 }
 
 impl LoadedRemote {
-    pub fn new(remote: Remote, state: LuaState) -> Self {
+    pub fn new(
+        remote: Remote,
+        state: LuaState,
+        data: DataStore,
+        secrets: Secrets,
+        media_art: MediaArt,
+    ) -> Self {
         let worker = LuaWorker::new(state);
-        Self { remote, worker }
+        Self {
+            remote,
+            worker,
+            data,
+            secrets,
+            media_art,
+        }
     }
 }
 
 pub fn load_remotes(
     remotes_dir: PathBuf,
+    data_dir: PathBuf,
+    secrets: SecretStore,
     lua_limits: LuaLimits,
 ) -> anyhow::Result<HashMap<RemoteId, LoadedRemote>> {
     let backend = UInputBackend::new().context("failed to initialize input backend")?;
@@ -35,7 +65,16 @@ pub fn load_remotes(
         .skip(1)
         .filter_map(Result::ok)
         .filter(|entry| entry.path().is_dir())
-        .map(|entry| load_remote(&remotes_dir, entry.path(), backend.clone(), lua_limits))
+        .map(|entry| {
+            load_remote(
+                &remotes_dir,
+                &data_dir,
+                entry.path(),
+                &secrets,
+                backend.clone(),
+                lua_limits,
+            )
+        })
         .filter_map(handle_load_error)
         .collect())
 }
@@ -53,7 +92,9 @@ fn handle_load_error(
 
 fn load_remote(
     base_path: &Path,
+    data_dir: &Path,
     path: &Path,
+    secrets: &SecretStore,
     backend: UInputBackend,
     lua_limits: LuaLimits,
 ) -> Result<Option<(RemoteId, LoadedRemote)>> {
@@ -75,11 +116,39 @@ fn load_remote(
 
     tracing::info!("loading remote {remote_id} from {}", path.display());
 
+    if meta.permissions == Permissions::All {
+        tracing::warn!("remote {remote_id} is granted all permissions with meta.permissions = *");
+    }
+
     let layout = load_remote_layout(path, &meta)?;
-    let lua = load_remote_script(base_path, path, &meta, lua_limits)?;
-    let settings = load_remote_settings(path, &meta)?;
+    let data_dir = data_dir.join(remote_id.to_string());
+    std::fs::create_dir_all(&data_dir).context("failed to create remote data directory")?;
+
+    let (lua, script) =
+        create_remote_state(base_path, path, &data_dir, &meta, secrets, lua_limits)?;
+    let schema = load_remote_schema(path)?;
+    let mut settings = load_remote_settings(path, &meta)?;
+    let secrets = secrets.scoped(remote_id.to_string());
+    import_plaintext_secrets(&remote_id, &schema, &mut settings, &secrets);
+    let data = DataStore::open(data_dir.join(DATA_FILE), DEFAULT_DATA_QUOTA)
+        .context("failed to open remote data store")?;
 
     lua.add_state(backend);
+    lua.add_state(schema.clone());
+    lua.add_state(data.clone());
+    lua.add_state(secrets.clone());
+    let media_art = MediaArt::new(format!(
+        "/r/{}/media/art",
+        uri_encode::encode_uri_component(remote_id.to_string())
+    ));
+    lua.add_state(media_art.clone());
+    lua.add_state(SettingsFile(
+        meta.resolve_settings_path(path)
+            .unwrap_or_else(|| path.join(meta.settings_file())),
+    ));
+    if let Some(script) = &script {
+        lua.exec(script)?;
+    }
     if let Err(error) = lua.set_settings(settings) {
         tracing::warn!("failed to set settings for remote {remote_id}: {error:#}");
     }
@@ -93,9 +162,13 @@ fn load_remote(
         path: path.to_path_buf(),
         meta,
         layout,
+        schema,
     };
 
-    Ok(Some((remote_id, LoadedRemote::new(remote, lua))))
+    Ok(Some((
+        remote_id,
+        LoadedRemote::new(remote, lua, data, secrets, media_art),
+    )))
 }
 
 fn load_remote_meta(path: &Path) -> Result<Option<RemoteMeta>> {
@@ -127,24 +200,98 @@ fn load_remote_layout(path: &Path, meta: &RemoteMeta) -> Result<Layout> {
     }
 }
 
-fn load_remote_script(
+/// Create the Lua state of a remote with its context attached, returning the
+/// script to run once the rest of the remote's state is attached as well
+fn create_remote_state(
     base_path: &Path,
     path: &Path,
+    data_dir: &Path,
     meta: &RemoteMeta,
+    secrets: &SecretStore,
     lua_limits: LuaLimits,
-) -> Result<LuaState> {
-    let (lua, remote_path) = if let Some(script_path) =
-        resolve_platform_file(path, meta.remote.as_ref(), "remote", "lua")
-    {
-        let state = LuaState::new(&script_path, base_path, lua_limits)?;
-        (state, script_path)
-    } else {
-        let state = LuaState::empty(lua_limits);
-        (state, path.join("remote.lua"))
+) -> Result<(LuaState, Option<PathBuf>)> {
+    let script = resolve_platform_file(path, meta.remote.as_ref(), "remote", "lua");
+    let lua = match &script {
+        Some(script) => {
+            // Modules are required relative to the script's directory
+            let script_dir = script
+                .parent()
+                .context("script path has no parent directory")?;
+            LuaState::new(script_dir, base_path, lua_limits, &meta.permissions)?
+        }
+        None => LuaState::empty(lua_limits)?,
     };
-    
-    state.add_state(RemoteContext::new(remote_path, path.to_path_buf());
-    Ok(lua)
+
+    let remote_path = script.clone().unwrap_or_else(|| path.join("remote.lua"));
+    let context = RemoteContext::new(remote_path, path.to_path_buf(), data_dir.to_path_buf());
+    lua.add_state(
+        FsRoots::for_remote(&context, meta.fs_roots.as_deref())
+            .exclude(secrets.paths())
+            .exclude([data_dir.join(DATA_FILE)]),
+    );
+    lua.add_state(context);
+    Ok((lua, script))
+}
+
+fn load_remote_schema(path: &Path) -> Result<SettingsSchema> {
+    let schema_path = path.join(SETTINGS_SCHEMA_FILE);
+
+    if !schema_path.is_file() {
+        return Ok(SettingsSchema::default());
+    }
+
+    let properties = serde_java_properties::from_reader(BufReader::new(
+        File::open(schema_path).context("failed to open settings schema")?,
+    ))
+    .context("failed to parse settings schema")?;
+
+    SettingsSchema::from_properties(properties).context("invalid settings schema")
+}
+
+/// Move secret settings found in the plaintext settings file into the secret
+/// store, so they never reach the Lua `settings` table
+fn import_plaintext_secrets(
+    remote_id: &RemoteId,
+    schema: &SettingsSchema,
+    settings: &mut HashMap<String, String>,
+    secrets: &Secrets,
+) {
+    let secret_keys = schema
+        .settings
+        .iter()
+        .filter(|spec| spec.kind == SettingType::Secret)
+        .map(|spec| &spec.key);
+
+    for key in secret_keys {
+        let Some(value) = settings.remove(key) else {
+            continue;
+        };
+
+        if value.is_empty() {
+            continue;
+        }
+
+        if secrets.contains(key) {
+            tracing::warn!(
+                "remote {remote_id} has secret '{key}' in its settings file, using the stored secret"
+            );
+            continue;
+        }
+
+        match secrets.set(key, &value) {
+            Ok(()) => tracing::warn!(
+                "remote {remote_id}: moved secret '{key}' from the settings file into the secret store, remove it from the settings file"
+            ),
+            Err(error) => {
+                tracing::error!("remote {remote_id}: failed to store secret '{key}': {error:#}")
+            }
+        }
+    }
+}
+
+/// Read the current values of a remote's settings file
+pub fn read_remote_settings(remote: &Remote) -> Result<HashMap<String, String>> {
+    load_remote_settings(&remote.path, &remote.meta)
 }
 
 fn load_remote_settings(path: &Path, meta: &RemoteMeta) -> Result<HashMap<String, String>> {
@@ -0,0 +1,528 @@
+//! `libs.audio`: PulseAudio and PipeWire through `pactl`, permission `audio`.
+//!
+//! `sinks` and `sources` list devices, `volume`, `setvolume`,
+//! `changevolume` and the mute functions act on them, `setdefaultsink`
+//! switches the output and `subscribe(callback)` reports change events.
+
+use std::{collections::HashMap, fmt, path::PathBuf, process::Stdio};
+
+use mlua::{Error, Function, Lua, LuaSerdeExt, Result, Table, Value};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+
+use crate::{
+    callback::dispatch,
+    subscription::{Subscription, get_subscription_map},
+};
+
+/// Upper limit for volumes set from Lua, in percent
+const MAX_VOLUME: i64 = 150;
+
+/// Volume of a channel at 100%
+const NORMAL_VOLUME: f64 = 65536.0;
+
+/// `pactl` executable talking to PulseAudio or PipeWire (`pipewire-pulse`)
+#[derive(Clone)]
+struct Pactl(PathBuf);
+
+impl Default for Pactl {
+    fn default() -> Self {
+        Self(PathBuf::from("pactl"))
+    }
+}
+
+impl Pactl {
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(&self.0);
+        command.args(args).env("LC_ALL", "C").kill_on_drop(true);
+        command
+    }
+
+    async fn run(&self, args: &[&str]) -> Result<String> {
+        let output = self
+            .command(args)
+            .output()
+            .await
+            .map_err(|error| Error::runtime(format!("failed to run pactl: {error}")))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(Error::runtime(format!(
+                "pactl {} failed: {}",
+                args.join(" "),
+                stderr.trim()
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    async fn default_device(&self, kind: DeviceKind) -> Result<String> {
+        let output = self.run(&[&format!("get-default-{kind}")]).await?;
+        Ok(output.trim().to_string())
+    }
+
+    async fn devices(&self, kind: DeviceKind) -> Result<Vec<Device>> {
+        let default = self.default_device(kind).await?;
+        let output = self.run(&["--format=json", "list", kind.plural()]).await?;
+        parse_devices(&output, &default)
+    }
+
+    /// Device by name or index, the default device if `None`
+    async fn device(&self, kind: DeviceKind, device: Option<&str>) -> Result<Device> {
+        let devices = self.devices(kind).await?;
+        let found = match device {
+            Some(device) => devices
+                .into_iter()
+                .find(|found| found.name == device || found.index.to_string() == device),
+            None => devices.into_iter().find(|found| found.default),
+        };
+
+        found.ok_or_else(|| match device {
+            Some(device) => Error::runtime(format!("{kind} '{device}' not found")),
+            None => Error::runtime(format!("no default {kind}")),
+        })
+    }
+}
+
+fn get_pactl(lua: &Lua) -> Pactl {
+    lua.app_data_ref::<Pactl>()
+        .expect("pactl not found in lua state")
+        .clone()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceKind {
+    Sink,
+    Source,
+}
+
+impl DeviceKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeviceKind::Sink => "sink",
+            DeviceKind::Source => "source",
+        }
+    }
+
+    fn plural(&self) -> &'static str {
+        match self {
+            DeviceKind::Sink => "sinks",
+            DeviceKind::Source => "sources",
+        }
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Sink or source as listed by `pactl --format=json`
+#[derive(Debug, Deserialize)]
+struct PactlDevice {
+    index: u32,
+    name: String,
+    #[serde(default)]
+    description: String,
+    mute: bool,
+    volume: HashMap<String, PactlChannel>,
+    #[serde(default)]
+    monitor_of_sink: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PactlChannel {
+    value: u32,
+}
+
+/// Audio sink or source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Device {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    /// Average volume of all channels in percent
+    pub volume: i64,
+    pub mute: bool,
+    pub default: bool,
+    /// Whether a source is the monitor of a sink
+    pub monitor: bool,
+}
+
+fn parse_devices(json: &str, default: &str) -> Result<Vec<Device>> {
+    let devices: Vec<PactlDevice> = serde_json::from_str(json)
+        .map_err(|error| Error::runtime(format!("failed to parse pactl output: {error}")))?;
+
+    let devices = devices
+        .into_iter()
+        .map(|device| {
+            let channels = device.volume.len().max(1) as f64;
+            let total = device
+                .volume
+                .values()
+                .map(|channel| f64::from(channel.value))
+                .sum::<f64>();
+
+            Device {
+                index: device.index,
+                default: device.name == default,
+                monitor: device
+                    .monitor_of_sink
+                    .is_some_and(|sink| !sink.is_empty() && sink != "n/a"),
+                name: device.name,
+                description: device.description,
+                volume: (total / channels * 100.0 / NORMAL_VOLUME).round() as i64,
+                mute: device.mute,
+            }
+        })
+        .collect();
+
+    Ok(devices)
+}
+
+/// Event reported by `pactl subscribe`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AudioEvent {
+    /// `new`, `change` or `remove`
+    pub event: String,
+    /// `sink`, `source`, `server`, `sink-input`, ...
+    pub facility: String,
+    pub index: Option<u32>,
+}
+
+/// Parse a line like `Event 'change' on sink #52`
+fn parse_event(line: &str) -> Option<AudioEvent> {
+    let rest = line.trim().strip_prefix("Event '")?;
+    let (event, rest) = rest.split_once("' on ")?;
+    let (facility, index) = match rest.split_once(" #") {
+        Some((facility, index)) => (facility, index.parse().ok()),
+        None => (rest, None),
+    };
+
+    Some(AudioEvent {
+        event: event.to_string(),
+        facility: facility.to_string(),
+        index,
+    })
+}
+
+fn volume_percent(volume: f64) -> String {
+    let volume = (volume.round() as i64).clamp(0, MAX_VOLUME);
+    format!("{volume}%")
+}
+
+fn volume_delta(delta: f64) -> String {
+    let delta = (delta.round() as i64).clamp(-MAX_VOLUME, MAX_VOLUME);
+    if delta < 0 {
+        format!("{delta}%")
+    } else {
+        format!("+{delta}%")
+    }
+}
+
+async fn sinks(lua: Lua, (): ()) -> Result<Value> {
+    lua.to_value(&get_pactl(&lua).devices(DeviceKind::Sink).await?)
+}
+
+async fn sources(lua: Lua, (): ()) -> Result<Value> {
+    lua.to_value(&get_pactl(&lua).devices(DeviceKind::Source).await?)
+}
+
+async fn volume(lua: Lua, sink: Option<String>) -> Result<i64> {
+    let pactl = get_pactl(&lua);
+    Ok(pactl
+        .device(DeviceKind::Sink, sink.as_deref())
+        .await?
+        .volume)
+}
+
+async fn set_volume(lua: Lua, (volume, sink): (f64, Option<String>)) -> Result<()> {
+    let pactl = get_pactl(&lua);
+    let sink = pactl.device(DeviceKind::Sink, sink.as_deref()).await?;
+    pactl
+        .run(&["set-sink-volume", &sink.name, &volume_percent(volume)])
+        .await?;
+    Ok(())
+}
+
+async fn change_volume(lua: Lua, (delta, sink): (f64, Option<String>)) -> Result<i64> {
+    let pactl = get_pactl(&lua);
+    let sink = pactl.device(DeviceKind::Sink, sink.as_deref()).await?;
+
+    // Relative changes are capped like absolute ones
+    let target = sink.volume + delta.round() as i64;
+    let volume = if target > MAX_VOLUME {
+        volume_percent(MAX_VOLUME as f64)
+    } else {
+        volume_delta(delta)
+    };
+    pactl.run(&["set-sink-volume", &sink.name, &volume]).await?;
+
+    Ok(pactl
+        .device(DeviceKind::Sink, Some(&sink.name))
+        .await?
+        .volume)
+}
+
+async fn muted(lua: Lua, sink: Option<String>) -> Result<bool> {
+    let pactl = get_pactl(&lua);
+    Ok(pactl.device(DeviceKind::Sink, sink.as_deref()).await?.mute)
+}
+
+async fn set_mute(lua: Lua, (mute, sink): (bool, Option<String>)) -> Result<()> {
+    let pactl = get_pactl(&lua);
+    let sink = pactl.device(DeviceKind::Sink, sink.as_deref()).await?;
+    let mute = if mute { "1" } else { "0" };
+    pactl.run(&["set-sink-mute", &sink.name, mute]).await?;
+    Ok(())
+}
+
+async fn toggle_mute(lua: Lua, sink: Option<String>) -> Result<bool> {
+    let pactl = get_pactl(&lua);
+    let sink = pactl.device(DeviceKind::Sink, sink.as_deref()).await?;
+    let mute = !sink.mute;
+    pactl
+        .run(&["set-sink-mute", &sink.name, if mute { "1" } else { "0" }])
+        .await?;
+    Ok(mute)
+}
+
+async fn default_sink(lua: Lua, (): ()) -> Result<String> {
+    get_pactl(&lua).default_device(DeviceKind::Sink).await
+}
+
+async fn set_default_sink(lua: Lua, sink: String) -> Result<()> {
+    let pactl = get_pactl(&lua);
+    let sink = pactl.device(DeviceKind::Sink, Some(&sink)).await?;
+    pactl.run(&["set-default-sink", &sink.name]).await?;
+    Ok(())
+}
+
+fn subscribe(lua: &Lua, callback: Function) -> Result<Subscription> {
+    let mut child = get_pactl(lua)
+        .command(&["subscribe"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|error| Error::runtime(format!("failed to run pactl: {error}")))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| Error::runtime("failed to capture pactl output"))?;
+
+    // Create a registry key to keep the function alive
+    let registry_key = lua.create_registry_value(callback)?;
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    let subscriptions = get_subscription_map(lua);
+    let id = subscriptions.add(async move {
+        // The child is killed when the subscription is cancelled
+        let _child = child;
+        let mut lines = BufReader::new(stdout).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let Some(event) = parse_event(&line) else {
+                continue;
+            };
+
+            let Some(lua) = weak_lua.try_upgrade() else {
+                break;
+            };
+
+            if let Ok(callback) = lua.registry_value::<Function>(&registry_key)
+                && let Err(error) = async {
+                    dispatch(&lua, "audio callback", callback, lua.to_value(&event)?).await
+                }
+                .await
+            {
+                tracing::error!("audio callback error: {error}");
+            }
+        }
+
+        if let Some(lua) = weak_lua.try_upgrade() {
+            let _ = lua.remove_registry_value(registry_key);
+        }
+    });
+
+    tracing::info!("created audio subscription with id: {id}");
+    Ok(Subscription { id, subscriptions })
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    lua.set_app_data(Pactl::default());
+
+    let module = lua.create_table()?;
+    module.set("sinks", lua.create_async_function(sinks)?)?;
+    module.set("sources", lua.create_async_function(sources)?)?;
+    module.set("volume", lua.create_async_function(volume)?)?;
+    module.set("setvolume", lua.create_async_function(set_volume)?)?;
+    module.set("changevolume", lua.create_async_function(change_volume)?)?;
+    module.set("muted", lua.create_async_function(muted)?)?;
+    module.set("setmute", lua.create_async_function(set_mute)?)?;
+    module.set("togglemute", lua.create_async_function(toggle_mute)?)?;
+    module.set("defaultsink", lua.create_async_function(default_sink)?)?;
+    module.set(
+        "setdefaultsink",
+        lua.create_async_function(set_default_sink)?,
+    )?;
+    module.set("subscribe", lua.create_function(subscribe)?)?;
+
+    libs.set("audio", &module)?;
+    lua.register_module("audio", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const SINKS: &str = r#"[
+        {
+            "index": 1,
+            "name": "alsa_output.speakers",
+            "description": "Speakers",
+            "mute": false,
+            "volume": {
+                "front-left": { "value": 32768, "value_percent": "50%" },
+                "front-right": { "value": 32768, "value_percent": "50%" }
+            }
+        },
+        {
+            "index": 2,
+            "name": "bluez_output.headphones",
+            "description": "Headphones",
+            "mute": true,
+            "volume": { "mono": { "value": 65536, "value_percent": "100%" } }
+        }
+    ]"#;
+
+    #[test]
+    fn test_parse_devices() {
+        let devices = parse_devices(SINKS, "bluez_output.headphones").unwrap();
+        assert_eq!(
+            devices[0],
+            Device {
+                index: 1,
+                name: "alsa_output.speakers".to_string(),
+                description: "Speakers".to_string(),
+                volume: 50,
+                mute: false,
+                default: false,
+                monitor: false,
+            }
+        );
+        assert!(devices[1].default && devices[1].mute);
+        assert_eq!(devices[1].volume, 100);
+    }
+
+    #[test]
+    fn test_parse_event() {
+        assert_eq!(
+            parse_event("Event 'change' on sink #52"),
+            Some(AudioEvent {
+                event: "change".to_string(),
+                facility: "sink".to_string(),
+                index: Some(52),
+            })
+        );
+        assert_eq!(
+            parse_event("Event 'change' on server").unwrap().facility,
+            "server"
+        );
+        assert_eq!(parse_event("Connection failure"), None);
+    }
+
+    #[test]
+    fn test_volume_arguments() {
+        assert_eq!(volume_percent(42.4), "42%");
+        assert_eq!(volume_percent(400.0), "150%");
+        assert_eq!(volume_percent(-5.0), "0%");
+        assert_eq!(volume_delta(5.0), "+5%");
+        assert_eq!(volume_delta(-5.0), "-5%");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_audio_with_pactl() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let log = temp_dir.path().join("calls.log");
+        let script = temp_dir.path().join("pactl");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> '{log}'\ncase \"$1\" in\n\
+                 get-default-sink) echo alsa_output.speakers ;;\n\
+                 --format=json) cat <<'EOF'\n{SINKS}\nEOF\n ;;\n\
+                 subscribe) echo \"Event 'change' on sink #1\"; sleep 5 ;;\n\
+                 esac\n",
+                log = log.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.set_app_data(Pactl(script));
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local sinks = libs.audio.sinks()
+            assert(#sinks == 2 and sinks[1].default)
+            assert(libs.audio.defaultsink() == "alsa_output.speakers")
+            assert(libs.audio.volume() == 50)
+            assert(libs.audio.muted("bluez_output.headphones"))
+
+            libs.audio.setvolume(70)
+            libs.audio.changevolume(-5, "2")
+            assert(libs.audio.togglemute() == true)
+            libs.audio.setdefaultsink("bluez_output.headphones")
+
+            events = {}
+            subscription = libs.audio.subscribe(function(event)
+                table.insert(events, event)
+            end)
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let calls = std::fs::read_to_string(&log).unwrap();
+        assert!(calls.contains("set-sink-volume alsa_output.speakers 70%"));
+        assert!(calls.contains("set-sink-volume bluez_output.headphones -5%"));
+        assert!(calls.contains("set-sink-mute alsa_output.speakers 1"));
+        assert!(calls.contains("set-default-sink bluez_output.headphones"));
+
+        let mut received = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            received = lua
+                .load("return #events > 0 and events[1].facility == 'sink'")
+                .eval::<bool>()
+                .unwrap();
+            if received {
+                break;
+            }
+        }
+        assert!(received, "audio event not received");
+        assert!(
+            lua.load("return subscription:cancel()")
+                .eval::<bool>()
+                .unwrap()
+        );
+    }
+}
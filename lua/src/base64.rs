@@ -0,0 +1,94 @@
+//! `libs.base64`: `encode(data, { url, pad }?)` and `decode(data)`, which
+//! accepts either alphabet.
+
+use base64::{
+    Engine,
+    alphabet::{STANDARD, URL_SAFE},
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use mlua::{Error, Lua, Result, Table};
+
+const CONFIG: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const STANDARD_ENGINE: GeneralPurpose = GeneralPurpose::new(&STANDARD, CONFIG);
+const URL_SAFE_ENGINE: GeneralPurpose = GeneralPurpose::new(&URL_SAFE, CONFIG);
+
+/// Encode data as base64, optionally with the URL-safe alphabet and without padding
+fn encode(_: &Lua, (data, options): (mlua::String, Option<Table>)) -> Result<String> {
+    let (url, pad) = match &options {
+        Some(options) => (
+            options.get::<Option<bool>>("url")?.unwrap_or(false),
+            options.get::<Option<bool>>("pad")?.unwrap_or(true),
+        ),
+        None => (false, true),
+    };
+
+    let engine = if url {
+        &URL_SAFE_ENGINE
+    } else {
+        &STANDARD_ENGINE
+    };
+    let mut encoded = engine.encode(data.as_bytes());
+    if !pad {
+        encoded.truncate(encoded.trim_end_matches('=').len());
+    }
+    Ok(encoded)
+}
+
+/// Decode base64 in either alphabet, with or without padding
+fn decode(lua: &Lua, data: mlua::String) -> Result<mlua::String> {
+    let data = data.as_bytes();
+    let data = data.trim_ascii();
+    let engine = if data.iter().any(|&byte| byte == b'-' || byte == b'_') {
+        &URL_SAFE_ENGINE
+    } else {
+        &STANDARD_ENGINE
+    };
+    let decoded = engine
+        .decode(data)
+        .map_err(|error| Error::runtime(format!("base64 decode error: {error}")))?;
+    lua.create_string(decoded)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("encode", lua.create_function(encode)?)?;
+    module.set("decode", lua.create_function(decode)?)?;
+
+    libs.set("base64", &module)?;
+    lua.register_module("base64", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local base64 = require("base64")
+            assert(base64.encode("hello") == "aGVsbG8=")
+            assert(base64.encode("hello", { pad = false }) == "aGVsbG8")
+            assert(base64.encode("\xfb\xff", { url = true }) == "-_8=")
+            assert(base64.encode("\xfb\xff") == "+/8=")
+            assert(base64.decode("aGVsbG8=") == "hello")
+            assert(base64.decode("aGVsbG8") == "hello")
+            assert(base64.decode("-_8") == "\xfb\xff")
+            assert(base64.decode("+/8=\n") == "\xfb\xff")
+            assert(base64.decode(base64.encode("\0\1\2")) == "\0\1\2")
+
+            local ok, err = pcall(base64.decode, "not base64!")
+            assert(not ok and tostring(err):find("base64 decode error"))
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use flume::{Receiver, Sender};
+use mlua::{Function, IntoLuaMulti, Lua, MultiValue, Result};
+use tokio::sync::oneshot;
+
+/// A Lua callback triggered by a background task (a timer, a signal, a
+/// socket, ...), queued to run on the remote's worker
+pub struct Callback {
+    name: String,
+    function: Function,
+    args: MultiValue,
+    reply: Option<oneshot::Sender<Result<()>>>,
+}
+
+/// Sending half of the callback queue, attached to the Lua state
+#[derive(Clone)]
+struct CallbackQueue(Sender<Callback>);
+
+/// Attach a callback queue to the Lua state and return its receiving half,
+/// which the worker drains between actions
+pub(crate) fn attach_queue(lua: &Lua) -> Receiver<Callback> {
+    // Unbounded, so that background tasks never wait for the worker, which
+    // may itself be waiting for them (e.g. an action in `proc:wait()` while
+    // the output readers queue line callbacks)
+    let (sender, receiver) = flume::unbounded();
+    lua.set_app_data(CallbackQueue(sender));
+    receiver
+}
+
+/// Queue a callback to run on the worker, or run it right away in states
+/// without a worker (e.g. in tests). Errors of the callback are logged as
+/// `failed to run {name}`.
+pub(crate) async fn dispatch(
+    lua: &Lua,
+    name: &str,
+    function: Function,
+    args: impl IntoLuaMulti,
+) -> Result<()> {
+    let callback = Callback {
+        name: name.to_string(),
+        function,
+        args: args.into_lua_multi(lua)?,
+        reply: None,
+    };
+
+    match queue(lua, callback) {
+        Ok(()) => Ok(()),
+        Err(callback) => {
+            run(callback).await;
+            Ok(())
+        }
+    }
+}
+
+/// Queue a callback without waiting for it, for callers that cannot await
+/// (e.g. client subscriptions triggering `focus` and `blur`)
+pub(crate) fn enqueue(
+    lua: &Lua,
+    name: &str,
+    function: Function,
+    args: impl IntoLuaMulti,
+) -> Result<()> {
+    let callback = Callback {
+        name: name.to_string(),
+        function,
+        args: args.into_lua_multi(lua)?,
+        reply: None,
+    };
+    queue(lua, callback).map_err(|_| mlua::Error::runtime(format!("no worker to run {name}")))
+}
+
+/// Like [`dispatch`], but wait for the callback to finish and return its
+/// error instead of logging it
+pub(crate) async fn dispatch_wait(
+    lua: &Lua,
+    name: &str,
+    function: Function,
+    args: impl IntoLuaMulti,
+) -> Result<()> {
+    let (reply, response) = oneshot::channel();
+    let callback = Callback {
+        name: name.to_string(),
+        function,
+        args: args.into_lua_multi(lua)?,
+        reply: Some(reply),
+    };
+
+    match queue(lua, callback) {
+        Ok(()) => response
+            .await
+            .map_err(|_| mlua::Error::runtime(format!("{name} was dropped by the worker")))?,
+        Err(callback) => callback.function.call_async::<()>(callback.args).await,
+    }
+}
+
+/// Queue a callback, handing it back if the state has no worker queue
+fn queue(lua: &Lua, callback: Callback) -> std::result::Result<(), Callback> {
+    let Some(queue) = lua
+        .app_data_ref::<CallbackQueue>()
+        .map(|queue| queue.clone())
+    else {
+        return Err(callback);
+    };
+    queue.0.send(callback).map_err(|error| error.into_inner())
+}
+
+/// Run a queued callback, with a fresh instruction budget like an action
+pub(crate) async fn run(callback: Callback) {
+    crate::state::reset_instruction_counter();
+    let result = callback.function.call_async::<()>(callback.args).await;
+
+    match callback.reply {
+        Some(reply) => {
+            let _ = reply.send(result);
+        }
+        None => {
+            if let Err(error) = result {
+                tracing::error!("failed to run {}: {error}", callback.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dispatch_queues_callbacks() {
+        let lua = Lua::new();
+        let callbacks = attach_queue(&lua);
+        lua.load("calls = {}").exec().unwrap();
+        let function: Function = lua
+            .load("function(value) table.insert(calls, value) end")
+            .eval()
+            .unwrap();
+
+        dispatch(&lua, "test callback", function.clone(), "first")
+            .await
+            .unwrap();
+        dispatch(&lua, "test callback", function, "second")
+            .await
+            .unwrap();
+
+        // Nothing runs until the worker drains the queue
+        let calls: Vec<String> = lua.globals().get("calls").unwrap();
+        assert!(calls.is_empty());
+
+        for callback in callbacks.drain() {
+            run(callback).await;
+        }
+        let calls: Vec<String> = lua.globals().get("calls").unwrap();
+        assert_eq!(calls, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_wait_returns_error() {
+        let lua = Lua::new();
+        let callbacks = attach_queue(&lua);
+        let function: Function = lua.load("function() error('boom') end").eval().unwrap();
+
+        let worker = tokio::spawn(async move {
+            let callback = callbacks.recv_async().await.unwrap();
+            run(callback).await;
+        });
+
+        let error = dispatch_wait(&lua, "test callback", function, ())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("boom"), "{error}");
+        worker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_without_queue_runs_inline() {
+        let lua = Lua::new();
+        let function: Function = lua
+            .load("function(value) called = value end")
+            .eval()
+            .unwrap();
+
+        dispatch(&lua, "test callback", function, 42).await.unwrap();
+        let called: i64 = lua.globals().get("called").unwrap();
+        assert_eq!(called, 42);
+    }
+}
@@ -0,0 +1,379 @@
+//! `libs.clipboard`: desktop clipboard, permission `clipboard`.
+//!
+//! Shells out to wl-clipboard on Wayland or xclip on X11. `get` and `set`
+//! handle text, `getimage(mime?)` and `setimage(data, mime?)` images, PNG by
+//! default, and `types()` lists the offered types.
+
+use std::{path::PathBuf, process::Stdio};
+
+use anyhow::{Context, bail};
+use mlua::{Error, Lua, Result, Table, Value};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Image type used when none is given
+const DEFAULT_IMAGE_TYPE: &str = "image/png";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// `wl-copy` and `wl-paste` from wl-clipboard
+    Wayland,
+    /// `xclip`
+    X11,
+}
+
+/// Desktop clipboard accessed through the command line tools of the display
+/// server
+#[derive(Debug, Clone)]
+pub struct Clipboard {
+    backend: Backend,
+    copy: PathBuf,
+    paste: PathBuf,
+}
+
+impl Clipboard {
+    pub fn new(backend: Backend) -> Self {
+        let (copy, paste) = match backend {
+            Backend::Wayland => ("wl-copy", "wl-paste"),
+            Backend::X11 => ("xclip", "xclip"),
+        };
+        Self::with_programs(backend, copy, paste)
+    }
+
+    /// Use custom executables instead of the ones found in `PATH`
+    pub fn with_programs(
+        backend: Backend,
+        copy: impl Into<PathBuf>,
+        paste: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            backend,
+            copy: copy.into(),
+            paste: paste.into(),
+        }
+    }
+
+    /// Clipboard of the current graphical session, Wayland taking precedence
+    /// over X11
+    pub fn detect() -> Option<Self> {
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            Some(Self::new(Backend::Wayland))
+        } else if std::env::var_os("DISPLAY").is_some() {
+            Some(Self::new(Backend::X11))
+        } else {
+            None
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    fn paste_command(&self, mime: Option<&str>) -> Command {
+        let mut command = Command::new(&self.paste);
+        match self.backend {
+            Backend::Wayland => {
+                command.arg("--no-newline");
+                match mime {
+                    Some(mime) => command.args(["--type", mime]),
+                    None => command.arg("--list-types"),
+                };
+            }
+            Backend::X11 => {
+                command.args(["-selection", "clipboard", "-out", "-target"]);
+                command.arg(mime.unwrap_or("TARGETS"));
+            }
+        }
+        command.kill_on_drop(true);
+        command
+    }
+
+    fn copy_command(&self, mime: &str) -> Command {
+        let mut command = Command::new(&self.copy);
+        match self.backend {
+            Backend::Wayland => command.args(["--type", mime]),
+            Backend::X11 => command.args(["-selection", "clipboard", "-in", "-target", mime]),
+        };
+        // Both tools stay in the background to serve the selection, so they
+        // must not hold on to our pipes
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command
+    }
+
+    fn text_type(&self) -> &'static str {
+        match self.backend {
+            Backend::Wayland => "text/plain;charset=utf-8",
+            Backend::X11 => "UTF8_STRING",
+        }
+    }
+
+    async fn paste(&self, mime: Option<&str>) -> anyhow::Result<Vec<u8>> {
+        let output = self
+            .paste_command(mime)
+            .output()
+            .await
+            .with_context(|| format!("failed to run {}", self.paste.display()))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = stderr.trim();
+            // An empty clipboard or a missing type is not an error
+            if stderr.contains("No selection")
+                || stderr.contains("Nothing is copied")
+                || stderr.contains("not available")
+                || stderr.contains("No suitable type")
+            {
+                return Ok(Vec::new());
+            }
+            bail!("{} failed: {stderr}", self.paste.display());
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// MIME types the current clipboard content is offered as
+    pub async fn types(&self) -> anyhow::Result<Vec<String>> {
+        let output = self.paste(None).await?;
+        Ok(String::from_utf8_lossy(&output)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    pub async fn get(&self, mime: &str) -> anyhow::Result<Vec<u8>> {
+        self.paste(Some(mime)).await
+    }
+
+    pub async fn set(&self, mime: &str, content: &[u8]) -> anyhow::Result<()> {
+        let mut child = self
+            .copy_command(mime)
+            .spawn()
+            .with_context(|| format!("failed to run {}", self.copy.display()))?;
+
+        let mut stdin = child.stdin.take().context("failed to open stdin")?;
+        stdin.write_all(content).await?;
+        drop(stdin);
+
+        // The tools fork once they have read the content, so this returns
+        // before the selection is released
+        let status = child.wait().await?;
+        if !status.success() {
+            bail!("{} failed with {status}", self.copy.display());
+        }
+        Ok(())
+    }
+
+    pub async fn get_text(&self) -> anyhow::Result<String> {
+        let content = self.get(self.text_type()).await?;
+        Ok(String::from_utf8_lossy(&content).into_owned())
+    }
+
+    pub async fn set_text(&self, text: &str) -> anyhow::Result<()> {
+        self.set(self.text_type(), text.as_bytes()).await
+    }
+}
+
+fn get_clipboard(lua: &Lua) -> Result<Clipboard> {
+    if let Some(clipboard) = lua.app_data_ref::<Clipboard>() {
+        return Ok(clipboard.clone());
+    }
+
+    let clipboard = Clipboard::detect().ok_or_else(|| {
+        Error::runtime("no clipboard available, neither WAYLAND_DISPLAY nor DISPLAY is set")
+    })?;
+    lua.set_app_data(clipboard.clone());
+    Ok(clipboard)
+}
+
+fn clipboard_error(error: anyhow::Error) -> Error {
+    Error::runtime(format!("clipboard error: {error:#}"))
+}
+
+async fn get(lua: Lua, (): ()) -> Result<String> {
+    get_clipboard(&lua)?
+        .get_text()
+        .await
+        .map_err(clipboard_error)
+}
+
+async fn set(lua: Lua, text: String) -> Result<()> {
+    get_clipboard(&lua)?
+        .set_text(&text)
+        .await
+        .map_err(clipboard_error)
+}
+
+async fn types(lua: Lua, (): ()) -> Result<Vec<String>> {
+    get_clipboard(&lua)?.types().await.map_err(clipboard_error)
+}
+
+/// Image data as a Lua string, `nil` if the clipboard holds no such image
+async fn get_image(lua: Lua, mime: Option<String>) -> Result<Value> {
+    let clipboard = get_clipboard(&lua)?;
+    let mime = mime.as_deref().unwrap_or(DEFAULT_IMAGE_TYPE);
+
+    let types = clipboard.types().await.map_err(clipboard_error)?;
+    if !types.iter().any(|offered| offered == mime) {
+        return Ok(Value::Nil);
+    }
+
+    let image = clipboard.get(mime).await.map_err(clipboard_error)?;
+    Ok(Value::String(lua.create_string(image)?))
+}
+
+async fn set_image(lua: Lua, (image, mime): (mlua::String, Option<String>)) -> Result<()> {
+    let mime = mime.as_deref().unwrap_or(DEFAULT_IMAGE_TYPE);
+    if !mime.starts_with("image/") {
+        return Err(Error::runtime(format!(
+            "invalid image type '{mime}', expected image/*"
+        )));
+    }
+
+    get_clipboard(&lua)?
+        .set(mime, &image.as_bytes())
+        .await
+        .map_err(clipboard_error)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("get", lua.create_async_function(get)?)?;
+    module.set("set", lua.create_async_function(set)?)?;
+    module.set("types", lua.create_async_function(types)?)?;
+    module.set("getimage", lua.create_async_function(get_image)?)?;
+    module.set("setimage", lua.create_async_function(set_image)?)?;
+
+    libs.set("clipboard", &module)?;
+    lua.register_module("clipboard", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::Path};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Fake clipboard tool keeping the content and its type in files, which
+    /// records its arguments and handles both the wl-clipboard and the xclip
+    /// command lines
+    const FAKE_TOOL: &str = r#"#!/bin/sh
+dir="$(dirname "$0")"
+echo "$(basename "$0") $*" >> "$dir/calls"
+mime=""
+list=""
+copy=""
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --type|-target) mime="$2"; shift ;;
+        --list-types) list=1 ;;
+        -in) copy=1 ;;
+    esac
+    shift
+done
+case "$(basename "$0")" in
+    wl-copy) copy=1 ;;
+esac
+[ "$mime" = "TARGETS" ] && list=1
+if [ -n "$copy" ]; then
+    cat > "$dir/content"
+    echo "$mime" > "$dir/type"
+elif [ -n "$list" ]; then
+    [ -f "$dir/type" ] || { echo "No selection" >&2; exit 1; }
+    cat "$dir/type"
+else
+    [ -f "$dir/type" ] || { echo "No selection" >&2; exit 1; }
+    [ "$mime" = "$(cat "$dir/type")" ] || { echo "No suitable type of content copied" >&2; exit 1; }
+    cat "$dir/content"
+fi
+"#;
+
+    fn install(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, FAKE_TOOL).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    /// Fake clipboard in a temporary directory
+    fn fake_clipboard(backend: Backend) -> (TempDir, Clipboard) {
+        let dir = TempDir::new().unwrap();
+        let clipboard = match backend {
+            Backend::Wayland => Clipboard::with_programs(
+                backend,
+                install(dir.path(), "wl-copy"),
+                install(dir.path(), "wl-paste"),
+            ),
+            Backend::X11 => {
+                let xclip = install(dir.path(), "xclip");
+                Clipboard::with_programs(backend, &xclip, &xclip)
+            }
+        };
+        (dir, clipboard)
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_text() {
+        for backend in [Backend::Wayland, Backend::X11] {
+            let (dir, clipboard) = fake_clipboard(backend);
+
+            assert_eq!(clipboard.get_text().await.unwrap(), "");
+            assert!(clipboard.types().await.unwrap().is_empty());
+
+            clipboard.set_text("hello from the phone").await.unwrap();
+            assert_eq!(clipboard.get_text().await.unwrap(), "hello from the phone");
+            assert_eq!(
+                clipboard.types().await.unwrap(),
+                vec![clipboard.text_type().to_string()]
+            );
+
+            let calls = std::fs::read_to_string(dir.path().join("calls")).unwrap();
+            match backend {
+                Backend::Wayland => assert!(
+                    calls.contains("wl-copy --type text/plain;charset=utf-8"),
+                    "{calls}"
+                ),
+                Backend::X11 => assert!(
+                    calls.contains("xclip -selection clipboard -in -target UTF8_STRING"),
+                    "{calls}"
+                ),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_module() {
+        let (_dir, clipboard) = fake_clipboard(Backend::Wayland);
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.set_app_data(clipboard);
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            libs.clipboard.set("copied")
+            assert(libs.clipboard.get() == "copied")
+            assert(libs.clipboard.getimage() == nil)
+
+            local png = "\137PNG\r\n\26\n\0\0"
+            libs.clipboard.setimage(png)
+            assert(libs.clipboard.types()[1] == "image/png")
+            assert(libs.clipboard.getimage() == png)
+            assert(libs.clipboard.get() == "")
+
+            assert(not pcall(libs.clipboard.setimage, png, "text/plain"))
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, bail};
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike,
+};
+
+/// Upper bound of candidate times checked by [`Schedule::next_after`], enough
+/// to cover several years of skipped months and days
+const MAX_ITERATIONS: usize = 100_000;
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A five-field cron expression (`minute hour day-of-month month day-of-week`).
+///
+/// Fields accept `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`),
+/// lists (`1,15`) and month/weekday names (`jan`, `mon-fri`). Sunday is
+/// both `0` and `7`. As in classic cron, when both day fields are
+/// restricted a time matches if either of them does. The macros `@yearly`,
+/// `@monthly`, `@weekly`, `@daily` and `@hourly` are supported as well.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    expression: String,
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// First time strictly after `after` that matches the schedule, in the
+    /// time zone of `after`.
+    ///
+    /// Local times skipped by a DST change never match and repeated local
+    /// times only match once.
+    pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = after.timezone();
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)?;
+        let mut time = start + Duration::minutes(1);
+
+        for _ in 0..MAX_ITERATIONS {
+            if !bit(self.months as u64, time.month()) {
+                time = first_of_next_month(time.date())?;
+                continue;
+            }
+            if !self.matches_day(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !bit(self.hours as u64, time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if !bit(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+                continue;
+            }
+
+            let candidate = match timezone.from_local_datetime(&time) {
+                LocalResult::Single(candidate) => Some(candidate),
+                LocalResult::Ambiguous(earliest, _) => Some(earliest),
+                LocalResult::None => None,
+            };
+            match candidate {
+                Some(candidate) if candidate > *after => return Some(candidate),
+                _ => time += Duration::minutes(1),
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = bit(self.days as u64, date.day());
+        let weekday = bit(self.weekdays as u64, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> anyhow::Result<Self> {
+        let expanded = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!(
+                "invalid cron expression '{expression}': expected 5 fields, got {}",
+                fields.len()
+            );
+        };
+
+        let parse = |field: &str, name: &str, min: u32, max: u32, names: &[&str]| {
+            parse_field(field, min, max, names)
+                .with_context(|| format!("invalid {name} field in cron expression '{expression}'"))
+        };
+
+        // Sunday may be written as 7, fold it onto 0
+        let mut weekdays = parse(weekday, "day-of-week", 0, 7, &WEEKDAY_NAMES)?;
+        if bit(weekdays, 7) {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            minutes: parse(minute, "minute", 0, 59, &[])?,
+            hours: parse(hour, "hour", 0, 23, &[])? as u32,
+            days: parse(day, "day-of-month", 1, 31, &[])? as u32,
+            months: parse(month, "month", 1, 12, &MONTH_NAMES)? as u16,
+            weekdays: weekdays as u8,
+            // Like Vixie cron, fields starting with `*` (e.g. `*/2`) don't
+            // restrict the day, so only the other day field applies
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+fn bit(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+fn first_of_next_month(date: NaiveDate) -> Option<NaiveDateTime> {
+    let (year, month) = match date.month() {
+        12 => (date.year() + 1, 1),
+        month => (date.year(), month + 1),
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)
+}
+
+/// Parse a comma separated cron field into a bit set of allowed values
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> anyhow::Result<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, min, names)?,
+                parse_value(end, min, names)?,
+            )
+        } else {
+            let value = parse_value(range, min, names)?;
+            // `5/10` means every 10 starting at 5
+            (value, if step.is_some() { max } else { value })
+        };
+
+        if start < min || end > max || start > end {
+            bail!("'{part}' is out of range {min}-{max}");
+        }
+
+        let step = match step {
+            Some(step) => step
+                .parse::<u32>()
+                .ok()
+                .filter(|&step| step > 0)
+                .with_context(|| format!("invalid step in '{part}'"))?,
+            None => 1,
+        };
+
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+fn parse_value(value: &str, min: u32, names: &[&str]) -> anyhow::Result<u32> {
+    if let Some(index) = names
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(value))
+    {
+        return Ok(index as u32 + min);
+    }
+    value
+        .parse()
+        .with_context(|| format!("invalid value '{value}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, Utc};
+
+    use super::*;
+
+    fn next(expression: &str, after: &str) -> String {
+        let schedule: Schedule = expression.parse().unwrap();
+        let after = after.parse::<DateTime<Utc>>().unwrap();
+        schedule.next_after(&after).unwrap().to_rfc3339()
+    }
+
+    #[test]
+    fn test_next_after() {
+        // Monday 2024-01-01
+        assert_eq!(
+            next("0 7 * * 1-5", "2024-01-01T06:59:30Z"),
+            "2024-01-01T07:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 7 * * mon-fri", "2024-01-01T07:00:00Z"),
+            "2024-01-02T07:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 7 * * 1-5", "2024-01-05T08:00:00Z"),
+            "2024-01-08T07:00:00+00:00"
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2024-01-01T10:14:59Z"),
+            "2024-01-01T10:15:00+00:00"
+        );
+        assert_eq!(
+            next("30 2 29 feb *", "2024-03-01T00:00:00Z"),
+            "2028-02-29T02:30:00+00:00"
+        );
+        assert_eq!(
+            next("@monthly", "2024-12-15T00:00:00Z"),
+            "2025-01-01T00:00:00+00:00"
+        );
+        // Sunday as 7
+        assert_eq!(
+            next("0 12 * * 7", "2024-01-01T00:00:00Z"),
+            "2024-01-07T12:00:00+00:00"
+        );
+        // Either day field matches when both are restricted
+        assert_eq!(
+            next("0 0 15 * sat", "2024-01-01T00:00:00Z"),
+            "2024-01-06T00:00:00+00:00"
+        );
+        // Stepped wildcards leave the day unrestricted, only Mondays match
+        assert_eq!(
+            next("0 0 */2 * 1", "2024-01-02T00:00:00Z"),
+            "2024-01-08T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn test_next_after_timezone() {
+        let schedule: Schedule = "0 7 * * *".parse().unwrap();
+        let offset = FixedOffset::east_opt(2 * 3600).unwrap();
+        let after = offset.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap();
+        assert_eq!(
+            schedule.next_after(&after).unwrap().to_rfc3339(),
+            "2024-01-02T07:00:00+02:00"
+        );
+    }
+
+    #[test]
+    fn test_never_matches() {
+        let schedule: Schedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(schedule.next_after(&Utc::now()), None);
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "* * * foo *",
+        ] {
+            assert!(
+                expression.parse::<Schedule>().is_err(),
+                "'{expression}' should be rejected"
+            );
+        }
+    }
+}
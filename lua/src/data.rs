@@ -0,0 +1,274 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use mlua::{Error, Lua, LuaSerdeExt, Result, Table, Value};
+use serde_json::Map;
+
+/// File name of the key-value store inside a remote's data directory
+pub const DATA_FILE: &str = "data.json";
+
+/// Default size limit of a remote's serialized key-value store
+pub const DEFAULT_DATA_QUOTA: usize = 1024 * 1024; // 1 MB
+
+/// Persistent key-value store of a remote, backing `libs.data`.
+///
+/// Values are JSON and the whole store is rewritten atomically on every
+/// change. Clones share the same store.
+#[derive(Clone, Debug)]
+pub struct DataStore(Arc<DataStoreInner>);
+
+#[derive(Debug)]
+struct DataStoreInner {
+    path: PathBuf,
+    quota: usize,
+    entries: Mutex<Map<String, serde_json::Value>>,
+}
+
+impl DataStore {
+    /// Open the store at `path`, starting empty if the file doesn't exist.
+    ///
+    /// A file that cannot be parsed is moved aside to `<path>.corrupt`, so
+    /// that the remote still loads and the old data can be recovered by hand.
+    pub fn open(path: PathBuf, quota: usize) -> anyhow::Result<Self> {
+        let entries = if path.is_file() {
+            let content = std::fs::read(&path).context("failed to read data store")?;
+            match serde_json::from_slice(&content) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    let mut corrupt_path = path.clone().into_os_string();
+                    corrupt_path.push(".corrupt");
+                    tracing::error!(
+                        "failed to parse data store '{}', moving it to '{}': {error}",
+                        path.display(),
+                        corrupt_path.display()
+                    );
+                    std::fs::rename(&path, &corrupt_path)
+                        .context("failed to move corrupt data store aside")?;
+                    Map::new()
+                }
+            }
+        } else {
+            Map::new()
+        };
+
+        Ok(Self(Arc::new(DataStoreInner {
+            path,
+            quota,
+            entries: Mutex::new(entries),
+        })))
+    }
+
+    /// Snapshot of all entries
+    pub fn entries(&self) -> Map<String, serde_json::Value> {
+        self.0.entries.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.0.entries.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn set(&self, key: String, value: serde_json::Value) -> anyhow::Result<()> {
+        self.update(|entries| {
+            entries.insert(key, value);
+        })
+    }
+
+    pub fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.update(|entries| {
+            entries.remove(key);
+        })
+    }
+
+    /// Apply a change and persist it, leaving the store untouched if the
+    /// result exceeds the quota or cannot be written
+    fn update(
+        &self,
+        change: impl FnOnce(&mut Map<String, serde_json::Value>),
+    ) -> anyhow::Result<()> {
+        let mut entries = self.0.entries.lock().unwrap();
+        let mut updated = entries.clone();
+        change(&mut updated);
+
+        let content = serde_json::to_vec(&updated)?;
+        if content.len() > self.0.quota {
+            anyhow::bail!(
+                "data store quota exceeded ({} of {} bytes)",
+                content.len(),
+                self.0.quota
+            );
+        }
+
+        write_atomic(&self.0.path, &content)?;
+        *entries = updated;
+        Ok(())
+    }
+}
+
+/// Write a file by writing a temporary file next to it and renaming it over
+/// the target, so readers never see a partial write
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().context("path has no parent directory")?;
+    std::fs::create_dir_all(dir)?;
+
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    temp_file.write_all(content)?;
+    temp_file.as_file().sync_all()?;
+    temp_file.persist(path)?;
+    Ok(())
+}
+
+// Only states created by the loader have a store, others (e.g. in tests) get
+// an error instead of a panic
+fn get_data_store(lua: &Lua) -> Result<DataStore> {
+    lua.app_data_ref::<DataStore>()
+        .map(|store| store.clone())
+        .ok_or_else(|| Error::runtime("data store is not available"))
+}
+
+fn get(lua: &Lua, key: String) -> Result<Value> {
+    match get_data_store(lua)?.get(&key) {
+        Some(value) => lua.to_value(&value),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn set(lua: &Lua, (key, value): (String, Value)) -> Result<()> {
+    let store = get_data_store(lua)?;
+
+    if value.is_nil() {
+        return store
+            .delete(&key)
+            .map_err(|error| Error::runtime(format!("failed to delete value: {error:#}")));
+    }
+
+    let value: serde_json::Value = lua.from_value(value)?;
+    store
+        .set(key, value)
+        .map_err(|error| Error::runtime(format!("failed to store value: {error:#}")))
+}
+
+fn delete(lua: &Lua, key: String) -> Result<()> {
+    get_data_store(lua)?
+        .delete(&key)
+        .map_err(|error| Error::runtime(format!("failed to delete value: {error:#}")))
+}
+
+fn keys(lua: &Lua, _: ()) -> Result<Vec<String>> {
+    let mut keys = get_data_store(lua)?
+        .entries()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    keys.sort();
+    Ok(keys)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("get", lua.create_function(get)?)?;
+    module.set("set", lua.create_function(set)?)?;
+    module.set("delete", lua.create_function(delete)?)?;
+    module.set("keys", lua.create_function(keys)?)?;
+
+    libs.set("data", &module)?;
+    lua.register_module("data", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_lua(store: DataStore) -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        lua.set_app_data(store);
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_data_set_get_delete() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join(DATA_FILE);
+        let store = DataStore::open(path.clone(), DEFAULT_DATA_QUOTA).unwrap();
+        let lua = data_lua(store);
+
+        lua.load(
+            r#"
+            libs.data.set("playlist", "Favourites")
+            libs.data.set("channels", { 1, 5, 7 })
+            libs.data.set("volume", { level = 42, muted = false })
+            libs.data.set("temporary", true)
+            libs.data.delete("temporary")
+
+            assert(libs.data.get("playlist") == "Favourites")
+            assert(libs.data.get("channels")[2] == 5)
+            assert(libs.data.get("volume").level == 42)
+            assert(libs.data.get("temporary") == nil)
+            assert(#libs.data.keys() == 3)
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        // A new store reads back what was persisted
+        let reopened = DataStore::open(path, DEFAULT_DATA_QUOTA).unwrap();
+        assert_eq!(
+            reopened.get("playlist"),
+            Some(serde_json::json!("Favourites"))
+        );
+        assert_eq!(
+            reopened.get("volume"),
+            Some(serde_json::json!({ "level": 42, "muted": false }))
+        );
+        assert_eq!(reopened.get("temporary"), None);
+    }
+
+    #[test]
+    fn test_data_quota_exceeded() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join(DATA_FILE);
+        let store = DataStore::open(path.clone(), 64).unwrap();
+        let lua = data_lua(store.clone());
+
+        lua.load(r#"libs.data.set("small", "ok")"#).exec().unwrap();
+
+        let error = lua
+            .load(r#"libs.data.set("big", string.rep("x", 100))"#)
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("quota exceeded"), "{error}");
+
+        // The failed write leaves memory and disk untouched
+        assert_eq!(store.get("big"), None);
+        let reopened = DataStore::open(path, 64).unwrap();
+        assert_eq!(reopened.get("small"), Some(serde_json::json!("ok")));
+        assert_eq!(reopened.get("big"), None);
+    }
+
+    #[test]
+    fn test_data_corrupt_file_moved_aside() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join(DATA_FILE);
+        std::fs::write(&path, "{ not json").unwrap();
+
+        let store = DataStore::open(path.clone(), DEFAULT_DATA_QUOTA).unwrap();
+        assert!(store.entries().is_empty());
+        assert!(!path.exists());
+
+        let corrupt_path = temp_dir.path().join(format!("{DATA_FILE}.corrupt"));
+        assert_eq!(std::fs::read_to_string(corrupt_path).unwrap(), "{ not json");
+
+        store.set("key".to_string(), serde_json::json!(1)).unwrap();
+        let reopened = DataStore::open(path, DEFAULT_DATA_QUOTA).unwrap();
+        assert_eq!(reopened.get("key"), Some(serde_json::json!(1)));
+    }
+}
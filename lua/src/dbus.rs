@@ -0,0 +1,867 @@
+//! `libs.dbus`: session and system bus client, permission `dbus`.
+//!
+//! `bus:call{...}` takes signature-typed or inferred arguments, `bus:get` and
+//! `bus:set` access properties and `bus:subscribe{...}` delivers signals on
+//! the worker queue.
+
+use std::{str::FromStr, sync::Arc};
+
+use futures_util::StreamExt;
+use mlua::{
+    Error, Function, Lua, MultiValue, RegistryKey, Result, Table, UserData, UserDataFields,
+    UserDataMethods, Value as LuaValue,
+};
+use tokio::sync::OnceCell;
+use zbus::{
+    Connection, MatchRule, Message, MessageStream,
+    zvariant::{
+        Array, Dict, ObjectPath, OwnedValue, Signature, Structure, StructureBuilder, Value,
+    },
+};
+
+use crate::{
+    callback::dispatch,
+    subscription::{Subscription, get_subscription_map},
+};
+
+/// Session bus connection shared by the desktop modules, opened on first use
+#[derive(Clone, Default)]
+pub(crate) struct SessionBus(Arc<OnceCell<Connection>>);
+
+impl From<Connection> for SessionBus {
+    fn from(connection: Connection) -> Self {
+        Self(Arc::new(OnceCell::from(connection)))
+    }
+}
+
+pub(crate) async fn session_connection(lua: &Lua) -> Result<Connection> {
+    let bus = match lua.app_data_ref::<SessionBus>() {
+        Some(bus) => bus.clone(),
+        None => {
+            let bus = SessionBus::default();
+            lua.set_app_data(bus.clone());
+            bus
+        }
+    };
+
+    bus.0
+        .get_or_try_init(Connection::session)
+        .await
+        .cloned()
+        .map_err(dbus_error)
+}
+
+/// System bus connection shared by the system modules, opened on first use
+#[derive(Clone, Default)]
+pub(crate) struct SystemBus(Arc<OnceCell<Connection>>);
+
+impl From<Connection> for SystemBus {
+    fn from(connection: Connection) -> Self {
+        Self(Arc::new(OnceCell::from(connection)))
+    }
+}
+
+pub(crate) async fn system_connection(lua: &Lua) -> Result<Connection> {
+    let bus = match lua.app_data_ref::<SystemBus>() {
+        Some(bus) => bus.clone(),
+        None => {
+            let bus = SystemBus::default();
+            lua.set_app_data(bus.clone());
+            bus
+        }
+    };
+
+    bus.0
+        .get_or_try_init(Connection::system)
+        .await
+        .cloned()
+        .map_err(dbus_error)
+}
+
+pub(crate) fn dbus_error(error: impl std::fmt::Display) -> Error {
+    Error::runtime(format!("dbus error: {error}"))
+}
+
+/// Value with an explicit D-Bus type, created by `libs.dbus.variant`
+struct Variant(OwnedValue);
+
+impl Variant {
+    fn value(&self) -> Result<Value<'static>> {
+        self.0.try_clone().map(Value::from).map_err(dbus_error)
+    }
+}
+
+impl UserData for Variant {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("signature", |_lua, this| {
+            Ok(this.0.value_signature().to_string())
+        });
+    }
+}
+
+/// Connection to a message bus
+struct Bus(Connection);
+
+impl UserData for Bus {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("name", |_lua, this| {
+            Ok(this.0.unique_name().map(|name| name.to_string()))
+        });
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("call", |lua, this, params: Table| async move {
+            let destination: Option<String> = params.get("destination")?;
+            let path: String = params.get("path")?;
+            let interface: Option<String> = params.get("interface")?;
+            let method: String = params.get("method")?;
+            let signature: Option<String> = params.get("signature")?;
+            let args: Option<Table> = params.get("args")?;
+
+            let args = match args {
+                Some(args) => args.sequence_values().collect::<Result<Vec<LuaValue>>>()?,
+                None => Vec::new(),
+            };
+            let body = marshal_args(&args, signature.as_deref())?;
+
+            let reply = call_method(
+                &this.0,
+                destination.as_deref(),
+                &path,
+                interface.as_deref(),
+                &method,
+                body,
+            )
+            .await?;
+            unmarshal_reply(&lua, &reply)
+        });
+
+        methods.add_async_method(
+            "get",
+            |lua, this, (destination, path, interface, property): (String, String, String, String)| async move {
+                let body = vec![Value::from(interface), Value::from(property)];
+                let reply = call_method(
+                    &this.0,
+                    Some(&destination),
+                    &path,
+                    Some("org.freedesktop.DBus.Properties"),
+                    "Get",
+                    body,
+                )
+                .await?;
+                let values = unmarshal_reply(&lua, &reply)?;
+                Ok(values.into_iter().next().unwrap_or(LuaValue::Nil))
+            },
+        );
+
+        methods.add_async_method(
+            "set",
+            |_lua,
+             this,
+             (destination, path, interface, property, value, signature): (
+                String,
+                String,
+                String,
+                String,
+                LuaValue,
+                Option<String>,
+            )| async move {
+                let value = match signature {
+                    Some(signature) => to_dbus(&value, &parse_signature(&signature)?)?,
+                    None => infer(&value)?,
+                };
+                let body = vec![
+                    Value::from(interface),
+                    Value::from(property),
+                    Value::Value(Box::new(value)),
+                ];
+                call_method(
+                    &this.0,
+                    Some(&destination),
+                    &path,
+                    Some("org.freedesktop.DBus.Properties"),
+                    "Set",
+                    body,
+                )
+                .await?;
+                Ok(())
+            },
+        );
+
+        methods.add_async_method("subscribe", |lua, this, params: Table| async move {
+            subscribe(lua, &this.0, params).await
+        });
+    }
+}
+
+async fn call_method(
+    connection: &Connection,
+    destination: Option<&str>,
+    path: &str,
+    interface: Option<&str>,
+    method: &str,
+    body: Vec<Value<'static>>,
+) -> Result<Message> {
+    let reply = if body.is_empty() {
+        connection
+            .call_method(destination, path, interface, method, &())
+            .await
+    } else {
+        let mut builder = StructureBuilder::new();
+        for value in body {
+            builder.push_value(value);
+        }
+        let body = builder.build().map_err(dbus_error)?;
+        connection
+            .call_method(destination, path, interface, method, &body)
+            .await
+    };
+
+    reply.map_err(dbus_error)
+}
+
+fn parse_signature(signature: &str) -> Result<Signature> {
+    Signature::from_str(signature)
+        .map_err(|error| Error::runtime(format!("invalid D-Bus signature '{signature}': {error}")))
+}
+
+/// Convert method arguments, typed by a signature or inferred from the values
+fn marshal_args(args: &[LuaValue], signature: Option<&str>) -> Result<Vec<Value<'static>>> {
+    let Some(signature) = signature else {
+        return args.iter().map(infer).collect();
+    };
+
+    let parsed = parse_signature(signature)?;
+    // Several complete types (e.g. `su`) parse into a structure, which must not
+    // be confused with a single structure argument (e.g. `(su)`)
+    let types = match &parsed {
+        Signature::Unit => Vec::new(),
+        Signature::Structure(fields) if parsed.string_len() != signature.len() => {
+            fields.iter().cloned().collect()
+        }
+        _ => vec![parsed],
+    };
+
+    if types.len() != args.len() {
+        return Err(Error::runtime(format!(
+            "signature '{signature}' expects {} arguments, got {}",
+            types.len(),
+            args.len()
+        )));
+    }
+
+    args.iter()
+        .zip(&types)
+        .map(|(value, signature)| to_dbus(value, signature))
+        .collect()
+}
+
+fn unmarshal_reply(lua: &Lua, message: &Message) -> Result<MultiValue> {
+    let body = message.body();
+    if matches!(body.signature(), Signature::Unit) {
+        return Ok(MultiValue::new());
+    }
+
+    let values = body.deserialize::<Structure>().map_err(dbus_error)?;
+    values
+        .fields()
+        .iter()
+        .map(|value| from_dbus(lua, value))
+        .collect()
+}
+
+fn integer(value: &LuaValue) -> Result<i64> {
+    match value {
+        LuaValue::Integer(value) => Ok(*value),
+        LuaValue::Number(value) if value.fract() == 0.0 => Ok(*value as i64),
+        _ => Err(Error::runtime(format!(
+            "expected an integer, got {}",
+            value.type_name()
+        ))),
+    }
+}
+
+fn ranged<T: TryFrom<i64>>(value: &LuaValue, signature: &Signature) -> Result<T> {
+    let value = integer(value)?;
+    T::try_from(value).map_err(|_| {
+        Error::runtime(format!(
+            "integer {value} is out of range for D-Bus type '{signature}'"
+        ))
+    })
+}
+
+fn string(value: &LuaValue) -> Result<String> {
+    match value {
+        LuaValue::String(value) => Ok(value.to_str()?.to_string()),
+        _ => Err(Error::runtime(format!(
+            "expected a string, got {}",
+            value.type_name()
+        ))),
+    }
+}
+
+fn table(value: &LuaValue) -> Result<&Table> {
+    match value {
+        LuaValue::Table(table) => Ok(table),
+        _ => Err(Error::runtime(format!(
+            "expected a table, got {}",
+            value.type_name()
+        ))),
+    }
+}
+
+/// Convert a Lua value to a D-Bus value of the given type
+fn to_dbus(value: &LuaValue, signature: &Signature) -> Result<Value<'static>> {
+    if let LuaValue::UserData(userdata) = value
+        && let Ok(variant) = userdata.borrow::<Variant>()
+    {
+        let inner = variant.value()?;
+        return match signature {
+            Signature::Variant => Ok(Value::Value(Box::new(inner))),
+            _ if inner.value_signature() == signature => Ok(inner),
+            _ => Err(Error::runtime(format!(
+                "expected D-Bus type '{signature}', got variant of type '{}'",
+                inner.value_signature()
+            ))),
+        };
+    }
+
+    let value = match signature {
+        Signature::U8 => Value::U8(ranged(value, signature)?),
+        Signature::Bool => match value {
+            LuaValue::Boolean(value) => Value::Bool(*value),
+            _ => {
+                return Err(Error::runtime(format!(
+                    "expected a boolean, got {}",
+                    value.type_name()
+                )));
+            }
+        },
+        Signature::I16 => Value::I16(ranged(value, signature)?),
+        Signature::U16 => Value::U16(ranged(value, signature)?),
+        Signature::I32 => Value::I32(ranged(value, signature)?),
+        Signature::U32 => Value::U32(ranged(value, signature)?),
+        Signature::I64 => Value::I64(integer(value)?),
+        Signature::U64 => Value::U64(ranged(value, signature)?),
+        Signature::F64 => match value {
+            LuaValue::Integer(value) => Value::F64(*value as f64),
+            LuaValue::Number(value) => Value::F64(*value),
+            _ => {
+                return Err(Error::runtime(format!(
+                    "expected a number, got {}",
+                    value.type_name()
+                )));
+            }
+        },
+        Signature::Str => Value::from(string(value)?),
+        Signature::Signature => Value::Signature(parse_signature(&string(value)?)?),
+        Signature::ObjectPath => {
+            let path = string(value)?;
+            Value::ObjectPath(ObjectPath::try_from(path).map_err(dbus_error)?)
+        }
+        Signature::Variant => Value::Value(Box::new(infer(value)?)),
+        Signature::Array(child) if matches!(**child, Signature::U8) => match value {
+            // Byte arrays are passed as Lua strings
+            LuaValue::String(bytes) => Value::from(bytes.as_bytes().to_vec()),
+            _ => array(value, child)?,
+        },
+        Signature::Array(child) => array(value, child)?,
+        Signature::Dict {
+            key: key_signature,
+            value: value_signature,
+        } => {
+            let mut dict = Dict::new(key_signature, value_signature);
+            for pair in table(value)?.pairs::<LuaValue, LuaValue>() {
+                let (key, value) = pair?;
+                dict.append(
+                    to_dbus(&key, key_signature)?,
+                    to_dbus(&value, value_signature)?,
+                )
+                .map_err(dbus_error)?;
+            }
+            Value::Dict(dict)
+        }
+        Signature::Structure(fields) => {
+            let table = table(value)?;
+            let mut builder = StructureBuilder::new();
+            for (index, field) in fields.iter().enumerate() {
+                let value = table.raw_get::<LuaValue>(index + 1)?;
+                builder.push_value(to_dbus(&value, field)?);
+            }
+            Value::Structure(builder.build().map_err(dbus_error)?)
+        }
+        _ => {
+            return Err(Error::runtime(format!(
+                "unsupported D-Bus type '{signature}'"
+            )));
+        }
+    };
+
+    Ok(value)
+}
+
+fn array(value: &LuaValue, child: &Signature) -> Result<Value<'static>> {
+    let mut array = Array::new(child);
+    for value in table(value)?.sequence_values::<LuaValue>() {
+        array.append(to_dbus(&value?, child)?).map_err(dbus_error)?;
+    }
+    Ok(Value::Array(array))
+}
+
+/// Convert a Lua value to a D-Bus value of the closest matching type.
+///
+/// Integers become `i` (or `x` if they don't fit), other numbers `d`,
+/// sequences `a*` of their common element type (`av` if mixed) and other
+/// tables `a{sv}`.
+fn infer(value: &LuaValue) -> Result<Value<'static>> {
+    let value = match value {
+        LuaValue::Boolean(value) => Value::Bool(*value),
+        LuaValue::Integer(value) => match i32::try_from(*value) {
+            Ok(value) => Value::I32(value),
+            Err(_) => Value::I64(*value),
+        },
+        LuaValue::Number(value) => Value::F64(*value),
+        LuaValue::String(value) => Value::from(value.to_str()?.to_string()),
+        LuaValue::UserData(userdata) if userdata.is::<Variant>() => {
+            userdata.borrow::<Variant>()?.value()?
+        }
+        LuaValue::Table(table) if table.raw_len() > 0 => {
+            let values = table
+                .sequence_values::<LuaValue>()
+                .map(|value| infer(&value?))
+                .collect::<Result<Vec<_>>>()?;
+
+            let signature = values[0].value_signature().clone();
+            let uniform = values
+                .iter()
+                .all(|value| value.value_signature() == &signature);
+
+            let mut array = if uniform {
+                Array::new(&signature)
+            } else {
+                Array::new(&Signature::Variant)
+            };
+            for value in values {
+                let value = if uniform {
+                    value
+                } else {
+                    Value::Value(Box::new(value))
+                };
+                array.append(value).map_err(dbus_error)?;
+            }
+            Value::Array(array)
+        }
+        LuaValue::Table(table) => {
+            let mut dict = Dict::new(&Signature::Str, &Signature::Variant);
+            for pair in table.pairs::<String, LuaValue>() {
+                let (key, value) = pair?;
+                dict.append(Value::from(key), Value::Value(Box::new(infer(&value)?)))
+                    .map_err(dbus_error)?;
+            }
+            Value::Dict(dict)
+        }
+        _ => {
+            return Err(Error::runtime(format!(
+                "cannot convert {} to a D-Bus value",
+                value.type_name()
+            )));
+        }
+    };
+
+    Ok(value)
+}
+
+/// Convert a D-Bus value to Lua, unwrapping variants
+fn from_dbus(lua: &Lua, value: &Value) -> Result<LuaValue> {
+    let value = match value {
+        Value::U8(value) => LuaValue::Integer((*value).into()),
+        Value::Bool(value) => LuaValue::Boolean(*value),
+        Value::I16(value) => LuaValue::Integer((*value).into()),
+        Value::U16(value) => LuaValue::Integer((*value).into()),
+        Value::I32(value) => LuaValue::Integer((*value).into()),
+        Value::U32(value) => LuaValue::Integer((*value).into()),
+        Value::I64(value) => LuaValue::Integer(*value),
+        Value::U64(value) => match i64::try_from(*value) {
+            Ok(value) => LuaValue::Integer(value),
+            Err(_) => LuaValue::Number(*value as f64),
+        },
+        Value::F64(value) => LuaValue::Number(*value),
+        Value::Str(value) => LuaValue::String(lua.create_string(value.as_str())?),
+        Value::Signature(value) => LuaValue::String(lua.create_string(value.to_string())?),
+        Value::ObjectPath(value) => LuaValue::String(lua.create_string(value.as_str())?),
+        Value::Value(value) => from_dbus(lua, value)?,
+        Value::Array(array) if matches!(array.element_signature(), Signature::U8) => {
+            let bytes = array
+                .inner()
+                .iter()
+                .filter_map(|value| match value {
+                    Value::U8(byte) => Some(*byte),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            LuaValue::String(lua.create_string(bytes)?)
+        }
+        Value::Array(array) => {
+            let table = lua.create_table_with_capacity(array.len(), 0)?;
+            for value in array.inner() {
+                table.raw_push(from_dbus(lua, value)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        Value::Dict(dict) => {
+            let table = lua.create_table()?;
+            for (key, value) in dict.iter() {
+                table.raw_set(from_dbus(lua, key)?, from_dbus(lua, value)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        Value::Structure(structure) => {
+            let table = lua.create_table_with_capacity(structure.fields().len(), 0)?;
+            for value in structure.fields() {
+                table.raw_push(from_dbus(lua, value)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        _ => LuaValue::Nil,
+    };
+
+    Ok(value)
+}
+
+/// Lua table describing a received signal
+fn signal_table(lua: &Lua, message: &Message) -> Result<Table> {
+    let header = message.header();
+    let signal = lua.create_table()?;
+    signal.set("sender", header.sender().map(|name| name.to_string()))?;
+    signal.set("path", header.path().map(|path| path.to_string()))?;
+    signal.set("interface", header.interface().map(|name| name.to_string()))?;
+    signal.set("member", header.member().map(|name| name.to_string()))?;
+
+    let args = lua.create_table()?;
+    for value in unmarshal_reply(lua, message)? {
+        args.raw_push(value)?;
+    }
+    signal.set("args", args)?;
+    Ok(signal)
+}
+
+async fn subscribe(lua: Lua, connection: &Connection, params: Table) -> Result<Subscription> {
+    let callback: Function = params.get("callback")?;
+    let sender: Option<String> = params.get("sender")?;
+    let path: Option<String> = params.get("path")?;
+    let interface: Option<String> = params.get("interface")?;
+    let member: Option<String> = params.get("member")?;
+
+    let mut rule = MatchRule::builder().msg_type(zbus::message::Type::Signal);
+    if let Some(sender) = &sender {
+        rule = rule.sender(sender.as_str()).map_err(dbus_error)?;
+    }
+    if let Some(path) = &path {
+        rule = rule.path(path.as_str()).map_err(dbus_error)?;
+    }
+    if let Some(interface) = &interface {
+        rule = rule.interface(interface.as_str()).map_err(dbus_error)?;
+    }
+    if let Some(member) = &member {
+        rule = rule.member(member.as_str()).map_err(dbus_error)?;
+    }
+    let rule = rule.build().into_owned();
+
+    let mut stream = MessageStream::for_match_rule(rule, connection, None)
+        .await
+        .map_err(dbus_error)?;
+
+    let subscriptions = get_subscription_map(&lua);
+
+    // Create a registry key to keep the function alive
+    let registry_key: RegistryKey = lua.create_registry_value(callback)?;
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    let id = subscriptions.add(async move {
+        while let Some(message) = stream.next().await {
+            let message = match message {
+                Ok(message) => message,
+                Err(error) => {
+                    tracing::warn!("dbus signal error: {error}");
+                    continue;
+                }
+            };
+
+            let Some(lua) = weak_lua.try_upgrade() else {
+                break;
+            };
+
+            let result = async {
+                let callback = lua.registry_value::<Function>(&registry_key)?;
+                let signal = signal_table(&lua, &message)?;
+                dispatch(&lua, "dbus signal callback", callback, signal).await
+            };
+            if let Err(error) = result.await {
+                tracing::error!("dbus signal callback error: {error}");
+            }
+        }
+
+        if let Some(lua) = weak_lua.try_upgrade() {
+            let _ = lua.remove_registry_value(registry_key);
+        }
+    });
+
+    tracing::info!("created dbus subscription with id: {id}");
+    Ok(Subscription { id, subscriptions })
+}
+
+async fn session(_lua: Lua, (): ()) -> Result<Bus> {
+    Connection::session().await.map(Bus).map_err(dbus_error)
+}
+
+async fn system(_lua: Lua, (): ()) -> Result<Bus> {
+    Connection::system().await.map(Bus).map_err(dbus_error)
+}
+
+async fn connect(_lua: Lua, address: String) -> Result<Bus> {
+    zbus::connection::Builder::address(address.as_str())
+        .map_err(dbus_error)?
+        .build()
+        .await
+        .map(Bus)
+        .map_err(dbus_error)
+}
+
+fn variant(_lua: &Lua, (signature, value): (String, LuaValue)) -> Result<Variant> {
+    let value = to_dbus(&value, &parse_signature(&signature)?)?;
+    value.try_to_owned().map(Variant).map_err(dbus_error)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("session", lua.create_async_function(session)?)?;
+    module.set("system", lua.create_async_function(system)?)?;
+    module.set("connect", lua.create_async_function(connect)?)?;
+    module.set("variant", lua.create_function(variant)?)?;
+
+    libs.set("dbus", &module)?;
+    lua.register_module("dbus", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Private session bus, stopped when dropped
+    pub(crate) struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        /// Start a private `dbus-daemon`, `None` if it is not installed
+        pub(crate) fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+
+            Some(Self {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+
+        pub(crate) fn connect(&self) -> zbus::connection::Builder<'static> {
+            zbus::connection::Builder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn create_lua(bus: &TestBus) -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua.globals().set("address", bus.address.as_str()).unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_marshal_args() {
+        let lua = Lua::new();
+        let args = lua
+            .load(r#"return "name", 7, { a = 1 }, { "x", "y" }, { "x", 1 }"#)
+            .eval::<MultiValue>()
+            .unwrap()
+            .into_vec();
+
+        let values = marshal_args(&args, None).unwrap();
+        let signatures = values
+            .iter()
+            .map(|value| value.value_signature().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(signatures, vec!["s", "i", "a{sv}", "as", "av"]);
+
+        let values = marshal_args(&args[..2], Some("su")).unwrap();
+        assert_eq!(values[1], Value::U32(7));
+
+        let args = lua
+            .load(r#"return { "name", 300 }, "\0\255""#)
+            .eval::<MultiValue>()
+            .unwrap()
+            .into_vec();
+        let values = marshal_args(&args, Some("(sq)ay")).unwrap();
+        assert_eq!(values[0].value_signature().to_string(), "(sq)");
+        assert_eq!(values[1], Value::from(vec![0u8, 255]));
+
+        // A single structure argument is not split into its fields
+        assert!(marshal_args(&args[..1], Some("(sq)")).is_ok());
+
+        // Integers are range checked
+        let error = marshal_args(&args[..1], Some("(sy)")).unwrap_err();
+        assert!(error.to_string().contains("out of range"));
+        assert!(marshal_args(&args, Some("s")).is_err());
+    }
+
+    #[test]
+    fn test_unmarshal_values() {
+        let lua = Lua::new();
+
+        let mut dict = Dict::new(&Signature::Str, &Signature::Variant);
+        dict.append(
+            Value::from("volume"),
+            Value::Value(Box::new(Value::F64(0.5))),
+        )
+        .unwrap();
+        let value = from_dbus(&lua, &Value::Dict(dict)).unwrap();
+        let table = table(&value).unwrap();
+        assert_eq!(table.get::<f64>("volume").unwrap(), 0.5);
+
+        let value = from_dbus(&lua, &Value::from(b"raw".to_vec())).unwrap();
+        assert_eq!(string(&value).unwrap(), "raw");
+    }
+
+    #[tokio::test]
+    async fn test_call_and_properties() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        };
+        let lua = create_lua(&bus);
+
+        lua.load(
+            r#"
+            local bus = libs.dbus.connect(address)
+            assert(bus.name:sub(1, 1) == ":")
+
+            local names = bus:call({
+                destination = "org.freedesktop.DBus",
+                path = "/org/freedesktop/DBus",
+                interface = "org.freedesktop.DBus",
+                method = "ListNames",
+            })
+            local found = false
+            for _, name in ipairs(names) do
+                found = found or name == bus.name
+            end
+            assert(found, "own name not listed")
+
+            local owner = bus:call({
+                destination = "org.freedesktop.DBus",
+                path = "/org/freedesktop/DBus",
+                interface = "org.freedesktop.DBus",
+                method = "GetNameOwner",
+                signature = "s",
+                args = { "org.freedesktop.DBus" },
+            })
+            assert(owner == "org.freedesktop.DBus")
+
+            local interfaces = bus:get(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "Interfaces"
+            )
+            assert(type(interfaces) == "table")
+
+            local ok, err = pcall(bus.call, bus, {
+                destination = "org.freedesktop.DBus",
+                path = "/org/freedesktop/DBus",
+                interface = "org.freedesktop.DBus",
+                method = "NoSuchMethod",
+            })
+            assert(not ok and tostring(err):find("dbus error"))
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_signal() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        };
+        let lua = create_lua(&bus);
+
+        lua.load(
+            r#"
+            signals = {}
+            local bus = libs.dbus.connect(address)
+            subscription = bus:subscribe({
+                sender = "org.freedesktop.DBus",
+                interface = "org.freedesktop.DBus",
+                member = "NameOwnerChanged",
+                callback = function(signal)
+                    table.insert(signals, signal)
+                end,
+            })
+            other = libs.dbus.connect(address)
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let other: String = lua.load("return other.name").eval().unwrap();
+        let mut received = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            received = lua
+                .load("for _, s in ipairs(signals) do if s.args[1] == ... then return true end end return false")
+                .call::<bool>(other.as_str())
+                .unwrap();
+            if received {
+                break;
+            }
+        }
+        assert!(received, "NameOwnerChanged signal not received");
+
+        let signal: Table = lua.load("return signals[1]").eval().unwrap();
+        assert_eq!(signal.get::<String>("member").unwrap(), "NameOwnerChanged");
+        assert!(
+            lua.load("return subscription:cancel()")
+                .eval::<bool>()
+                .unwrap()
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use std::process::Command;
 
 use mlua::{Error, Function, Lua, MultiValue, Result, Table};
+use uniremote_core::Permission;
+
+use crate::permission::{denied_function, is_granted};
 
 static DEFAULT_OPEN_PROGRAM: &str = "xdg-open";
 
@@ -37,12 +40,21 @@ fn get_shell_function(libs: &Table) -> mlua::Result<Function> {
 
 fn load_os_functions(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     let os = lua.globals().get::<Table>("os")?;
+    os.set("throw", lua.create_function(throw)?)?;
+
+    // Spawning programs requires the same permission as `libs.script`
+    if !is_granted(lua, Permission::Script) {
+        for name in ["script", "open", "start"] {
+            os.set(name, denied_function(lua, Permission::Script)?)?;
+        }
+        return Ok(());
+    }
+
     if let Ok(shell_fn) = get_shell_function(libs) {
         os.set("script", shell_fn)?;
     }
     os.set("open", lua.create_function(open)?)?;
     os.set("start", lua.create_function(start)?)?;
-    os.set("throw", lua.create_function(throw)?)?;
     Ok(())
 }
 
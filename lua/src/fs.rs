@@ -1,19 +1,205 @@
+//! `libs.fs`: file system access confined to the filesystem roots.
+//!
+//! Paths are limited to the remote directory, its data directory and the home
+//! directory, or the roots declared in `meta.fsroots`.
+//! `watch(path, { recursive, debounce = 0.2 }?, callback)` reports `create`,
+//! `modify`, `delete` and `rename` events as `{ type, path, from, dir }` once
+//! a path has been quiet for `debounce` seconds, and returns a cancellable
+//! subscription.
+
 use std::{
+    collections::HashMap,
+    ffi::OsString,
     fs::{self, File},
-    io::Write,
+    io::{self, Write},
     path::{Path, PathBuf},
-    time::UNIX_EPOCH,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
 };
 
-use mlua::{Error, Lua, Result, Table, Value};
+use futures_util::StreamExt;
+use inotify::{Event, EventMask, EventStream, Inotify, WatchDescriptor, WatchMask, Watches};
+use mlua::{Error, Function, Lua, RegistryKey, Result, Table, Value, WeakLua};
+use tokio::time::{Instant, sleep_until};
 use uniremote_core::RemoteContext;
 
+use crate::{
+    callback::dispatch,
+    subscription::{Subscription, get_subscription_map},
+};
+
+/// Directories that `libs.fs` operations are confined to, minus excluded
+/// paths such as the secret store.
+///
+/// Lua states without roots (e.g. in tests) are unrestricted.
+#[derive(Clone, Debug)]
+pub struct FsRoots {
+    roots: Vec<PathBuf>,
+    excluded: Vec<PathBuf>,
+}
+
+impl FsRoots {
+    /// Create the set of roots, canonicalizing each one. Roots that do not
+    /// exist are skipped.
+    pub fn new(roots: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            roots: roots
+                .into_iter()
+                .filter_map(|root| {
+                    root.canonicalize()
+                        .inspect_err(|error| {
+                            tracing::warn!(
+                                "skipping filesystem root '{}': {error}",
+                                root.display()
+                            );
+                        })
+                        .ok()
+                })
+                .collect(),
+            excluded: Vec::new(),
+        }
+    }
+
+    /// Deny access to the given paths (and everything below them) even when
+    /// they are inside one of the roots. The paths do not have to exist yet.
+    pub fn exclude(mut self, paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.excluded.extend(paths.into_iter().filter_map(|path| {
+            std::path::absolute(&path)
+                .and_then(|path| canonicalize_lenient(&path))
+                .inspect_err(|error| {
+                    tracing::warn!("failed to exclude path '{}': {error}", path.display());
+                })
+                .ok()
+        }));
+        self
+    }
+
+    /// Roots for a remote: its own directory and data directory, plus either
+    /// the declared roots (with `~` expanded) or the user's home directory
+    pub fn for_remote(ctx: &RemoteContext, declared: Option<&[PathBuf]>) -> Self {
+        let extra = match declared {
+            Some(declared) => declared
+                .iter()
+                .map(|root| PathBuf::from(shellexpand::tilde(&root.to_string_lossy()).as_ref()))
+                .collect(),
+            None => dirs::home_dir().into_iter().collect::<Vec<_>>(),
+        };
+
+        Self::new(
+            [ctx.remote_dir.clone(), ctx.data_dir.clone()]
+                .into_iter()
+                .chain(extra),
+        )
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Whether a resolved path is a directory containing an excluded path, so
+    /// that deleting, moving or copying it would touch the excluded path too
+    fn contains_excluded(&self, path: &Path) -> bool {
+        self.excluded
+            .iter()
+            .any(|excluded| excluded.starts_with(path))
+    }
+
+    /// Resolve a path to its canonical form and make sure it stays within
+    /// one of the roots, following symlinks the same way `include` does
+    fn resolve(&self, path: &Path) -> Result<PathBuf> {
+        let canonical = std::path::absolute(path)
+            .and_then(|path| canonicalize_lenient(&path))
+            .map_err(|error| {
+                Error::runtime(format!(
+                    "failed to resolve path '{}': {error}",
+                    path.display()
+                ))
+            })?;
+
+        if self
+            .excluded
+            .iter()
+            .any(|excluded| canonical.starts_with(excluded))
+        {
+            Err(Error::runtime(format!(
+                "access denied: path '{}' is excluded from the filesystem roots",
+                path.display()
+            )))
+        } else if self.roots.iter().any(|root| canonical.starts_with(root)) {
+            Ok(canonical)
+        } else {
+            Err(Error::runtime(format!(
+                "access denied: path '{}' is outside the allowed filesystem roots",
+                path.display()
+            )))
+        }
+    }
+}
+
+/// Canonicalize a path that may not exist yet by canonicalizing its closest
+/// existing ancestor and appending the remaining components
+fn canonicalize_lenient(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+
+    loop {
+        match existing.canonicalize() {
+            Ok(mut canonical) => {
+                canonical.extend(missing.iter().rev());
+                return Ok(canonical);
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                // A dangling symlink would be followed on write and could
+                // create its target outside of the roots
+                if existing.is_symlink() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("'{}' is a dangling symlink", existing.display()),
+                    ));
+                }
+
+                // `..` or an empty path cannot be resolved without the
+                // directory existing
+                let (Some(name), Some(parent)) = (existing.file_name(), existing.parent()) else {
+                    return Err(error);
+                };
+                missing.push(name);
+                existing = parent;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
 fn get_remote_context(lua: &Lua) -> RemoteContext {
     lua.app_data_ref::<RemoteContext>()
         .expect("remote context not found in lua state")
         .clone()
 }
 
+/// Resolve a path passed from Lua against the filesystem roots
+fn resolve_path(lua: &Lua, path: &str) -> Result<PathBuf> {
+    match lua.app_data_ref::<FsRoots>() {
+        Some(roots) => roots.resolve(Path::new(path)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Resolve a path that is operated on as a whole tree (deleted, moved or
+/// copied), which must not contain any excluded path
+fn resolve_tree(lua: &Lua, path: &str) -> Result<PathBuf> {
+    let resolved = resolve_path(lua, path)?;
+    if lua
+        .app_data_ref::<FsRoots>()
+        .is_some_and(|roots| roots.contains_excluded(&resolved))
+    {
+        return Err(Error::runtime(format!(
+            "access denied: path '{path}' contains a path excluded from the filesystem roots"
+        )));
+    }
+    Ok(resolved)
+}
+
 // Context functions
 
 fn remotefile(lua: &Lua, _: ()) -> Result<String> {
@@ -52,6 +238,11 @@ fn appdir(lua: &Lua, _: ()) -> Result<String> {
         .to_string())
 }
 
+fn datadir(lua: &Lua, _: ()) -> Result<String> {
+    let ctx = get_remote_context(lua);
+    Ok(ctx.data_dir.display().to_string())
+}
+
 fn special(_lua: &Lua, _csidl: String) -> Result<String> {
     // CSIDL folders are Windows-specific and not supported
     Err(Error::runtime(
@@ -88,8 +279,8 @@ fn extension(_lua: &Lua, path: String) -> Result<String> {
         .to_string())
 }
 
-fn exists(_lua: &Lua, path: String) -> Result<bool> {
-    Ok(Path::new(&path).exists())
+fn exists(lua: &Lua, path: String) -> Result<bool> {
+    Ok(resolve_path(lua, &path)?.exists())
 }
 
 fn copy_dir_all(src: &Path, dst: &Path) -> std::io::Result<()> {
@@ -109,15 +300,15 @@ fn copy_dir_all(src: &Path, dst: &Path) -> std::io::Result<()> {
     Ok(())
 }
 
-fn copy(_lua: &Lua, (source, destination): (String, String)) -> Result<()> {
-    let src = Path::new(&source);
-    let dst = Path::new(&destination);
+fn copy(lua: &Lua, (source, destination): (String, String)) -> Result<()> {
+    let src = resolve_tree(lua, &source)?;
+    let dst = resolve_path(lua, &destination)?;
 
     if src.is_file() {
-        fs::copy(&source, &destination)
+        fs::copy(&src, &dst)
             .map_err(|error| Error::runtime(format!("failed to copy file: {error}")))?;
     } else if src.is_dir() {
-        copy_dir_all(src, dst)
+        copy_dir_all(&src, &dst)
             .map_err(|error| Error::runtime(format!("failed to copy directory: {error}")))?;
     } else {
         return Err(Error::runtime("source path does not exist"));
@@ -125,8 +316,11 @@ fn copy(_lua: &Lua, (source, destination): (String, String)) -> Result<()> {
     Ok(())
 }
 
-fn move_path(_lua: &Lua, (source, destination): (String, String)) -> Result<()> {
-    fs::rename(&source, &destination).map_err(|error| {
+fn move_path(lua: &Lua, (source, destination): (String, String)) -> Result<()> {
+    let src = resolve_tree(lua, &source)?;
+    let dst = resolve_path(lua, &destination)?;
+
+    fs::rename(&src, &dst).map_err(|error| {
         Error::runtime(format!(
             "failed to move '{source}' to '{destination}': {error}"
         ))
@@ -139,12 +333,22 @@ fn rename(lua: &Lua, (source, destination): (String, String)) -> Result<()> {
     move_path(lua, (source, destination))
 }
 
-fn delete(_lua: &Lua, (path, recursive): (String, Option<bool>)) -> Result<()> {
-    let path = Path::new(&path);
+fn delete(lua: &Lua, (path, recursive): (String, Option<bool>)) -> Result<()> {
+    let path = resolve_tree(lua, &path)?;
     let recursive = recursive.unwrap_or(false);
 
+    if lua
+        .app_data_ref::<FsRoots>()
+        .is_some_and(|roots| roots.roots().contains(&path))
+    {
+        return Err(Error::runtime(format!(
+            "access denied: filesystem root '{}' cannot be deleted",
+            path.display()
+        )));
+    }
+
     if path.is_file() {
-        fs::remove_file(path).map_err(|error| {
+        fs::remove_file(&path).map_err(|error| {
             Error::runtime(format!(
                 "failed to delete file '{}': {error}",
                 path.display()
@@ -152,14 +356,14 @@ fn delete(_lua: &Lua, (path, recursive): (String, Option<bool>)) -> Result<()> {
         })?;
     } else if path.is_dir() {
         if recursive {
-            fs::remove_dir_all(path).map_err(|error| {
+            fs::remove_dir_all(&path).map_err(|error| {
                 Error::runtime(format!(
                     "failed to delete directory '{}': {error}",
                     path.display()
                 ))
             })?;
         } else {
-            fs::remove_dir(path).map_err(|error| {
+            fs::remove_dir(&path).map_err(|error| {
                 Error::runtime(format!(
                     "failed to delete directory '{}': {error}",
                     path.display()
@@ -217,11 +421,21 @@ fn absolute(lua: &Lua, rel: String) -> Result<String> {
     Ok(abs.display().to_string())
 }
 
-fn temp(_lua: &Lua, _: ()) -> Result<String> {
-    let temp_file = tempfile::Builder::new()
-        .prefix("uniremote_")
-        .tempfile()
-        .map_err(|error| Error::runtime(format!("failed to create temp file: {error}")))?;
+fn temp(lua: &Lua, _: ()) -> Result<String> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("uniremote_");
+
+    // Sandboxed remotes can only write inside their roots, so temp files are
+    // created in the remote's data directory instead of the system one
+    let temp_file = if lua.app_data_ref::<FsRoots>().is_some() {
+        let temp_dir = get_remote_context(lua).data_dir.join("tmp");
+        fs::create_dir_all(&temp_dir)
+            .map_err(|error| Error::runtime(format!("failed to create temp directory: {error}")))?;
+        builder.tempfile_in(temp_dir)
+    } else {
+        builder.tempfile()
+    }
+    .map_err(|error| Error::runtime(format!("failed to create temp file: {error}")))?;
 
     let path = temp_file.path().to_path_buf();
     // Drop the temp file so it doesn't get cleaned up automatically
@@ -232,17 +446,24 @@ fn temp(_lua: &Lua, _: ()) -> Result<String> {
 
 // Tree functions
 
-fn roots(_lua: &Lua, _: ()) -> Result<Vec<String>> {
-    Ok(vec!["/".to_string()])
+fn roots(lua: &Lua, _: ()) -> Result<Vec<String>> {
+    match lua.app_data_ref::<FsRoots>() {
+        Some(roots) => Ok(roots
+            .roots()
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect()),
+        None => Ok(vec!["/".to_string()]),
+    }
 }
 
-fn files(_lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String>> {
-    let path = Path::new(&path);
+fn files(lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String>> {
+    let path = resolve_path(lua, &path)?;
     let show_hidden = hidden.unwrap_or(false);
 
     let mut files = Vec::new();
 
-    let entries = fs::read_dir(path).map_err(|error| {
+    let entries = fs::read_dir(&path).map_err(|error| {
         Error::runtime(format!(
             "failed to read directory '{}': {error}",
             path.display()
@@ -273,13 +494,13 @@ fn files(_lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<Strin
     Ok(files)
 }
 
-fn dirs(_lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String>> {
-    let path = Path::new(&path);
+fn dirs(lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String>> {
+    let path = resolve_path(lua, &path)?;
     let show_hidden = hidden.unwrap_or(false);
 
     let mut dirs = Vec::new();
 
-    let entries = fs::read_dir(path).map_err(|error| {
+    let entries = fs::read_dir(&path).map_err(|error| {
         Error::runtime(format!(
             "failed to read directory '{}': {error}",
             path.display()
@@ -310,13 +531,13 @@ fn dirs(_lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String
     Ok(dirs)
 }
 
-fn list(_lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String>> {
-    let path = Path::new(&path);
+fn list(lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String>> {
+    let path = resolve_path(lua, &path)?;
     let show_hidden = hidden.unwrap_or(false);
 
     let mut items = Vec::new();
 
-    let entries = fs::read_dir(path).map_err(|error| {
+    let entries = fs::read_dir(&path).map_err(|error| {
         Error::runtime(format!(
             "failed to read directory '{}': {error}",
             path.display()
@@ -346,9 +567,9 @@ fn list(_lua: &Lua, (path, hidden): (String, Option<bool>)) -> Result<Vec<String
 
 // Create functions
 
-fn createdir(_lua: &Lua, path: String) -> Result<()> {
-    let path = Path::new(&path);
-    fs::create_dir(path).map_err(|error| {
+fn createdir(lua: &Lua, path: String) -> Result<()> {
+    let path = resolve_path(lua, &path)?;
+    fs::create_dir(&path).map_err(|error| {
         Error::runtime(format!(
             "failed to create directory '{}': {error}",
             path.display()
@@ -356,9 +577,9 @@ fn createdir(_lua: &Lua, path: String) -> Result<()> {
     })
 }
 
-fn createdirs(_lua: &Lua, path: String) -> Result<()> {
-    let path = Path::new(&path);
-    fs::create_dir_all(path).map_err(|error| {
+fn createdirs(lua: &Lua, path: String) -> Result<()> {
+    let path = resolve_path(lua, &path)?;
+    fs::create_dir_all(&path).map_err(|error| {
         Error::runtime(format!(
             "failed to create directories '{}': {error}",
             path.display()
@@ -366,9 +587,9 @@ fn createdirs(_lua: &Lua, path: String) -> Result<()> {
     })
 }
 
-fn createfile(_lua: &Lua, path: String) -> Result<()> {
-    let path = Path::new(&path);
-    File::create(path).map_err(|error| {
+fn createfile(lua: &Lua, path: String) -> Result<()> {
+    let path = resolve_path(lua, &path)?;
+    File::create(&path).map_err(|error| {
         Error::runtime(format!(
             "failed to create file '{}': {error}",
             path.display()
@@ -379,9 +600,9 @@ fn createfile(_lua: &Lua, path: String) -> Result<()> {
 
 // Read & Write functions
 
-fn write(_lua: &Lua, (path, content): (String, String)) -> Result<()> {
-    let path = Path::new(&path);
-    fs::write(path, content).map_err(|error| {
+fn write(lua: &Lua, (path, content): (String, String)) -> Result<()> {
+    let path = resolve_path(lua, &path)?;
+    fs::write(&path, content).map_err(|error| {
         Error::runtime(format!(
             "failed to write to file '{}': {error}",
             path.display()
@@ -390,7 +611,7 @@ fn write(_lua: &Lua, (path, content): (String, String)) -> Result<()> {
 }
 
 fn writelines(lua: &Lua, (path, lines): (String, Value)) -> Result<()> {
-    let path = Path::new(&path);
+    let path = resolve_path(lua, &path)?;
 
     // Convert Lua table to Vec<String>
     let lines_table: Table = lua.unpack(lines)?;
@@ -402,7 +623,7 @@ fn writelines(lua: &Lua, (path, lines): (String, Value)) -> Result<()> {
     }
 
     let content = lines_vec.join("\n");
-    fs::write(path, content).map_err(|error| {
+    fs::write(&path, content).map_err(|error| {
         Error::runtime(format!(
             "failed to write lines to file '{}': {error}",
             path.display()
@@ -410,12 +631,12 @@ fn writelines(lua: &Lua, (path, lines): (String, Value)) -> Result<()> {
     })
 }
 
-fn append(_lua: &Lua, (path, content): (String, String)) -> Result<()> {
-    let path = Path::new(&path);
+fn append(lua: &Lua, (path, content): (String, String)) -> Result<()> {
+    let path = resolve_path(lua, &path)?;
     let mut file = fs::OpenOptions::new()
         .append(true)
         .create(true)
-        .open(path)
+        .open(&path)
         .map_err(|error| {
             Error::runtime(format!(
                 "failed to open file '{}' for appending: {error}",
@@ -432,7 +653,7 @@ fn append(_lua: &Lua, (path, content): (String, String)) -> Result<()> {
 }
 
 fn appendlines(lua: &Lua, (path, lines): (String, Value)) -> Result<()> {
-    let path = Path::new(&path);
+    let path = resolve_path(lua, &path)?;
 
     // Convert Lua table to Vec<String>
     let lines_table: Table = lua.unpack(lines)?;
@@ -448,7 +669,7 @@ fn appendlines(lua: &Lua, (path, lines): (String, Value)) -> Result<()> {
     let mut file = fs::OpenOptions::new()
         .append(true)
         .create(true)
-        .open(path)
+        .open(&path)
         .map_err(|error| {
             Error::runtime(format!(
                 "failed to open file '{}' for appending: {error}",
@@ -464,16 +685,16 @@ fn appendlines(lua: &Lua, (path, lines): (String, Value)) -> Result<()> {
     })
 }
 
-fn read(_lua: &Lua, path: String) -> Result<String> {
-    let path = Path::new(&path);
-    fs::read_to_string(path).map_err(|error| {
+fn read(lua: &Lua, path: String) -> Result<String> {
+    let path = resolve_path(lua, &path)?;
+    fs::read_to_string(&path).map_err(|error| {
         Error::runtime(format!("failed to read file '{}': {error}", path.display()))
     })
 }
 
 fn readlines(lua: &Lua, path: String) -> Result<Table> {
-    let path = Path::new(&path);
-    let content = fs::read_to_string(path).map_err(|error| {
+    let path = resolve_path(lua, &path)?;
+    let content = fs::read_to_string(&path).map_err(|error| {
         Error::runtime(format!("failed to read file '{}': {error}", path.display()))
     })?;
 
@@ -487,18 +708,18 @@ fn readlines(lua: &Lua, path: String) -> Result<Table> {
 
 // Attribute functions
 
-fn isfile(_lua: &Lua, path: String) -> Result<bool> {
-    let path = Path::new(&path);
+fn isfile(lua: &Lua, path: String) -> Result<bool> {
+    let path = resolve_path(lua, &path)?;
     Ok(path.is_file())
 }
 
-fn isdir(_lua: &Lua, path: String) -> Result<bool> {
-    let path = Path::new(&path);
+fn isdir(lua: &Lua, path: String) -> Result<bool> {
+    let path = resolve_path(lua, &path)?;
     Ok(path.is_dir())
 }
 
-fn ishidden(_lua: &Lua, path: String) -> Result<bool> {
-    let path = Path::new(&path);
+fn ishidden(lua: &Lua, path: String) -> Result<bool> {
+    let path = resolve_path(lua, &path)?;
 
     #[cfg(unix)]
     {
@@ -530,8 +751,8 @@ fn ishidden(_lua: &Lua, path: String) -> Result<bool> {
     }
 }
 
-fn size(_lua: &Lua, path: String) -> Result<u64> {
-    let path = Path::new(&path);
+fn size(lua: &Lua, path: String) -> Result<u64> {
+    let path = resolve_path(lua, &path)?;
     let metadata = path.metadata().map_err(|error| {
         Error::runtime(format!(
             "failed to get metadata for '{}': {error}",
@@ -542,7 +763,7 @@ fn size(_lua: &Lua, path: String) -> Result<u64> {
     if metadata.is_dir() {
         // For directories, calculate total size of all files
         let mut total_size = 0;
-        for entry in walkdir::WalkDir::new(path) {
+        for entry in walkdir::WalkDir::new(&path) {
             let entry = entry
                 .map_err(|error| Error::runtime(format!("failed to walk directory: {error}")))?;
             if entry.file_type().is_file() {
@@ -560,8 +781,8 @@ fn size(_lua: &Lua, path: String) -> Result<u64> {
     }
 }
 
-fn created(_lua: &Lua, path: String) -> Result<u64> {
-    let path = Path::new(&path);
+fn created(lua: &Lua, path: String) -> Result<u64> {
+    let path = resolve_path(lua, &path)?;
     let metadata = path.metadata().map_err(|error| {
         Error::runtime(format!(
             "failed to get metadata for '{}': {error}",
@@ -583,8 +804,8 @@ fn created(_lua: &Lua, path: String) -> Result<u64> {
     Ok(duration.as_secs())
 }
 
-fn modified(_lua: &Lua, path: String) -> Result<u64> {
-    let path = Path::new(&path);
+fn modified(lua: &Lua, path: String) -> Result<u64> {
+    let path = resolve_path(lua, &path)?;
     let metadata = path.metadata().map_err(|error| {
         Error::runtime(format!(
             "failed to get metadata for '{}': {error}",
@@ -606,6 +827,393 @@ fn modified(_lua: &Lua, path: String) -> Result<u64> {
     Ok(duration.as_secs())
 }
 
+// Watch functions
+
+/// Quiet period before the events of a path are delivered
+const DEFAULT_WATCH_DEBOUNCE: f64 = 0.2;
+
+/// Watch subscriptions of the Lua state, cancelled on `destroy`
+#[derive(Clone, Default)]
+struct WatchIds(Arc<Mutex<Vec<u64>>>);
+
+impl WatchIds {
+    fn insert(&self, id: u64) -> WatchGuard {
+        self.0.lock().unwrap().push(id);
+        WatchGuard {
+            ids: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Owned by the task of a watch, so that its id is forgotten once the watch
+/// ends or is cancelled
+struct WatchGuard {
+    ids: WatchIds,
+    id: u64,
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        self.ids.0.lock().unwrap().retain(|id| *id != self.id);
+    }
+}
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::MODIFY
+        | WatchMask::DELETE
+        | WatchMask::DELETE_SELF
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WatchEventKind {
+    Create,
+    Modify,
+    Delete,
+    Rename,
+}
+
+impl WatchEventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Modify => "modify",
+            Self::Delete => "delete",
+            Self::Rename => "rename",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WatchEvent {
+    kind: WatchEventKind,
+    path: PathBuf,
+    from: Option<PathBuf>,
+    dir: bool,
+    cookie: Option<u32>,
+    updated: Instant,
+}
+
+impl WatchEvent {
+    fn to_table(&self, lua: &Lua) -> Result<Table> {
+        let table = lua.create_table()?;
+        table.set("type", self.kind.as_str())?;
+        table.set("path", self.path.display().to_string())?;
+        if let Some(from) = &self.from {
+            table.set("from", from.display().to_string())?;
+        }
+        table.set("dir", self.dir)?;
+        Ok(table)
+    }
+}
+
+/// Events waiting for their path to settle, merged per path so that a file
+/// being written is reported once
+struct PendingEvents {
+    events: Vec<WatchEvent>,
+    debounce: Duration,
+}
+
+impl PendingEvents {
+    fn new(debounce: Duration) -> Self {
+        Self {
+            events: Vec::new(),
+            debounce,
+        }
+    }
+
+    fn push(&mut self, kind: WatchEventKind, path: PathBuf, from: Option<PathBuf>, dir: bool) {
+        let updated = Instant::now();
+
+        let Some(index) = self.events.iter().position(|event| event.path == path) else {
+            self.events.push(WatchEvent {
+                kind,
+                path,
+                from,
+                dir,
+                cookie: None,
+                updated,
+            });
+            return;
+        };
+
+        use WatchEventKind::*;
+        let existing = &mut self.events[index];
+        let kind = match (existing.kind, kind) {
+            // A file that came and went within the debounce period
+            (Create, Delete) => {
+                self.events.remove(index);
+                return;
+            }
+            (Create | Rename, Modify) => existing.kind,
+            (Delete, Create) => Modify,
+            (_, kind) => kind,
+        };
+
+        existing.kind = kind;
+        existing.dir |= dir;
+        existing.updated = updated;
+        if kind == Rename {
+            existing.from = from;
+        }
+    }
+
+    /// First half of a rename, reported as a deletion unless the matching
+    /// `moved_to` arrives
+    fn moved_from(&mut self, cookie: u32, path: PathBuf, dir: bool) {
+        self.push(WatchEventKind::Delete, path.clone(), None, dir);
+        if let Some(event) = self.events.iter_mut().find(|event| event.path == path) {
+            event.cookie = Some(cookie);
+        }
+    }
+
+    fn moved_to(&mut self, cookie: u32, path: PathBuf, dir: bool) {
+        let from = self
+            .events
+            .iter()
+            .position(|event| event.cookie == Some(cookie))
+            .map(|index| self.events.remove(index).path);
+
+        match from {
+            Some(from) => self.push(WatchEventKind::Rename, path, Some(from), dir),
+            None => self.push(WatchEventKind::Create, path, None, dir),
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.events
+            .iter()
+            .map(|event| event.updated + self.debounce)
+            .min()
+    }
+
+    fn take_ready(&mut self) -> Vec<WatchEvent> {
+        let now = Instant::now();
+        let (ready, pending) = std::mem::take(&mut self.events)
+            .into_iter()
+            .partition(|event| event.updated + self.debounce <= now);
+        self.events = pending;
+        ready
+    }
+}
+
+/// inotify watches of a path, and of its subdirectories when recursive
+struct Watcher {
+    events: EventStream<Vec<u8>>,
+    watches: Watches,
+    paths: HashMap<WatchDescriptor, PathBuf>,
+    recursive: bool,
+}
+
+impl Watcher {
+    fn new(path: &Path, recursive: bool) -> io::Result<Self> {
+        let inotify = Inotify::init()?;
+        let mut watcher = Self {
+            watches: inotify.watches(),
+            events: inotify.into_event_stream(vec![0; 4096])?,
+            paths: HashMap::new(),
+            recursive,
+        };
+        watcher.add(path)?;
+        Ok(watcher)
+    }
+
+    fn add(&mut self, path: &Path) -> io::Result<()> {
+        let wd = self.watches.add(path, watch_mask())?;
+        self.paths.insert(wd, path.to_path_buf());
+
+        if self.recursive && path.is_dir() {
+            let dirs = walkdir::WalkDir::new(path)
+                .min_depth(1)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_dir());
+            for entry in dirs {
+                // Watching a moved directory again returns its existing
+                // descriptor, which updates the path it reports
+                let wd = self.watches.add(entry.path(), watch_mask())?;
+                self.paths.insert(wd, entry.into_path());
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, event: Event<OsString>, pending: &mut PendingEvents) {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            tracing::warn!("file system watch queue overflowed, events were lost");
+            return;
+        }
+        if event.mask.contains(EventMask::IGNORED) {
+            self.paths.remove(&event.wd);
+            return;
+        }
+
+        let Some(base) = self.paths.get(&event.wd) else {
+            return;
+        };
+        let path = match &event.name {
+            Some(name) => base.join(name),
+            None => base.clone(),
+        };
+        let dir = event.mask.contains(EventMask::ISDIR);
+
+        // Follow directories created or moved into a recursive watch
+        let appeared = event
+            .mask
+            .intersects(EventMask::CREATE | EventMask::MOVED_TO);
+        if dir
+            && appeared
+            && self.recursive
+            && let Err(error) = self.add(&path)
+        {
+            tracing::warn!("failed to watch '{}': {error}", path.display());
+        }
+
+        if event.mask.contains(EventMask::CREATE) {
+            pending.push(WatchEventKind::Create, path, None, dir);
+        } else if event.mask.contains(EventMask::MODIFY) {
+            pending.push(WatchEventKind::Modify, path, None, dir);
+        } else if event
+            .mask
+            .intersects(EventMask::DELETE | EventMask::DELETE_SELF)
+        {
+            pending.push(WatchEventKind::Delete, path, None, dir);
+        } else if event.mask.contains(EventMask::MOVED_FROM) {
+            pending.moved_from(event.cookie, path, dir);
+        } else if event.mask.contains(EventMask::MOVED_TO) {
+            pending.moved_to(event.cookie, path, dir);
+        }
+    }
+}
+
+/// Watch a file or directory, calling `callback(event)` with debounced
+/// `{ type, path, from, dir }` events
+fn watch(
+    lua: &Lua,
+    (path, options, callback): (String, Value, Option<Function>),
+) -> Result<Subscription> {
+    let (options, callback) = match (options, callback) {
+        (Value::Function(callback), None) => (None, callback),
+        (Value::Table(options), Some(callback)) => (Some(options), callback),
+        (Value::Nil, Some(callback)) => (None, callback),
+        _ => return Err(Error::runtime("expected watch(path, options?, callback)")),
+    };
+
+    let (recursive, debounce) = match &options {
+        Some(options) => (
+            options.get::<Option<bool>>("recursive")?.unwrap_or(false),
+            options.get::<Option<f64>>("debounce")?,
+        ),
+        None => (false, None),
+    };
+    let debounce = Duration::try_from_secs_f64(debounce.unwrap_or(DEFAULT_WATCH_DEBOUNCE))
+        .map_err(|error| Error::runtime(format!("invalid debounce: {error}")))?;
+
+    let path = resolve_path(lua, &path)?;
+    let watcher = Watcher::new(&path, recursive).map_err(|error| {
+        Error::runtime(format!("failed to watch '{}': {error}", path.display()))
+    })?;
+
+    let subscriptions = get_subscription_map(lua);
+
+    // Create a registry key to keep the function alive
+    let registry_key = lua.create_registry_value(callback)?;
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    let ids = get_watch_ids(lua);
+    let id = subscriptions.add_with(|id| {
+        let guard = ids.insert(id);
+        async move {
+            run_watch(watcher, debounce, weak_lua, registry_key).await;
+            drop(guard);
+        }
+    });
+
+    tracing::info!(
+        "created watch subscription with id: {id} for '{}'",
+        path.display()
+    );
+    Ok(Subscription { id, subscriptions })
+}
+
+/// Deliver the debounced events of a watch until it has nothing left to
+/// watch or the Lua state is gone
+async fn run_watch(
+    mut watcher: Watcher,
+    debounce: Duration,
+    weak_lua: WeakLua,
+    registry_key: RegistryKey,
+) {
+    let mut pending = PendingEvents::new(debounce);
+
+    while !watcher.paths.is_empty() {
+        let deadline = pending.deadline();
+        tokio::select! {
+            event = watcher.events.next() => match event {
+                Some(Ok(event)) => watcher.handle(event, &mut pending),
+                Some(Err(error)) => {
+                    tracing::error!("file system watch error: {error}");
+                    break;
+                }
+                None => break,
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let Some(lua) = weak_lua.try_upgrade() else {
+                    break;
+                };
+                let Ok(callback) = lua.registry_value::<Function>(&registry_key) else {
+                    break;
+                };
+
+                for event in pending.take_ready() {
+                    let result = async {
+                        let event = event.to_table(&lua)?;
+                        dispatch(&lua, "watch callback", callback.clone(), event).await
+                    };
+                    if let Err(error) = result.await {
+                        tracing::error!("watch callback error: {error}");
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(lua) = weak_lua.try_upgrade() {
+        let _ = lua.remove_registry_value(registry_key);
+    }
+}
+
+fn get_watch_ids(lua: &Lua) -> WatchIds {
+    if let Some(ids) = lua.app_data_ref::<WatchIds>() {
+        return ids.clone();
+    }
+
+    let ids = WatchIds::default();
+    lua.set_app_data(ids.clone());
+    ids
+}
+
+/// Cancel all file system watches of the Lua state
+pub(crate) fn unwatch_all(lua: &Lua) {
+    let Some(ids) = lua.app_data_ref::<WatchIds>() else {
+        return;
+    };
+
+    // Taken before cancelling, as the guards of cancelled watches lock the ids
+    let ids = std::mem::take(&mut *ids.0.lock().unwrap());
+    let subscriptions = get_subscription_map(lua);
+    for id in ids {
+        if subscriptions.remove(id) {
+            tracing::info!("cancelled watch subscription with id: {id}");
+        }
+    }
+}
+
 pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     let module = lua.create_table()?;
 
@@ -617,6 +1225,7 @@ pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     // Directories
     module.set("homedir", lua.create_function(homedir)?)?;
     module.set("appdir", lua.create_function(appdir)?)?;
+    module.set("datadir", lua.create_function(datadir)?)?;
     module.set("special", lua.create_function(special)?)?;
 
     // Common
@@ -664,6 +1273,9 @@ pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     module.set("created", lua.create_function(created)?)?;
     module.set("modified", lua.create_function(modified)?)?;
 
+    // Watch
+    module.set("watch", lua.create_function(watch)?)?;
+
     libs.set("fs", &module)?;
     lua.register_module("fs", module)?;
     Ok(())
@@ -921,4 +1533,275 @@ mod tests {
         assert_eq!(line2, "Line 2");
         assert_eq!(line3, "Line 3");
     }
+
+    fn sandboxed_lua(root: &Path) -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        lua.set_app_data(FsRoots::new([root.to_path_buf()]));
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_fs_roots_allow_paths_inside() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lua = sandboxed_lua(temp_dir.path());
+        lua.globals()
+            .set("root", temp_dir.path().display().to_string())
+            .unwrap();
+
+        lua.load(
+            r#"
+            local fs = require("fs")
+            fs.createdirs(root .. "/a/b")
+            fs.write(root .. "/a/b/file.txt", "data")
+            result = fs.read(root .. "/a/b/../b/file.txt")
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        let result: String = lua.globals().get("result").unwrap();
+        assert_eq!(result, "data");
+    }
+
+    #[test]
+    fn test_fs_roots_deny_paths_outside() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(temp_dir.path().join("secret.txt"), "secret").unwrap();
+
+        let lua = sandboxed_lua(&root);
+        lua.globals()
+            .set("root", root.display().to_string())
+            .unwrap();
+
+        for script in [
+            r#"require("fs").read(root .. "/../secret.txt")"#,
+            r#"require("fs").write(root .. "/../new.txt", "data")"#,
+            r#"require("fs").delete(root .. "/..", true)"#,
+            r#"require("fs").list("/")"#,
+        ] {
+            let error = lua.load(script).exec().unwrap_err().to_string();
+            assert!(error.contains("access denied"), "{script}: {error}");
+        }
+
+        assert!(temp_dir.path().join("secret.txt").exists());
+        assert!(!temp_dir.path().join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_fs_roots_deny_symlink_escape() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(temp_dir.path().join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(temp_dir.path(), root.join("escape")).unwrap();
+
+        let lua = sandboxed_lua(&root);
+        lua.globals()
+            .set("root", root.display().to_string())
+            .unwrap();
+
+        let error = lua
+            .load(r#"require("fs").read(root .. "/escape/secret.txt")"#)
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("access denied"), "{error}");
+    }
+
+    #[test]
+    fn test_fs_roots_deny_dangling_symlink() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let outside = temp_dir.path().join("outside.txt");
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("missing"), root.join("dir")).unwrap();
+
+        let lua = sandboxed_lua(&root);
+        lua.globals()
+            .set("root", root.display().to_string())
+            .unwrap();
+
+        for script in [
+            r#"require("fs").write(root .. "/link", "data")"#,
+            r#"require("fs").append(root .. "/link", "data")"#,
+            r#"require("fs").createfile(root .. "/link")"#,
+            r#"require("fs").createdirs(root .. "/dir/sub")"#,
+        ] {
+            let error = lua.load(script).exec().unwrap_err().to_string();
+            assert!(error.contains("dangling symlink"), "{script}: {error}");
+        }
+
+        assert!(!outside.exists());
+        assert!(!temp_dir.path().join("missing").exists());
+    }
+
+    #[test]
+    fn test_fs_roots_deny_excluded_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = temp_dir.path().join("config");
+        std::fs::create_dir(&config).unwrap();
+        std::fs::write(config.join("secrets.key"), "key").unwrap();
+        std::fs::write(config.join("other.txt"), "other").unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        lua.set_app_data(
+            FsRoots::new([temp_dir.path().to_path_buf()]).exclude([config.join("secrets.key")]),
+        );
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua.globals()
+            .set("root", temp_dir.path().display().to_string())
+            .unwrap();
+
+        for script in [
+            r#"require("fs").read(root .. "/config/secrets.key")"#,
+            r#"require("fs").read(root .. "/config/../config/secrets.key")"#,
+            r#"require("fs").write(root .. "/config/secrets.key", "data")"#,
+            r#"require("fs").copy(root .. "/config", root .. "/copy")"#,
+            r#"require("fs").move(root .. "/config", root .. "/moved")"#,
+            r#"require("fs").delete(root .. "/config", true)"#,
+        ] {
+            let error = lua.load(script).exec().unwrap_err().to_string();
+            assert!(error.contains("access denied"), "{script}: {error}");
+        }
+
+        let other: String = lua
+            .load(r#"return require("fs").read(root .. "/config/other.txt")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(other, "other");
+        assert_eq!(
+            std::fs::read_to_string(config.join("secrets.key")).unwrap(),
+            "key"
+        );
+        assert!(!temp_dir.path().join("copy").exists());
+    }
+
+    fn watch_lua(root: &Path, options: &str) -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua.globals()
+            .set("root", root.display().to_string())
+            .unwrap();
+
+        lua.load(format!(
+            r#"
+            events = {{}}
+            subscription = require("fs").watch(root, {options}, function(event)
+                local line = event.type .. " " .. event.path:sub(#root + 2)
+                if event.from then
+                    line = line .. " " .. event.from:sub(#root + 2)
+                end
+                table.insert(events, line)
+            end)
+        "#
+        ))
+        .exec()
+        .unwrap();
+        lua
+    }
+
+    async fn take_events(lua: &Lua) -> Vec<String> {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let events: Vec<String> = lua.globals().get("events").unwrap();
+        lua.load("events = {}").exec().unwrap();
+        events
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        std::fs::create_dir(root.join("existing")).unwrap();
+        let lua = watch_lua(root, "{ recursive = true, debounce = 0.05 }");
+
+        std::fs::write(root.join("a.txt"), "a").unwrap();
+        std::fs::write(root.join("existing/b.txt"), "b").unwrap();
+        assert_eq!(
+            take_events(&lua).await,
+            ["create a.txt", "create existing/b.txt"]
+        );
+
+        // A change followed by a rename is reported as the rename
+        std::fs::write(root.join("a.txt"), "changed").unwrap();
+        std::fs::rename(root.join("a.txt"), root.join("c.txt")).unwrap();
+        assert_eq!(take_events(&lua).await, ["rename c.txt a.txt"]);
+
+        // New directories are followed in recursive watches
+        std::fs::create_dir(root.join("new")).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(root.join("new/d.txt"), "d").unwrap();
+        std::fs::remove_file(root.join("c.txt")).unwrap();
+        assert_eq!(
+            take_events(&lua).await,
+            ["create new", "create new/d.txt", "delete c.txt"]
+        );
+
+        // Moving out of the watched tree is a deletion
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::rename(root.join("new/d.txt"), outside.path().join("d.txt")).unwrap();
+        assert_eq!(take_events(&lua).await, ["delete new/d.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_debounce() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let lua = watch_lua(root, "{ debounce = 0.1 }");
+
+        // Writes in quick succession are reported once
+        let mut file = File::create(root.join("download.part")).unwrap();
+        for _ in 0..5 {
+            file.write_all(b"chunk").unwrap();
+            file.flush().unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        drop(file);
+        assert_eq!(take_events(&lua).await, ["create download.part"]);
+
+        // Files that come and go within the debounce period are not reported
+        std::fs::write(root.join("temp"), "").unwrap();
+        std::fs::remove_file(root.join("temp")).unwrap();
+        assert!(take_events(&lua).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watch_cancel() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        let lua = watch_lua(root, "{ debounce = 0.05 }");
+
+        assert_eq!(get_watch_ids(&lua).0.lock().unwrap().len(), 1);
+        lua.load("assert(subscription:cancel())").exec().unwrap();
+        std::fs::write(root.join("a.txt"), "a").unwrap();
+        assert!(take_events(&lua).await.is_empty());
+        assert!(get_watch_ids(&lua).0.lock().unwrap().is_empty());
+
+        // Watches left open are cancelled on destroy
+        lua.load(
+            r#"require("fs").watch(root, function(event) table.insert(events, event.type) end)"#,
+        )
+        .exec()
+        .unwrap();
+        unwatch_all(&lua);
+        std::fs::write(root.join("b.txt"), "b").unwrap();
+        assert!(take_events(&lua).await.is_empty());
+
+        let error = lua
+            .load(r#"require("fs").watch(root .. "/missing", function() end)"#)
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("failed to watch"), "{error}");
+    }
 }
@@ -1,6 +1,6 @@
 use std::path::Path;
 
-use mlua::{Error, Lua};
+use mlua::{ChunkMode, Error, Lua};
 
 pub fn load(lua: &Lua, remote_dir: &Path, remotes_dir: &Path) -> anyhow::Result<()> {
     init_global_tables(lua)?;
@@ -51,10 +51,11 @@ fn load_include(lua: &Lua, remote_dir: &Path, remotes_dir: &Path) -> anyhow::Res
             ))
         })?;
 
-        // Execute the script in the current lua context
-        // Use the resolved file path for better debugging information
+        // Execute the script in the current lua context, refusing precompiled
+        // bytecode. Use the resolved file path for better debugging information
         lua.load(script_content)
             .set_name(file_path_canonical.display().to_string())
+            .set_mode(ChunkMode::Text)
             .exec()
             .map_err(|error| Error::runtime(format!("failed to execute included file: {error}")))?;
 
@@ -317,4 +318,27 @@ result = actions.foo()
         assert_eq!(lua.globals().get::<f64>("result4").unwrap(), 130.0);
         assert_eq!(lua.globals().get::<f64>("result5").unwrap(), 130.0);
     }
+
+    #[test]
+    fn test_include_rejects_bytecode() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_path = temp_dir.path();
+
+        let lua = Lua::new();
+        load(&lua, temp_path, temp_path).unwrap();
+
+        let bytecode: mlua::String = lua
+            .load(r#"return string.dump(function() compiled = true end)"#)
+            .eval()
+            .unwrap();
+        fs::write(temp_path.join("compiled.lua"), bytecode.as_bytes()).unwrap();
+
+        let error = lua
+            .load(r#"include("compiled.lua")"#)
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("attempt to load a binary chunk"), "{error}");
+        assert_eq!(lua.globals().get::<Option<bool>>("compiled").unwrap(), None);
+    }
 }
@@ -0,0 +1,120 @@
+//! `libs.hash`: message digests.
+//!
+//! `md5`, `sha1`, `sha256`, `sha384`, `sha512(data, encoding?)` and
+//! `hmac(algorithm, key, data, encoding?)` return hex digests by default, or
+//! `"base64"` or `"raw"` ones.
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use mlua::{Error, Lua, Result, Table, Value};
+use ring::{digest, hmac};
+
+/// Format a digest as lowercase hex (default), `"base64"` or `"raw"` bytes
+fn format_digest(lua: &Lua, bytes: &[u8], encoding: Option<String>) -> Result<Value> {
+    match encoding.as_deref().unwrap_or("hex") {
+        "hex" => {
+            let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+            lua.create_string(hex).map(Value::String)
+        }
+        "base64" => lua.create_string(STANDARD.encode(bytes)).map(Value::String),
+        "raw" => lua.create_string(bytes).map(Value::String),
+        other => Err(Error::runtime(format!(
+            "hash error: unknown encoding '{other}'"
+        ))),
+    }
+}
+
+fn digest_algorithm(name: &str) -> Option<&'static digest::Algorithm> {
+    match name {
+        "sha1" => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        "sha256" => Some(&digest::SHA256),
+        "sha384" => Some(&digest::SHA384),
+        "sha512" => Some(&digest::SHA512),
+        _ => None,
+    }
+}
+
+fn hmac_algorithm(name: &str) -> Option<hmac::Algorithm> {
+    match name {
+        "sha1" => Some(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY),
+        "sha256" => Some(hmac::HMAC_SHA256),
+        "sha384" => Some(hmac::HMAC_SHA384),
+        "sha512" => Some(hmac::HMAC_SHA512),
+        _ => None,
+    }
+}
+
+fn md5(lua: &Lua, (data, encoding): (mlua::String, Option<String>)) -> Result<Value> {
+    format_digest(lua, &md5::compute(data.as_bytes()).0, encoding)
+}
+
+fn hmac(
+    lua: &Lua,
+    (name, key, data, encoding): (String, mlua::String, mlua::String, Option<String>),
+) -> Result<Value> {
+    let algorithm = hmac_algorithm(&name).ok_or_else(|| {
+        Error::runtime(format!("hash error: unsupported hmac algorithm '{name}'"))
+    })?;
+    let key = hmac::Key::new(algorithm, &key.as_bytes());
+    let tag = hmac::sign(&key, &data.as_bytes());
+    format_digest(lua, tag.as_ref(), encoding)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    for name in ["sha1", "sha256", "sha384", "sha512"] {
+        let algorithm = digest_algorithm(name).expect("known digest algorithm");
+        let function = lua.create_function(
+            move |lua, (data, encoding): (mlua::String, Option<String>)| {
+                format_digest(
+                    lua,
+                    digest::digest(algorithm, &data.as_bytes()).as_ref(),
+                    encoding,
+                )
+            },
+        )?;
+        module.set(name, function)?;
+    }
+    module.set("md5", lua.create_function(md5)?)?;
+    module.set("hmac", lua.create_function(hmac)?)?;
+
+    libs.set("hash", &module)?;
+    lua.register_module("hash", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local hash = require("hash")
+            assert(hash.md5("") == "d41d8cd98f00b204e9800998ecf8427e")
+            assert(hash.md5("hello") == "5d41402abc4b2a76b9719d911017c592")
+            assert(hash.sha1("hello") == "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d")
+            assert(hash.sha256("hello") == "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+            assert(#hash.sha512("hello") == 128)
+            assert(#hash.sha256("hello", "raw") == 32)
+            assert(hash.md5("hello", "base64") == "XUFAKrxLKna5cZ2REBfFkg==")
+            assert(hash.hmac("sha256", "key", "The quick brown fox jumps over the lazy dog")
+                == "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8")
+            assert(hash.hmac("sha1", "key", "The quick brown fox jumps over the lazy dog")
+                == "de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9")
+
+            local ok, err = pcall(hash.hmac, "md4", "key", "data")
+            assert(not ok and tostring(err):find("unsupported hmac algorithm"))
+            ok, err = pcall(hash.sha256, "data", "hex32")
+            assert(not ok and tostring(err):find("unknown encoding"))
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+}
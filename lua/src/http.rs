@@ -1,42 +1,127 @@
-use std::{str::FromStr, time::Duration};
+//! `libs.http`: HTTP client, permission `http`.
+//!
+//! All requests of a remote share one client, including its cookie jar.
+//! `get(url, callback?)`, `post(url, data?, callback?)` and
+//! `request{ method, url, query, headers, content, mime, json, username,
+//! password, bearer, timeout, redirects }` return the body and the response
+//! `{ status, reason, ok, mime, headers, url, content }`, or pass
+//! `(err, response)` to the callback.
+//!
+//! Non-2xx responses are regular responses, bodies are binary-safe strings
+//! and `response:json()` decodes JSON content.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use mlua::{Error, Function, Lua, Result, Table, Value};
+use mlua::{Error, Function, Lua, LuaSerdeExt, MultiValue, Result, Table, Value};
 use reqwest::{
-    Method, RequestBuilder, Response,
-    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, Method, RequestBuilder, Response,
+    cookie::Jar,
+    header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue},
+    redirect::Policy,
 };
 
-async fn get(lua: Lua, (url, callback): (String, Option<Function>)) -> Result<Value> {
-    let client = create_client()?;
-    let request = client.get(&url);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// HTTP clients of the Lua state, one per redirect limit, sharing a cookie
+/// jar so that sessions survive between requests
+#[derive(Clone, Default)]
+struct HttpClients(Arc<HttpClientsInner>);
+
+#[derive(Default)]
+struct HttpClientsInner {
+    jar: Arc<Jar>,
+    clients: Mutex<HashMap<usize, Client>>,
+}
+
+impl HttpClients {
+    /// Client following at most `max_redirects` redirects
+    fn get(&self, max_redirects: usize) -> Result<Client> {
+        let mut clients = self.0.clients.lock().unwrap();
+        if let Some(client) = clients.get(&max_redirects) {
+            return Ok(client.clone());
+        }
+
+        let policy = match max_redirects {
+            0 => Policy::none(),
+            limit => Policy::limited(limit),
+        };
+        let client = Client::builder()
+            .timeout(DEFAULT_TIMEOUT)
+            .redirect(policy)
+            .cookie_provider(self.0.jar.clone())
+            .build()
+            .map_err(|error| {
+                Error::runtime(format_args!("failed to create http client: {error}"))
+            })?;
+        clients.insert(max_redirects, client.clone());
+        Ok(client)
+    }
+}
+
+fn get_client(lua: &Lua, max_redirects: usize) -> Result<Client> {
+    if let Some(clients) = lua.app_data_ref::<HttpClients>() {
+        return clients.get(max_redirects);
+    }
+
+    let clients = HttpClients::default();
+    lua.set_app_data(clients.clone());
+    clients.get(max_redirects)
+}
+
+async fn get(lua: Lua, (url, callback): (String, Option<Function>)) -> Result<MultiValue> {
+    let request = get_client(&lua, DEFAULT_MAX_REDIRECTS)?.get(&url);
 
     request_internal(lua, request, callback).await
 }
 
 async fn post(
     lua: Lua,
-    (url, data, callback): (String, Option<String>, Option<Function>),
-) -> Result<Value> {
-    let client = create_client()?;
-    let mut request = client.post(&url);
+    (url, data, callback): (String, Option<mlua::String>, Option<Function>),
+) -> Result<MultiValue> {
+    let mut request = get_client(&lua, DEFAULT_MAX_REDIRECTS)?.post(&url);
 
     if let Some(body) = data {
-        request = request.body(body);
+        request = request.body(body.as_bytes().to_vec());
     }
 
     request_internal(lua, request, callback).await
 }
 
-async fn request(lua: Lua, (request_table, callback): (Table, Option<Function>)) -> Result<Value> {
-    let method = request_table.get::<String>("method").and_then(|m| {
-        m.parse::<Method>()
-            .map_err(|_| Error::runtime("invalid method"))
-    })?;
+/// Query parameter value from a Lua string, number or boolean
+fn query_value(value: Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.to_str()?.to_string()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        value => Err(Error::runtime(format!(
+            "invalid query value of type {}",
+            value.type_name()
+        ))),
+    }
+}
+
+async fn request(
+    lua: Lua,
+    (request_table, callback): (Table, Option<Function>),
+) -> Result<MultiValue> {
+    let method = request_table
+        .get::<Option<String>>("method")?
+        .unwrap_or_else(|| "GET".to_string())
+        .parse::<Method>()
+        .map_err(|_| Error::runtime("invalid method"))?;
 
     let url = request_table.get::<String>("url")?;
 
-    let content: Option<String> = request_table.get("content").ok();
-    let mime: Option<String> = request_table.get("mime").ok();
+    let content: Option<mlua::String> = request_table.get("content")?;
+    let json: Value = request_table.get("json")?;
+    let mime: Option<String> = request_table.get("mime")?;
 
     let headers = request_table
         .get::<Table>("headers")
@@ -53,82 +138,115 @@ async fn request(lua: Lua, (request_table, callback): (Table, Option<Function>))
         })
         .unwrap_or_default();
 
-    let client = create_client()?;
+    let max_redirects = request_table
+        .get::<Option<usize>>("redirects")?
+        .unwrap_or(DEFAULT_MAX_REDIRECTS);
+    let client = get_client(&lua, max_redirects)?;
 
     let mut request = client.request(method, url).headers(headers);
 
+    if let Some(query) = request_table.get::<Option<Table>>("query")? {
+        let query = query
+            .pairs::<String, Value>()
+            .map(|pair| {
+                let (name, value) = pair?;
+                Ok((name, query_value(value)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        request = request.query(&query);
+    }
+
+    if let Some(username) = request_table.get::<Option<String>>("username")? {
+        let password = request_table.get::<Option<String>>("password")?;
+        request = request.basic_auth(username, password);
+    }
+
+    if let Some(token) = request_table.get::<Option<String>>("bearer")? {
+        request = request.bearer_auth(token);
+    }
+
+    if let Some(timeout) = request_table.get::<Option<f64>>("timeout")? {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|error| Error::runtime(format!("invalid timeout: {error}")))?;
+        request = request.timeout(timeout);
+    }
+
+    if !json.is_nil() {
+        request = request.json(&lua.from_value::<serde_json::Value>(json)?);
+    }
+
     if let Some(mime) = mime {
-        request = request.header("Content-Type", mime);
+        request = request.header(CONTENT_TYPE, mime);
     }
 
     if let Some(content) = content {
-        request = request.body(content);
+        request = request.body(content.as_bytes().to_vec());
     }
 
     request_internal(lua, request, callback).await
 }
 
-fn create_client() -> Result<reqwest::Client> {
-    reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .map_err(|error| Error::runtime(format_args!("failed to create http client: {error}")))
-}
-
+/// Send the request and pass the response to the callback, or return the
+/// body and the response when there is none.
+///
+/// Responses of any status are passed on, only failed requests are errors.
 async fn request_internal(
     lua: Lua,
     request: RequestBuilder,
     callback: Option<Function>,
-) -> Result<Value> {
-    match request.send().await.and_then(Response::error_for_status) {
-        Ok(response) => {
-            tracing::info!(
-                "http request to {}: status={}",
-                response.url(),
-                response.status()
-            );
-
+) -> Result<MultiValue> {
+    let result = match request.send().await {
+        Ok(response) => create_response_table(&lua, response).await,
+        Err(error) => Err(Error::runtime(format!("http request failed: {error}"))),
+    };
+
+    match result {
+        Ok(response_table) => {
             if let Some(callback) = callback {
-                let response_table = create_response_table(&lua, response).await?;
                 callback
                     .call_async::<()>((Value::Nil, response_table))
                     .await?;
-                Ok(Value::Nil)
+                Ok(MultiValue::new())
             } else {
-                let content = read_response_body(response).await?;
-                Ok(Value::String(lua.create_string(content)?))
+                let content: Value = response_table.get("content")?;
+                Ok(MultiValue::from_vec(vec![
+                    content,
+                    Value::Table(response_table),
+                ]))
             }
         }
         Err(error) => {
-            let error_msg = format!("http request failed: {error}");
+            let error_msg = error.to_string();
             tracing::error!("{error_msg}");
 
             if let Some(callback) = callback {
                 callback.call_async::<()>((error_msg, Value::Nil)).await?;
-                Ok(Value::Nil)
+                Ok(MultiValue::new())
             } else {
-                Err(Error::runtime(error_msg))
+                Err(error)
             }
         }
     }
 }
 
-async fn read_response_body(response: Response) -> Result<String> {
-    response
-        .text()
-        .await
-        .map_err(|error| Error::runtime(format_args!("failed to read response body: {error}")))
+/// Decode the content of a response table as JSON
+fn decode_json(lua: &Lua, response: Table) -> Result<Value> {
+    let content: mlua::String = response.get("content")?;
+    let value: serde_json::Value = serde_json::from_slice(&content.as_bytes())
+        .map_err(|error| Error::runtime(format!("invalid JSON response: {error}")))?;
+    lua.to_value(&value)
 }
 
 async fn create_response_table(lua: &Lua, response: Response) -> Result<Table> {
     let table = lua.create_table()?;
 
     let status = response.status();
+    tracing::info!("http request to {}: status={status}", response.url());
+
     let reason = status.canonical_reason().unwrap_or("");
-    let status = status.as_u16();
     let mime = response
         .headers()
-        .get("Content-Type")
+        .get(CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
         .unwrap_or("");
 
@@ -138,16 +256,19 @@ async fn create_response_table(lua: &Lua, response: Response) -> Result<Table> {
     }
 
     table.set("headers", headers)?;
-    table.set("status", status)?;
+    table.set("status", status.as_u16())?;
     table.set("reason", reason)?;
+    table.set("ok", status.is_success())?;
     table.set("mime", mime)?;
+    table.set("url", response.url().as_str())?;
+    table.set("json", lua.create_function(decode_json)?)?;
 
     let content = response
-        .text()
+        .bytes()
         .await
         .map_err(|error| Error::runtime(format_args!("failed to read response body: {error}")))?;
 
-    table.set("content", content)?;
+    table.set("content", lua.create_string(content)?)?;
 
     Ok(table)
 }
@@ -165,8 +286,148 @@ pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
 
 #[cfg(test)]
 mod tests {
+    use axum::{
+        Json, Router,
+        extract::{Query, Request},
+        http::{StatusCode, header},
+        response::{IntoResponse, Redirect},
+        routing::{any, get as get_route},
+    };
+    use tokio::net::TcpListener;
+
     use super::*;
 
+    /// Describe the request the way httpbin does
+    async fn echo(
+        Query(query): Query<HashMap<String, String>>,
+        request: Request,
+    ) -> impl IntoResponse {
+        let (parts, body) = request.into_parts();
+        let header = |name: HeaderName| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("")
+                .to_string()
+        };
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+
+        Json(serde_json::json!({
+            "method": parts.method.as_str(),
+            "query": query,
+            "authorization": header(header::AUTHORIZATION),
+            "cookie": header(header::COOKIE),
+            "content_type": header(header::CONTENT_TYPE),
+            "body": String::from_utf8_lossy(&body),
+            "body_size": body.len(),
+        }))
+    }
+
+    async fn serve() -> String {
+        let app = Router::new()
+            .route("/echo", any(echo))
+            .route(
+                "/missing",
+                get_route(|| async { (StatusCode::NOT_FOUND, "no such thing") }),
+            )
+            .route(
+                "/login",
+                get_route(|| async { ([(header::SET_COOKIE, "session=abc; Path=/")], "welcome") }),
+            )
+            .route("/redirect", get_route(|| async { Redirect::to("/echo") }))
+            .route("/binary", get_route(|| async { vec![0u8, 159, 255] }))
+            .route(
+                "/slow",
+                get_route(|| async {
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    "late"
+                }),
+            );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{address}")
+    }
+
+    fn create_lua() -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    #[tokio::test]
+    async fn test_local_requests() {
+        let lua = create_lua();
+        lua.globals().set("base", serve().await).unwrap();
+        lua.load(
+            r#"
+            local http = require("http")
+
+            -- Non-2xx responses are regular responses
+            local content, response = http.get(base .. "/missing")
+            assert(content == "no such thing")
+            assert(response.status == 404 and not response.ok)
+
+            local err, missing
+            http.get(base .. "/missing", function(e, r) err, missing = e, r end)
+            assert(err == nil and missing.status == 404)
+
+            -- Query, basic auth and JSON bodies
+            local _, response = http.request({
+                method = "POST",
+                url = base .. "/echo",
+                query = { q = "volume up", step = 5, mute = false },
+                username = "user",
+                password = "secret",
+                json = { level = 42, tags = { "a", "b" } },
+            })
+            local echo = response:json()
+            assert(response.ok and echo.method == "POST")
+            assert(echo.query.q == "volume up" and echo.query.step == "5" and echo.query.mute == "false")
+            assert(echo.authorization == "Basic dXNlcjpzZWNyZXQ=")
+            assert(echo.content_type == "application/json")
+            local _, response = libs.http.request({ url = base .. "/echo", json = { level = 42 } })
+            assert(response:json().body == '{"level":42}')
+
+            -- Bearer auth and binary request bodies
+            local _, response = http.request({
+                method = "PUT",
+                url = base .. "/echo",
+                bearer = "token",
+                mime = "application/octet-stream",
+                content = "\0\1\2\255",
+            })
+            local echo = response:json()
+            assert(echo.authorization == "Bearer token" and echo.body_size == 4)
+
+            -- Binary response bodies
+            assert(http.get(base .. "/binary") == "\0\159\255")
+
+            -- Cookies are kept between requests
+            http.get(base .. "/login")
+            local _, response = http.get(base .. "/echo")
+            assert(response:json().cookie == "session=abc")
+
+            -- Redirects are followed unless disabled
+            local _, response = http.get(base .. "/redirect")
+            assert(response.status == 200 and response.url == base .. "/echo")
+            local _, response = http.request({ url = base .. "/redirect", redirects = 0 })
+            assert(response.status == 303 and response.headers.location == "/echo")
+
+            -- Timeouts fail the request
+            local ok, err = pcall(http.request, { url = base .. "/slow", timeout = 0.1 })
+            assert(not ok and tostring(err):find("http request failed"))
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+    }
+
     #[tokio::test]
     async fn test_http_request_with_table() {
         let lua = Lua::new();
@@ -0,0 +1,111 @@
+//! `libs.json`: JSON encoding and decoding.
+//!
+//! `encode(value, { pretty }?)` and `decode(text)` map JSON `null` to
+//! `json.null` and keep decoded arrays as arrays. `array(t?)` marks a table as
+//! an array, other empty tables encode as `{}`.
+
+use mlua::{Error, Lua, LuaSerdeExt, Result, Table, Value};
+
+/// Encode a Lua value as JSON
+///
+/// Empty tables encode as objects unless marked with `json.array`, and
+/// `json.null` encodes as `null`.
+fn encode(lua: &Lua, (value, options): (Value, Option<Table>)) -> Result<String> {
+    let pretty = match &options {
+        Some(options) => options.get::<Option<bool>>("pretty")?.unwrap_or(false),
+        None => false,
+    };
+
+    let json: serde_json::Value = lua.from_value(value)?;
+    let result = if pretty {
+        serde_json::to_string_pretty(&json)
+    } else {
+        serde_json::to_string(&json)
+    };
+    result.map_err(|error| Error::runtime(format!("json encode error: {error}")))
+}
+
+/// Decode JSON into Lua values, with `null` mapped to `json.null` and arrays
+/// marked so that they encode back as arrays
+fn decode(lua: &Lua, content: mlua::String) -> Result<Value> {
+    let value: serde_json::Value = serde_json::from_slice(&content.as_bytes())
+        .map_err(|error| Error::runtime(format!("json decode error: {error}")))?;
+    lua.to_value(&value)
+}
+
+/// Mark a table (or a new empty one) as a JSON array
+fn array(lua: &Lua, table: Option<Table>) -> Result<Table> {
+    let table = match table {
+        Some(table) => table,
+        None => lua.create_table()?,
+    };
+    table.set_metatable(Some(lua.array_metatable()))?;
+    Ok(table)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("encode", lua.create_function(encode)?)?;
+    module.set("decode", lua.create_function(decode)?)?;
+    module.set("array", lua.create_function(array)?)?;
+    module.set("null", lua.null())?;
+
+    libs.set("json", &module)?;
+    lua.register_module("json", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_lua() -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_encode() {
+        let lua = create_lua();
+        lua.load(
+            r#"
+            local json = require("json")
+            assert(json.encode({ b = 1, a = { true, "x", 1.5 } }) == '{"a":[true,"x",1.5],"b":1}')
+            assert(json.encode({}) == '{}')
+            assert(json.encode(json.array()) == '[]')
+            assert(json.encode({ value = json.null }) == '{"value":null}')
+            assert(json.encode("a\"b") == '"a\\"b"')
+            assert(json.encode({ 1, 2 }, { pretty = true }) == '[\n  1,\n  2\n]')
+            assert(not pcall(json.encode, { f = print }))
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_decode() {
+        let lua = create_lua();
+        lua.load(
+            r#"
+            local json = require("json")
+            local value = json.decode('{"list":[1,2.5,"three",null],"empty":[],"object":{},"flag":false}')
+            assert(value.list[1] == 1 and math.type(value.list[1]) == "integer")
+            assert(value.list[2] == 2.5 and value.list[3] == "three")
+            assert(value.list[4] == json.null)
+            assert(value.flag == false)
+            assert(json.encode(value.empty) == '[]')
+            assert(json.encode(value.object) == '{}')
+            assert(json.encode(json.decode('[null,{"a":[]}]')) == '[null,{"a":[]}]')
+
+            local ok, err = pcall(json.decode, '{"broken":')
+            assert(not ok and tostring(err):find("json decode error"))
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+}
@@ -1,17 +1,42 @@
 pub use state::{LuaLimits, LuaState};
 use uniremote_input::UInputBackend;
 
+pub mod audio;
+pub mod base64;
+pub mod callback;
+pub mod clipboard;
+pub mod cron;
+pub mod data;
+pub mod dbus;
 pub mod extra;
 pub mod fs;
 pub mod globals;
+pub mod hash;
 pub mod http;
+pub mod json;
 pub mod keyboard;
+pub mod media;
+pub mod midi;
 pub mod mouse;
+pub mod net;
+pub mod notify;
+pub mod osc;
+pub mod permission;
+pub mod power;
 pub mod ps;
+pub mod sandbox;
+pub mod screen;
 pub mod script;
+pub mod secrets;
+pub mod sensors;
 pub mod server;
+pub mod settings;
 pub mod state;
+pub mod subscription;
 pub mod timer;
+pub mod utf8;
+pub mod websocket;
+pub mod window;
 
 fn get_input_backend(lua: &mlua::Lua) -> UInputBackend {
     lua.app_data_ref::<UInputBackend>()
@@ -0,0 +1,965 @@
+//! `libs.media`: MPRIS players, permission `media`.
+//!
+//! `players`, `status`, `playpause`, `seek`, `setposition`, `setvolume`
+//! and friends act on the given player or the active one.
+//! `bind{ title = "id", art = "id", playing = "id", position = "id", ... }`
+//! pushes widget updates whenever the player changes, seeks, or advances
+//! while playing. Bound art is served to clients at `/r/{id}/media/art`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+
+use futures_util::StreamExt;
+use mlua::{Error, Function, Lua, LuaSerdeExt, Result, Table, Value as LuaValue};
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use uniremote_core::{ActionId, ServerMessage};
+use zbus::{
+    Connection, MatchRule, MessageStream,
+    zvariant::{DynamicType, ObjectPath, OwnedValue, Value},
+};
+
+use crate::{
+    callback::dispatch,
+    dbus::{dbus_error, session_connection},
+    subscription::{Subscription, get_subscription_map},
+};
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// How often `bind` polls the position of a playing player, which players
+/// do not signal as it advances
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest album art served to clients
+const MAX_ART_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Timeout for downloading album art from remote URLs
+const ART_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// State of an MPRIS player
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NowPlaying {
+    /// Player id, the bus name without the MPRIS prefix
+    pub player: String,
+    pub identity: Option<String>,
+    /// `Playing`, `Paused` or `Stopped`
+    pub status: String,
+    pub title: Option<String>,
+    /// Artists joined with ", "
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    #[serde(rename = "arturl")]
+    pub art_url: Option<String>,
+    /// Track length in seconds
+    pub length: Option<f64>,
+    /// Playback position in seconds
+    pub position: Option<f64>,
+    /// Volume between 0.0 and 1.0
+    pub volume: Option<f64>,
+    #[serde(skip)]
+    track_id: Option<String>,
+}
+
+impl NowPlaying {
+    fn is_playing(&self) -> bool {
+        self.status == "Playing"
+    }
+}
+
+/// Album art of the player bound with `libs.media.bind`. Clients load it
+/// from the server, as players mostly report local files they can't reach.
+#[derive(Debug, Clone, Default)]
+pub struct MediaArt(Arc<MediaArtInner>);
+
+#[derive(Debug, Default)]
+struct MediaArtInner {
+    /// Path the server serves the art at, empty if it doesn't
+    path: String,
+    url: Mutex<Option<String>>,
+    /// Counter of art changes, so that clients don't show cached art
+    version: AtomicU64,
+}
+
+impl MediaArt {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self(Arc::new(MediaArtInner {
+            path: path.into(),
+            ..MediaArtInner::default()
+        }))
+    }
+
+    fn get(lua: &Lua) -> Self {
+        lua.app_data_ref::<Self>()
+            .map(|art| art.clone())
+            .unwrap_or_default()
+    }
+
+    /// Art URL reported by the player
+    pub fn url(&self) -> Option<String> {
+        self.0.url.lock().unwrap().clone()
+    }
+
+    fn set_url(&self, url: Option<&str>) {
+        let mut current = self.0.url.lock().unwrap();
+        if current.as_deref() != url {
+            *current = url.map(ToString::to_string);
+            self.0.version.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// URL clients load the current art from, empty without art
+    fn client_url(&self) -> String {
+        if self.0.path.is_empty() || self.url().is_none() {
+            return String::new();
+        }
+        let version = self.0.version.load(Ordering::Relaxed);
+        format!("{}?v={version}", self.0.path)
+    }
+
+    /// Read the current art with its mime type. Only images are returned, so
+    /// that players can't expose other files to clients.
+    pub async fn load(&self) -> anyhow::Result<Option<(Vec<u8>, &'static str)>> {
+        let Some(url) = self.url() else {
+            return Ok(None);
+        };
+
+        let data = read_art(&url).await?;
+        let mime = image_mime(&data).context("album art is not an image")?;
+        Ok(Some((data, mime)))
+    }
+}
+
+async fn read_art(url: &str) -> anyhow::Result<Vec<u8>> {
+    let url = reqwest::Url::parse(url).context("invalid album art url")?;
+    let data = match url.scheme() {
+        "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|()| anyhow::anyhow!("invalid album art path"))?;
+            let mut data = Vec::new();
+            tokio::fs::File::open(path)
+                .await
+                .context("failed to open album art")?
+                .take(MAX_ART_SIZE + 1)
+                .read_to_end(&mut data)
+                .await
+                .context("failed to read album art")?;
+            data
+        }
+        "http" | "https" => reqwest::Client::new()
+            .get(url)
+            .timeout(ART_TIMEOUT)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context("failed to download album art")?
+            .bytes()
+            .await
+            .context("failed to download album art")?
+            .to_vec(),
+        scheme => bail!("unsupported album art url scheme: {scheme}"),
+    };
+
+    if data.len() as u64 > MAX_ART_SIZE {
+        bail!("album art is larger than {MAX_ART_SIZE} bytes");
+    }
+    Ok(data)
+}
+
+/// Mime type of image data, recognised by its signature
+fn image_mime(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP".as_slice()) {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn unwrap_variant<'a, 'v>(value: &'a Value<'v>) -> &'a Value<'v> {
+    match value {
+        Value::Value(inner) => unwrap_variant(inner),
+        value => value,
+    }
+}
+
+fn string(value: &Value) -> Option<String> {
+    match unwrap_variant(value) {
+        Value::Str(value) => Some(value.to_string()),
+        Value::ObjectPath(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// String or array of strings joined with ", "
+fn strings(value: &Value) -> Option<String> {
+    match unwrap_variant(value) {
+        Value::Array(values) => {
+            let values = values.inner().iter().filter_map(string).collect::<Vec<_>>();
+            (!values.is_empty()).then(|| values.join(", "))
+        }
+        value => string(value),
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match unwrap_variant(value) {
+        Value::U8(value) => Some((*value).into()),
+        Value::I16(value) => Some((*value).into()),
+        Value::U16(value) => Some((*value).into()),
+        Value::I32(value) => Some((*value).into()),
+        Value::U32(value) => Some((*value).into()),
+        Value::I64(value) => Some(*value as f64),
+        Value::U64(value) => Some(*value as f64),
+        Value::F64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn microseconds_to_seconds(value: &Value) -> Option<f64> {
+    number(value).map(|value| value / 1_000_000.0)
+}
+
+fn player_name(player: &str) -> String {
+    if player.starts_with(MPRIS_PREFIX) {
+        player.to_string()
+    } else {
+        format!("{MPRIS_PREFIX}{player}")
+    }
+}
+
+async fn call<B>(
+    connection: &Connection,
+    name: &str,
+    interface: &str,
+    method: &str,
+    body: &B,
+) -> Result<zbus::Message>
+where
+    B: Serialize + DynamicType,
+{
+    connection
+        .call_method(Some(name), MPRIS_PATH, Some(interface), method, body)
+        .await
+        .map_err(dbus_error)
+}
+
+async fn get_all(
+    connection: &Connection,
+    name: &str,
+    interface: &str,
+) -> Result<HashMap<String, OwnedValue>> {
+    call(
+        connection,
+        name,
+        PROPERTIES_INTERFACE,
+        "GetAll",
+        &(interface,),
+    )
+    .await?
+    .body()
+    .deserialize()
+    .map_err(dbus_error)
+}
+
+/// Bus names of all running MPRIS players, sorted
+async fn player_names(connection: &Connection) -> Result<Vec<String>> {
+    let names: Vec<String> = connection
+        .call_method(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            Some("org.freedesktop.DBus"),
+            "ListNames",
+            &(),
+        )
+        .await
+        .map_err(dbus_error)?
+        .body()
+        .deserialize()
+        .map_err(dbus_error)?;
+
+    let mut names = names
+        .into_iter()
+        .filter(|name| name.starts_with(MPRIS_PREFIX))
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+async fn now_playing(connection: &Connection, name: &str) -> Result<NowPlaying> {
+    let properties = get_all(connection, name, PLAYER_INTERFACE).await?;
+    let mut now = NowPlaying {
+        player: name.trim_start_matches(MPRIS_PREFIX).to_string(),
+        status: properties
+            .get("PlaybackStatus")
+            .and_then(|value| string(value))
+            .unwrap_or_else(|| "Stopped".to_string()),
+        position: properties
+            .get("Position")
+            .and_then(|value| microseconds_to_seconds(value)),
+        volume: properties.get("Volume").and_then(|value| number(value)),
+        ..NowPlaying::default()
+    };
+
+    if let Some(Value::Dict(metadata)) = properties
+        .get("Metadata")
+        .map(|value| unwrap_variant(value))
+    {
+        for (key, value) in metadata.iter() {
+            match string(key).as_deref() {
+                Some("xesam:title") => now.title = string(value),
+                Some("xesam:artist") => now.artist = strings(value),
+                Some("xesam:album") => now.album = string(value),
+                Some("mpris:artUrl") => now.art_url = string(value),
+                Some("mpris:length") => now.length = microseconds_to_seconds(value),
+                Some("mpris:trackid") => now.track_id = string(value),
+                _ => {}
+            }
+        }
+    }
+
+    // The root interface is optional for our purposes
+    if let Ok(properties) = get_all(connection, name, MPRIS_INTERFACE).await {
+        now.identity = properties.get("Identity").and_then(|value| string(value));
+    }
+
+    Ok(now)
+}
+
+/// Bus name of the given player, or of the active one: the first playing
+/// player, otherwise the first one found
+async fn resolve_player(connection: &Connection, player: Option<&str>) -> Result<Option<String>> {
+    if let Some(player) = player {
+        return Ok(Some(player_name(player)));
+    }
+
+    let names = player_names(connection).await?;
+    for name in &names {
+        let properties = get_all(connection, name, PLAYER_INTERFACE).await?;
+        if properties
+            .get("PlaybackStatus")
+            .and_then(|value| string(value))
+            .as_deref()
+            == Some("Playing")
+        {
+            return Ok(Some(name.clone()));
+        }
+    }
+
+    Ok(names.into_iter().next())
+}
+
+async fn require_player(connection: &Connection, player: Option<&str>) -> Result<String> {
+    resolve_player(connection, player)
+        .await?
+        .ok_or_else(|| Error::runtime("no media player found"))
+}
+
+async fn players(lua: Lua, (): ()) -> Result<LuaValue> {
+    let connection = session_connection(&lua).await?;
+
+    let mut players = Vec::new();
+    for name in player_names(&connection).await? {
+        players.push(now_playing(&connection, &name).await?);
+    }
+    lua.to_value(&players)
+}
+
+async fn status(lua: Lua, player: Option<String>) -> Result<LuaValue> {
+    let connection = session_connection(&lua).await?;
+
+    match resolve_player(&connection, player.as_deref()).await? {
+        Some(name) => lua.to_value(&now_playing(&connection, &name).await?),
+        None => Ok(LuaValue::Nil),
+    }
+}
+
+/// Create a Lua function calling a player method without arguments
+fn control(lua: &Lua, method: &'static str) -> Result<Function> {
+    lua.create_async_function(move |lua, player: Option<String>| async move {
+        let connection = session_connection(&lua).await?;
+        let name = require_player(&connection, player.as_deref()).await?;
+        call(&connection, &name, PLAYER_INTERFACE, method, &()).await?;
+        Ok(())
+    })
+}
+
+async fn seek(lua: Lua, (offset, player): (f64, Option<String>)) -> Result<()> {
+    let connection = session_connection(&lua).await?;
+    let name = require_player(&connection, player.as_deref()).await?;
+    let offset = (offset * 1_000_000.0) as i64;
+    call(&connection, &name, PLAYER_INTERFACE, "Seek", &(offset,)).await?;
+    Ok(())
+}
+
+async fn set_position(lua: Lua, (position, player): (f64, Option<String>)) -> Result<()> {
+    let connection = session_connection(&lua).await?;
+    let name = require_player(&connection, player.as_deref()).await?;
+
+    let track_id = now_playing(&connection, &name)
+        .await?
+        .track_id
+        .ok_or_else(|| Error::runtime("player does not report a track id"))?;
+    let track_id = ObjectPath::try_from(track_id).map_err(dbus_error)?;
+    let position = (position * 1_000_000.0) as i64;
+
+    call(
+        &connection,
+        &name,
+        PLAYER_INTERFACE,
+        "SetPosition",
+        &(track_id, position),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn volume(lua: Lua, player: Option<String>) -> Result<Option<f64>> {
+    let connection = session_connection(&lua).await?;
+    let name = require_player(&connection, player.as_deref()).await?;
+    Ok(now_playing(&connection, &name).await?.volume)
+}
+
+async fn set_volume(lua: Lua, (volume, player): (f64, Option<String>)) -> Result<()> {
+    let connection = session_connection(&lua).await?;
+    let name = require_player(&connection, player.as_deref()).await?;
+    let volume = volume.clamp(0.0, 1.0);
+
+    call(
+        &connection,
+        &name,
+        PROPERTIES_INTERFACE,
+        "Set",
+        &(PLAYER_INTERFACE, "Volume", Value::from(volume)),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Widget ids bound to fields of the active player by `libs.media.bind`
+#[derive(Debug, Default)]
+struct Bindings {
+    player: Option<String>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    art: Option<String>,
+    status: Option<String>,
+    position: Option<String>,
+    volume: Option<String>,
+    playing: Option<String>,
+    media_art: MediaArt,
+}
+
+impl Bindings {
+    fn from_table(table: &Table, media_art: MediaArt) -> Result<Self> {
+        Ok(Self {
+            player: table.get("player")?,
+            title: table.get("title")?,
+            artist: table.get("artist")?,
+            album: table.get("album")?,
+            art: table.get("art")?,
+            status: table.get("status")?,
+            position: table.get("position")?,
+            volume: table.get("volume")?,
+            playing: table.get("playing")?,
+            media_art,
+        })
+    }
+
+    /// Widget updates showing the player state, cleared without a player
+    fn updates(&self, now: Option<&NowPlaying>) -> Vec<ServerMessage> {
+        if self.art.is_some() {
+            self.media_art
+                .set_url(now.and_then(|now| now.art_url.as_deref()));
+        }
+
+        let text = |value: Option<&String>| value.cloned().unwrap_or_default();
+        let fields: [(_, _, serde_json::Value); 6] = [
+            (
+                &self.title,
+                "text",
+                text(now.and_then(|now| now.title.as_ref())).into(),
+            ),
+            (
+                &self.artist,
+                "text",
+                text(now.and_then(|now| now.artist.as_ref())).into(),
+            ),
+            (
+                &self.album,
+                "text",
+                text(now.and_then(|now| now.album.as_ref())).into(),
+            ),
+            (&self.art, "image", self.media_art.client_url().into()),
+            (
+                &self.status,
+                "text",
+                text(now.map(|now| &now.status)).into(),
+            ),
+            (
+                &self.playing,
+                "checked",
+                now.is_some_and(NowPlaying::is_playing).into(),
+            ),
+        ];
+
+        let mut updates = fields
+            .into_iter()
+            .filter_map(|(id, key, value)| {
+                let id = id.as_ref()?;
+                Some(update(id, serde_json::json!({ "id": id, key: value })))
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(id) = &self.position {
+            let position = now.and_then(|now| now.position).unwrap_or_default();
+            let length = now.and_then(|now| now.length).unwrap_or_default();
+            updates.push(update(
+                id,
+                serde_json::json!({
+                    "id": id,
+                    "progress": position.round(),
+                    "progressmax": length.round(),
+                }),
+            ));
+        }
+
+        if let Some(id) = &self.volume {
+            let volume = now.and_then(|now| now.volume).unwrap_or_default();
+            updates.push(update(
+                id,
+                serde_json::json!({
+                    "id": id,
+                    "progress": (volume * 100.0).round(),
+                    "progressmax": 100,
+                }),
+            ));
+        }
+
+        updates
+    }
+}
+
+fn update(id: &str, args: serde_json::Value) -> ServerMessage {
+    ServerMessage::Update {
+        action: ActionId::from(id.to_string()),
+        args,
+    }
+}
+
+/// State of the bound player, `None` if there is no player
+async fn bound_state(connection: &Connection, player: Option<&str>) -> Option<NowPlaying> {
+    let name = resolve_player(connection, player).await.ok()??;
+    now_playing(connection, &name).await.ok()
+}
+
+async fn bind(lua: Lua, params: Table) -> Result<Subscription> {
+    let bindings = Bindings::from_table(&params, MediaArt::get(&lua))?;
+    let callback: Option<Function> = params.get("callback")?;
+    let connection = session_connection(&lua).await?;
+
+    let changes = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .path(MPRIS_PATH)
+        .and_then(|rule| rule.interface(PROPERTIES_INTERFACE))
+        .and_then(|rule| rule.member("PropertiesChanged"))
+        .map_err(dbus_error)?
+        .build();
+    let seeks = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .path(MPRIS_PATH)
+        .and_then(|rule| rule.interface(PLAYER_INTERFACE))
+        .and_then(|rule| rule.member("Seeked"))
+        .map_err(dbus_error)?
+        .build();
+    let owners = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.DBus")
+        .and_then(|rule| rule.interface("org.freedesktop.DBus"))
+        .and_then(|rule| rule.member("NameOwnerChanged"))
+        .and_then(|rule| rule.arg0ns(MPRIS_INTERFACE))
+        .map_err(dbus_error)?
+        .build();
+
+    let changes = MessageStream::for_match_rule(changes, &connection, None)
+        .await
+        .map_err(dbus_error)?;
+    let seeks = MessageStream::for_match_rule(seeks, &connection, None)
+        .await
+        .map_err(dbus_error)?;
+    let owners = MessageStream::for_match_rule(owners, &connection, None)
+        .await
+        .map_err(dbus_error)?;
+    let mut events = futures_util::stream::select_all([changes, seeks, owners]);
+
+    // Only poll the position if something shows it
+    let poll_position = bindings.position.is_some() || callback.is_some();
+    let player = bindings.player.clone();
+    let broadcast_tx = crate::server::get_broadcast_sender(&lua);
+    let send = move |now: Option<&NowPlaying>| {
+        for message in bindings.updates(now) {
+            if let Err(error) = broadcast_tx.try_send(message) {
+                tracing::debug!("failed to send media update (no active connections): {error}");
+            }
+        }
+    };
+
+    let mut last = bound_state(&connection, player.as_deref()).await;
+    send(last.as_ref());
+
+    // Create a registry key to keep the callback alive
+    let registry_key = callback
+        .map(|callback| lua.create_registry_value(callback))
+        .transpose()?;
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    let subscriptions = get_subscription_map(&lua);
+    let id = subscriptions.add(async move {
+        let mut ticks = tokio::time::interval(POSITION_INTERVAL);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+        loop {
+            let playing = last.as_ref().is_some_and(NowPlaying::is_playing);
+            tokio::select! {
+                event = events.next() => if event.is_none() {
+                    break;
+                },
+                _ = ticks.tick(), if poll_position && playing => {}
+            }
+
+            let now = bound_state(&connection, player.as_deref()).await;
+            if now == last {
+                continue;
+            }
+
+            send(now.as_ref());
+            last = now;
+
+            let Some(lua) = weak_lua.try_upgrade() else {
+                break;
+            };
+
+            if let Some(registry_key) = &registry_key
+                && let Ok(callback) = lua.registry_value::<Function>(registry_key)
+                && let Err(error) =
+                    async { dispatch(&lua, "media callback", callback, lua.to_value(&last)?).await }
+                        .await
+            {
+                tracing::error!("media callback error: {error}");
+            }
+        }
+
+        if let Some(lua) = weak_lua.try_upgrade()
+            && let Some(registry_key) = registry_key
+        {
+            let _ = lua.remove_registry_value(registry_key);
+        }
+    });
+
+    tracing::info!("bound media widgets with subscription id: {id}");
+    Ok(Subscription { id, subscriptions })
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("players", lua.create_async_function(players)?)?;
+    module.set("status", lua.create_async_function(status)?)?;
+    module.set("play", control(lua, "Play")?)?;
+    module.set("pause", control(lua, "Pause")?)?;
+    module.set("playpause", control(lua, "PlayPause")?)?;
+    module.set("stop", control(lua, "Stop")?)?;
+    module.set("next", control(lua, "Next")?)?;
+    module.set("previous", control(lua, "Previous")?)?;
+    module.set("seek", lua.create_async_function(seek)?)?;
+    module.set("setposition", lua.create_async_function(set_position)?)?;
+    module.set("volume", lua.create_async_function(volume)?)?;
+    module.set("setvolume", lua.create_async_function(set_volume)?)?;
+    module.set("bind", lua.create_async_function(bind)?)?;
+
+    libs.set("media", &module)?;
+    lua.register_module("media", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use zbus::object_server::SignalEmitter;
+
+    use super::*;
+    use crate::dbus::{SessionBus, tests::TestBus};
+
+    struct FakeRoot;
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2")]
+    impl FakeRoot {
+        #[zbus(property)]
+        fn identity(&self) -> String {
+            "Fake Player".to_string()
+        }
+    }
+
+    struct FakePlayer {
+        status: String,
+        volume: f64,
+        /// Position in microseconds
+        position: i64,
+    }
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl FakePlayer {
+        async fn play_pause(&mut self, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+            self.status = if self.status == "Playing" {
+                "Paused".to_string()
+            } else {
+                "Playing".to_string()
+            };
+            let _ = self.playback_status_changed(&emitter).await;
+        }
+
+        async fn seek(&mut self, offset: i64, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+            self.position += offset;
+            let _ = Self::seeked(&emitter, self.position).await;
+        }
+
+        #[zbus(signal)]
+        async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+        #[zbus(property(emits_changed_signal = "false"))]
+        fn position(&self) -> i64 {
+            self.position
+        }
+
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            self.status.clone()
+        }
+
+        #[zbus(property)]
+        fn metadata(&self) -> HashMap<String, OwnedValue> {
+            HashMap::from([
+                (
+                    "xesam:title".to_string(),
+                    OwnedValue::from(zbus::zvariant::Str::from_static("Song")),
+                ),
+                (
+                    "xesam:artist".to_string(),
+                    Value::from(vec!["Alice", "Bob"]).try_to_owned().unwrap(),
+                ),
+                ("mpris:length".to_string(), OwnedValue::from(180_000_000i64)),
+                (
+                    "mpris:artUrl".to_string(),
+                    OwnedValue::from(zbus::zvariant::Str::from_static("file:///cover.png")),
+                ),
+            ])
+        }
+
+        #[zbus(property)]
+        fn volume(&self) -> f64 {
+            self.volume
+        }
+
+        #[zbus(property)]
+        fn set_volume(&mut self, volume: f64) {
+            self.volume = volume;
+        }
+    }
+
+    #[test]
+    fn test_bindings_updates() {
+        let bindings = Bindings {
+            title: Some("title".to_string()),
+            playing: Some("play".to_string()),
+            position: Some("seek".to_string()),
+            ..Bindings::default()
+        };
+        let now = NowPlaying {
+            status: "Playing".to_string(),
+            title: Some("Song".to_string()),
+            length: Some(180.0),
+            position: Some(42.4),
+            ..NowPlaying::default()
+        };
+
+        let args = bindings
+            .updates(Some(&now))
+            .into_iter()
+            .map(|message| match message {
+                ServerMessage::Update { args, .. } => args,
+                _ => panic!("Expected Update message"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            vec![
+                serde_json::json!({ "id": "title", "text": "Song" }),
+                serde_json::json!({ "id": "play", "checked": true }),
+                serde_json::json!({ "id": "seek", "progress": 42.0, "progressmax": 180.0 }),
+            ]
+        );
+
+        // Without a player the widgets are cleared
+        let cleared = bindings.updates(None);
+        assert!(matches!(
+            &cleared[0],
+            ServerMessage::Update { args, .. } if args["text"] == ""
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_media_art_load() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let image = temp_dir.path().join("cover");
+        std::fs::write(&image, b"\x89PNG\r\n\x1a\nrest").unwrap();
+        let text = temp_dir.path().join("notes.txt");
+        std::fs::write(&text, "not an image").unwrap();
+
+        let art = MediaArt::new("/r/test/media/art");
+        assert_eq!(art.client_url(), "");
+        assert!(art.load().await.unwrap().is_none());
+
+        let url = |path: &std::path::Path| format!("file://{}", path.display());
+        art.set_url(Some(&url(&image)));
+        assert_eq!(art.client_url(), "/r/test/media/art?v=1");
+        let (data, mime) = art.load().await.unwrap().unwrap();
+        assert_eq!(mime, "image/png");
+        assert!(data.ends_with(b"rest"));
+
+        // Other files are not served as art
+        art.set_url(Some(&url(&text)));
+        assert_eq!(art.client_url(), "/r/test/media/art?v=2");
+        assert!(art.load().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_media_player() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        };
+
+        let _player = bus
+            .connect()
+            .name("org.mpris.MediaPlayer2.fake")
+            .unwrap()
+            .serve_at(MPRIS_PATH, FakeRoot)
+            .unwrap()
+            .serve_at(
+                MPRIS_PATH,
+                FakePlayer {
+                    status: "Paused".to_string(),
+                    volume: 1.0,
+                    position: 0,
+                },
+            )
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let client = bus.connect().build().await.unwrap();
+        let (tx, rx) = flume::unbounded();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        let media_art = MediaArt::new("/r/fake/media/art");
+        lua.set_app_data(SessionBus::from(client));
+        lua.set_app_data(media_art.clone());
+        lua.set_app_data(tx);
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local players = libs.media.players()
+            assert(#players == 1 and players[1].player == "fake")
+            assert(players[1].identity == "Fake Player")
+
+            local status = libs.media.status()
+            assert(status.status == "Paused")
+            assert(status.title == "Song" and status.artist == "Alice, Bob")
+            assert(status.length == 180 and status.arturl == "file:///cover.png")
+
+            libs.media.setvolume(0.25, "fake")
+            assert(libs.media.volume() == 0.25)
+
+            binding = libs.media.bind({
+                title = "title", playing = "play", art = "cover", position = "seek",
+            })
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let args = rx
+            .drain()
+            .map(|message| match message {
+                ServerMessage::Update { args, .. } => args,
+                _ => panic!("Expected Update message"),
+            })
+            .collect::<Vec<_>>();
+        assert!(args.contains(&serde_json::json!({ "id": "title", "text": "Song" })));
+        assert!(args.contains(&serde_json::json!({ "id": "play", "checked": false })));
+        assert!(
+            args.contains(&serde_json::json!({ "id": "cover", "image": "/r/fake/media/art?v=1" }))
+        );
+        assert_eq!(media_art.url().as_deref(), Some("file:///cover.png"));
+
+        // Seeking is signalled even while paused
+        lua.load("libs.media.seek(30)").exec_async().await.unwrap();
+
+        let seeked = serde_json::json!({ "id": "seek", "progress": 30.0, "progressmax": 180.0 });
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let ServerMessage::Update { args, .. } = rx.recv_async().await.unwrap()
+                    && args == seeked
+                {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(received.is_ok(), "seeked position was not pushed");
+
+        lua.load("libs.media.playpause()")
+            .exec_async()
+            .await
+            .unwrap();
+
+        let playing = serde_json::json!({ "id": "play", "checked": true });
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let ServerMessage::Update { args, .. } = rx.recv_async().await.unwrap()
+                    && args == playing
+                {
+                    break;
+                }
+            }
+        })
+        .await;
+        assert!(received.is_ok(), "playing state was not pushed");
+
+        assert!(lua.load("return binding:cancel()").eval::<bool>().unwrap());
+    }
+}
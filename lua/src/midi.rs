@@ -0,0 +1,430 @@
+//! `libs.midi`: virtual ALSA sequencer ports, permission `midi`.
+//!
+//! `open(name?)` returns a port with an `address` field (`client:port`) and
+//! `note_on`, `note_off`, `cc`, `program` and `pitchbend`. Channels count from
+//! 1 to 16, data bytes from 0 to 127 and pitch bend from -8192 to 8191.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::fd::AsRawFd,
+    sync::Mutex,
+};
+
+use anyhow::{Context, ensure};
+use mlua::{Error, Lua, Result, Table, UserData, UserDataFields, UserDataMethods};
+
+/// ALSA sequencer device of the kernel, used directly so that neither
+/// libasound nor its headers are needed
+const SEQUENCER_DEVICE: &str = "/dev/snd/seq";
+
+/// Port name used when none is given
+const DEFAULT_PORT_NAME: &str = "uniremote";
+
+// Definitions from the kernel's `sound/asequencer.h`
+const fn ioctl(direction: u64, number: u64, size: usize) -> u64 {
+    (direction << 30) | ((size as u64) << 16) | ((b'S' as u64) << 8) | number
+}
+
+const IOC_READ: u64 = 2;
+const IOC_WRITE: u64 = 1;
+
+const IOCTL_CLIENT_ID: u64 = ioctl(IOC_READ, 0x01, size_of::<i32>());
+const IOCTL_GET_CLIENT_INFO: u64 = ioctl(IOC_READ | IOC_WRITE, 0x10, size_of::<ClientInfo>());
+const IOCTL_SET_CLIENT_INFO: u64 = ioctl(IOC_WRITE, 0x11, size_of::<ClientInfo>());
+const IOCTL_CREATE_PORT: u64 = ioctl(IOC_READ | IOC_WRITE, 0x20, size_of::<PortInfo>());
+
+const PORT_CAP_READ: u32 = 1 << 0;
+const PORT_CAP_SUBS_READ: u32 = 1 << 5;
+const PORT_TYPE_MIDI_GENERIC: u32 = 1 << 1;
+const PORT_TYPE_APPLICATION: u32 = 1 << 20;
+
+const QUEUE_DIRECT: u8 = 253;
+const ADDRESS_UNKNOWN: u8 = 253;
+const ADDRESS_SUBSCRIBERS: u8 = 254;
+
+const EVENT_NOTEON: u8 = 6;
+const EVENT_NOTEOFF: u8 = 7;
+const EVENT_CONTROLLER: u8 = 10;
+const EVENT_PGMCHANGE: u8 = 11;
+const EVENT_PITCHBEND: u8 = 13;
+
+/// Size of `struct snd_seq_event` with fixed length data
+const EVENT_SIZE: usize = 28;
+
+#[repr(C)]
+struct ClientInfo {
+    client: i32,
+    client_type: i32,
+    name: [u8; 64],
+    filter: u32,
+    multicast_filter: [u8; 8],
+    event_filter: [u8; 32],
+    num_ports: i32,
+    event_lost: i32,
+    card: i32,
+    pid: i32,
+    reserved: [u8; 56],
+}
+
+#[repr(C)]
+struct PortInfo {
+    client: u8,
+    port: u8,
+    name: [u8; 64],
+    capability: u32,
+    port_type: u32,
+    midi_channels: i32,
+    midi_voices: i32,
+    synth_voices: i32,
+    read_use: i32,
+    write_use: i32,
+    kernel: usize,
+    flags: u32,
+    time_queue: u8,
+    reserved: [u8; 59],
+}
+
+/// Null terminated name, truncated to fit the kernel's name fields
+fn name_bytes(name: &str) -> [u8; 64] {
+    let mut bytes = [0; 64];
+    let length = name.len().min(bytes.len() - 1);
+    bytes[..length].copy_from_slice(&name.as_bytes()[..length]);
+    bytes
+}
+
+/// Channel voice message sent to the subscribers of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    Controller {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// Bend from -8192 to 8191, 0 being the center
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+}
+
+impl Event {
+    /// Encode as a `struct snd_seq_event` sent directly from `port` to its
+    /// subscribers
+    fn encode(&self, port: u8) -> [u8; EVENT_SIZE] {
+        let mut data = [0; 12];
+        let event_type = match *self {
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                data[..3].copy_from_slice(&[channel, note, velocity]);
+                EVENT_NOTEON
+            }
+            Event::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => {
+                data[..3].copy_from_slice(&[channel, note, velocity]);
+                EVENT_NOTEOFF
+            }
+            Event::Controller {
+                channel,
+                controller,
+                value,
+            } => {
+                data[0] = channel;
+                data[4..8].copy_from_slice(&u32::from(controller).to_ne_bytes());
+                data[8..].copy_from_slice(&i32::from(value).to_ne_bytes());
+                EVENT_CONTROLLER
+            }
+            Event::ProgramChange { channel, program } => {
+                data[0] = channel;
+                data[8..].copy_from_slice(&i32::from(program).to_ne_bytes());
+                EVENT_PGMCHANGE
+            }
+            Event::PitchBend { channel, value } => {
+                data[0] = channel;
+                data[8..].copy_from_slice(&i32::from(value).to_ne_bytes());
+                EVENT_PITCHBEND
+            }
+        };
+
+        // type, flags, tag, queue, time, source, destination and data
+        let mut event = [0; EVENT_SIZE];
+        event[0] = event_type;
+        event[3] = QUEUE_DIRECT;
+        event[13] = port;
+        event[14] = ADDRESS_SUBSCRIBERS;
+        event[15] = ADDRESS_UNKNOWN;
+        event[16..].copy_from_slice(&data);
+        event
+    }
+}
+
+/// Readable ALSA sequencer port other applications can subscribe to, like
+/// the port of a hardware MIDI keyboard
+pub struct VirtualPort {
+    device: Mutex<File>,
+    client: u8,
+    port: u8,
+}
+
+impl VirtualPort {
+    pub fn open(name: &str) -> anyhow::Result<Self> {
+        let device = OpenOptions::new()
+            .write(true)
+            .open(SEQUENCER_DEVICE)
+            .with_context(|| format!("failed to open ALSA sequencer {SEQUENCER_DEVICE}"))?;
+        let fd = device.as_raw_fd();
+
+        let mut client = 0i32;
+        // SAFETY: the kernel writes an int to the pointer
+        let result = unsafe { libc::ioctl(fd, IOCTL_CLIENT_ID as _, &mut client) };
+        ensure!(result == 0, "failed to get sequencer client id");
+
+        // SAFETY: both structs match the kernel's definitions
+        let mut info: ClientInfo = unsafe { std::mem::zeroed() };
+        info.client = client;
+        let result = unsafe { libc::ioctl(fd, IOCTL_GET_CLIENT_INFO as _, &mut info) };
+        ensure!(result == 0, "failed to get sequencer client info");
+        info.name = name_bytes(name);
+        let result = unsafe { libc::ioctl(fd, IOCTL_SET_CLIENT_INFO as _, &info) };
+        ensure!(result == 0, "failed to set sequencer client name");
+
+        let mut port: PortInfo = unsafe { std::mem::zeroed() };
+        port.client = client as u8;
+        port.name = name_bytes(name);
+        port.capability = PORT_CAP_READ | PORT_CAP_SUBS_READ;
+        port.port_type = PORT_TYPE_MIDI_GENERIC | PORT_TYPE_APPLICATION;
+        port.midi_channels = 16;
+        let result = unsafe { libc::ioctl(fd, IOCTL_CREATE_PORT as _, &mut port) };
+        ensure!(
+            result == 0,
+            "failed to create sequencer port: {}",
+            std::io::Error::last_os_error()
+        );
+
+        tracing::info!("created MIDI port {client}:{} '{name}'", port.port);
+        Ok(Self {
+            device: Mutex::new(device),
+            client: client as u8,
+            port: port.port,
+        })
+    }
+
+    /// Sequencer address of the port, as shown by `aconnect -l`
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.client, self.port)
+    }
+
+    pub fn send(&self, event: Event) -> anyhow::Result<()> {
+        self.device
+            .lock()
+            .unwrap()
+            .write_all(&event.encode(self.port))
+            .context("failed to send MIDI event")
+    }
+}
+
+fn midi_error(error: anyhow::Error) -> Error {
+    Error::runtime(format!("midi error: {error:#}"))
+}
+
+/// Lua channels count from 1 to 16 like in music software
+fn channel(channel: u8) -> Result<u8> {
+    match channel {
+        1..=16 => Ok(channel - 1),
+        _ => Err(Error::runtime(format!(
+            "invalid MIDI channel {channel}, expected 1 to 16"
+        ))),
+    }
+}
+
+fn data_byte(name: &str, value: u8) -> Result<u8> {
+    if value > 127 {
+        return Err(Error::runtime(format!(
+            "invalid MIDI {name} {value}, expected 0 to 127"
+        )));
+    }
+    Ok(value)
+}
+
+impl UserData for VirtualPort {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("address", |_, this| Ok(this.address()));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method(
+            "note_on",
+            |_, this, (channel_number, note, velocity): (u8, u8, Option<u8>)| {
+                let event = Event::NoteOn {
+                    channel: channel(channel_number)?,
+                    note: data_byte("note", note)?,
+                    velocity: data_byte("velocity", velocity.unwrap_or(100))?,
+                };
+                this.send(event).map_err(midi_error)
+            },
+        );
+
+        methods.add_method(
+            "note_off",
+            |_, this, (channel_number, note, velocity): (u8, u8, Option<u8>)| {
+                let event = Event::NoteOff {
+                    channel: channel(channel_number)?,
+                    note: data_byte("note", note)?,
+                    velocity: data_byte("velocity", velocity.unwrap_or(0))?,
+                };
+                this.send(event).map_err(midi_error)
+            },
+        );
+
+        methods.add_method(
+            "cc",
+            |_, this, (channel_number, controller, value): (u8, u8, u8)| {
+                let event = Event::Controller {
+                    channel: channel(channel_number)?,
+                    controller: data_byte("controller", controller)?,
+                    value: data_byte("value", value)?,
+                };
+                this.send(event).map_err(midi_error)
+            },
+        );
+
+        methods.add_method("program", |_, this, (channel_number, program): (u8, u8)| {
+            let event = Event::ProgramChange {
+                channel: channel(channel_number)?,
+                program: data_byte("program", program)?,
+            };
+            this.send(event).map_err(midi_error)
+        });
+
+        methods.add_method(
+            "pitchbend",
+            |_, this, (channel_number, value): (u8, i16)| {
+                if !(-8192..=8191).contains(&value) {
+                    return Err(Error::runtime(format!(
+                        "invalid pitch bend {value}, expected -8192 to 8191"
+                    )));
+                }
+                let event = Event::PitchBend {
+                    channel: channel(channel_number)?,
+                    value,
+                };
+                this.send(event).map_err(midi_error)
+            },
+        );
+    }
+}
+
+fn open(_lua: &Lua, name: Option<String>) -> Result<VirtualPort> {
+    VirtualPort::open(name.as_deref().unwrap_or(DEFAULT_PORT_NAME)).map_err(midi_error)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("open", lua.create_function(open)?)?;
+
+    libs.set("midi", &module)?;
+    lua.register_module("midi", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_struct_sizes() {
+        // Sizes are part of the ioctl numbers, so they must match the kernel
+        assert_eq!(size_of::<ClientInfo>(), 188);
+        assert_eq!(size_of::<PortInfo>(), 168);
+        assert_eq!(IOCTL_CLIENT_ID, 0x8004_5301);
+        assert_eq!(IOCTL_CREATE_PORT, 0xc0a8_5320);
+    }
+
+    #[test]
+    fn test_encode_events() {
+        let note = Event::NoteOn {
+            channel: 9,
+            note: 36,
+            velocity: 127,
+        }
+        .encode(3);
+        assert_eq!(&note[..4], &[EVENT_NOTEON, 0, 0, QUEUE_DIRECT]);
+        assert_eq!(&note[12..16], &[0, 3, ADDRESS_SUBSCRIBERS, ADDRESS_UNKNOWN]);
+        assert_eq!(&note[16..20], &[9, 36, 127, 0]);
+
+        let cc = Event::Controller {
+            channel: 0,
+            controller: 7,
+            value: 100,
+        }
+        .encode(0);
+        assert_eq!(cc[0], EVENT_CONTROLLER);
+        assert_eq!(&cc[20..24], &7u32.to_ne_bytes());
+        assert_eq!(&cc[24..28], &100i32.to_ne_bytes());
+
+        let bend = Event::PitchBend {
+            channel: 1,
+            value: -8192,
+        }
+        .encode(0);
+        assert_eq!(bend[0], EVENT_PITCHBEND);
+        assert_eq!(&bend[24..28], &(-8192i32).to_ne_bytes());
+    }
+
+    #[test]
+    fn test_virtual_port() {
+        if !std::path::Path::new(SEQUENCER_DEVICE).exists() {
+            eprintln!("ALSA sequencer is not available (load snd-seq-dummy), skipping");
+            return;
+        }
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        let address: String = lua
+            .load(
+                r#"
+                local port = libs.midi.open("uniremote test")
+                port:note_on(1, 60, 100)
+                port:note_off(1, 60)
+                port:cc(16, 7, 127)
+                port:program(10, 5)
+                port:pitchbend(1, 8191)
+                assert(not pcall(port.note_on, port, 0, 60))
+                assert(not pcall(port.cc, port, 1, 128, 0))
+                return port.address
+            "#,
+            )
+            .eval()
+            .unwrap();
+
+        let clients = std::fs::read_to_string("/proc/asound/seq/clients").unwrap_or_default();
+        assert!(address.contains(':'));
+        assert!(
+            clients.is_empty() || clients.contains("uniremote test"),
+            "{clients}"
+        );
+    }
+}
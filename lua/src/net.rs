@@ -0,0 +1,699 @@
+//! `libs.net`: network devices, permission `net`.
+//!
+//! - `wol(mac, { address, port }?)` sends a Wake-on-LAN magic packet
+//! - `udp({ bind, broadcast }?)` returns a socket with
+//!   `send(data, host, port)`, `receive({ timeout }?)` and `port()`
+//! - `tcp(host, port, { timeout }?)` returns a connection with `send(data)`,
+//!   `readline({ timeout }?)`, `read(n, { timeout }?)` and `close()`
+//! - `interfaces()` lists `{ name, address, family, loopback }`
+//!
+//! Reads return `nil, "timeout"` or `nil, "closed"`. `subscribe(callback)` on
+//! either socket delivers datagrams or lines on the worker queue.
+
+use std::{io, net::IpAddr, sync::Arc, time::Duration};
+
+use futures_util::{Stream, StreamExt, stream};
+use mlua::{
+    Error, Function, IntoLuaMulti, Lua, MultiValue, Result, String as LuaString, Table, UserData,
+    UserDataMethods, Value,
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        TcpStream, UdpSocket,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::Mutex,
+};
+
+use crate::{
+    callback::dispatch,
+    subscription::{Subscription, get_subscription_map},
+};
+
+/// Timeout for connecting and reading when none is given
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Port Wake-on-LAN packets are sent to by default (discard)
+const WOL_PORT: u16 = 9;
+
+/// Largest datagram `receive` returns
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Most bytes a single TCP `read` returns, whatever size is asked for
+const MAX_READ_SIZE: usize = 64 * 1024;
+
+/// Longest line `readline` and TCP subscriptions accept
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+fn net_error(error: impl std::fmt::Display) -> Error {
+    Error::runtime(format!("net error: {error}"))
+}
+
+/// Parse a MAC address written with `:` or `-` separators or none at all
+fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let hex: String = mac.chars().filter(|c| *c != ':' && *c != '-').collect();
+    let invalid = || Error::runtime(format!("invalid MAC address '{mac}'"));
+    if hex.len() != 12 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut bytes = [0; 6];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// Six `0xFF` bytes followed by the MAC address repeated 16 times
+fn magic_packet(mac: [u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0xFF; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+    packet
+}
+
+fn get_timeout(options: Option<&Table>) -> Result<Duration> {
+    let seconds = match options {
+        Some(options) => options.get::<Option<f64>>("timeout")?,
+        None => None,
+    };
+    match seconds {
+        Some(seconds) if seconds.is_finite() && seconds >= 0.0 => {
+            Ok(Duration::from_secs_f64(seconds))
+        }
+        Some(seconds) => Err(Error::runtime(format!("invalid timeout {seconds}"))),
+        None => Ok(DEFAULT_TIMEOUT),
+    }
+}
+
+/// `nil` and the reason, the way reads report timeouts and closed connections
+fn failure(lua: &Lua, reason: &str) -> Result<MultiValue> {
+    Ok(MultiValue::from_vec(vec![
+        Value::Nil,
+        Value::String(lua.create_string(reason)?),
+    ]))
+}
+
+async fn wol(_lua: Lua, (mac, options): (String, Option<Table>)) -> Result<()> {
+    let packet = magic_packet(parse_mac(&mac)?);
+    let (address, port) = match &options {
+        Some(options) => (
+            options.get::<Option<String>>("address")?,
+            options.get::<Option<u16>>("port")?,
+        ),
+        None => (None, None),
+    };
+    let address = address.unwrap_or_else(|| "255.255.255.255".to_string());
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(net_error)?;
+    socket.set_broadcast(true).map_err(net_error)?;
+    socket
+        .send_to(&packet, (address.as_str(), port.unwrap_or(WOL_PORT)))
+        .await
+        .map_err(net_error)?;
+
+    tracing::info!("sent wake-on-lan packet to {mac} via {address}");
+    Ok(())
+}
+
+/// UDP socket created by `libs.net.udp`
+struct Udp(Arc<UdpSocket>);
+
+impl UserData for Udp {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("port", |_, this, ()| {
+            Ok(this.0.local_addr().map_err(net_error)?.port())
+        });
+
+        methods.add_async_method(
+            "send",
+            |_, this, (data, host, port): (LuaString, String, u16)| async move {
+                this.0
+                    .send_to(&data.as_bytes(), (host.as_str(), port))
+                    .await
+                    .map_err(net_error)
+            },
+        );
+
+        methods.add_async_method("receive", |lua, this, options: Option<Table>| async move {
+            let timeout = get_timeout(options.as_ref())?;
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            let Ok(received) = tokio::time::timeout(timeout, this.0.recv_from(&mut buffer)).await
+            else {
+                return failure(&lua, "timeout");
+            };
+
+            let (size, sender) = received.map_err(net_error)?;
+            Ok(MultiValue::from_vec(vec![
+                Value::String(lua.create_string(&buffer[..size])?),
+                Value::String(lua.create_string(sender.ip().to_string())?),
+                Value::Integer(sender.port().into()),
+            ]))
+        });
+
+        methods.add_method("subscribe", |lua, this, callback: Function| {
+            let datagrams = stream::unfold(this.0.clone(), |socket| async move {
+                let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+                let (size, sender) = socket.recv_from(&mut buffer).await.ok()?;
+                buffer.truncate(size);
+                Some(((buffer, sender), socket))
+            });
+
+            subscribe(lua, callback, "udp", datagrams, |lua, (data, sender)| {
+                (
+                    lua.create_string(data)?,
+                    sender.ip().to_string(),
+                    sender.port(),
+                )
+                    .into_lua_multi(lua)
+            })
+        });
+    }
+}
+
+async fn udp(_lua: Lua, options: Option<Table>) -> Result<Udp> {
+    let (bind, broadcast) = match &options {
+        Some(options) => (
+            options.get::<Option<String>>("bind")?,
+            options.get::<Option<bool>>("broadcast")?,
+        ),
+        None => (None, None),
+    };
+
+    let socket = UdpSocket::bind(bind.as_deref().unwrap_or("0.0.0.0:0"))
+        .await
+        .map_err(net_error)?;
+    socket
+        .set_broadcast(broadcast.unwrap_or(false))
+        .map_err(net_error)?;
+    Ok(Udp(Arc::new(socket)))
+}
+
+/// Read half of a TCP connection, which keeps the start of a line across
+/// `readline` calls that time out before the line is complete
+struct LineReader {
+    reader: BufReader<OwnedReadHalf>,
+    line: Vec<u8>,
+    /// Skipping the rest of a line that was too long
+    discarding: bool,
+}
+
+impl LineReader {
+    fn new(reader: OwnedReadHalf) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+            discarding: false,
+        }
+    }
+
+    /// Read the next line without its line ending, or `None` once the
+    /// connection is closed. Cancel safe, as bytes of an incomplete line are
+    /// only moved from the read buffer into `line`.
+    async fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+
+            let (length, complete) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(index) => (index + 1, true),
+                None => (available.len(), false),
+            };
+            if self.discarding {
+                self.discarding = !complete;
+                self.reader.consume(length);
+                continue;
+            }
+            if self.line.len() + length > MAX_LINE_LENGTH {
+                self.line.clear();
+                self.discarding = !complete;
+                self.reader.consume(length);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line is longer than {MAX_LINE_LENGTH} bytes"),
+                ));
+            }
+
+            self.line.extend_from_slice(&available[..length]);
+            self.reader.consume(length);
+            if complete {
+                break;
+            }
+        }
+
+        let mut line = std::mem::take(&mut self.line);
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    /// Read up to `buffer.len()` bytes, starting with those of an incomplete
+    /// line left by a `readline` that timed out
+    async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.line.is_empty() {
+            return self.reader.read(buffer).await;
+        }
+
+        let length = buffer.len().min(self.line.len());
+        buffer[..length].copy_from_slice(&self.line[..length]);
+        self.line.drain(..length);
+        Ok(length)
+    }
+}
+
+/// TCP connection created by `libs.net.tcp`
+struct Tcp {
+    /// Taken by `subscribe`, after which reads are no longer possible
+    reader: Arc<Mutex<Option<LineReader>>>,
+    writer: Arc<Mutex<Option<OwnedWriteHalf>>>,
+}
+
+impl Tcp {
+    fn closed() -> Error {
+        Error::runtime("connection is closed or read by a subscription")
+    }
+}
+
+impl UserData for Tcp {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("send", |_, this, data: LuaString| async move {
+            let mut writer = this.writer.lock().await;
+            let writer = writer.as_mut().ok_or_else(Tcp::closed)?;
+            writer.write_all(&data.as_bytes()).await.map_err(net_error)
+        });
+
+        methods.add_async_method("readline", |lua, this, options: Option<Table>| async move {
+            let timeout = get_timeout(options.as_ref())?;
+            let mut reader = this.reader.lock().await;
+            let reader = reader.as_mut().ok_or_else(Tcp::closed)?;
+
+            let Ok(line) = tokio::time::timeout(timeout, reader.next_line()).await else {
+                return failure(&lua, "timeout");
+            };
+            let Some(line) = line.map_err(net_error)? else {
+                return failure(&lua, "closed");
+            };
+            Ok(MultiValue::from_vec(vec![Value::String(
+                lua.create_string(line)?,
+            )]))
+        });
+
+        methods.add_async_method(
+            "read",
+            |lua, this, (size, options): (usize, Option<Table>)| async move {
+                let timeout = get_timeout(options.as_ref())?;
+                let mut reader = this.reader.lock().await;
+                let reader = reader.as_mut().ok_or_else(Tcp::closed)?;
+
+                let mut buffer = vec![0; size.min(MAX_READ_SIZE)];
+                let Ok(read) = tokio::time::timeout(timeout, reader.read(&mut buffer)).await else {
+                    return failure(&lua, "timeout");
+                };
+                let read = read.map_err(net_error)?;
+                if read == 0 && size > 0 {
+                    return failure(&lua, "closed");
+                }
+                Ok(MultiValue::from_vec(vec![Value::String(
+                    lua.create_string(&buffer[..read])?,
+                )]))
+            },
+        );
+
+        methods.add_method("subscribe", |lua, this, callback: Function| {
+            let reader = this
+                .reader
+                .try_lock()
+                .ok()
+                .and_then(|mut reader| reader.take())
+                .ok_or_else(Tcp::closed)?;
+
+            let lines = stream::unfold(reader, |mut reader| async move {
+                match reader.next_line().await {
+                    Ok(Some(line)) => Some((line, reader)),
+                    Ok(None) => None,
+                    Err(error) => {
+                        tracing::warn!("tcp subscription stopped: {error}");
+                        None
+                    }
+                }
+            });
+
+            subscribe(lua, callback, "tcp", lines, |lua, line| {
+                lua.create_string(line)?.into_lua_multi(lua)
+            })
+        });
+
+        methods.add_async_method("close", |_, this, ()| async move {
+            this.reader.lock().await.take();
+            if let Some(mut writer) = this.writer.lock().await.take() {
+                let _ = writer.shutdown().await;
+            }
+            Ok(())
+        });
+    }
+}
+
+async fn tcp(_lua: Lua, (host, port, options): (String, u16, Option<Table>)) -> Result<Tcp> {
+    let timeout = get_timeout(options.as_ref())?;
+    let stream = tokio::time::timeout(timeout, TcpStream::connect((host.as_str(), port)))
+        .await
+        .map_err(|_| Error::runtime(format!("timed out connecting to {host}:{port}")))?
+        .map_err(net_error)?;
+    stream.set_nodelay(true).map_err(net_error)?;
+
+    let (reader, writer) = stream.into_split();
+    Ok(Tcp {
+        reader: Arc::new(Mutex::new(Some(LineReader::new(reader)))),
+        writer: Arc::new(Mutex::new(Some(writer))),
+    })
+}
+
+/// Queue `callback` on the worker with the arguments built from each message
+/// of `messages` until the stream ends
+pub(crate) fn subscribe<S, T>(
+    lua: &Lua,
+    callback: Function,
+    kind: &'static str,
+    messages: S,
+    args: fn(&Lua, T) -> Result<MultiValue>,
+) -> Result<Subscription>
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let subscriptions = get_subscription_map(lua);
+
+    // Create a registry key to keep the function alive
+    let registry_key = lua.create_registry_value(callback)?;
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    let id = subscriptions.add(async move {
+        let mut messages = std::pin::pin!(messages);
+        while let Some(message) = messages.next().await {
+            let Some(lua) = weak_lua.try_upgrade() else {
+                break;
+            };
+            let Ok(callback) = lua.registry_value::<Function>(&registry_key) else {
+                break;
+            };
+
+            let name = format!("{kind} callback");
+            let result = async { dispatch(&lua, &name, callback, args(&lua, message)?).await };
+            if let Err(error) = result.await {
+                tracing::error!("{kind} callback error: {error}");
+            }
+        }
+
+        if let Some(lua) = weak_lua.try_upgrade() {
+            let _ = lua.remove_registry_value(registry_key);
+        }
+    });
+
+    tracing::info!("created {kind} subscription with id: {id}");
+    Ok(Subscription { id, subscriptions })
+}
+
+/// Address of a local network interface
+#[derive(Debug, Serialize)]
+struct Interface {
+    name: String,
+    address: String,
+    family: &'static str,
+    loopback: bool,
+}
+
+fn interfaces(lua: &Lua, (): ()) -> Result<Value> {
+    let interfaces: Vec<_> = local_ip_address::list_afinet_netifas()
+        .map_err(net_error)?
+        .into_iter()
+        .map(|(name, address)| Interface {
+            name,
+            family: match address {
+                IpAddr::V4(_) => "ipv4",
+                IpAddr::V6(_) => "ipv6",
+            },
+            loopback: address.is_loopback(),
+            address: address.to_string(),
+        })
+        .collect();
+    mlua::LuaSerdeExt::to_value(lua, &interfaces)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("wol", lua.create_async_function(wol)?)?;
+    module.set("udp", lua.create_async_function(udp)?)?;
+    module.set("tcp", lua.create_async_function(tcp)?)?;
+    module.set("interfaces", lua.create_function(interfaces)?)?;
+
+    libs.set("net", &module)?;
+    lua.register_module("net", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn create_lua() -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    async fn wait_for(lua: &Lua, condition: &str) -> bool {
+        for _ in 0..50 {
+            if lua.load(condition).eval::<bool>().unwrap() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[test]
+    fn test_magic_packet() {
+        let mac = parse_mac("01:23:45:67:89:AB").unwrap();
+        assert_eq!(mac, [0x01, 0x23, 0x45, 0x67, 0x89, 0xab]);
+        assert_eq!(parse_mac("01-23-45-67-89-ab").unwrap(), mac);
+        assert_eq!(parse_mac("0123456789ab").unwrap(), mac);
+        assert!(parse_mac("01:23:45:67:89").is_err());
+        assert!(parse_mac("01:23:45:67:89:zz").is_err());
+
+        let packet = magic_packet(mac);
+        assert_eq!(packet.len(), 102);
+        assert_eq!(&packet[..6], &[0xff; 6]);
+        assert_eq!(&packet[96..], &mac);
+    }
+
+    #[tokio::test]
+    async fn test_wol() {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let lua = create_lua();
+        lua.load(format!(
+            r#"libs.net.wol("01:23:45:67:89:ab", {{ address = "127.0.0.1", port = {port} }})"#
+        ))
+        .exec_async()
+        .await
+        .unwrap();
+
+        let mut packet = [0; 200];
+        let size = listener.recv(&mut packet).await.unwrap();
+        assert_eq!(
+            &packet[..size],
+            magic_packet(parse_mac("0123456789ab").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let device = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = device.local_addr().unwrap().port();
+
+        let lua = create_lua();
+        lua.globals().set("device_port", port).unwrap();
+        lua.load(
+            r#"
+            socket = libs.net.udp({ bind = "127.0.0.1:0" })
+            assert(socket:send("PWR?", "127.0.0.1", device_port) == 4)
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        // Answer like a projector would
+        let mut buffer = [0; 16];
+        let (size, client) = device.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..size], b"PWR?");
+        device.send_to(b"PWR=01", client).await.unwrap();
+
+        lua.load(
+            r#"
+            local data, host, port = socket:receive()
+            assert(data == "PWR=01" and host == "127.0.0.1" and port == device_port)
+            local data, reason = socket:receive({ timeout = 0.05 })
+            assert(data == nil and reason == "timeout")
+
+            subscription = socket:subscribe(function(data) received = data end)
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        device.send_to(b"ERR", client).await.unwrap();
+        assert!(wait_for(&lua, "return received == 'ERR'").await);
+        lua.load("subscription:cancel()").exec().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Receiver answering each command, then sending an unsolicited line
+        let device = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                writer
+                    .write_all(format!("OK {line}\r\n").as_bytes())
+                    .await
+                    .unwrap();
+                if line == "VOL?" {
+                    writer.write_all(b"VOL 42\r\n").await.unwrap();
+                }
+            }
+        });
+
+        let lua = create_lua();
+        lua.globals().set("device_port", port).unwrap();
+        lua.load(
+            r#"
+            connection = libs.net.tcp("127.0.0.1", device_port, { timeout = 1 })
+            connection:send("PWON\r\n")
+            assert(connection:readline() == "OK PWON")
+            local line, reason = connection:readline({ timeout = 0.05 })
+            assert(line == nil and reason == "timeout")
+
+            connection:send("MU")
+            connection:send("ON\r\n")
+            assert(connection:read(4) == "OK M")
+            assert(connection:readline() == "UON")
+
+            connection:send("X\r\n")
+            assert(connection:read(math.maxinteger) == "OK X\r\n")
+
+            lines = {}
+            subscription = connection:subscribe(function(line) table.insert(lines, line) end)
+            assert(not pcall(connection.readline, connection))
+            connection:send("VOL?\r\n")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        assert!(wait_for(&lua, "return lines[2] == 'VOL 42'").await);
+        lua.load("assert(lines[1] == 'OK VOL?') connection:close()")
+            .exec_async()
+            .await
+            .unwrap();
+        device.await.unwrap();
+
+        assert!(
+            !lua.load("return pcall(libs.net.tcp, '127.0.0.1', 1, { timeout = 1 })")
+                .eval_async::<bool>()
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_partial_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Sends the rest of a line whenever it receives a byte
+        let device = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut byte = [0];
+            stream.write_all(b"PAR").await.unwrap();
+            stream.read_exact(&mut byte).await.unwrap();
+            stream.write_all(b"TIAL\r\nNEXT").await.unwrap();
+            stream.read_exact(&mut byte).await.unwrap();
+            stream.write_all(b"\r\n").await.unwrap();
+            let long_line = vec![b'x'; MAX_LINE_LENGTH + 1];
+            stream.write_all(&long_line).await.unwrap();
+            stream.write_all(b"\nAFTER\n").await.unwrap();
+            stream.read_exact(&mut byte).await.unwrap();
+        });
+
+        let lua = create_lua();
+        lua.globals().set("device_port", port).unwrap();
+        lua.load(
+            r#"
+            local connection = libs.net.tcp("127.0.0.1", device_port, { timeout = 1 })
+            local line, reason = connection:readline({ timeout = 0.1 })
+            assert(line == nil and reason == "timeout")
+            connection:send(".")
+            assert(connection:readline() == "PARTIAL")
+
+            line, reason = connection:readline({ timeout = 0.1 })
+            assert(line == nil and reason == "timeout")
+            assert(connection:read(2) == "NE")
+            connection:send(".")
+            assert(connection:readline() == "XT")
+
+            local ok, err = pcall(connection.readline, connection)
+            assert(not ok and tostring(err):find("line is longer than"))
+            assert(connection:readline() == "AFTER")
+            connection:send(".")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+        device.await.unwrap();
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let lua = create_lua();
+        let loopback = lua
+            .load(
+                r#"
+                for _, interface in ipairs(libs.net.interfaces()) do
+                    if interface.loopback and interface.family == "ipv4" then
+                        return interface.address
+                    end
+                end
+            "#,
+            )
+            .eval::<Option<String>>()
+            .unwrap();
+        assert_eq!(loopback.as_deref(), Some("127.0.0.1"));
+    }
+}
@@ -0,0 +1,426 @@
+//! `libs.notify`: desktop notifications, permission `notify`.
+//!
+//! `send{ summary, body, urgency, actions, callback, onclose }` returns an id
+//! for `close(id)`. `callback(action)` and `onclose(reason)` run on the worker
+//! queue.
+
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use mlua::{Error, Function, IntoLua, Lua, Result, Table, Value as LuaValue};
+use zbus::{MatchRule, Message, MessageStream, zvariant::Value};
+
+use crate::{
+    callback::dispatch,
+    dbus::{dbus_error, session_connection},
+    subscription::get_subscription_map,
+};
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+const NOTIFICATIONS_INTERFACE: &str = "org.freedesktop.Notifications";
+
+/// Application name reported to the notification server by default
+const DEFAULT_APP_NAME: &str = "uniremote";
+
+/// Signal of the notification server concerning a notification
+#[derive(Debug, PartialEq)]
+enum NotificationSignal {
+    ActionInvoked { id: u32, action: String },
+    Closed { id: u32, reason: u32 },
+}
+
+impl NotificationSignal {
+    fn parse(message: &Message) -> Option<Self> {
+        let header = message.header();
+        match header.member()?.as_str() {
+            "ActionInvoked" => {
+                let (id, action) = message.body().deserialize().ok()?;
+                Some(Self::ActionInvoked { id, action })
+            }
+            "NotificationClosed" => {
+                let (id, reason) = message.body().deserialize().ok()?;
+                Some(Self::Closed { id, reason })
+            }
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            Self::ActionInvoked { id, .. } | Self::Closed { id, .. } => *id,
+        }
+    }
+}
+
+/// Flatten actions into the `[key, label, ...]` list of the protocol.
+///
+/// Actions are either a sequence of `{ key, label }` pairs, which keeps their
+/// order, or a table mapping keys to labels, which is sorted by key.
+fn parse_actions(actions: &Table) -> Result<Vec<String>> {
+    let mut pairs = Vec::new();
+    for pair in actions.pairs::<LuaValue, LuaValue>() {
+        match pair? {
+            (LuaValue::Integer(index), LuaValue::Table(action)) => {
+                let key: String = action.get(1)?;
+                let label: Option<String> = action.get(2)?;
+                pairs.push((Some(index), key.clone(), label.unwrap_or(key)));
+            }
+            (LuaValue::String(key), LuaValue::String(label)) => {
+                pairs.push((None, key.to_str()?.to_string(), label.to_str()?.to_string()));
+            }
+            _ => {
+                return Err(Error::runtime(
+                    "actions must be { key, label } pairs or a table of labels by key",
+                ));
+            }
+        }
+    }
+
+    pairs.sort();
+    Ok(pairs
+        .into_iter()
+        .flat_map(|(_, key, label)| [key, label])
+        .collect())
+}
+
+fn parse_urgency(urgency: &str) -> Result<u8> {
+    match urgency {
+        "low" => Ok(0),
+        "normal" => Ok(1),
+        "critical" => Ok(2),
+        _ => Err(Error::runtime(format!(
+            "invalid urgency '{urgency}', expected low, normal or critical"
+        ))),
+    }
+}
+
+async fn send(lua: Lua, params: Table) -> Result<u32> {
+    let summary: String = params.get("summary")?;
+    let body: Option<String> = params.get("body")?;
+    let icon: Option<String> = params.get("icon")?;
+    let app_name: Option<String> = params.get("app_name")?;
+    let replaces: Option<u32> = params.get("replaces")?;
+    let timeout: Option<i32> = params.get("timeout")?;
+    let urgency: Option<String> = params.get("urgency")?;
+    let actions: Option<Table> = params.get("actions")?;
+    let callback: Option<Function> = params.get("callback")?;
+    let onclose: Option<Function> = params.get("onclose")?;
+
+    let actions = match &actions {
+        Some(actions) => parse_actions(actions)?,
+        None => Vec::new(),
+    };
+
+    let mut hints = HashMap::new();
+    if let Some(urgency) = &urgency {
+        hints.insert("urgency", Value::U8(parse_urgency(urgency)?));
+    }
+
+    let connection = session_connection(&lua).await?;
+
+    // Subscribe before sending so no signal is missed
+    let signals = if callback.is_some() || onclose.is_some() {
+        let rule = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender(NOTIFICATIONS_NAME)
+            .and_then(|rule| rule.path(NOTIFICATIONS_PATH))
+            .and_then(|rule| rule.interface(NOTIFICATIONS_INTERFACE))
+            .map_err(dbus_error)?
+            .build();
+        let stream = MessageStream::for_match_rule(rule, &connection, None)
+            .await
+            .map_err(dbus_error)?;
+        Some(stream)
+    } else {
+        None
+    };
+
+    let id: u32 = connection
+        .call_method(
+            Some(NOTIFICATIONS_NAME),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_INTERFACE),
+            "Notify",
+            &(
+                app_name.as_deref().unwrap_or(DEFAULT_APP_NAME),
+                replaces.unwrap_or(0),
+                icon.as_deref().unwrap_or_default(),
+                summary.as_str(),
+                body.as_deref().unwrap_or_default(),
+                actions,
+                hints,
+                timeout.unwrap_or(-1),
+            ),
+        )
+        .await
+        .map_err(dbus_error)?
+        .body()
+        .deserialize()
+        .map_err(dbus_error)?;
+
+    let Some(mut signals) = signals else {
+        return Ok(id);
+    };
+
+    // Create registry keys to keep the callbacks alive
+    let callback = callback
+        .map(|callback| lua.create_registry_value(callback))
+        .transpose()?;
+    let onclose = onclose
+        .map(|onclose| lua.create_registry_value(onclose))
+        .transpose()?;
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    get_subscription_map(&lua).add(async move {
+        while let Some(Ok(message)) = signals.next().await {
+            let Some(signal) = NotificationSignal::parse(&message) else {
+                continue;
+            };
+            if signal.id() != id {
+                continue;
+            }
+
+            let Some(lua) = weak_lua.try_upgrade() else {
+                break;
+            };
+
+            let (key, args, closed) = match signal {
+                NotificationSignal::ActionInvoked { action, .. } => {
+                    (&callback, action.into_lua(&lua), false)
+                }
+                NotificationSignal::Closed { reason, .. } => {
+                    (&onclose, reason.into_lua(&lua), true)
+                }
+            };
+
+            if let Some(key) = key
+                && let Ok(function) = lua.registry_value::<Function>(key)
+                && let Err(error) =
+                    async { dispatch(&lua, "notification callback", function, args?).await }.await
+            {
+                tracing::error!("notification callback error: {error}");
+            }
+
+            if closed {
+                break;
+            }
+        }
+
+        if let Some(lua) = weak_lua.try_upgrade() {
+            for key in [callback, onclose].into_iter().flatten() {
+                let _ = lua.remove_registry_value(key);
+            }
+        }
+    });
+
+    Ok(id)
+}
+
+async fn close(lua: Lua, id: u32) -> Result<()> {
+    session_connection(&lua)
+        .await?
+        .call_method(
+            Some(NOTIFICATIONS_NAME),
+            NOTIFICATIONS_PATH,
+            Some(NOTIFICATIONS_INTERFACE),
+            "CloseNotification",
+            &(id,),
+        )
+        .await
+        .map_err(dbus_error)?;
+    Ok(())
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("send", lua.create_async_function(send)?)?;
+    module.set("close", lua.create_async_function(close)?)?;
+
+    libs.set("notify", &module)?;
+    lua.register_module("notify", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use zbus::{object_server::SignalEmitter, zvariant::OwnedValue};
+
+    use super::*;
+    use crate::dbus::{SessionBus, tests::TestBus};
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Sent {
+        app_name: String,
+        summary: String,
+        actions: Vec<String>,
+        urgency: Option<u8>,
+    }
+
+    #[derive(Default)]
+    struct FakeNotifications {
+        sent: Arc<Mutex<Vec<Sent>>>,
+        closed: Arc<Mutex<Vec<u32>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl FakeNotifications {
+        #[allow(clippy::too_many_arguments)]
+        async fn notify(
+            &self,
+            app_name: String,
+            _replaces_id: u32,
+            _app_icon: String,
+            summary: String,
+            _body: String,
+            actions: Vec<String>,
+            hints: HashMap<String, OwnedValue>,
+            _expire_timeout: i32,
+            #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+        ) -> u32 {
+            let id = {
+                let mut sent = self.sent.lock().unwrap();
+                sent.push(Sent {
+                    app_name,
+                    summary,
+                    actions: actions.clone(),
+                    urgency: hints
+                        .get("urgency")
+                        .and_then(|value| u8::try_from(value).ok()),
+                });
+                sent.len() as u32
+            };
+
+            // Invoke the first action right away, as if the user clicked it
+            if let Some(action) = actions.first() {
+                let _ = Self::action_invoked(&emitter, id, action).await;
+                let _ = Self::notification_closed(&emitter, id, 2).await;
+            }
+            id
+        }
+
+        fn close_notification(&self, id: u32) {
+            self.closed.lock().unwrap().push(id);
+        }
+
+        #[zbus(signal)]
+        async fn action_invoked(
+            emitter: &SignalEmitter<'_>,
+            id: u32,
+            action_key: &str,
+        ) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn notification_closed(
+            emitter: &SignalEmitter<'_>,
+            id: u32,
+            reason: u32,
+        ) -> zbus::Result<()>;
+    }
+
+    #[test]
+    fn test_parse_actions() {
+        let lua = Lua::new();
+
+        let actions = lua
+            .load(r#"return { { "open", "Open" }, { "later" } }"#)
+            .eval::<Table>()
+            .unwrap();
+        assert_eq!(
+            parse_actions(&actions).unwrap(),
+            vec!["open", "Open", "later", "later"]
+        );
+
+        let actions = lua
+            .load(r#"return { snooze = "Snooze", dismiss = "Dismiss" }"#)
+            .eval::<Table>()
+            .unwrap();
+        assert_eq!(
+            parse_actions(&actions).unwrap(),
+            vec!["dismiss", "Dismiss", "snooze", "Snooze"]
+        );
+
+        let actions = lua.load("return { 1, 2 }").eval::<Table>().unwrap();
+        assert!(parse_actions(&actions).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_notification() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        };
+
+        let notifications = FakeNotifications::default();
+        let sent = notifications.sent.clone();
+        let closed = notifications.closed.clone();
+        let _server = bus
+            .connect()
+            .name(NOTIFICATIONS_NAME)
+            .unwrap()
+            .serve_at(NOTIFICATIONS_PATH, notifications)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.set_app_data(SessionBus::from(bus.connect().build().await.unwrap()));
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local plain = libs.notify.send({ summary = "Plain" })
+            libs.notify.close(plain)
+
+            libs.notify.send({
+                summary = "Download finished",
+                urgency = "critical",
+                actions = { { "open", "Open" }, { "dismiss", "Dismiss" } },
+                callback = function(action) invoked = action end,
+                onclose = function(reason) closed_reason = reason end,
+            })
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let mut done = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            done = lua
+                .load("return invoked == 'open' and closed_reason == 2")
+                .eval::<bool>()
+                .unwrap();
+            if done {
+                break;
+            }
+        }
+        assert!(done, "notification callbacks were not called");
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(
+            sent[1],
+            Sent {
+                app_name: DEFAULT_APP_NAME.to_string(),
+                summary: "Download finished".to_string(),
+                actions: vec!["open", "Open", "dismiss", "Dismiss"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                urgency: Some(2),
+            }
+        );
+        assert_eq!(*closed.lock().unwrap(), vec![1]);
+    }
+}
@@ -0,0 +1,667 @@
+//! `libs.osc`: Open Sound Control over UDP, permission `osc`.
+//!
+//! `encode(address, ...)` or `encode(packet)` returns packet data and
+//! `decode(data)` returns messages as `{ address, types, args }` and bundles
+//! as `{ timetag, elements }`. Integers, numbers, strings and booleans map to
+//! `i`/`h`, `f`, `s` and `T`/`F`, other types are given as
+//! `{ type = "d", value = 0.5 }`. `send(host, port, ...)` sends a packet and
+//! `listen(port, callback, { bind }?)` returns a cancellable subscription.
+
+use std::sync::Arc;
+
+use anyhow::{Context, bail, ensure};
+use futures_util::stream;
+use mlua::{Error, IntoLuaMulti, Lua, MultiValue, Result, Table, Value};
+use tokio::net::UdpSocket;
+
+use crate::{net::subscribe, subscription::Subscription};
+
+/// Time tag of bundles that are to be processed immediately
+const IMMEDIATELY: u64 = 1;
+
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+
+/// Largest datagram `listen` receives
+const MAX_PACKET_SIZE: usize = 65507;
+
+/// Deepest nesting of bundles `decode` accepts
+const MAX_BUNDLE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    String(String),
+    Symbol(String),
+    Char(char),
+    Blob(Vec<u8>),
+    Midi([u8; 4]),
+    TimeTag(u64),
+    Bool(bool),
+    Nil,
+    Impulse,
+}
+
+impl Arg {
+    fn type_tag(&self) -> char {
+        match self {
+            Arg::Int(_) => 'i',
+            Arg::Long(_) => 'h',
+            Arg::Float(_) => 'f',
+            Arg::Double(_) => 'd',
+            Arg::String(_) => 's',
+            Arg::Symbol(_) => 'S',
+            Arg::Char(_) => 'c',
+            Arg::Blob(_) => 'b',
+            Arg::Midi(_) => 'm',
+            Arg::TimeTag(_) => 't',
+            Arg::Bool(true) => 'T',
+            Arg::Bool(false) => 'F',
+            Arg::Nil => 'N',
+            Arg::Impulse => 'I',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub address: String,
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Message(Message),
+    Bundle { timetag: u64, elements: Vec<Packet> },
+}
+
+/// Append a string with its terminating null byte, padded to 4 bytes
+fn write_string(buffer: &mut Vec<u8>, string: &str) {
+    buffer.extend_from_slice(string.as_bytes());
+    buffer.push(0);
+    pad(buffer);
+}
+
+fn write_blob(buffer: &mut Vec<u8>, blob: &[u8]) {
+    buffer.extend_from_slice(&(blob.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(blob);
+    pad(buffer);
+}
+
+fn pad(buffer: &mut Vec<u8>) {
+    buffer.resize(buffer.len().next_multiple_of(4), 0);
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.write(&mut buffer);
+        buffer
+    }
+
+    fn write(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Packet::Message(message) => {
+                write_string(buffer, &message.address);
+                let tags: String = std::iter::once(',')
+                    .chain(message.args.iter().map(Arg::type_tag))
+                    .collect();
+                write_string(buffer, &tags);
+
+                for arg in &message.args {
+                    match arg {
+                        Arg::Int(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+                        Arg::Long(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+                        Arg::Float(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+                        Arg::Double(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+                        Arg::String(value) | Arg::Symbol(value) => write_string(buffer, value),
+                        Arg::Char(value) => {
+                            buffer.extend_from_slice(&(*value as u32).to_be_bytes())
+                        }
+                        Arg::Blob(value) => write_blob(buffer, value),
+                        Arg::Midi(value) => buffer.extend_from_slice(value),
+                        Arg::TimeTag(value) => buffer.extend_from_slice(&value.to_be_bytes()),
+                        Arg::Bool(_) | Arg::Nil | Arg::Impulse => {}
+                    }
+                }
+            }
+            Packet::Bundle { timetag, elements } => {
+                buffer.extend_from_slice(BUNDLE_TAG);
+                buffer.extend_from_slice(&timetag.to_be_bytes());
+                for element in elements {
+                    let start = buffer.len();
+                    buffer.extend_from_slice(&[0; 4]);
+                    element.write(buffer);
+                    let size = (buffer.len() - start - 4) as u32;
+                    buffer[start..start + 4].copy_from_slice(&size.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        Self::decode_nested(data, 0)
+    }
+
+    fn decode_nested(data: &[u8], depth: usize) -> anyhow::Result<Self> {
+        let mut reader = Reader {
+            data,
+            position: 0,
+            depth,
+        };
+        let packet = reader.packet()?;
+        ensure!(reader.position == data.len(), "trailing bytes after packet");
+        Ok(packet)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    /// Number of bundles around the packet being read
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(size)
+            .filter(|end| *end <= self.data.len())
+            .context("packet is truncated")?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn skip_padding(&mut self) -> anyhow::Result<()> {
+        let padding = self.position.next_multiple_of(4) - self.position;
+        self.take(padding)?;
+        Ok(())
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let rest = &self.data[self.position..];
+        let length = rest
+            .iter()
+            .position(|byte| *byte == 0)
+            .context("string is not terminated")?;
+        let string = std::str::from_utf8(&rest[..length])
+            .context("string is not valid UTF-8")?
+            .to_string();
+        self.take(length + 1)?;
+        self.skip_padding()?;
+        Ok(string)
+    }
+
+    fn blob(&mut self) -> anyhow::Result<Vec<u8>> {
+        let size = u32::from_be_bytes(self.array()?) as usize;
+        let blob = self.take(size)?.to_vec();
+        self.skip_padding()?;
+        Ok(blob)
+    }
+
+    fn packet(&mut self) -> anyhow::Result<Packet> {
+        if self.data[self.position..].starts_with(BUNDLE_TAG) {
+            ensure!(
+                self.depth < MAX_BUNDLE_DEPTH,
+                "bundles are nested too deeply"
+            );
+            self.take(BUNDLE_TAG.len())?;
+            let timetag = u64::from_be_bytes(self.array()?);
+            let mut elements = Vec::new();
+            while self.position < self.data.len() {
+                let size = u32::from_be_bytes(self.array()?) as usize;
+                elements.push(Packet::decode_nested(self.take(size)?, self.depth + 1)?);
+            }
+            return Ok(Packet::Bundle { timetag, elements });
+        }
+
+        let address = self.string()?;
+        ensure!(
+            address.starts_with('/'),
+            "invalid address pattern '{address}'"
+        );
+
+        // Some old implementations omit the type tags of messages without
+        // arguments
+        if self.position == self.data.len() {
+            return Ok(Packet::Message(Message {
+                address,
+                args: Vec::new(),
+            }));
+        }
+
+        let tags = self.string()?;
+        let Some(tags) = tags.strip_prefix(',') else {
+            bail!("invalid type tags '{tags}'");
+        };
+
+        let mut args = Vec::with_capacity(tags.len());
+        for tag in tags.chars() {
+            args.push(match tag {
+                'i' => Arg::Int(i32::from_be_bytes(self.array()?)),
+                'h' => Arg::Long(i64::from_be_bytes(self.array()?)),
+                'f' => Arg::Float(f32::from_be_bytes(self.array()?)),
+                'd' => Arg::Double(f64::from_be_bytes(self.array()?)),
+                's' => Arg::String(self.string()?),
+                'S' => Arg::Symbol(self.string()?),
+                'c' => {
+                    let code = u32::from_be_bytes(self.array()?);
+                    Arg::Char(char::from_u32(code).context("invalid character")?)
+                }
+                'b' => Arg::Blob(self.blob()?),
+                'm' => Arg::Midi(self.array()?),
+                't' => Arg::TimeTag(u64::from_be_bytes(self.array()?)),
+                'T' => Arg::Bool(true),
+                'F' => Arg::Bool(false),
+                'N' => Arg::Nil,
+                'I' => Arg::Impulse,
+                tag => bail!("unsupported type tag '{tag}'"),
+            });
+        }
+
+        Ok(Packet::Message(Message { address, args }))
+    }
+}
+
+fn osc_error(error: impl std::fmt::Display) -> Error {
+    Error::runtime(format!("osc error: {error}"))
+}
+
+/// Argument from a plain Lua value, or a `{ type = "d", value = 1.5 }` table
+/// for types that cannot be inferred
+fn arg_from_lua(value: Value) -> Result<Arg> {
+    match value {
+        Value::Integer(value) => Ok(match i32::try_from(value) {
+            Ok(value) => Arg::Int(value),
+            Err(_) => Arg::Long(value),
+        }),
+        Value::Number(value) => Ok(Arg::Float(value as f32)),
+        Value::String(value) => Ok(Arg::String(value.to_str()?.to_string())),
+        Value::Boolean(value) => Ok(Arg::Bool(value)),
+        Value::Table(table) => {
+            let tag: String = table.get("type")?;
+            Ok(match tag.as_str() {
+                "i" => Arg::Int(table.get("value")?),
+                "h" => Arg::Long(table.get("value")?),
+                "f" => Arg::Float(table.get("value")?),
+                "d" => Arg::Double(table.get("value")?),
+                "s" => Arg::String(table.get("value")?),
+                "S" => Arg::Symbol(table.get("value")?),
+                "c" => {
+                    let value: String = table.get("value")?;
+                    let mut chars = value.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(char), None) => Arg::Char(char),
+                        _ => return Err(osc_error(format!("invalid char '{value}'"))),
+                    }
+                }
+                "b" => Arg::Blob(table.get::<mlua::String>("value")?.as_bytes().to_vec()),
+                "m" => {
+                    let value = table.get::<mlua::String>("value")?;
+                    Arg::Midi(
+                        value
+                            .as_bytes()
+                            .as_ref()
+                            .try_into()
+                            .map_err(|_| osc_error("MIDI messages must be 4 bytes long"))?,
+                    )
+                }
+                "t" => Arg::TimeTag(table.get::<i64>("value")? as u64),
+                "T" => Arg::Bool(true),
+                "F" => Arg::Bool(false),
+                "N" => Arg::Nil,
+                "I" => Arg::Impulse,
+                tag => return Err(osc_error(format!("unsupported type tag '{tag}'"))),
+            })
+        }
+        value => Err(osc_error(format!(
+            "unsupported argument type {}",
+            value.type_name()
+        ))),
+    }
+}
+
+fn arg_to_lua(lua: &Lua, arg: &Arg) -> Result<Value> {
+    let typed = |tag: &str| -> Result<Value> {
+        let table = lua.create_table()?;
+        table.set("type", tag)?;
+        Ok(Value::Table(table))
+    };
+
+    Ok(match arg {
+        Arg::Int(value) => Value::Integer((*value).into()),
+        Arg::Long(value) => Value::Integer(*value),
+        Arg::Float(value) => Value::Number((*value).into()),
+        Arg::Double(value) => Value::Number(*value),
+        Arg::String(value) | Arg::Symbol(value) => Value::String(lua.create_string(value)?),
+        Arg::Char(value) => Value::String(lua.create_string(value.to_string())?),
+        Arg::Blob(value) => Value::String(lua.create_string(value)?),
+        Arg::Midi(value) => Value::String(lua.create_string(value)?),
+        Arg::TimeTag(value) => Value::Integer(*value as i64),
+        Arg::Bool(value) => Value::Boolean(*value),
+        // Array tables cannot hold nil
+        Arg::Nil => typed("N")?,
+        Arg::Impulse => typed("I")?,
+    })
+}
+
+/// Packet from a `{ address, args }` message or `{ timetag, elements }`
+/// bundle table
+fn packet_from_lua(table: &Table) -> Result<Packet> {
+    if let Some(elements) = table.get::<Option<Table>>("elements")? {
+        let timetag = table
+            .get::<Option<i64>>("timetag")?
+            .map_or(IMMEDIATELY, |timetag| timetag as u64);
+        let elements = elements
+            .sequence_values::<Table>()
+            .map(|element| packet_from_lua(&element?))
+            .collect::<Result<_>>()?;
+        return Ok(Packet::Bundle { timetag, elements });
+    }
+
+    let address: String = table.get("address")?;
+    let args = match table.get::<Option<Table>>("args")? {
+        Some(args) => args
+            .sequence_values::<Value>()
+            .map(|arg| arg_from_lua(arg?))
+            .collect::<Result<_>>()?,
+        None => Vec::new(),
+    };
+    Ok(Packet::Message(Message { address, args }))
+}
+
+fn packet_to_lua(lua: &Lua, packet: &Packet) -> Result<Table> {
+    let table = lua.create_table()?;
+    match packet {
+        Packet::Message(message) => {
+            let args = lua.create_table()?;
+            let mut types = String::from(",");
+            for arg in &message.args {
+                args.push(arg_to_lua(lua, arg)?)?;
+                types.push(arg.type_tag());
+            }
+            table.set("address", message.address.as_str())?;
+            table.set("types", types)?;
+            table.set("args", args)?;
+        }
+        Packet::Bundle { timetag, elements } => {
+            let lua_elements = lua.create_table()?;
+            for element in elements {
+                lua_elements.push(packet_to_lua(lua, element)?)?;
+            }
+            table.set("timetag", *timetag as i64)?;
+            table.set("elements", lua_elements)?;
+        }
+    }
+    Ok(table)
+}
+
+/// Packet from either a packet table or an address followed by arguments
+fn packet_from_args(mut args: MultiValue) -> Result<Packet> {
+    match args.pop_front() {
+        Some(Value::Table(table)) => packet_from_lua(&table),
+        Some(Value::String(address)) => Ok(Packet::Message(Message {
+            address: address.to_str()?.to_string(),
+            args: args.into_iter().map(arg_from_lua).collect::<Result<_>>()?,
+        })),
+        _ => Err(osc_error("expected a packet table or an address")),
+    }
+}
+
+fn encode(lua: &Lua, args: MultiValue) -> Result<mlua::String> {
+    lua.create_string(packet_from_args(args)?.encode())
+}
+
+fn decode(lua: &Lua, data: mlua::String) -> Result<Table> {
+    let packet = Packet::decode(&data.as_bytes()).map_err(osc_error)?;
+    packet_to_lua(lua, &packet)
+}
+
+async fn send(_lua: Lua, (host, port, args): (String, u16, MultiValue)) -> Result<()> {
+    let packet = packet_from_args(args)?.encode();
+    let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(osc_error)?;
+    socket
+        .send_to(&packet, (host.as_str(), port))
+        .await
+        .map_err(osc_error)?;
+    Ok(())
+}
+
+/// Call `callback(packet, host, port)` with every valid packet received on
+/// the port, skipping invalid ones
+async fn listen(
+    lua: Lua,
+    (port, callback, options): (u16, mlua::Function, Option<Table>),
+) -> Result<Subscription> {
+    let bind = match &options {
+        Some(options) => options.get::<Option<String>>("bind")?,
+        None => None,
+    };
+    let socket = UdpSocket::bind((bind.as_deref().unwrap_or("0.0.0.0"), port))
+        .await
+        .map_err(osc_error)?;
+
+    let packets = stream::unfold(Arc::new(socket), |socket| async move {
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let (size, sender) = socket.recv_from(&mut buffer).await.ok()?;
+            match Packet::decode(&buffer[..size]) {
+                Ok(packet) => return Some(((packet, sender), socket)),
+                Err(error) => tracing::warn!("ignoring invalid OSC packet from {sender}: {error}"),
+            }
+        }
+    });
+
+    subscribe(&lua, callback, "osc", packets, |lua, (packet, sender)| {
+        (
+            packet_to_lua(lua, &packet)?,
+            sender.ip().to_string(),
+            sender.port(),
+        )
+            .into_lua_multi(lua)
+    })
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("encode", lua.create_function(encode)?)?;
+    module.set("decode", lua.create_function(decode)?)?;
+    module.set("send", lua.create_async_function(send)?)?;
+    module.set("listen", lua.create_async_function(listen)?)?;
+
+    libs.set("osc", &module)?;
+    lua.register_module("osc", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn create_lua() -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    #[test]
+    fn test_encode_message() {
+        // Example from the OSC 1.0 specification
+        let packet = Packet::Message(Message {
+            address: "/foo".to_string(),
+            args: vec![
+                Arg::Int(1000),
+                Arg::Int(-1),
+                Arg::String("hello".to_string()),
+                Arg::Float(1.234),
+                Arg::Float(5.678),
+            ],
+        });
+        let encoded = packet.encode();
+        assert_eq!(
+            encoded,
+            [
+                b"/foo\0\0\0\0,iisff\0\0".as_slice(),
+                &[0x00, 0x00, 0x03, 0xe8, 0xff, 0xff, 0xff, 0xff],
+                b"hello\0\0\0",
+                &[0x3f, 0x9d, 0xf3, 0xb6, 0x40, 0xb5, 0xb2, 0x2d],
+            ]
+            .concat()
+        );
+        assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let packet = Packet::Bundle {
+            timetag: IMMEDIATELY,
+            elements: vec![
+                Packet::Message(Message {
+                    address: "/mixer/fader".to_string(),
+                    args: vec![
+                        Arg::Double(0.5),
+                        Arg::Blob(vec![1, 2, 3]),
+                        Arg::Bool(true),
+                        Arg::Nil,
+                        Arg::Midi([0, 0x90, 60, 100]),
+                    ],
+                }),
+                Packet::Bundle {
+                    timetag: 42,
+                    elements: vec![Packet::Message(Message {
+                        address: "/transport/play".to_string(),
+                        args: Vec::new(),
+                    })],
+                },
+            ],
+        };
+        let encoded = packet.encode();
+        assert_eq!(encoded.len() % 4, 0);
+        assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+
+        assert!(Packet::decode(&encoded[..encoded.len() - 4]).is_err());
+        assert!(Packet::decode(b"foo\0").is_err());
+        assert!(Packet::decode(b"/foo\0\0\0\0,x\0\0").is_err());
+    }
+
+    #[test]
+    fn test_nested_bundle_depth() {
+        let nest = |depth| {
+            let mut packet = Packet::Message(Message {
+                address: "/ping".to_string(),
+                args: Vec::new(),
+            });
+            for _ in 0..depth {
+                packet = Packet::Bundle {
+                    timetag: IMMEDIATELY,
+                    elements: vec![packet],
+                };
+            }
+            packet.encode()
+        };
+
+        assert!(Packet::decode(&nest(MAX_BUNDLE_DEPTH)).is_ok());
+        let error = Packet::decode(&nest(MAX_BUNDLE_DEPTH + 1)).unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"), "{error}");
+    }
+
+    #[test]
+    fn test_lua_packets() {
+        let lua = create_lua();
+        lua.load(
+            r#"
+            local data = libs.osc.encode("/track/1/volume", 0.75, 3, "name", true)
+            local message = libs.osc.decode(data)
+            assert(message.address == "/track/1/volume" and message.types == ",fisT")
+            assert(message.args[1] == 0.75 and message.args[2] == 3)
+            assert(message.args[3] == "name" and message.args[4] == true)
+
+            local bundle = libs.osc.decode(libs.osc.encode({
+                elements = {
+                    { address = "/a", args = { { type = "d", value = 0.1 }, { type = "N" } } },
+                    { address = "/b", args = { { type = "b", value = "\0\1" } } },
+                },
+            }))
+            assert(bundle.timetag == 1 and #bundle.elements == 2)
+            assert(bundle.elements[1].types == ",dN" and bundle.elements[1].args[1] == 0.1)
+            assert(bundle.elements[1].args[2].type == "N")
+            assert(bundle.elements[2].args[1] == "\0\1")
+
+            assert(not pcall(libs.osc.decode, "garbage"))
+            assert(not pcall(libs.osc.encode, "/x", { type = "q" }))
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_and_listen() {
+        // Find a free port for the listener
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let lua = create_lua();
+        lua.globals().set("port", port).unwrap();
+        lua.load(
+            r#"
+            received = {}
+            subscription = libs.osc.listen(port, function(packet, host)
+                table.insert(received, { packet = packet, host = host })
+            end, { bind = "127.0.0.1" })
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        // Invalid packets are skipped
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(b"garbage", ("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        lua.load(r#"libs.osc.send("127.0.0.1", port, "/cue/go", 12)"#)
+            .exec_async()
+            .await
+            .unwrap();
+
+        let mut received = false;
+        for _ in 0..50 {
+            if lua.load("return #received == 1").eval::<bool>().unwrap() {
+                received = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(received);
+
+        lua.load(
+            r#"
+            local message = received[1].packet
+            assert(message.address == "/cue/go" and message.args[1] == 12)
+            assert(received[1].host == "127.0.0.1")
+            subscription:cancel()
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+}
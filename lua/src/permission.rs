@@ -0,0 +1,98 @@
+//! Gating of `libs.*` modules behind `meta.permissions`.
+//!
+//! Remotes list permissions like `keyboard mouse http`, or `*` for all of
+//! them; without the entry only `keyboard` and `mouse` are granted. Using an
+//! undeclared module raises a permission error.
+
+use mlua::{Error, Function, Lua, MetaMethod, Table, Value};
+use uniremote_core::{Permission, Permissions};
+
+/// Check whether the remote owning this Lua state was granted a permission.
+///
+/// States without declared permissions (e.g. in tests) are unrestricted.
+pub fn is_granted(lua: &Lua, permission: Permission) -> bool {
+    lua.app_data_ref::<Permissions>()
+        .is_none_or(|permissions| permissions.allows(permission))
+}
+
+fn permission_error(permission: Permission) -> Error {
+    Error::runtime(format!(
+        "permission denied: remote does not declare '{permission}' in meta.permissions"
+    ))
+}
+
+/// Create a function that always fails with a permission error
+pub fn denied_function(lua: &Lua, permission: Permission) -> mlua::Result<Function> {
+    lua.create_function(move |_lua, _: mlua::MultiValue| -> mlua::Result<()> {
+        Err(permission_error(permission))
+    })
+}
+
+/// Load a `libs.*` module if the permission is granted, otherwise install a
+/// stub module that raises a permission error on any access
+pub fn load_module(
+    lua: &Lua,
+    libs: &Table,
+    permission: Permission,
+    loader: fn(&Lua, &Table) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if is_granted(lua, permission) {
+        return loader(lua, libs);
+    }
+
+    tracing::info!("module '{permission}' is not loaded because the permission is not declared");
+
+    let module = lua.create_table()?;
+    let metatable = lua.create_table()?;
+    metatable.set(
+        MetaMethod::Index.name(),
+        lua.create_function(move |_lua, _: (Table, Value)| -> mlua::Result<()> {
+            Err(permission_error(permission))
+        })?,
+    )?;
+    module.set_metatable(Some(metatable))?;
+
+    libs.set(permission.as_str(), &module)?;
+    lua.register_module(permission.as_str(), module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denied_module_raises_permission_error() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        lua.set_app_data(Permissions::Only(vec![Permission::Keyboard]));
+
+        load_module(&lua, &libs, Permission::Ps, crate::ps::load).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        let error = lua.load("libs.ps.usage()").exec().unwrap_err().to_string();
+        assert!(error.contains("permission denied"), "{error}");
+        assert!(error.contains("'ps'"), "{error}");
+
+        let error = lua
+            .load(r#"require("ps").usage()"#)
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("permission denied"), "{error}");
+    }
+
+    #[test]
+    fn test_granted_module_is_loaded() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        lua.set_app_data(Permissions::Only(vec![Permission::Ps]));
+
+        load_module(&lua, &libs, Permission::Ps, crate::ps::load).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load("assert(type(libs.ps.usage) == 'function')")
+            .exec()
+            .unwrap();
+    }
+}
@@ -0,0 +1,358 @@
+//! `libs.power`: logind power management, permission `power`.
+//!
+//! `suspend`, `hibernate`, `reboot`, `poweroff`, `lock`, `can(action)` and
+//! `inhibit{ what, why, mode }`. Hibernate, reboot and poweroff first send a
+//! `confirm` server message and only proceed when a client answers
+//! `{ type = "confirm", nonce, confirmed = true }`; without a connected
+//! client they are declined.
+
+use std::sync::Mutex;
+
+use mlua::{Error, Lua, Result, Table, UserData, UserDataMethods};
+use zbus::{Connection, zvariant::OwnedFd};
+
+use crate::dbus::{dbus_error, system_connection};
+
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+/// Session of the caller, or the user's graphical session if the server
+/// doesn't run inside one
+const AUTO_SESSION_PATH: &str = "/org/freedesktop/login1/session/auto";
+
+/// Name reported to logind as the holder of inhibitor locks
+const INHIBITOR_WHO: &str = "uniremote";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PowerAction {
+    Suspend,
+    Hibernate,
+    Reboot,
+    PowerOff,
+}
+
+impl PowerAction {
+    fn parse(action: &str) -> Result<Self> {
+        match action {
+            "suspend" => Ok(Self::Suspend),
+            "hibernate" => Ok(Self::Hibernate),
+            "reboot" => Ok(Self::Reboot),
+            "poweroff" => Ok(Self::PowerOff),
+            _ => Err(Error::runtime(format!(
+                "invalid power action '{action}', expected suspend, hibernate, reboot or poweroff"
+            ))),
+        }
+    }
+
+    /// Name of the logind manager method
+    fn method(self) -> &'static str {
+        match self {
+            Self::Suspend => "Suspend",
+            Self::Hibernate => "Hibernate",
+            Self::Reboot => "Reboot",
+            Self::PowerOff => "PowerOff",
+        }
+    }
+
+    /// Question asked to the client before a destructive action, if any
+    fn confirmation(self) -> Option<&'static str> {
+        match self {
+            Self::Suspend => None,
+            Self::Hibernate => Some("Hibernate the computer?"),
+            Self::Reboot => Some("Reboot the computer?"),
+            Self::PowerOff => Some("Power off the computer?"),
+        }
+    }
+}
+
+async fn call_manager<B>(connection: &Connection, method: &str, body: &B) -> Result<zbus::Message>
+where
+    B: serde::Serialize + zbus::zvariant::DynamicType,
+{
+    connection
+        .call_method(
+            Some(LOGIND_NAME),
+            LOGIND_PATH,
+            Some(MANAGER_INTERFACE),
+            method,
+            body,
+        )
+        .await
+        .map_err(dbus_error)
+}
+
+/// Perform a power action, asking a client for confirmation first if the
+/// action is destructive. Returns whether the action was performed.
+async fn perform(lua: &Lua, action: PowerAction, message: Option<String>) -> Result<bool> {
+    if let Some(question) = action.confirmation() {
+        let message = message.unwrap_or_else(|| question.to_string());
+        if !crate::server::confirm(lua, message).await? {
+            tracing::info!("power action {action:?} was not confirmed");
+            return Ok(false);
+        }
+    }
+
+    let connection = system_connection(lua).await?;
+    // Not interactive, polkit must allow the action without authentication
+    call_manager(&connection, action.method(), &(false,)).await?;
+    Ok(true)
+}
+
+/// Whether logind allows an action: `yes`, `no`, `challenge` or `na`
+async fn can(lua: Lua, action: String) -> Result<String> {
+    let action = PowerAction::parse(&action)?;
+    let connection = system_connection(&lua).await?;
+    call_manager(&connection, &format!("Can{}", action.method()), &())
+        .await?
+        .body()
+        .deserialize()
+        .map_err(dbus_error)
+}
+
+async fn lock(lua: Lua, (): ()) -> Result<()> {
+    system_connection(&lua)
+        .await?
+        .call_method(
+            Some(LOGIND_NAME),
+            AUTO_SESSION_PATH,
+            Some(SESSION_INTERFACE),
+            "Lock",
+            &(),
+        )
+        .await
+        .map_err(dbus_error)?;
+    Ok(())
+}
+
+/// Inhibitor lock, held until released or garbage collected
+struct Inhibitor(Mutex<Option<OwnedFd>>);
+
+impl UserData for Inhibitor {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("release", |_, this, ()| {
+            // Closing the file descriptor releases the lock
+            Ok(this.0.lock().unwrap().take().is_some())
+        });
+
+        methods.add_method("active", |_, this, ()| Ok(this.0.lock().unwrap().is_some()));
+    }
+}
+
+async fn inhibit(lua: Lua, params: Option<Table>) -> Result<Inhibitor> {
+    let (what, why, mode) = match params {
+        Some(params) => (
+            params.get::<Option<String>>("what")?,
+            params.get::<Option<String>>("why")?,
+            params.get::<Option<String>>("mode")?,
+        ),
+        None => (None, None, None),
+    };
+
+    let mode = mode.unwrap_or_else(|| "block".to_string());
+    if mode != "block" && mode != "delay" {
+        return Err(Error::runtime(format!(
+            "invalid inhibitor mode '{mode}', expected block or delay"
+        )));
+    }
+
+    let connection = system_connection(&lua).await?;
+    let fd: OwnedFd = call_manager(
+        &connection,
+        "Inhibit",
+        &(
+            what.as_deref().unwrap_or("sleep"),
+            INHIBITOR_WHO,
+            why.as_deref().unwrap_or("Requested by a remote"),
+            mode.as_str(),
+        ),
+    )
+    .await?
+    .body()
+    .deserialize()
+    .map_err(dbus_error)?;
+
+    Ok(Inhibitor(Mutex::new(Some(fd))))
+}
+
+fn add_action(lua: &Lua, module: &Table, name: &str, action: PowerAction) -> anyhow::Result<()> {
+    module.set(
+        name,
+        lua.create_async_function(move |lua, message: Option<String>| async move {
+            perform(&lua, action, message).await
+        })?,
+    )?;
+    Ok(())
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    add_action(lua, &module, "suspend", PowerAction::Suspend)?;
+    add_action(lua, &module, "hibernate", PowerAction::Hibernate)?;
+    add_action(lua, &module, "reboot", PowerAction::Reboot)?;
+    add_action(lua, &module, "poweroff", PowerAction::PowerOff)?;
+    module.set("can", lua.create_async_function(can)?)?;
+    module.set("lock", lua.create_async_function(lock)?)?;
+    module.set("inhibit", lua.create_async_function(inhibit)?)?;
+
+    libs.set("power", &module)?;
+    lua.register_module("power", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use uniremote_core::ServerMessage;
+
+    use super::*;
+    use crate::dbus::{SystemBus, tests::TestBus};
+
+    #[derive(Default)]
+    struct FakeLogind {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeLogind {
+        fn record(&self, call: impl Into<String>) {
+            self.calls.lock().unwrap().push(call.into());
+        }
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeLogind {
+        fn suspend(&self, _interactive: bool) {
+            self.record("Suspend");
+        }
+
+        fn reboot(&self, _interactive: bool) {
+            self.record("Reboot");
+        }
+
+        fn power_off(&self, _interactive: bool) {
+            self.record("PowerOff");
+        }
+
+        fn can_hibernate(&self) -> String {
+            "na".to_string()
+        }
+
+        fn inhibit(&self, what: String, who: String, _why: String, mode: String) -> OwnedFd {
+            self.record(format!("Inhibit {what} {who} {mode}"));
+            let file = tempfile::tempfile().unwrap();
+            std::os::fd::OwnedFd::from(file).into()
+        }
+    }
+
+    struct FakeSession {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Session")]
+    impl FakeSession {
+        fn lock(&self) {
+            self.calls.lock().unwrap().push("Lock".to_string());
+        }
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(
+            PowerAction::parse("poweroff").unwrap(),
+            PowerAction::PowerOff
+        );
+        assert_eq!(PowerAction::parse("suspend").unwrap().confirmation(), None);
+        assert!(
+            PowerAction::parse("reboot")
+                .unwrap()
+                .confirmation()
+                .is_some()
+        );
+        assert!(PowerAction::parse("shutdown").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_power_actions() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        };
+
+        let logind = FakeLogind::default();
+        let calls = logind.calls.clone();
+        let session = FakeSession {
+            calls: calls.clone(),
+        };
+        let _server = bus
+            .connect()
+            .name(LOGIND_NAME)
+            .unwrap()
+            .serve_at(LOGIND_PATH, logind)
+            .unwrap()
+            .serve_at(AUTO_SESSION_PATH, session)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        crate::server::load(&lua, &libs).unwrap();
+        lua.set_app_data(SystemBus::from(bus.connect().build().await.unwrap()));
+        lua.globals().set("libs", libs).unwrap();
+
+        // The outbox receiver kept by the worker, the task below is the client
+        let (tx, outbox) = flume::unbounded();
+        let rx = outbox.clone();
+        lua.set_app_data(tx);
+
+        // Decline the reboot and accept the power off
+        let answers = {
+            let lua = lua.clone();
+            tokio::spawn(async move {
+                for confirmed in [false, true] {
+                    let ServerMessage::Confirm { nonce, .. } = rx.recv_async().await.unwrap()
+                    else {
+                        panic!("Expected Confirm message");
+                    };
+                    assert!(crate::server::resolve_confirmation(&lua, &nonce, confirmed));
+                }
+            })
+        };
+
+        let (suspended, rebooted, powered_off, can_hibernate) = lua
+            .load(
+                r#"
+                local inhibitor = libs.power.inhibit({ what = "idle", why = "Watching" })
+                assert(inhibitor:active())
+                assert(inhibitor:release())
+                assert(not inhibitor:active())
+
+                libs.power.lock()
+                return libs.power.suspend(), libs.power.reboot(), libs.power.poweroff(),
+                    libs.power.can("hibernate")
+            "#,
+            )
+            .eval_async::<(bool, bool, bool, String)>()
+            .await
+            .unwrap();
+        answers.await.unwrap();
+
+        assert!(suspended);
+        assert!(!rebooted);
+        assert!(powered_off);
+        assert_eq!(can_hibernate, "na");
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "Inhibit idle uniremote block",
+                "Lock",
+                "Suspend",
+                "PowerOff"
+            ]
+        );
+    }
+}
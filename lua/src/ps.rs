@@ -1,5 +1,13 @@
-use mlua::{Lua, Result, Table};
-use sysinfo::System;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use mlua::{Error, Lua, Result, Table, Value};
+use sysinfo::{
+    MINIMUM_CPU_UPDATE_INTERVAL, Pid, Process, ProcessRefreshKind, ProcessesToUpdate, Signal,
+    System, Users,
+};
 
 fn usage(lua: &Lua, _: ()) -> Result<Table> {
     let mut sys = System::new();
@@ -32,9 +40,198 @@ fn usage(lua: &Lua, _: ()) -> Result<Table> {
     Ok(result)
 }
 
+/// Process list kept between calls, CPU usage is computed from the
+/// difference to the previous refresh
+#[derive(Clone)]
+struct Processes(Arc<ProcessesInner>);
+
+struct ProcessesInner {
+    system: Mutex<System>,
+    /// Whether the list was refreshed before, so that CPU usage is known
+    primed: AtomicBool,
+}
+
+impl Processes {
+    fn get(lua: &Lua) -> Self {
+        lua.app_data_ref::<Self>()
+            .expect("process list not found in lua state")
+            .clone()
+    }
+
+    fn refresh(&self) {
+        self.0.system.lock().unwrap().refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing()
+                .with_cpu()
+                .with_memory()
+                .with_cmd(sysinfo::UpdateKind::OnlyIfNotSet)
+                .with_user(sysinfo::UpdateKind::OnlyIfNotSet),
+        );
+    }
+}
+
+/// Refresh all processes with command lines and users
+async fn processes(lua: &Lua) -> Processes {
+    let processes = Processes::get(lua);
+    processes.refresh();
+
+    // The first refresh has nothing to compute CPU usage from
+    if !processes.0.primed.swap(true, Ordering::Relaxed) {
+        tokio::time::sleep(MINIMUM_CPU_UPDATE_INTERVAL).await;
+        processes.refresh();
+    }
+
+    processes
+}
+
+fn process_table(lua: &Lua, process: &Process, users: &Users) -> Result<Table> {
+    let cmdline = process
+        .cmd()
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let user = process
+        .user_id()
+        .and_then(|uid| users.get_user_by_id(uid))
+        .map(|user| user.name().to_string());
+
+    let table = lua.create_table()?;
+    table.set("pid", process.pid().as_u32())?;
+    table.set("ppid", process.parent().map(Pid::as_u32))?;
+    table.set("name", process.name().to_string_lossy())?;
+    table.set("cmdline", cmdline)?;
+    table.set("user", user)?;
+    table.set("cpu", process.cpu_usage())?;
+    table.set("memory", process.memory())?;
+    Ok(table)
+}
+
+fn process_list<'a>(lua: &Lua, processes: impl Iterator<Item = &'a Process>) -> Result<Vec<Table>> {
+    let users = Users::new_with_refreshed_list();
+    let mut processes = processes.collect::<Vec<_>>();
+    processes.sort_by_key(|process| process.pid());
+    processes
+        .into_iter()
+        .map(|process| process_table(lua, process, &users))
+        .collect()
+}
+
+async fn list(lua: Lua, _: ()) -> Result<Vec<Table>> {
+    let processes = processes(&lua).await;
+    let sys = processes.0.system.lock().unwrap();
+    process_list(&lua, sys.processes().values())
+}
+
+async fn find(lua: Lua, name: String) -> Result<Vec<Table>> {
+    let processes = processes(&lua).await;
+    let sys = processes.0.system.lock().unwrap();
+    process_list(&lua, sys.processes_by_exact_name(name.as_ref()))
+}
+
+async fn children(lua: Lua, pid: u32) -> Result<Vec<Table>> {
+    let pid = Pid::from_u32(pid);
+    let processes = processes(&lua).await;
+    let sys = processes.0.system.lock().unwrap();
+    process_list(
+        &lua,
+        sys.processes()
+            .values()
+            .filter(|process| process.parent() == Some(pid)),
+    )
+}
+
+fn exists(_lua: &Lua, pid: u32) -> Result<bool> {
+    let mut sys = System::new();
+    let pid = Pid::from_u32(pid);
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        true,
+        ProcessRefreshKind::nothing(),
+    );
+
+    // Zombies are gone as far as the caller is concerned, they only wait to
+    // be reaped by their parent
+    Ok(sys
+        .process(pid)
+        .is_some_and(|process| process.status() != sysinfo::ProcessStatus::Zombie))
+}
+
+fn kill(_lua: &Lua, (pid, signal): (u32, Option<Value>)) -> Result<bool> {
+    let signal = match signal {
+        None | Some(Value::Nil) => Signal::Term,
+        Some(value) => parse_signal(&value)?,
+    };
+
+    let mut sys = System::new();
+    let pid = Pid::from_u32(pid);
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        true,
+        ProcessRefreshKind::nothing(),
+    );
+
+    let process = sys
+        .process(pid)
+        .ok_or_else(|| Error::runtime(format!("process {pid} does not exist")))?;
+
+    tracing::info!("sending {signal:?} to process {pid}");
+
+    process
+        .kill_with(signal)
+        .ok_or_else(|| Error::runtime(format!("signal {signal:?} is not supported")))
+}
+
+/// Parse a signal given either as a number or as a name like `TERM`,
+/// `SIGKILL` or `hup`
+fn parse_signal(value: &Value) -> Result<Signal> {
+    if let Some(number) = value.as_integer() {
+        return match number {
+            1 => Ok(Signal::Hangup),
+            2 => Ok(Signal::Interrupt),
+            3 => Ok(Signal::Quit),
+            9 => Ok(Signal::Kill),
+            10 => Ok(Signal::User1),
+            12 => Ok(Signal::User2),
+            15 => Ok(Signal::Term),
+            18 => Ok(Signal::Continue),
+            19 => Ok(Signal::Stop),
+            _ => Err(Error::runtime(format!(
+                "unsupported signal number {number}"
+            ))),
+        };
+    }
+
+    let name = value.to_string()?.to_uppercase();
+    match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => Ok(Signal::Hangup),
+        "INT" => Ok(Signal::Interrupt),
+        "QUIT" => Ok(Signal::Quit),
+        "KILL" => Ok(Signal::Kill),
+        "USR1" => Ok(Signal::User1),
+        "USR2" => Ok(Signal::User2),
+        "TERM" => Ok(Signal::Term),
+        "CONT" => Ok(Signal::Continue),
+        "STOP" => Ok(Signal::Stop),
+        _ => Err(Error::runtime(format!("unsupported signal '{name}'"))),
+    }
+}
+
 pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    lua.set_app_data(Processes(Arc::new(ProcessesInner {
+        system: Mutex::new(System::new()),
+        primed: AtomicBool::new(false),
+    })));
+
     let module = lua.create_table()?;
     module.set("usage", lua.create_function(usage)?)?;
+    module.set("list", lua.create_async_function(list)?)?;
+    module.set("find", lua.create_async_function(find)?)?;
+    module.set("children", lua.create_async_function(children)?)?;
+    module.set("exists", lua.create_function(exists)?)?;
+    module.set("kill", lua.create_function(kill)?)?;
 
     libs.set("ps", &module)?;
     lua.register_module("ps", module)?;
@@ -159,4 +356,127 @@ mod tests {
             "total memory should exceed used memory"
         );
     }
+
+    fn ps_lua() -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    #[tokio::test]
+    async fn test_ps_list_contains_current_process() {
+        let lua = ps_lua();
+        lua.globals().set("own_pid", std::process::id()).unwrap();
+
+        lua.load(
+            r#"
+            local found = nil
+            for _, process in ipairs(libs.ps.list()) do
+                if process.pid == own_pid then
+                    found = process
+                end
+            end
+
+            assert(found ~= nil, "current process should be listed")
+            assert(type(found.name) == "string")
+            assert(type(found.cmdline) == "string")
+            assert(type(found.memory) == "number")
+            assert(type(found.cpu) == "number")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ps_list_cpu_usage() {
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "while :; do :; done"])
+            .spawn()
+            .unwrap();
+
+        // sysinfo reports no usage for processes without CPU time at the
+        // previous refresh, so let the child run first
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let lua = ps_lua();
+        lua.globals().set("child_pid", child.id()).unwrap();
+
+        let result = lua
+            .load(
+                r#"
+                for _, process in ipairs(libs.ps.list()) do
+                    if process.pid == child_pid then
+                        return process.cpu
+                    end
+                end
+            "#,
+            )
+            .eval_async::<f32>()
+            .await;
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(result.unwrap() > 0.0, "busy process should use CPU");
+    }
+
+    #[tokio::test]
+    async fn test_ps_spawn_and_kill_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+
+        let lua = ps_lua();
+        lua.globals().set("child_pid", child.id()).unwrap();
+        lua.globals().set("own_pid", std::process::id()).unwrap();
+
+        lua.load(
+            r#"
+            assert(libs.ps.exists(child_pid), "child should exist")
+
+            local is_child = false
+            for _, process in ipairs(libs.ps.children(own_pid)) do
+                if process.pid == child_pid then
+                    is_child = true
+                    assert(process.ppid == own_pid)
+                end
+            end
+            assert(is_child, "child should be listed in children")
+
+            local found = false
+            for _, process in ipairs(libs.ps.find("sleep")) do
+                if process.pid == child_pid then
+                    found = true
+                end
+            end
+            assert(found, "child should be found by name")
+
+            assert(libs.ps.kill(child_pid, "KILL"), "kill should succeed")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let status = child.wait().unwrap();
+        assert!(!status.success());
+
+        lua.load("assert(not libs.ps.exists(child_pid), 'child should be gone')")
+            .exec()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ps_kill_invalid_signal() {
+        let lua = ps_lua();
+        lua.globals().set("own_pid", std::process::id()).unwrap();
+
+        let result = lua.load("libs.ps.kill(own_pid, 'BOGUS')").exec();
+        assert!(result.is_err());
+    }
 }
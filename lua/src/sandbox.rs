@@ -0,0 +1,288 @@
+//! Curated standard library of remote scripts.
+//!
+//! `io`, `debug`, `dofile`, `loadfile` and binary chunks are removed, `os` is
+//! limited to time functions and `require` only loads from the remote
+//! directory and the shared `lib` directory.
+
+use std::path::{Path, PathBuf};
+
+use mlua::{ChunkMode, Error, Function, Lua, LuaOptions, MultiValue, StdLib, Table, Value};
+
+/// Name of the directory inside the remotes directory that holds Lua modules
+/// shared between remotes
+pub const SHARED_LIB_DIR: &str = "lib";
+
+/// Functions kept in the `os` table, uniremote extras are added on top later
+const OS_ALLOWED: &[&str] = &["clock", "date", "difftime", "time"];
+
+/// Global functions that can read or execute arbitrary files
+const GLOBALS_DENIED: &[&str] = &["dofile", "loadfile"];
+
+/// Functions removed from the `package` table
+const PACKAGE_DENIED: &[&str] = &["loadlib", "searchpath"];
+
+/// Create a new Lua state with the curated standard library.
+///
+/// `require` only resolves modules registered from Rust until
+/// [`restrict_require`] is called with a list of allowed directories.
+pub fn create() -> anyhow::Result<Lua> {
+    // `io` and `debug` are never loaded, the rest is trimmed down below
+    let stdlib = StdLib::COROUTINE
+        | StdLib::TABLE
+        | StdLib::STRING
+        | StdLib::UTF8
+        | StdLib::MATH
+        | StdLib::OS
+        | StdLib::PACKAGE;
+
+    let lua = Lua::new_with(stdlib, LuaOptions::default())?;
+    restrict_globals(&lua)?;
+    restrict_os(&lua)?;
+    restrict_package(&lua)?;
+    Ok(lua)
+}
+
+fn restrict_globals(lua: &Lua) -> anyhow::Result<()> {
+    let globals = lua.globals();
+    for name in GLOBALS_DENIED {
+        globals.raw_set(*name, Value::Nil)?;
+    }
+
+    // Only allow loading source chunks, binary chunks can crash the VM
+    let load: Function = globals.get("load")?;
+    let text_load = lua.create_function(
+        move |_lua, (chunk, name, _mode, env): (Value, Value, Value, Value)| {
+            if env.is_nil() {
+                load.call::<MultiValue>((chunk, name, "t"))
+            } else {
+                load.call::<MultiValue>((chunk, name, "t", env))
+            }
+        },
+    )?;
+    globals.raw_set("load", text_load)?;
+
+    Ok(())
+}
+
+fn restrict_os(lua: &Lua) -> anyhow::Result<()> {
+    let os: Table = lua.globals().get("os")?;
+    let denied = os
+        .pairs::<String, Value>()
+        .filter_map(mlua::Result::ok)
+        .map(|(name, _)| name)
+        .filter(|name| !OS_ALLOWED.contains(&name.as_str()))
+        .collect::<Vec<_>>();
+
+    for name in denied {
+        os.raw_set(name, Value::Nil)?;
+    }
+
+    Ok(())
+}
+
+fn restrict_package(lua: &Lua) -> anyhow::Result<()> {
+    let package: Table = lua.globals().get("package")?;
+    for name in PACKAGE_DENIED {
+        package.raw_set(*name, Value::Nil)?;
+    }
+    package.raw_set("path", "")?;
+    package.raw_set("cpath", "")?;
+
+    // Keep only the preload searcher, file searchers are added by
+    // `restrict_require`
+    let searchers: Table = package.get("searchers")?;
+    let preload: Function = searchers.raw_get(1)?;
+    let restricted = lua.create_table()?;
+    restricted.raw_push(preload)?;
+    package.raw_set("searchers", restricted)?;
+
+    Ok(())
+}
+
+/// Allow `require` to load Lua modules from the given directories.
+///
+/// Module names are resolved like `package.path` does with `?.lua;?/init.lua`
+/// patterns. Resolved files are canonicalized and must stay inside the
+/// directory they were found in, which prevents escaping via `..` or symlinks.
+/// Directories that do not exist are skipped.
+pub fn restrict_require(lua: &Lua, dirs: &[&Path]) -> anyhow::Result<()> {
+    let roots: Vec<PathBuf> = dirs
+        .iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect();
+
+    let searcher = lua.create_function(move |lua, name: String| {
+        let relative = name.replace('.', "/");
+
+        for root in &roots {
+            for candidate in [format!("{relative}.lua"), format!("{relative}/init.lua")] {
+                let Ok(path) = root.join(&candidate).canonicalize() else {
+                    continue;
+                };
+
+                if !path.starts_with(root) {
+                    return Err(Error::runtime(format!(
+                        "access denied: module '{name}' is outside the allowed directories"
+                    )));
+                }
+
+                let source = std::fs::read(&path).map_err(|error| {
+                    Error::runtime(format!(
+                        "failed to read module '{}': {error}",
+                        path.display()
+                    ))
+                })?;
+
+                let loader = lua
+                    .load(source)
+                    .set_name(format!("@{}", path.display()))
+                    .set_mode(ChunkMode::Text)
+                    .into_function()?;
+
+                return Ok(MultiValue::from_iter([
+                    Value::Function(loader),
+                    Value::String(lua.create_string(path.display().to_string())?),
+                ]));
+            }
+        }
+
+        Ok(MultiValue::from_iter([Value::String(lua.create_string(
+            format!("\n\tno module '{name}' in remote or shared library directories"),
+        )?)]))
+    })?;
+
+    let package: Table = lua.globals().get("package")?;
+    let searchers: Table = package.get("searchers")?;
+    searchers.raw_push(searcher)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_sandbox_removes_unsafe_libraries() {
+        let lua = create().unwrap();
+
+        lua.load(
+            r#"
+            assert(io == nil, "io should not be available")
+            assert(debug == nil, "debug should not be available")
+            assert(dofile == nil, "dofile should not be available")
+            assert(loadfile == nil, "loadfile should not be available")
+            assert(os.execute == nil, "os.execute should not be available")
+            assert(os.remove == nil, "os.remove should not be available")
+            assert(os.getenv == nil, "os.getenv should not be available")
+            assert(package.loadlib == nil, "package.loadlib should not be available")
+            assert(type(os.time()) == "number")
+            assert(type(os.date()) == "string")
+            assert(type(string.format) == "function")
+            assert(type(math.floor) == "function")
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_load_rejects_binary_chunks() {
+        let lua = create().unwrap();
+
+        lua.load(
+            r#"
+            local fn = load("return 1 + 1")
+            assert(fn() == 2)
+
+            local bytecode = string.dump(function() return 1 end)
+            local chunk, err = load(bytecode)
+            assert(chunk == nil, "binary chunk should be rejected")
+            assert(err:find("binary"), err)
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_remote_script_rejects_binary_chunks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let script = temp_dir.path().join("remote.lua");
+
+        let lua = Lua::new();
+        let bytecode: mlua::String = lua
+            .load("return string.dump(function() actions = {} end)")
+            .eval()
+            .unwrap();
+        fs::write(&script, bytecode.as_bytes()).unwrap();
+
+        let state = crate::LuaState::new(
+            temp_dir.path(),
+            temp_dir.path(),
+            crate::LuaLimits::default(),
+            &uniremote_core::Permissions::All,
+        )
+        .unwrap();
+        let error = state
+            .exec(&script)
+            .expect_err("binary script should be rejected");
+        assert!(error.to_string().contains("binary chunk"), "{error}");
+    }
+
+    #[test]
+    fn test_require_without_directories() {
+        let lua = create().unwrap();
+
+        let result = lua.load(r#"require("anything")"#).exec();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_require_from_remote_and_shared_dirs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let remote_dir = temp_dir.path().join("remote");
+        let shared_dir = temp_dir.path().join(SHARED_LIB_DIR);
+        fs::create_dir_all(remote_dir.join("utils")).unwrap();
+        fs::create_dir_all(&shared_dir).unwrap();
+
+        fs::write(remote_dir.join("helper.lua"), "return { value = 1 }").unwrap();
+        fs::write(remote_dir.join("utils/init.lua"), "return { value = 2 }").unwrap();
+        fs::write(shared_dir.join("common.lua"), "return { value = 3 }").unwrap();
+
+        let lua = create().unwrap();
+        restrict_require(&lua, &[&remote_dir, &shared_dir]).unwrap();
+
+        lua.load(
+            r#"
+            assert(require("helper").value == 1)
+            assert(require("utils").value == 2)
+            assert(require("common").value == 3)
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_require_symlink_escape_blocked() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let remote_dir = temp_dir.path().join("remote");
+        fs::create_dir_all(&remote_dir).unwrap();
+        fs::write(temp_dir.path().join("secret.lua"), "return true").unwrap();
+        std::os::unix::fs::symlink(
+            temp_dir.path().join("secret.lua"),
+            remote_dir.join("secret.lua"),
+        )
+        .unwrap();
+
+        let lua = create().unwrap();
+        restrict_require(&lua, &[&remote_dir]).unwrap();
+
+        let result = lua.load(r#"require("secret")"#).exec();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("access denied"));
+    }
+}
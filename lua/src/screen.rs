@@ -0,0 +1,421 @@
+//! `libs.screen`: X11 screen capture, permission `screen`.
+//!
+//! `capture(region?, { scale, quality }?)` returns JPEG data and `size()` the
+//! screen size. Remotes declaring `screen` by name also serve
+//! `/api/r/{id}/screen?scale=&quality=` as a snapshot, or as an MJPEG stream
+//! with `stream=true&fps=`.
+
+use std::sync::Arc;
+
+use anyhow::{Context, bail};
+use jpeg_encoder::{ColorType, Encoder};
+use mlua::{Error, Lua, Result, Table, Value};
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{ConnectionExt, ImageFormat, ImageOrder, Window},
+    rust_connection::RustConnection,
+};
+
+/// JPEG quality used when none is given
+pub const DEFAULT_QUALITY: u8 = 75;
+
+/// Part of the screen to capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Captured image as packed RGB pixels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub rgb: Vec<u8>,
+}
+
+/// Layout of the pixels returned by `GetImage`
+#[derive(Debug, Clone, Copy)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    scanline_pad: u8,
+    msb_first: bool,
+    red_mask: u32,
+    green_mask: u32,
+    blue_mask: u32,
+}
+
+/// Root window of an X11 display to take screenshots of
+pub struct Screen {
+    conn: RustConnection,
+    root: Window,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+}
+
+impl Screen {
+    pub fn connect(display: Option<&str>) -> anyhow::Result<Self> {
+        let (conn, screen) = x11rb::connect(display).context("failed to connect to X server")?;
+        let setup = conn.setup();
+        let screen = &setup.roots[screen];
+
+        let visual = screen
+            .allowed_depths
+            .iter()
+            .flat_map(|depth| &depth.visuals)
+            .find(|visual| visual.visual_id == screen.root_visual)
+            .context("root visual not found")?;
+        let pixmap = setup
+            .pixmap_formats
+            .iter()
+            .find(|format| format.depth == screen.root_depth)
+            .context("pixmap format of the root window not found")?;
+
+        let format = PixelFormat {
+            bits_per_pixel: pixmap.bits_per_pixel,
+            scanline_pad: pixmap.scanline_pad,
+            msb_first: setup.image_byte_order == ImageOrder::MSB_FIRST,
+            red_mask: visual.red_mask,
+            green_mask: visual.green_mask,
+            blue_mask: visual.blue_mask,
+        };
+        if !matches!(format.bits_per_pixel, 16 | 24 | 32) {
+            bail!(
+                "unsupported pixel format with {} bits",
+                format.bits_per_pixel
+            );
+        }
+
+        Ok(Self {
+            root: screen.root,
+            width: screen.width_in_pixels.into(),
+            height: screen.height_in_pixels.into(),
+            format,
+            conn,
+        })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Capture a region of the screen, the whole screen if `None`. The region
+    /// is clipped to the screen.
+    pub fn capture(&self, region: Option<Region>) -> anyhow::Result<Frame> {
+        let region = clip(region, self.width, self.height).context("region is off screen")?;
+        let image = self
+            .conn
+            .get_image(
+                ImageFormat::Z_PIXMAP,
+                self.root,
+                region.x as i16,
+                region.y as i16,
+                region.width as u16,
+                region.height as u16,
+                !0,
+            )?
+            .reply()
+            .context("failed to capture screen")?;
+
+        Ok(Frame {
+            width: region.width,
+            height: region.height,
+            rgb: to_rgb(&image.data, region.width, region.height, &self.format),
+        })
+    }
+}
+
+fn clip(region: Option<Region>, width: u32, height: u32) -> Option<Region> {
+    let Some(region) = region else {
+        return Some(Region {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        });
+    };
+
+    let left = region.x.clamp(0, width as i32);
+    let top = region.y.clamp(0, height as i32);
+    let right = (region.x as i64 + region.width as i64).clamp(0, width as i64) as i32;
+    let bottom = (region.y as i64 + region.height as i64).clamp(0, height as i64) as i32;
+    (right > left && bottom > top).then(|| Region {
+        x: left,
+        y: top,
+        width: (right - left) as u32,
+        height: (bottom - top) as u32,
+    })
+}
+
+/// Scale a channel selected by a mask to 8 bits
+fn channel(pixel: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let value = (pixel & mask) >> mask.trailing_zeros();
+    let max = mask >> mask.trailing_zeros();
+    (value * 255 / max) as u8
+}
+
+fn to_rgb(data: &[u8], width: u32, height: u32, format: &PixelFormat) -> Vec<u8> {
+    let bytes_per_pixel = format.bits_per_pixel as usize / 8;
+    let pad = format.scanline_pad as usize / 8;
+    let stride = (width as usize * bytes_per_pixel).div_ceil(pad) * pad;
+
+    let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+    for row in data.chunks(stride).take(height as usize) {
+        for bytes in row.chunks_exact(bytes_per_pixel).take(width as usize) {
+            let pixel = if format.msb_first {
+                bytes
+                    .iter()
+                    .fold(0u32, |pixel, &byte| (pixel << 8) | byte as u32)
+            } else {
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0u32, |pixel, &byte| (pixel << 8) | byte as u32)
+            };
+            rgb.extend([
+                channel(pixel, format.red_mask),
+                channel(pixel, format.green_mask),
+                channel(pixel, format.blue_mask),
+            ]);
+        }
+    }
+    rgb
+}
+
+impl Frame {
+    /// Shrink the frame by a factor between 0 and 1
+    pub fn scale(self, factor: f64) -> Self {
+        if !(factor > 0.0 && factor < 1.0) {
+            return self;
+        }
+
+        let width = ((self.width as f64 * factor).round() as u32).max(1);
+        let height = ((self.height as f64 * factor).round() as u32).max(1);
+        let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+        for y in 0..height {
+            let source_y = (y as u64 * self.height as u64 / height as u64) as usize;
+            for x in 0..width {
+                let source_x = (x as u64 * self.width as u64 / width as u64) as usize;
+                let offset = (source_y * self.width as usize + source_x) * 3;
+                rgb.extend_from_slice(&self.rgb[offset..offset + 3]);
+            }
+        }
+
+        Self { width, height, rgb }
+    }
+
+    /// Encode as JPEG with a quality from 1 to 100
+    pub fn to_jpeg(&self, quality: u8) -> anyhow::Result<Vec<u8>> {
+        let (Ok(width), Ok(height)) = (u16::try_from(self.width), u16::try_from(self.height))
+        else {
+            bail!("frame of {}x{} is too large", self.width, self.height);
+        };
+
+        let mut jpeg = Vec::new();
+        Encoder::new(&mut jpeg, quality.clamp(1, 100)).encode(
+            &self.rgb,
+            width,
+            height,
+            ColorType::Rgb,
+        )?;
+        Ok(jpeg)
+    }
+}
+
+fn screen_error(error: anyhow::Error) -> Error {
+    Error::runtime(format!("screen error: {error:#}"))
+}
+
+fn get_screen(lua: &Lua) -> Result<Arc<Screen>> {
+    if let Some(screen) = lua.app_data_ref::<Arc<Screen>>() {
+        return Ok(screen.clone());
+    }
+
+    let screen = Arc::new(Screen::connect(None).map_err(screen_error)?);
+    lua.set_app_data(screen.clone());
+    Ok(screen)
+}
+
+fn parse_region(region: Option<Table>) -> Result<Option<Region>> {
+    region
+        .map(|region| {
+            Ok(Region {
+                x: region.get::<Option<i32>>("x")?.unwrap_or(0),
+                y: region.get::<Option<i32>>("y")?.unwrap_or(0),
+                width: region.get("width")?,
+                height: region.get("height")?,
+            })
+        })
+        .transpose()
+}
+
+/// JPEG of a region of the screen as a Lua string
+async fn capture(lua: Lua, (region, options): (Option<Table>, Option<Table>)) -> Result<Value> {
+    let region = parse_region(region)?;
+    let (scale, quality) = match options {
+        Some(options) => (
+            options.get::<Option<f64>>("scale")?,
+            options.get::<Option<u8>>("quality")?,
+        ),
+        None => (None, None),
+    };
+
+    let screen = get_screen(&lua)?;
+    let jpeg = tokio::task::spawn_blocking(move || {
+        screen
+            .capture(region)?
+            .scale(scale.unwrap_or(1.0))
+            .to_jpeg(quality.unwrap_or(DEFAULT_QUALITY))
+    })
+    .await
+    .map_err(Error::external)?
+    .map_err(screen_error)?;
+
+    Ok(Value::String(lua.create_string(jpeg)?))
+}
+
+fn size(lua: &Lua, (): ()) -> Result<(u32, u32)> {
+    Ok(get_screen(lua)?.size())
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("capture", lua.create_async_function(capture)?)?;
+    module.set("size", lua.create_function(size)?)?;
+
+    libs.set("screen", &module)?;
+    lua.register_module("screen", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        process::{Command, Stdio},
+        time::Duration,
+    };
+
+    use super::*;
+
+    const BGRX: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        scanline_pad: 32,
+        msb_first: false,
+        red_mask: 0xff0000,
+        green_mask: 0x00ff00,
+        blue_mask: 0x0000ff,
+    };
+
+    #[test]
+    fn test_to_rgb() {
+        // Red and blue pixels in the byte order of little endian X servers
+        let data = [0, 0, 255, 0, 255, 0, 0, 0];
+        assert_eq!(to_rgb(&data, 2, 1, &BGRX), vec![255, 0, 0, 0, 0, 255]);
+
+        // RGB565 with rows padded to 32 bits
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            scanline_pad: 32,
+            msb_first: false,
+            red_mask: 0xf800,
+            green_mask: 0x07e0,
+            blue_mask: 0x001f,
+        };
+        let data = [0x00, 0xf8, 0, 0, 0x1f, 0x00, 0, 0];
+        assert_eq!(to_rgb(&data, 1, 2, &rgb565), vec![255, 0, 0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_clip() {
+        let region = |x, y, width, height| Region {
+            x,
+            y,
+            width,
+            height,
+        };
+        assert_eq!(clip(None, 640, 480), Some(region(0, 0, 640, 480)));
+        assert_eq!(
+            clip(Some(region(-10, 400, 100, 100)), 640, 480),
+            Some(region(0, 400, 90, 80))
+        );
+        assert_eq!(clip(Some(region(700, 0, 10, 10)), 640, 480), None);
+    }
+
+    #[test]
+    fn test_scale_and_encode() {
+        let frame = Frame {
+            width: 4,
+            height: 2,
+            rgb: (0..24).collect(),
+        };
+
+        let scaled = frame.clone().scale(0.5);
+        assert_eq!((scaled.width, scaled.height), (2, 1));
+        assert_eq!(scaled.rgb, vec![0, 1, 2, 6, 7, 8]);
+        assert_eq!(frame.clone().scale(2.0), frame);
+
+        let jpeg = frame.to_jpeg(DEFAULT_QUALITY).unwrap();
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let number = 190 + std::process::id() % 100;
+        let Ok(mut server) = Command::new("Xvfb")
+            .args([
+                &format!(":{number}"),
+                "-screen",
+                "0",
+                "320x240x24",
+                "-nolisten",
+                "tcp",
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            eprintln!("Xvfb is not available, skipping");
+            return;
+        };
+
+        let socket = format!("/tmp/.X11-unix/X{number}");
+        for _ in 0..50 {
+            if Path::new(&socket).exists() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        let screen = Screen::connect(Some(&format!(":{number}"))).unwrap();
+        assert_eq!(screen.size(), (320, 240));
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.set_app_data(Arc::new(screen));
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local width, height = libs.screen.size()
+            assert(width == 320 and height == 240)
+            local jpeg = libs.screen.capture({ x = 10, y = 10, width = 100, height = 50 }, { scale = 0.5 })
+            assert(jpeg:sub(1, 2) == "\255\216")
+            assert(not pcall(libs.screen.capture, { x = 1000, y = 0, width = 10, height = 10 }))
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let _ = server.kill();
+        let _ = server.wait();
+    }
+}
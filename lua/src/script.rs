@@ -1,16 +1,37 @@
-use std::{fs, io::Write, os::unix::fs::PermissionsExt, process::Command};
+use std::{
+    fs, io::Write, os::unix::fs::PermissionsExt, path::PathBuf, process::Stdio, sync::Mutex,
+};
 
-use mlua::{Error, Lua, MultiValue, Result, Table};
+use mlua::{
+    Error, Function, Lua, MultiValue, RegistryKey, Result, Table, UserData, UserDataFields,
+    UserDataMethods, WeakLua,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+    sync::{oneshot, watch},
+    time::{self, Duration},
+};
+
+use crate::callback::dispatch;
 
 static DEFAULT_SHELL: &str = "/bin/sh";
 
+/// How long to keep reading the output of an exited process, for background
+/// children that inherited its pipes and keep them open
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
+
+/// Longest output line passed to `onstdout` and `onstderr` callbacks
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
 pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     let module = lua.create_table()?;
 
-    let shell = lua.create_function(shell)?;
+    let shell = lua.create_async_function(shell)?;
 
     module.set("default", &shell)?;
     module.set("shell", shell)?;
+    module.set("run", lua.create_function(run)?)?;
 
     libs.set("script", &module)?;
     lua.register_module("script", module)?;
@@ -18,7 +39,7 @@ pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn shell(_lua: &Lua, args: MultiValue) -> Result<(String, String, i32)> {
+async fn shell(lua: Lua, args: MultiValue) -> Result<(mlua::String, mlua::String, i32)> {
     if args.is_empty() {
         return Err(Error::runtime("shell requires at least one argument"));
     }
@@ -29,6 +50,7 @@ fn shell(_lua: &Lua, args: MultiValue) -> Result<(String, String, i32)> {
             .arg("-c")
             .arg(args[0].to_string()?)
             .output()
+            .await
             .map_err(|error| Error::runtime(format!("failed to execute command: {error}")))?
     } else {
         // Multiple args: create temporary script
@@ -41,29 +63,564 @@ fn shell(_lua: &Lua, args: MultiValue) -> Result<(String, String, i32)> {
             })?;
         }
 
+        // Close the file before executing it, the path removes the script
+        // from disk when dropped
+        let path = temp_file.into_temp_path();
+
         // Make executable
-        let path = temp_file.path();
-        let mut perms = fs::metadata(path)
+        let mut perms = fs::metadata(&path)
             .map_err(|error| Error::runtime(format!("failed to get file metadata: {error}")))?
             .permissions();
         perms.set_mode(0o700);
-        fs::set_permissions(path, perms)
+        fs::set_permissions(&path, perms)
             .map_err(|error| Error::runtime(format!("failed to set permissions: {error}")))?;
 
-        // Execute
-        Command::new(path)
+        // Execute, scripts without a shebang line are run by the default shell
+        let has_shebang = args[0].to_string()?.starts_with("#!");
+        let mut command = if has_shebang {
+            Command::new(&path)
+        } else {
+            let mut command = Command::new(DEFAULT_SHELL);
+            command.arg(&path);
+            command
+        };
+
+        command
             .output()
+            .await
             .map_err(|error| Error::runtime(format!("failed to execute script: {error}")))?
-
-        // temp_file is automatically deleted when it goes out of scope
     };
 
+    // Output is returned as raw bytes, Lua strings don't have to be UTF-8
     Ok((
-        String::from_utf8(output.stdout)
-            .map_err(|error| Error::runtime(format!("failed to parse command output: {error}")))?,
-        String::from_utf8(output.stderr).map_err(|error| {
-            Error::runtime(format!("failed to parse command error output: {error}"))
-        })?,
+        lua.create_string(output.stdout)?,
+        lua.create_string(output.stderr)?,
         output.status.code().unwrap_or_default(),
     ))
 }
+
+/// Outcome of a process started with `script.run`
+#[derive(Clone, Debug, Default)]
+struct ProcessOutput {
+    /// Exit code, `None` if the process was terminated by a signal
+    code: Option<i32>,
+    /// Collected stdout, empty when an `onstdout` callback is set
+    stdout: Vec<u8>,
+    /// Collected stderr, empty when an `onstderr` callback is set
+    stderr: Vec<u8>,
+    timed_out: bool,
+}
+
+/// Handle to a process started with `script.run`
+struct ProcessHandle {
+    pid: Option<u32>,
+    kill: Mutex<Option<oneshot::Sender<()>>>,
+    output: watch::Receiver<Option<ProcessOutput>>,
+}
+
+impl UserData for ProcessHandle {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("pid", |_lua, this| Ok(this.pid));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("kill", |_lua, this, ()| {
+            if let Some(kill) = this.kill.lock().unwrap().take() {
+                let _ = kill.send(());
+            }
+            Ok(())
+        });
+
+        methods.add_method("running", |_lua, this, ()| {
+            Ok(this.output.borrow().is_none())
+        });
+
+        methods.add_async_method("wait", |lua, this, ()| async move {
+            let mut output = this.output.clone();
+            let output = output
+                .wait_for(Option::is_some)
+                .await
+                .map_err(|_| Error::runtime("process task ended unexpectedly"))?
+                .clone()
+                .unwrap_or_default();
+
+            Ok((
+                lua.create_string(output.stdout)?,
+                lua.create_string(output.stderr)?,
+                output.code,
+                output.timed_out,
+            ))
+        });
+    }
+}
+
+/// Lua callbacks of a process, kept in the registry while it runs
+struct ProcessCallbacks {
+    lua: WeakLua,
+    stdout: Option<RegistryKey>,
+    stderr: Option<RegistryKey>,
+    exit: Option<RegistryKey>,
+}
+
+impl ProcessCallbacks {
+    async fn call(&self, key: &RegistryKey, args: impl mlua::IntoLuaMulti) {
+        let Some(lua) = self.lua.try_upgrade() else {
+            return;
+        };
+
+        if let Ok(callback) = lua.registry_value::<Function>(key)
+            && let Err(error) = dispatch(&lua, "process callback", callback, args).await
+        {
+            tracing::error!("process callback error: {error}");
+        }
+    }
+}
+
+impl Drop for ProcessCallbacks {
+    fn drop(&mut self) {
+        if let Some(lua) = self.lua.try_upgrade() {
+            for key in [self.stdout.take(), self.stderr.take(), self.exit.take()]
+                .into_iter()
+                .flatten()
+            {
+                let _ = lua.remove_registry_value(key);
+            }
+        }
+    }
+}
+
+fn registry_callback(lua: &Lua, options: &Table, name: &str) -> Result<Option<RegistryKey>> {
+    options
+        .get::<Option<Function>>(name)?
+        .map(|callback| lua.create_registry_value(callback))
+        .transpose()
+}
+
+fn run(lua: &Lua, options: Table) -> Result<ProcessHandle> {
+    let program: String = options
+        .get("cmd")
+        .map_err(|_| Error::runtime("run requires a 'cmd' field"))?;
+    let args: Option<Vec<String>> = options.get("args")?;
+    let env: Option<Table> = options.get("env")?;
+    let cwd: Option<PathBuf> = options.get::<Option<String>>("cwd")?.map(PathBuf::from);
+    let stdin: Option<mlua::String> = options.get("stdin")?;
+    let timeout: Option<u64> = options.get("timeout")?;
+
+    let mut command = Command::new(&program);
+    command
+        .args(args.unwrap_or_default())
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true);
+
+    if let Some(env) = env {
+        for pair in env.pairs::<String, String>() {
+            let (name, value) = pair?;
+            command.env(name, value);
+        }
+    }
+
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+
+    let mut child = command
+        .spawn()
+        .map_err(|error| Error::runtime(format!("failed to execute '{program}': {error}")))?;
+
+    let callbacks = ProcessCallbacks {
+        lua: lua.weak(),
+        stdout: registry_callback(lua, &options, "onstdout")?,
+        stderr: registry_callback(lua, &options, "onstderr")?,
+        exit: registry_callback(lua, &options, "onexit")?,
+    };
+
+    let pid = child.id();
+    let (kill_tx, kill_rx) = oneshot::channel();
+    let (output_tx, output_rx) = watch::channel(None);
+
+    if let (Some(mut pipe), Some(stdin)) = (child.stdin.take(), stdin) {
+        let stdin = stdin.as_bytes().to_vec();
+        tokio::spawn(async move {
+            if let Err(error) = pipe.write_all(&stdin).await {
+                tracing::warn!("failed to write process stdin: {error}");
+            }
+        });
+    }
+
+    let stdout_pipe = child.stdout.take();
+    let stderr_pipe = child.stderr.take();
+
+    tracing::info!("started process '{program}' with pid {pid:?}");
+
+    tokio::spawn(async move {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        let (status, timed_out) = {
+            let readers = async {
+                tokio::join!(
+                    read_output(
+                        stdout_pipe,
+                        &callbacks,
+                        callbacks.stdout.as_ref(),
+                        &mut stdout
+                    ),
+                    read_output(
+                        stderr_pipe,
+                        &callbacks,
+                        callbacks.stderr.as_ref(),
+                        &mut stderr
+                    ),
+                )
+            };
+
+            let wait = async {
+                let mut timed_out = false;
+                let timeout = async {
+                    match timeout {
+                        Some(timeout) => time::sleep(Duration::from_millis(timeout)).await,
+                        None => std::future::pending().await,
+                    }
+                };
+
+                let status = tokio::select! {
+                    status = child.wait() => status,
+                    _ = kill_rx => {
+                        kill_group(&mut child, pid);
+                        child.wait().await
+                    }
+                    _ = timeout => {
+                        tracing::info!("process {pid:?} timed out, killing it");
+                        timed_out = true;
+                        kill_group(&mut child, pid);
+                        child.wait().await
+                    }
+                };
+
+                (status, timed_out)
+            };
+
+            tokio::pin!(readers, wait);
+
+            let mut readers_done = false;
+            let result = loop {
+                tokio::select! {
+                    result = &mut wait => break result,
+                    _ = &mut readers, if !readers_done => readers_done = true,
+                }
+            };
+
+            if !readers_done && time::timeout(OUTPUT_GRACE, &mut readers).await.is_err() {
+                tracing::warn!(
+                    "process {pid:?} exited but its output is still open, stop reading it"
+                );
+            }
+
+            result
+        };
+
+        let code = match status {
+            Ok(status) => status.code(),
+            Err(error) => {
+                tracing::error!("failed to wait for process {pid:?}: {error}");
+                None
+            }
+        };
+
+        tracing::info!("process {pid:?} exited with code {code:?}");
+
+        if let Some(exit) = &callbacks.exit {
+            callbacks.call(exit, (code, timed_out)).await;
+        }
+
+        let _ = output_tx.send(Some(ProcessOutput {
+            code,
+            stdout,
+            stderr,
+            timed_out,
+        }));
+    });
+
+    Ok(ProcessHandle {
+        pid,
+        kill: Mutex::new(Some(kill_tx)),
+        output: output_rx,
+    })
+}
+
+/// Kill a process along with the children it started, which share its
+/// process group
+fn kill_group(child: &mut tokio::process::Child, pid: Option<u32>) {
+    if let Some(pid) = pid {
+        // SAFETY: killpg only sends a signal, the process group id is the pid
+        // of our own child, which was started as the leader of a new group
+        unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+    }
+    let _ = child.start_kill();
+}
+
+/// Read a process output pipe, either passing it line by line to a callback
+/// or collecting it into `output` when there is no callback. The output read
+/// so far is kept if the reader is dropped before the pipe is closed.
+async fn read_output(
+    pipe: Option<impl AsyncRead + Unpin>,
+    callbacks: &ProcessCallbacks,
+    callback: Option<&RegistryKey>,
+    output: &mut Vec<u8>,
+) {
+    let Some(pipe) = pipe else {
+        return;
+    };
+
+    let mut reader = BufReader::new(pipe);
+
+    let Some(callback) = callback else {
+        if let Err(error) = reader.read_to_end(output).await {
+            tracing::warn!("failed to read process output: {error}");
+        }
+        return;
+    };
+
+    // Lines longer than the limit are skipped up to the next newline
+    let mut buffer = Vec::new();
+    let mut discarding = false;
+    loop {
+        buffer.clear();
+        let mut line_reader = (&mut reader).take(MAX_LINE_LENGTH as u64);
+        match line_reader.read_until(b'\n', &mut buffer).await {
+            Ok(0) => break,
+            Ok(_) if discarding || !buffer.ends_with(b"\n") && buffer.len() == MAX_LINE_LENGTH => {
+                if !discarding {
+                    tracing::warn!("process output line is longer than {MAX_LINE_LENGTH} bytes");
+                }
+                discarding = !buffer.ends_with(b"\n");
+            }
+            Ok(_) => {
+                let line = buffer.strip_suffix(b"\n").unwrap_or(&buffer);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                callbacks.call(callback, mlua::BString::from(line)).await;
+            }
+            Err(error) => {
+                tracing::warn!("failed to read process output: {error}");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script_lua() -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    #[tokio::test]
+    async fn test_shell_non_utf8_output() {
+        let lua = script_lua();
+
+        lua.load(
+            r#"
+            out, err, code = libs.script.shell("printf '\\377\\376'")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let out: mlua::String = lua.globals().get("out").unwrap();
+        assert_eq!(out.as_bytes().as_ref(), b"\xff\xfe");
+        assert_eq!(lua.globals().get::<i32>("code").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shell_multiline_script() {
+        let lua = script_lua();
+
+        lua.load(
+            r#"
+            out, err, code = libs.script.shell("echo one", "echo two", "exit 3")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let out: String = lua.globals().get("out").unwrap();
+        assert_eq!(out, "one\ntwo\n");
+        assert_eq!(lua.globals().get::<i32>("code").unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_run_wait_collects_output() {
+        let lua = script_lua();
+
+        lua.load(
+            r#"
+            local proc = libs.script.run{
+                cmd = "sh",
+                args = { "-c", "cat; echo \"$GREETING\" >&2; exit 2" },
+                env = { GREETING = "hello" },
+                stdin = "from stdin",
+            }
+            out, err, code, timed_out = proc:wait()
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let out: String = lua.globals().get("out").unwrap();
+        let err: String = lua.globals().get("err").unwrap();
+        assert_eq!(out, "from stdin");
+        assert_eq!(err, "hello\n");
+        assert_eq!(lua.globals().get::<i32>("code").unwrap(), 2);
+        assert!(!lua.globals().get::<bool>("timed_out").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_run_streams_lines() {
+        let lua = script_lua();
+
+        lua.load(
+            r#"
+            lines = {}
+            exit_code = nil
+            local proc = libs.script.run{
+                cmd = "sh",
+                args = { "-c", "echo first; echo second" },
+                onstdout = function(line) table.insert(lines, line) end,
+                onexit = function(code) exit_code = code end,
+            }
+            proc:wait()
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let lines: Vec<String> = lua.globals().get("lines").unwrap();
+        assert_eq!(lines, vec!["first", "second"]);
+        assert_eq!(lua.globals().get::<i32>("exit_code").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_skips_long_lines() {
+        let lua = script_lua();
+        lua.globals()
+            .set("length", MAX_LINE_LENGTH * 2 + 1)
+            .unwrap();
+
+        lua.load(
+            r#"
+            lines = {}
+            local proc = libs.script.run{
+                cmd = "sh",
+                args = { "-c", "head -c " .. length .. " /dev/zero | tr '\\0' x; echo; echo after" },
+                onstdout = function(line) table.insert(lines, line) end,
+            }
+            proc:wait()
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        let lines: Vec<String> = lua.globals().get("lines").unwrap();
+        assert_eq!(lines, vec!["after"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout() {
+        let lua = script_lua();
+
+        lua.load(
+            r#"
+            local proc = libs.script.run{ cmd = "sleep", args = { "10" }, timeout = 100 }
+            out, err, code, timed_out = proc:wait()
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        assert!(lua.globals().get::<Option<i32>>("code").unwrap().is_none());
+        assert!(lua.globals().get::<bool>("timed_out").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_run_kill() {
+        let lua = script_lua();
+
+        lua.load(
+            r#"
+            local proc = libs.script.run{ cmd = "sleep", args = { "10" } }
+            assert(proc.pid ~= nil)
+            assert(proc:running())
+            proc:kill()
+            out, err, code = proc:wait()
+            still_running = proc:running()
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        assert!(lua.globals().get::<Option<i32>>("code").unwrap().is_none());
+        assert!(!lua.globals().get::<bool>("still_running").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_run_background_child_keeps_output_open() {
+        let lua = script_lua();
+        let started = std::time::Instant::now();
+
+        lua.load(
+            r#"
+            local proc = libs.script.run{ cmd = "sh", args = { "-c", "sleep 30 & echo hi" } }
+            out, err, code = proc:wait()
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(lua.globals().get::<String>("out").unwrap(), "hi\n");
+        assert_eq!(lua.globals().get::<i32>("code").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_timeout_kills_process_group() {
+        let lua = script_lua();
+        let started = std::time::Instant::now();
+
+        lua.load(
+            r#"
+            local proc = libs.script.run{
+                cmd = "sh", args = { "-c", "sleep 30 & sleep 30" }, timeout = 100,
+            }
+            out, err, code, timed_out = proc:wait()
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(lua.globals().get::<bool>("timed_out").unwrap());
+    }
+}
@@ -0,0 +1,443 @@
+//! `libs.secrets` and the encrypted secret store.
+//!
+//! `secret` settings live in `secrets.enc` in the config directory, read with
+//! `get(name)`. Secret values are masked in `print`, in the server log and in
+//! messages to clients.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, anyhow};
+use mlua::{Error, Lua, Result, Table, Value, Variadic};
+use ring::{
+    aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use uniremote_core::ServerMessage;
+
+use crate::data::write_atomic;
+
+/// File holding the encrypted secrets of all remotes
+pub const SECRETS_FILE: &str = "secrets.enc";
+
+/// File holding the key the secrets file is encrypted with
+pub const SECRETS_KEY_FILE: &str = "secrets.key";
+
+/// Replacement for secret values in logs and messages
+const REDACTED: &str = "********";
+
+type Entries = HashMap<String, HashMap<String, String>>;
+
+/// Encrypted store for remote credentials.
+///
+/// Secrets of all remotes are kept in one file encrypted with
+/// ChaCha20-Poly1305. The key is generated on first use and stored next to it
+/// with owner-only permissions, which keeps credentials out of the plaintext
+/// settings files that get copied and shared along with remotes.
+#[derive(Clone)]
+pub struct SecretStore(Arc<SecretStoreInner>);
+
+struct SecretStoreInner {
+    path: PathBuf,
+    key: LessSafeKey,
+    entries: Mutex<Entries>,
+}
+
+impl SecretStore {
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir).context("failed to create secrets directory")?;
+
+        let key = load_or_create_key(&dir.join(SECRETS_KEY_FILE))?;
+        let path = dir.join(SECRETS_FILE);
+        let entries = if path.is_file() {
+            let content = std::fs::read(&path).context("failed to read secrets file")?;
+            let plaintext = decrypt(&key, content)?;
+            serde_json::from_slice(&plaintext).context("failed to parse secrets file")?
+        } else {
+            Entries::new()
+        };
+
+        Ok(Self(Arc::new(SecretStoreInner {
+            path,
+            key,
+            entries: Mutex::new(entries),
+        })))
+    }
+
+    /// The key and secrets files, which remotes must not be able to read
+    /// through `libs.fs`
+    pub fn paths(&self) -> [PathBuf; 2] {
+        [
+            self.0.path.with_file_name(SECRETS_KEY_FILE),
+            self.0.path.clone(),
+        ]
+    }
+
+    /// Access to the secrets of a single remote
+    pub fn scoped(&self, remote: impl Into<String>) -> Secrets {
+        Secrets {
+            store: self.clone(),
+            remote: remote.into(),
+        }
+    }
+
+    /// Mask the secret values of all remotes in `text`, for output that is
+    /// not tied to a single remote like the server log
+    pub fn redact(&self, text: &str) -> String {
+        let entries = self.0.entries.lock().unwrap();
+        mask(text, entries.values().flat_map(HashMap::values))
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Entries)) -> anyhow::Result<()> {
+        let mut entries = self.0.entries.lock().unwrap();
+        let mut updated = entries.clone();
+        change(&mut updated);
+
+        let plaintext = serde_json::to_vec(&updated)?;
+        write_atomic(&self.0.path, &encrypt(&self.0.key, plaintext)?)?;
+        *entries = updated;
+        Ok(())
+    }
+}
+
+/// Replace every non-empty value in `text` with [`REDACTED`]
+fn mask<'a>(text: &str, values: impl Iterator<Item = &'a String>) -> String {
+    values
+        .filter(|value| !value.is_empty())
+        .fold(text.to_string(), |text, value| {
+            text.replace(value.as_str(), REDACTED)
+        })
+}
+
+fn load_or_create_key(path: &Path) -> anyhow::Result<LessSafeKey> {
+    let key_bytes = if path.is_file() {
+        std::fs::read(path).context("failed to read secrets key")?
+    } else {
+        let mut key_bytes = vec![0; CHACHA20_POLY1305.key_len()];
+        SystemRandom::new()
+            .fill(&mut key_bytes)
+            .map_err(|_| anyhow!("failed to generate secrets key"))?;
+        write_private_file(path, &key_bytes).context("failed to write secrets key")?;
+        key_bytes
+    };
+
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key_bytes)
+        .map_err(|_| anyhow!("invalid secrets key in {}", path.display()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn write_private_file(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(content)
+}
+
+fn encrypt(key: &LessSafeKey, mut plaintext: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("failed to generate nonce"))?;
+
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut plaintext,
+    )
+    .map_err(|_| anyhow!("failed to encrypt secrets"))?;
+
+    let mut content = nonce.to_vec();
+    content.extend(plaintext);
+    Ok(content)
+}
+
+fn decrypt(key: &LessSafeKey, content: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if content.len() < NONCE_LEN {
+        anyhow::bail!("secrets file is truncated");
+    }
+
+    let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| anyhow!("invalid nonce in secrets file"))?;
+
+    let mut ciphertext = ciphertext.to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+        .map_err(|_| anyhow!("failed to decrypt secrets file, wrong key or corrupted file"))?;
+    Ok(plaintext.to_vec())
+}
+
+/// Secrets of a single remote, backing `libs.secrets`
+#[derive(Clone)]
+pub struct Secrets {
+    store: SecretStore,
+    remote: String,
+}
+
+impl Secrets {
+    pub fn get(&self, name: &str) -> Option<String> {
+        let entries = self.store.0.entries.lock().unwrap();
+        entries.get(&self.remote)?.get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn set(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        self.store.update(|entries| {
+            entries
+                .entry(self.remote.clone())
+                .or_default()
+                .insert(name.to_string(), value.to_string());
+        })
+    }
+
+    pub fn delete(&self, name: &str) -> anyhow::Result<()> {
+        self.store.update(|entries| {
+            if let Some(secrets) = entries.get_mut(&self.remote) {
+                secrets.remove(name);
+            }
+        })
+    }
+
+    /// Mask all secret values of the remote in `text`
+    pub fn redact(&self, text: &str) -> String {
+        let entries = self.store.0.entries.lock().unwrap();
+        match entries.get(&self.remote) {
+            Some(secrets) => mask(text, secrets.values()),
+            None => text.to_string(),
+        }
+    }
+
+    /// Mask all secret values of the remote in a message for its clients.
+    ///
+    /// Clipboard contents are left alone, they are the user's own text.
+    pub fn redact_message(&self, message: ServerMessage) -> ServerMessage {
+        match message {
+            ServerMessage::Update { action, mut args } => {
+                self.redact_json(&mut args);
+                ServerMessage::Update { action, args }
+            }
+            ServerMessage::Error { message } => ServerMessage::Error {
+                message: self.redact(&message),
+            },
+            ServerMessage::Notification {
+                app,
+                summary,
+                body,
+                icon,
+            } => ServerMessage::Notification {
+                app: self.redact(&app),
+                summary: self.redact(&summary),
+                body: self.redact(&body),
+                icon,
+            },
+            ServerMessage::Confirm { nonce, message } => ServerMessage::Confirm {
+                nonce,
+                message: self.redact(&message),
+            },
+            message @ ServerMessage::Clipboard { .. } => message,
+        }
+    }
+
+    fn redact_json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(text) => *text = self.redact(text),
+            serde_json::Value::Array(values) => {
+                values.iter_mut().for_each(|value| self.redact_json(value));
+            }
+            serde_json::Value::Object(map) => {
+                map.values_mut().for_each(|value| self.redact_json(value));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Mask secrets of the remote in text leaving the Lua state
+pub(crate) fn redact(lua: &Lua, text: &str) -> String {
+    match lua.app_data_ref::<Secrets>() {
+        Some(secrets) => secrets.redact(text),
+        None => text.to_string(),
+    }
+}
+
+/// Mask secrets of the remote in a message leaving the Lua state
+pub(crate) fn redact_message(lua: &Lua, message: ServerMessage) -> ServerMessage {
+    match lua.app_data_ref::<Secrets>() {
+        Some(secrets) => secrets.redact_message(message),
+        None => message,
+    }
+}
+
+fn get_secrets(lua: &Lua) -> Result<Secrets> {
+    lua.app_data_ref::<Secrets>()
+        .map(|secrets| secrets.clone())
+        .ok_or_else(|| Error::runtime("secret store is not available"))
+}
+
+fn get(lua: &Lua, name: String) -> Result<Option<String>> {
+    Ok(get_secrets(lua)?.get(&name))
+}
+
+fn set(lua: &Lua, (name, value): (String, String)) -> Result<()> {
+    get_secrets(lua)?
+        .set(&name, &value)
+        .map_err(|error| Error::runtime(format!("failed to store secret '{name}': {error:#}")))
+}
+
+fn delete(lua: &Lua, name: String) -> Result<()> {
+    get_secrets(lua)?
+        .delete(&name)
+        .map_err(|error| Error::runtime(format!("failed to delete secret '{name}': {error:#}")))
+}
+
+/// `print` replacement that masks secrets before writing to stdout
+fn print(lua: &Lua, values: Variadic<Value>) -> Result<()> {
+    let line = values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Result<Vec<_>>>()?
+        .join("\t");
+    println!("{}", redact(lua, &line));
+    Ok(())
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("get", lua.create_function(get)?)?;
+    module.set("set", lua.create_function(set)?)?;
+    module.set("delete", lua.create_function(delete)?)?;
+
+    lua.globals().set("print", lua.create_function(print)?)?;
+
+    libs.set("secrets", &module)?;
+    lua.register_module("secrets", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_store_roundtrip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let store = SecretStore::open(temp_dir.path()).unwrap();
+        store.scoped("media").set("token", "hunter2").unwrap();
+        store.scoped("lights").set("token", "bulb").unwrap();
+
+        // Secrets are encrypted at rest
+        let content = std::fs::read(temp_dir.path().join(SECRETS_FILE)).unwrap();
+        assert!(!String::from_utf8_lossy(&content).contains("hunter2"));
+
+        let reopened = SecretStore::open(temp_dir.path()).unwrap();
+        assert_eq!(
+            reopened.scoped("media").get("token").as_deref(),
+            Some("hunter2")
+        );
+        assert_eq!(
+            reopened.scoped("lights").get("token").as_deref(),
+            Some("bulb")
+        );
+        assert_eq!(reopened.scoped("other").get("token"), None);
+
+        reopened.scoped("media").delete("token").unwrap();
+        let reopened = SecretStore::open(temp_dir.path()).unwrap();
+        assert!(!reopened.scoped("media").contains("token"));
+    }
+
+    #[test]
+    fn test_secret_store_wrong_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = SecretStore::open(temp_dir.path()).unwrap();
+        store.scoped("media").set("token", "hunter2").unwrap();
+
+        std::fs::remove_file(temp_dir.path().join(SECRETS_KEY_FILE)).unwrap();
+        assert!(SecretStore::open(temp_dir.path()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_store_key_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        SecretStore::open(temp_dir.path()).unwrap();
+
+        let metadata = std::fs::metadata(temp_dir.path().join(SECRETS_KEY_FILE)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_secrets_lua_and_redaction() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let secrets = SecretStore::open(temp_dir.path()).unwrap().scoped("media");
+        secrets.set("token", "hunter2").unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        lua.set_app_data(secrets.clone());
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            assert(libs.secrets.get("token") == "hunter2")
+            assert(libs.secrets.get("missing") == nil)
+            libs.secrets.set("refresh", "s3cret")
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        assert_eq!(secrets.get("refresh").as_deref(), Some("s3cret"));
+        assert_eq!(
+            redact(&lua, "Authorization: Bearer hunter2"),
+            "Authorization: Bearer ********"
+        );
+
+        let message = ServerMessage::Update {
+            action: "info".into(),
+            args: serde_json::json!({ "id": "info", "text": ["s3cret"] }),
+        };
+        let ServerMessage::Update { args, .. } = redact_message(&lua, message) else {
+            panic!("Expected Update message");
+        };
+        assert_eq!(
+            args,
+            serde_json::json!({ "id": "info", "text": ["********"] })
+        );
+
+        let message = ServerMessage::Error {
+            message: "login failed for hunter2".to_string(),
+        };
+        let ServerMessage::Error { message } = redact_message(&lua, message) else {
+            panic!("Expected Error message");
+        };
+        assert_eq!(message, "login failed for ********");
+    }
+
+    #[test]
+    fn test_secret_store_redacts_all_remotes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = SecretStore::open(temp_dir.path()).unwrap();
+        store.scoped("media").set("token", "hunter2").unwrap();
+        store.scoped("lights").set("key", "s3cret").unwrap();
+
+        assert_eq!(
+            store.redact("token hunter2, key s3cret"),
+            "token ********, key ********"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use mlua::{Lua, Result, Table};
+use sysinfo::{Components, CpuRefreshKind, Disks, MINIMUM_CPU_UPDATE_INTERVAL, Networks, System};
+
+const DEFAULT_SYSFS_ROOT: &str = "/sys";
+
+/// Root of the sysfs tree used for readings that sysinfo doesn't cover.
+///
+/// Set it as app data before loading the module to read from a fake tree.
+#[derive(Clone, Debug)]
+pub struct SysfsRoot(pub PathBuf);
+
+impl Default for SysfsRoot {
+    fn default() -> Self {
+        Self(PathBuf::from(DEFAULT_SYSFS_ROOT))
+    }
+}
+
+/// Sensor state kept between calls, CPU and network readings are computed
+/// from the difference to the previous refresh
+#[derive(Clone)]
+struct Sensors(Arc<SensorsInner>);
+
+struct SensorsInner {
+    sysfs_root: PathBuf,
+    system: Mutex<System>,
+    networks: Mutex<Networks>,
+    /// Whether the CPUs were refreshed before, so that their usage is known
+    primed: AtomicBool,
+}
+
+impl Sensors {
+    fn refresh_cpu(&self) {
+        self.0
+            .system
+            .lock()
+            .unwrap()
+            .refresh_cpu_specifics(CpuRefreshKind::everything());
+    }
+}
+
+fn get_sensors(lua: &Lua) -> Sensors {
+    lua.app_data_ref::<Sensors>()
+        .expect("sensors state not found in lua state")
+        .clone()
+}
+
+async fn cpu(lua: Lua, _: ()) -> Result<Table> {
+    let sensors = get_sensors(&lua);
+    sensors.refresh_cpu();
+
+    // The first refresh has nothing to compute CPU usage from
+    if !sensors.0.primed.swap(true, Ordering::Relaxed) {
+        tokio::time::sleep(MINIMUM_CPU_UPDATE_INTERVAL).await;
+        sensors.refresh_cpu();
+    }
+
+    let system = sensors.0.system.lock().unwrap();
+
+    let cores = lua.create_table()?;
+    for cpu in system.cpus() {
+        let core = lua.create_table()?;
+        core.set("name", cpu.name())?;
+        core.set("usage", cpu.cpu_usage())?;
+        core.set("frequency", cpu.frequency())?;
+        cores.raw_push(core)?;
+    }
+
+    let result = lua.create_table()?;
+    result.set("usage", system.global_cpu_usage())?;
+    result.set("cores", cores)?;
+    Ok(result)
+}
+
+fn temperatures(lua: &Lua, _: ()) -> Result<Vec<Table>> {
+    Components::new_with_refreshed_list()
+        .list()
+        .iter()
+        .map(|component| {
+            let table = lua.create_table()?;
+            table.set("label", component.label())?;
+            table.set("temperature", component.temperature())?;
+            table.set("max", component.max())?;
+            table.set("critical", component.critical())?;
+            Ok(table)
+        })
+        .collect()
+}
+
+fn disks(lua: &Lua, _: ()) -> Result<Vec<Table>> {
+    Disks::new_with_refreshed_list()
+        .list()
+        .iter()
+        .map(|disk| {
+            let total = disk.total_space();
+            let available = disk.available_space();
+
+            let table = lua.create_table()?;
+            table.set("name", disk.name().to_string_lossy())?;
+            table.set("mount", disk.mount_point().display().to_string())?;
+            table.set("filesystem", disk.file_system().to_string_lossy())?;
+            table.set("total", total)?;
+            table.set("available", available)?;
+            table.set("used", total.saturating_sub(available))?;
+            table.set("removable", disk.is_removable())?;
+            Ok(table)
+        })
+        .collect()
+}
+
+fn network(lua: &Lua, _: ()) -> Result<Vec<Table>> {
+    let sensors = get_sensors(lua);
+    let mut networks = sensors.0.networks.lock().unwrap();
+    networks.refresh(true);
+
+    let mut interfaces = networks.list().iter().collect::<Vec<_>>();
+    interfaces.sort_by_key(|(name, _)| name.as_str());
+
+    interfaces
+        .into_iter()
+        .map(|(name, data)| {
+            let table = lua.create_table()?;
+            table.set("name", name.as_str())?;
+            table.set("received", data.received())?;
+            table.set("transmitted", data.transmitted())?;
+            table.set("totalreceived", data.total_received())?;
+            table.set("totaltransmitted", data.total_transmitted())?;
+            Ok(table)
+        })
+        .collect()
+}
+
+fn loadavg(lua: &Lua, _: ()) -> Result<Table> {
+    let load = System::load_average();
+
+    let result = lua.create_table()?;
+    result.set("one", load.one)?;
+    result.set("five", load.five)?;
+    result.set("fifteen", load.fifteen)?;
+    Ok(result)
+}
+
+fn uptime(_lua: &Lua, _: ()) -> Result<u64> {
+    Ok(System::uptime())
+}
+
+fn read_sysfs(path: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(path.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+fn read_sysfs_number(path: &Path, name: &str) -> Option<i64> {
+    read_sysfs(path, name).and_then(|value| value.parse().ok())
+}
+
+fn battery(lua: &Lua, _: ()) -> Result<Vec<Table>> {
+    let sensors = get_sensors(lua);
+    let power_supply = sensors.0.sysfs_root.join("class/power_supply");
+
+    let Ok(entries) = fs::read_dir(&power_supply) else {
+        return Ok(Vec::new());
+    };
+
+    let mut supplies = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| read_sysfs(path, "type").as_deref() == Some("Battery"))
+        .collect::<Vec<_>>();
+    supplies.sort();
+
+    supplies
+        .iter()
+        .map(|path| {
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            let table = lua.create_table()?;
+            table.set("name", name)?;
+            table.set("capacity", read_sysfs_number(path, "capacity"))?;
+            table.set(
+                "status",
+                read_sysfs(path, "status").map(|status| status.to_lowercase()),
+            )?;
+            // Batteries report either energy (µWh/µW) or charge (µAh/µA)
+            table.set(
+                "energy",
+                read_sysfs_number(path, "energy_now").or(read_sysfs_number(path, "charge_now")),
+            )?;
+            table.set(
+                "energyfull",
+                read_sysfs_number(path, "energy_full").or(read_sysfs_number(path, "charge_full")),
+            )?;
+            table.set(
+                "power",
+                read_sysfs_number(path, "power_now").or(read_sysfs_number(path, "current_now")),
+            )?;
+            Ok(table)
+        })
+        .collect()
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let sysfs_root = lua
+        .app_data_ref::<SysfsRoot>()
+        .map(|root| root.clone())
+        .unwrap_or_default();
+
+    lua.set_app_data(Sensors(Arc::new(SensorsInner {
+        sysfs_root: sysfs_root.0,
+        system: Mutex::new(System::new()),
+        networks: Mutex::new(Networks::new_with_refreshed_list()),
+        primed: AtomicBool::new(false),
+    })));
+
+    let module = lua.create_table()?;
+    module.set("cpu", lua.create_async_function(cpu)?)?;
+    module.set("temperatures", lua.create_function(temperatures)?)?;
+    module.set("disks", lua.create_function(disks)?)?;
+    module.set("network", lua.create_function(network)?)?;
+    module.set("loadavg", lua.create_function(loadavg)?)?;
+    module.set("uptime", lua.create_function(uptime)?)?;
+    module.set("battery", lua.create_function(battery)?)?;
+
+    libs.set("sensors", &module)?;
+    lua.register_module("sensors", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensors_lua(sysfs_root: &Path) -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        lua.set_app_data(SysfsRoot(sysfs_root.to_path_buf()));
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    fn write_supply(root: &Path, name: &str, files: &[(&str, &str)]) {
+        let path = root.join("class/power_supply").join(name);
+        fs::create_dir_all(&path).unwrap();
+        for (file, content) in files {
+            fs::write(path.join(file), format!("{content}\n")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sensors_battery_from_fake_sysfs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        write_supply(
+            temp_dir.path(),
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("capacity", "87"),
+                ("status", "Discharging"),
+                ("energy_now", "43500000"),
+                ("energy_full", "50000000"),
+                ("power_now", "7200000"),
+            ],
+        );
+        write_supply(temp_dir.path(), "AC", &[("type", "Mains"), ("online", "0")]);
+
+        let lua = sensors_lua(temp_dir.path());
+        lua.load(
+            r#"
+            local batteries = libs.sensors.battery()
+            assert(#batteries == 1, "only batteries should be listed")
+            local bat = batteries[1]
+            assert(bat.name == "BAT0")
+            assert(bat.capacity == 87)
+            assert(bat.status == "discharging")
+            assert(bat.energy == 43500000)
+            assert(bat.energyfull == 50000000)
+            assert(bat.power == 7200000)
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sensors_battery_missing_sysfs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lua = sensors_lua(temp_dir.path());
+
+        lua.load("assert(#libs.sensors.battery() == 0)")
+            .exec()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sensors_cpu_under_load() {
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "while :; do :; done"])
+            .spawn()
+            .unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lua = sensors_lua(temp_dir.path());
+
+        let result = lua
+            .load(
+                r#"
+                local cpu = libs.sensors.cpu()
+                assert(#cpu.cores > 0, "at least one core should be reported")
+                assert(cpu.cores[1].frequency > 0, "frequency should be reported")
+                return cpu.usage
+            "#,
+            )
+            .eval_async::<f32>()
+            .await;
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(result.unwrap() > 0.0, "busy CPU should be reported as used");
+    }
+
+    #[tokio::test]
+    async fn test_sensors_system_readings() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let lua = sensors_lua(temp_dir.path());
+
+        lua.load(
+            r#"
+            local cpu = libs.sensors.cpu()
+            assert(type(cpu.usage) == "number")
+            assert(type(cpu.cores[1].usage) == "number")
+
+            local load = libs.sensors.loadavg()
+            assert(load.one >= 0 and load.five >= 0 and load.fifteen >= 0)
+
+            assert(libs.sensors.uptime() > 0)
+
+            for _, disk in ipairs(libs.sensors.disks()) do
+                assert(disk.used + disk.available == disk.total)
+            end
+
+            for _, interface in ipairs(libs.sensors.network()) do
+                assert(type(interface.name) == "string")
+                assert(interface.totalreceived >= 0)
+            end
+
+            assert(type(libs.sensors.temperatures()) == "table")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+    }
+}
@@ -1,13 +1,95 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use flume::Sender;
 use mlua::{Error, Lua, LuaSerdeExt, Result, Table, Variadic};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::sync::oneshot;
 use uniremote_core::{ActionId, ServerMessage};
 
-fn get_broadcast_sender(lua: &Lua) -> Sender<ServerMessage> {
+/// How long to wait for a client to answer a confirmation request
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(crate) fn get_broadcast_sender(lua: &Lua) -> Sender<ServerMessage> {
     lua.app_data_ref::<Sender<ServerMessage>>()
         .expect("broadcast sender not found in lua state")
         .clone()
 }
 
+/// Confirmation requests waiting for an answer from a client, by nonce
+#[derive(Clone, Default)]
+pub(crate) struct Confirmations(Arc<Mutex<HashMap<String, oneshot::Sender<bool>>>>);
+
+impl Confirmations {
+    fn get(lua: &Lua) -> Self {
+        if let Some(confirmations) = lua.app_data_ref::<Self>() {
+            return confirmations.clone();
+        }
+
+        let confirmations = Self::default();
+        lua.set_app_data(confirmations.clone());
+        confirmations
+    }
+
+    fn insert(&self, nonce: String, reply: oneshot::Sender<bool>) {
+        self.0.lock().unwrap().insert(nonce, reply);
+    }
+
+    fn remove(&self, nonce: &str) -> Option<oneshot::Sender<bool>> {
+        self.0.lock().unwrap().remove(nonce)
+    }
+}
+
+/// Ask connected clients to confirm an action.
+///
+/// Resolves to `false` if the request is declined or nobody answers in time.
+pub(crate) async fn confirm(lua: &Lua, message: String) -> Result<bool> {
+    // The worker keeps one receiver of the outbox, every other one belongs
+    // to a connected client
+    let broadcast_tx = get_broadcast_sender(lua);
+    if broadcast_tx.receiver_count() <= 1 {
+        tracing::warn!("no client connected to confirm: {message}");
+        return Ok(false);
+    }
+
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::runtime("failed to generate confirmation nonce"))?;
+    let nonce: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+    let confirmations = Confirmations::get(lua);
+    let (reply, response) = oneshot::channel();
+    confirmations.insert(nonce.clone(), reply);
+
+    let message = ServerMessage::Confirm {
+        nonce: nonce.clone(),
+        message,
+    };
+    tracing::debug!("sending confirmation request: {message:?}");
+    if let Err(error) = broadcast_tx.try_send(message) {
+        confirmations.remove(&nonce);
+        tracing::warn!("failed to send confirmation request: {error}");
+        return Ok(false);
+    }
+
+    let confirmed = tokio::time::timeout(CONFIRM_TIMEOUT, response).await;
+    confirmations.remove(&nonce);
+    Ok(matches!(confirmed, Ok(Ok(true))))
+}
+
+/// Pass a client's answer to the pending confirmation request with the
+/// given nonce, returning `false` if there is no such request
+pub(crate) fn resolve_confirmation(lua: &Lua, nonce: &str, confirmed: bool) -> bool {
+    match Confirmations::get(lua).remove(nonce) {
+        Some(reply) => reply.send(confirmed).is_ok(),
+        None => false,
+    }
+}
+
 fn update(lua: &Lua, updates: Variadic<Table>) -> Result<()> {
     let broadcast_tx = get_broadcast_sender(lua);
 
@@ -40,6 +122,12 @@ fn update(lua: &Lua, updates: Variadic<Table>) -> Result<()> {
 pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     let module = lua.create_table()?;
     module.set("update", lua.create_function(update)?)?;
+    module.set(
+        "confirm",
+        lua.create_async_function(
+            |lua, message: String| async move { confirm(&lua, message).await },
+        )?,
+    )?;
 
     libs.set("server", &module)?;
     lua.register_module("server", module)?;
@@ -132,6 +220,66 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_server_confirm() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        let (tx, rx) = flume::unbounded();
+        lua.set_app_data(tx);
+
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        let answer = |confirmed| {
+            let lua = lua.clone();
+            let rx = rx.clone();
+            async move {
+                let nonce = match rx.recv_async().await.unwrap() {
+                    ServerMessage::Confirm { nonce, message } => {
+                        assert_eq!(message, "Shut down?");
+                        nonce
+                    }
+                    _ => panic!("Expected Confirm message"),
+                };
+                assert!(!resolve_confirmation(&lua, "unknown", true));
+                assert!(resolve_confirmation(&lua, &nonce, confirmed));
+            }
+        };
+
+        for confirmed in [true, false] {
+            let task = tokio::spawn(answer(confirmed));
+            let result = lua
+                .load(r#"return libs.server.confirm("Shut down?")"#)
+                .eval_async::<bool>()
+                .await
+                .unwrap();
+            task.await.unwrap();
+            assert_eq!(result, confirmed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_confirm_without_clients() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        // Only the worker's own receiver, no client subscriptions
+        let (tx, rx) = flume::unbounded::<ServerMessage>();
+        lua.set_app_data(tx);
+
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        let result = lua
+            .load(r#"return libs.server.confirm("Shut down?")"#)
+            .eval_async::<bool>()
+            .await
+            .unwrap();
+        assert!(!result);
+        assert!(rx.is_empty());
+    }
+
     #[test]
     fn test_server_update_complex_types() {
         let lua = Lua::new();
@@ -0,0 +1,587 @@
+//! `libs.settings`: saving the `settings` table.
+//!
+//! `save()` writes changed values back to the settings file, keeping
+//! comments, order and keys the table doesn't have, and fires
+//! `events.settingschanged(keys)`. Schema defaults are only written once the
+//! file has the key.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::PathBuf,
+};
+
+use mlua::{Error, Function, Lua, Result, Table, Value};
+use uniremote_core::{SettingType, SettingsSchema};
+
+use crate::data::write_atomic;
+
+/// Path of the settings file a remote's `settings` table is saved to
+#[derive(Clone, Debug)]
+pub struct SettingsFile(pub PathBuf);
+
+/// A key-value entry of a properties file with the physical lines it spans
+#[derive(Debug, PartialEq)]
+struct Entry {
+    key: String,
+    value: String,
+    lines: Range<usize>,
+}
+
+fn is_comment_or_blank(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('!')
+}
+
+fn strip_line_ending(line: &str) -> &str {
+    line.strip_suffix('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .unwrap_or(line)
+}
+
+/// A line continues on the next one if it ends with an odd number of
+/// backslashes
+fn is_continued(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+fn unescape_char(chars: &mut impl Iterator<Item = char>) -> Option<char> {
+    match chars.next()? {
+        't' => Some('\t'),
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        'f' => Some('\x0c'),
+        'u' => {
+            let hex = chars.by_ref().take(4).collect::<String>();
+            u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+        }
+        c => Some(c),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            result.extend(unescape_char(&mut chars));
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn parse_key_value(logical_line: &str) -> (String, String) {
+    let mut key = String::new();
+    let mut chars = logical_line.trim_start().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => key.extend(unescape_char(&mut chars)),
+            '=' | ':' => break,
+            c if c.is_whitespace() => {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                chars.next_if(|c| *c == '=' || *c == ':');
+                break;
+            }
+            c => key.push(c),
+        }
+    }
+
+    let rest = chars.collect::<String>();
+    (key, unescape(rest.trim_start()))
+}
+
+/// Parse the entries of a properties file, keeping track of their lines
+fn parse_entries(lines: &[&str]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut index = 0;
+
+    while index < lines.len() {
+        if is_comment_or_blank(lines[index]) {
+            index += 1;
+            continue;
+        }
+
+        let start = index;
+        let mut logical_line = strip_line_ending(lines[index]).to_string();
+        while is_continued(&logical_line) && index + 1 < lines.len() {
+            logical_line.pop();
+            index += 1;
+            logical_line.push_str(strip_line_ending(lines[index]).trim_start());
+        }
+        index += 1;
+
+        let (key, value) = parse_key_value(&logical_line);
+        entries.push(Entry {
+            key,
+            value,
+            lines: start..index,
+        });
+    }
+
+    entries
+}
+
+fn escape(s: &str, is_key: bool) -> String {
+    let mut result = String::with_capacity(s.len());
+    for (index, c) in s.chars().enumerate() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            '\x0c' => result.push_str("\\f"),
+            ' ' if is_key || index == 0 => result.push_str("\\ "),
+            '=' | ':' if is_key => {
+                result.push('\\');
+                result.push(c);
+            }
+            '#' | '!' if is_key && index == 0 => {
+                result.push('\\');
+                result.push(c);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Apply `values` to the content of a properties file.
+///
+/// Comments, blank lines, the order of existing keys and keys missing from
+/// `values` are kept, changed entries are rewritten in place and new keys are
+/// appended in alphabetical order. Of a key listed more than once only the
+/// last entry, the one that takes effect, is rewritten. Returns the new
+/// content and the changed keys.
+fn update_properties(original: &str, values: &HashMap<String, String>) -> (String, Vec<String>) {
+    let lines = original.split_inclusive('\n').collect::<Vec<_>>();
+    let entries = parse_entries(&lines);
+    let last_entries = entries
+        .iter()
+        .enumerate()
+        .map(|(position, entry)| (entry.key.as_str(), position))
+        .collect::<HashMap<_, _>>();
+
+    let mut output = String::with_capacity(original.len());
+    let mut changed = Vec::new();
+    let mut seen = HashSet::new();
+    let mut index = 0;
+
+    for (position, entry) in entries.iter().enumerate() {
+        output.extend(lines[index..entry.lines.start].iter().copied());
+        index = entry.lines.end;
+        seen.insert(entry.key.as_str());
+
+        let value = values
+            .get(&entry.key)
+            .filter(|_| last_entries[entry.key.as_str()] == position);
+        match value {
+            Some(value) if *value != entry.value => {
+                let last_line = lines[entry.lines.end - 1];
+                let line_ending = &last_line[strip_line_ending(last_line).len()..];
+                output.push_str(&format!(
+                    "{}={}{line_ending}",
+                    escape(&entry.key, true),
+                    escape(value, false)
+                ));
+                changed.push(entry.key.clone());
+            }
+            _ => output.extend(lines[entry.lines.clone()].iter().copied()),
+        }
+    }
+    output.extend(lines[index..].iter().copied());
+
+    let mut added = values
+        .iter()
+        .filter(|(key, _)| !seen.contains(key.as_str()))
+        .collect::<Vec<_>>();
+    added.sort();
+
+    if !added.is_empty() && !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    for (key, value) in added {
+        output.push_str(&format!("{}={}\n", escape(key, true), escape(value, false)));
+        changed.push(key.clone());
+    }
+
+    changed.sort();
+    changed.dedup();
+    (output, changed)
+}
+
+fn setting_to_string(key: &str, value: Value) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value.to_str()?.to_string()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        value => Err(Error::runtime(format!(
+            "setting '{key}' has unsupported type {}",
+            value.type_name()
+        ))),
+    }
+}
+
+/// Convert raw settings file values to typed values using the remote's
+/// schema.
+///
+/// Keys without a schema entry stay strings, invalid values and keys missing
+/// from `raw` fall back to the schema default. Secret settings are left out.
+pub(crate) fn typed_settings(
+    schema: Option<&SettingsSchema>,
+    raw: HashMap<String, String>,
+) -> Vec<(String, serde_json::Value)> {
+    let mut typed = Vec::with_capacity(raw.len());
+
+    for (key, value) in &raw {
+        let Some(spec) = schema.and_then(|schema| schema.get(key)) else {
+            typed.push((key.clone(), serde_json::Value::from(value.as_str())));
+            continue;
+        };
+
+        // Secrets are only available through `libs.secrets`
+        if spec.kind == SettingType::Secret {
+            continue;
+        }
+
+        match spec.parse(value) {
+            Ok(value) => typed.push((key.clone(), value)),
+            Err(error) => {
+                tracing::warn!("invalid value for setting '{key}': {error}, using default");
+                typed.extend(spec.default_value().map(|value| (key.clone(), value)));
+            }
+        }
+    }
+
+    let defaults = schema
+        .iter()
+        .flat_map(|schema| &schema.settings)
+        .filter(|spec| spec.kind != SettingType::Secret && !raw.contains_key(&spec.key))
+        .filter_map(|spec| Some((spec.key.clone(), spec.default_value()?)));
+    typed.extend(defaults);
+
+    typed
+}
+
+/// Leave out settings that are not in the file and still have their schema
+/// default, so that only values the user changed get written
+fn without_defaults(
+    mut values: HashMap<String, String>,
+    original: &str,
+    schema: Option<&SettingsSchema>,
+) -> HashMap<String, String> {
+    let Some(schema) = schema else {
+        return values;
+    };
+
+    let lines = original.split_inclusive('\n').collect::<Vec<_>>();
+    let stored = parse_entries(&lines)
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect::<HashSet<_>>();
+
+    values.retain(|key, value| {
+        let is_default = schema.get(key).is_some_and(|spec| {
+            spec.default_value()
+                .is_some_and(|default| spec.parse(value).ok() == Some(default))
+        });
+        stored.contains(key) || !is_default
+    });
+    values
+}
+
+/// Write the `settings` table back to the settings file and fire
+/// `events.settingschanged` with the changed keys
+pub(crate) fn save_settings(lua: &Lua) -> Result<Vec<String>> {
+    let path = lua
+        .app_data_ref::<SettingsFile>()
+        .map(|file| file.0.clone())
+        .ok_or_else(|| Error::runtime("settings file is not configured for this remote"))?;
+
+    let settings: Table = lua.globals().get("settings")?;
+    let values = settings
+        .pairs::<String, Value>()
+        .map(|pair| {
+            let (key, value) = pair?;
+            let value = setting_to_string(&key, value)?;
+            Ok((key, value))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let original = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(error) => {
+            return Err(Error::runtime(format!(
+                "failed to read settings file '{}': {error}",
+                path.display()
+            )));
+        }
+    };
+
+    let schema = lua.app_data_ref::<SettingsSchema>();
+    let values = without_defaults(values, &original, schema.as_deref());
+    drop(schema);
+
+    let (content, changed) = update_properties(&original, &values);
+    if changed.is_empty() {
+        return Ok(changed);
+    }
+
+    write_atomic(&path, content.as_bytes()).map_err(|error| {
+        Error::runtime(format!(
+            "failed to write settings file '{}': {error:#}",
+            path.display()
+        ))
+    })?;
+
+    let events: Table = lua.globals().get("events")?;
+    if let Some(event_fn) = events.get::<Option<Function>>("settingschanged")? {
+        event_fn.call::<()>(changed.clone())?;
+    }
+
+    Ok(changed)
+}
+
+fn save(lua: &Lua, _: ()) -> Result<Vec<String>> {
+    save_settings(lua)
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("save", lua.create_function(save)?)?;
+
+    libs.set("settings", &module)?;
+    lua.register_module("settings", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let content = "# comment\nhost = localhost\nport:8080\n\n! other comment\nlong=first \\\n    second\nkey\\ with\\ spaces value\n";
+        let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+        let entries = parse_entries(&lines);
+
+        let parsed = entries
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parsed,
+            vec![
+                ("host", "localhost"),
+                ("port", "8080"),
+                ("long", "first second"),
+                ("key with spaces", "value"),
+            ]
+        );
+        assert_eq!(entries[2].lines, 5..7);
+    }
+
+    #[test]
+    fn test_update_properties_keeps_comments_and_order() {
+        let original =
+            "# Player settings\nhost = localhost\n\n# Port of the API\nport=8080\nlegacy=true\n";
+        let (content, changed) = update_properties(
+            original,
+            &values(&[("host", "localhost"), ("port", "9090"), ("volume", "50")]),
+        );
+
+        assert_eq!(
+            content,
+            "# Player settings\nhost = localhost\n\n# Port of the API\nport=9090\nlegacy=true\nvolume=50\n"
+        );
+        assert_eq!(changed, vec!["port", "volume"]);
+    }
+
+    #[test]
+    fn test_update_properties_duplicate_keys() {
+        let original = "port=8080\nhost=localhost\nport=9090\n";
+
+        // The last entry takes effect, so it is the one compared and updated
+        let (content, changed) = update_properties(original, &values(&[("port", "9090")]));
+        assert_eq!(content, original);
+        assert!(changed.is_empty());
+
+        let (content, changed) = update_properties(original, &values(&[("port", "7070")]));
+        assert_eq!(content, "port=8080\nhost=localhost\nport=7070\n");
+        assert_eq!(changed, vec!["port"]);
+    }
+
+    #[test]
+    fn test_update_properties_escapes_values() {
+        let (content, changed) =
+            update_properties("", &values(&[("path", "C:\\media"), ("title", " a\nb")]));
+
+        assert_eq!(content, "path=C:\\\\media\ntitle=\\ a\\nb\n");
+        assert_eq!(changed, vec!["path", "title"]);
+
+        let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+        let entries = parse_entries(&lines);
+        assert_eq!(entries[0].value, "C:\\media");
+        assert_eq!(entries[1].value, " a\nb");
+    }
+
+    #[test]
+    fn test_update_properties_unchanged() {
+        let original = "a = 1\r\nb = two\r\n";
+        let (content, changed) = update_properties(original, &values(&[("a", "1"), ("b", "two")]));
+
+        assert_eq!(content, original);
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn test_settings_save() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("settings.prop");
+        fs::write(&path, "# Server address\nhost=localhost\nport=8080\n").unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        lua.set_app_data(SettingsFile(path.clone()));
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            settings = { host = "localhost", port = "8080" }
+            events = {}
+            events.settingschanged = function(keys)
+                changed = table.concat(keys, ",")
+            end
+
+            assert(#libs.settings.save() == 0, "nothing changed yet")
+            assert(changed == nil, "event should not fire without changes")
+
+            settings.port = 9090
+            settings.muted = false
+            libs.settings.save()
+            assert(changed == "muted,port", changed)
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Server address\nhost=localhost\nport=9090\nmuted=false\n"
+        );
+    }
+
+    #[test]
+    fn test_typed_settings() {
+        let schema = SettingsSchema::from_properties(
+            [
+                ("port.type", "int"),
+                ("port.default", "8080"),
+                ("muted.type", "bool"),
+                ("muted.default", "false"),
+                ("volume.type", "int"),
+                ("token.type", "secret"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        )
+        .unwrap();
+
+        let mut typed = typed_settings(
+            Some(&schema),
+            values(&[
+                ("port", "9090"),
+                ("volume", "loud"),
+                ("name", "Living room"),
+            ]),
+        );
+        typed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Invalid values without a default and secrets are left out
+        assert_eq!(
+            typed,
+            vec![
+                ("muted".to_string(), serde_json::json!(false)),
+                ("name".to_string(), serde_json::json!("Living room")),
+                ("port".to_string(), serde_json::json!(9090)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settings_save_skips_defaults() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("settings.prop");
+        fs::write(&path, "muted=true\n").unwrap();
+
+        let schema = SettingsSchema::from_properties(
+            [
+                ("port.type", "int"),
+                ("port.default", "8080"),
+                ("muted.type", "bool"),
+                ("muted.default", "false"),
+                ("host.default", "localhost"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        )
+        .unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        lua.set_app_data(SettingsFile(path.clone()));
+        lua.set_app_data(schema);
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        // Defaults stay out of the file, stored keys are kept up to date even
+        // when set back to their default
+        lua.load(
+            r#"
+            settings = { port = 8080, host = "media.local", muted = false }
+            events = {}
+            libs.settings.save()
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "muted=false\nhost=media.local\n"
+        );
+    }
+
+    #[test]
+    fn test_settings_save_unsupported_type() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        lua.set_app_data(SettingsFile(temp_dir.path().join("settings.prop")));
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        let error = lua
+            .load("settings = { nested = {} }; libs.settings.save()")
+            .exec()
+            .unwrap_err();
+        assert!(error.to_string().contains("unsupported type"), "{error}");
+    }
+}
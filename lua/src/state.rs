@@ -1,12 +1,17 @@
 use std::{
+    collections::HashMap,
     path::Path,
     sync::atomic::{AtomicU64, Ordering},
 };
 
+use flume::Receiver;
 use mlua::{
-    Error, Function, HookTriggers, Lua, LuaSerdeExt, MaybeSend, MultiValue, Table, VmState,
+    ChunkMode, Error, Function, HookTriggers, Lua, LuaSerdeExt, MaybeSend, MultiValue, Table,
+    VmState,
 };
-use uniremote_core::ActionId;
+use uniremote_core::{ActionId, Permission, Permissions, ServerMessage, SettingsSchema};
+
+use crate::{callback::Callback, permission::load_module};
 
 // Default Lua security limits
 const DEFAULT_LUA_MEMORY_LIMIT_MB: usize = 10; // 10 MB
@@ -34,37 +39,66 @@ impl Default for LuaLimits {
 // Global instruction counter that can be reset per action call
 static INSTRUCTION_COUNTER: AtomicU64 = AtomicU64::new(0);
 
+/// Give the next action or callback a fresh instruction budget
+pub(crate) fn reset_instruction_counter() {
+    INSTRUCTION_COUNTER.store(0, Ordering::Relaxed);
+}
+
 pub struct LuaState {
     lua: Lua,
+    callbacks: Receiver<Callback>,
 }
 
 impl LuaState {
-    pub fn empty(limits: LuaLimits) -> Self {
-        let lua = Lua::new();
+    pub fn empty(limits: LuaLimits) -> anyhow::Result<Self> {
+        let lua = crate::sandbox::create()?;
         apply_security_limits(&lua, limits);
-        LuaState { lua }
+        let callbacks = crate::callback::attach_queue(&lua);
+        Ok(LuaState { lua, callbacks })
     }
 
     pub fn add_state<T: MaybeSend + 'static>(&self, state: T) {
         self.lua.set_app_data(state);
     }
 
-    pub fn new(script: &Path, remotes_dir: &Path, limits: LuaLimits) -> anyhow::Result<Self> {
-        let lua = Lua::new();
+    /// Create the state of a remote with its globals and `libs.*` modules.
+    ///
+    /// The script is run separately with [`LuaState::exec`], so that the
+    /// loader can first attach the remote's context, data store and secrets
+    /// for top-level code to use.
+    pub fn new(
+        remote_dir: &Path,
+        remotes_dir: &Path,
+        limits: LuaLimits,
+        permissions: &Permissions,
+    ) -> anyhow::Result<Self> {
+        let lua = crate::sandbox::create()?;
         apply_security_limits(&lua, limits);
-
-        // Get the directory containing the script (remote directory)
-        let remote_dir = script
-            .parent()
-            .ok_or_else(|| anyhow::anyhow!("script path has no parent directory"))?;
+        lua.set_app_data(permissions.clone());
+        let callbacks = crate::callback::attach_queue(&lua);
 
         crate::globals::load(&lua, remote_dir, remotes_dir)?;
+        crate::sandbox::restrict_require(
+            &lua,
+            &[
+                remote_dir,
+                &remotes_dir.join(crate::sandbox::SHARED_LIB_DIR),
+            ],
+        )?;
         load_modules(&lua)?;
 
-        let script_content = std::fs::read(script)?;
-        lua.load(script_content).exec()?;
+        Ok(LuaState { lua, callbacks })
+    }
 
-        Ok(LuaState { lua })
+    /// Run the remote's script
+    pub fn exec(&self, script: &Path) -> anyhow::Result<()> {
+        let script_content = std::fs::read(script)?;
+        reset_instruction_counter();
+        self.lua
+            .load(script_content)
+            .set_mode(ChunkMode::Text)
+            .exec()?;
+        Ok(())
     }
 
     fn actions(&self) -> anyhow::Result<Table> {
@@ -97,17 +131,42 @@ impl LuaState {
         Ok(settings)
     }
 
-    pub fn set_settings(
-        &self,
-        settings: impl IntoIterator<Item = (String, String)>,
-    ) -> anyhow::Result<()> {
+    /// Set settings from raw settings file values, typed according to the
+    /// remote's [`SettingsSchema`] if one was added to the state
+    pub fn set_settings(&self, settings: HashMap<String, String>) -> anyhow::Result<()> {
+        let schema = self.lua.app_data_ref::<SettingsSchema>();
+        let typed = crate::settings::typed_settings(schema.as_deref(), settings);
+        drop(schema);
+
         let table = self.settings()?;
-        for (key, value) in settings {
-            table.raw_set(key, value)?;
+        for (key, value) in typed {
+            table.raw_set(key, self.lua.to_value(&value)?)?;
         }
         Ok(())
     }
 
+    /// Set settings and save them to the settings file, returning the keys
+    /// that changed
+    pub fn update_settings(
+        &self,
+        settings: HashMap<String, String>,
+    ) -> anyhow::Result<Vec<String>> {
+        self.set_settings(settings)?;
+        Ok(crate::settings::save_settings(&self.lua)?)
+    }
+
+    /// Mask the remote's secrets in a message that is about to be sent to
+    /// its clients
+    pub fn redact_message(&self, message: ServerMessage) -> ServerMessage {
+        crate::secrets::redact_message(&self.lua, message)
+    }
+
+    /// Answer a pending confirmation request, returning `false` if it is
+    /// unknown or has already been answered
+    pub fn confirm(&self, nonce: &str, confirmed: bool) -> bool {
+        crate::server::resolve_confirmation(&self.lua, nonce, confirmed)
+    }
+
     pub fn detect(&self) -> anyhow::Result<bool> {
         if let Ok(event_fn) = self.event("detect") {
             return Ok(event_fn.call::<bool>(())?);
@@ -115,21 +174,63 @@ impl LuaState {
         Ok(true)
     }
 
-    pub fn trigger_event(&self, event_name: &str) -> anyhow::Result<()> {
+    pub async fn trigger_event(&self, event_name: &str) -> anyhow::Result<()> {
+        if let Ok(event_fn) = self.event(event_name) {
+            reset_instruction_counter();
+            event_fn.call_async::<()>(()).await?;
+        }
+        Ok(())
+    }
+
+    /// Queue an event handler to run on the worker, for callers that cannot
+    /// await it
+    pub fn queue_event(&self, event_name: &str) -> anyhow::Result<()> {
         if let Ok(event_fn) = self.event(event_name) {
-            event_fn.call::<()>(())?;
+            crate::callback::enqueue(
+                &self.lua,
+                &format!("{event_name} event handler"),
+                event_fn,
+                (),
+            )?;
         }
         Ok(())
     }
 
-    pub fn call_action(
+    /// Callbacks queued by timers, subscriptions and other background tasks,
+    /// to be run one at a time with [`LuaState::run_callback`]
+    pub fn callbacks(&self) -> Receiver<Callback> {
+        self.callbacks.clone()
+    }
+
+    pub async fn run_callback(&self, callback: Callback) {
+        crate::callback::run(callback).await;
+    }
+
+    /// Whether the remote has persisted cron timers, which only fire while
+    /// its worker runs
+    pub fn has_saved_timers(&self) -> bool {
+        crate::timer::has_saved_crons(&self.lua)
+    }
+
+    /// Run the destroy event handler, then close the connections and watches
+    /// the remote left open
+    pub async fn destroy(&self) -> anyhow::Result<()> {
+        let result = self.trigger_event("destroy").await;
+        crate::websocket::close_all(&self.lua);
+        crate::fs::unwatch_all(&self.lua);
+        result
+    }
+
+    pub async fn call_action(
         &self,
         action_id: ActionId,
         args: Option<Vec<serde_json::Value>>,
     ) -> anyhow::Result<()> {
         // Reset instruction counter at the start of each action call
-        INSTRUCTION_COUNTER.store(0, Ordering::Relaxed);
+        reset_instruction_counter();
 
+        // Actions are called asynchronously so they can await async library
+        // functions such as `http.get` or `proc:wait()`
         let action_fn = self.action(&action_id)?;
         let preaction = self.event("preaction").ok();
         let postaction = self.event("postaction").ok();
@@ -143,31 +244,33 @@ impl LuaState {
             );
 
             let run = if let Some(preaction) = preaction {
-                preaction.call::<bool>((&*action_id, args.clone()))?
+                preaction
+                    .call_async::<bool>((&*action_id, args.clone()))
+                    .await?
             } else {
                 true
             };
 
             if run {
-                action_fn.call::<()>(args.clone())?;
+                action_fn.call_async::<()>(args.clone()).await?;
             }
 
             if let Some(postaction) = postaction {
-                postaction.call::<()>((&*action_id, args))?;
+                postaction.call_async::<()>((&*action_id, args)).await?;
             }
         } else {
             let run = if let Some(preaction) = preaction {
-                preaction.call::<bool>(&*action_id)?
+                preaction.call_async::<bool>(&*action_id).await?
             } else {
                 true
             };
 
             if run {
-                action_fn.call::<()>(())?;
+                action_fn.call_async::<()>(()).await?;
             }
 
             if let Some(postaction) = postaction {
-                postaction.call::<()>(&*action_id)?;
+                postaction.call_async::<()>(&*action_id).await?;
             }
         }
 
@@ -177,15 +280,35 @@ impl LuaState {
 
 fn load_modules(lua: &Lua) -> anyhow::Result<()> {
     let libs = lua.create_table()?;
-    crate::keyboard::load(lua, &libs)?;
-    crate::mouse::load(lua, &libs)?;
-    crate::ps::load(lua, &libs)?;
-    crate::script::load(lua, &libs)?;
+    load_module(lua, &libs, Permission::Keyboard, crate::keyboard::load)?;
+    load_module(lua, &libs, Permission::Mouse, crate::mouse::load)?;
+    load_module(lua, &libs, Permission::Ps, crate::ps::load)?;
+    load_module(lua, &libs, Permission::Script, crate::script::load)?;
     crate::server::load(lua, &libs)?;
     crate::timer::load(lua, &libs)?;
+    crate::data::load(lua, &libs)?;
+    crate::settings::load(lua, &libs)?;
+    crate::secrets::load(lua, &libs)?;
     crate::extra::load(lua, &libs)?;
-    crate::http::load(lua, &libs)?;
-    crate::fs::load(lua, &libs)?;
+    crate::json::load(lua, &libs)?;
+    crate::base64::load(lua, &libs)?;
+    crate::hash::load(lua, &libs)?;
+    crate::utf8::load(lua, &libs)?;
+    load_module(lua, &libs, Permission::Http, crate::http::load)?;
+    load_module(lua, &libs, Permission::Fs, crate::fs::load)?;
+    load_module(lua, &libs, Permission::Sensors, crate::sensors::load)?;
+    load_module(lua, &libs, Permission::Dbus, crate::dbus::load)?;
+    load_module(lua, &libs, Permission::Media, crate::media::load)?;
+    load_module(lua, &libs, Permission::Audio, crate::audio::load)?;
+    load_module(lua, &libs, Permission::Notify, crate::notify::load)?;
+    load_module(lua, &libs, Permission::Power, crate::power::load)?;
+    load_module(lua, &libs, Permission::Clipboard, crate::clipboard::load)?;
+    load_module(lua, &libs, Permission::Window, crate::window::load)?;
+    load_module(lua, &libs, Permission::Screen, crate::screen::load)?;
+    load_module(lua, &libs, Permission::Net, crate::net::load)?;
+    load_module(lua, &libs, Permission::Websocket, crate::websocket::load)?;
+    load_module(lua, &libs, Permission::Osc, crate::osc::load)?;
+    load_module(lua, &libs, Permission::Midi, crate::midi::load)?;
     lua.globals().set("libs", libs)?;
     Ok(())
 }
@@ -0,0 +1,135 @@
+//! Background tasks of a Lua state, like signal subscriptions, watches and
+//! socket readers, with the cancellable `Subscription` handle returned to
+//! scripts.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use mlua::{Lua, UserData, UserDataFields, UserDataMethods};
+use tokio::task::{JoinHandle, spawn};
+
+/// Running subscriptions, aborted together with the Lua state
+#[derive(Clone)]
+pub(crate) struct SubscriptionMap(Arc<SubscriptionMapInner>);
+
+struct SubscriptionMapInner {
+    map: Mutex<HashMap<u64, JoinHandle<()>>>,
+    counter: AtomicU64,
+}
+
+impl SubscriptionMap {
+    fn new() -> Self {
+        Self(Arc::new(SubscriptionMapInner {
+            map: Mutex::new(HashMap::new()),
+            counter: AtomicU64::new(1),
+        }))
+    }
+
+    pub(crate) fn add(&self, fut: impl Future<Output = ()> + Send + 'static) -> u64 {
+        self.add_with(|_| fut)
+    }
+
+    /// Spawn the future built by `make`, which is given the id of the
+    /// subscription. The subscription is forgotten once the future ends.
+    pub(crate) fn add_with<F>(&self, make: impl FnOnce(u64) -> F) -> u64
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let id = self.0.counter.fetch_add(1, Ordering::SeqCst);
+        let fut = make(id);
+        let inner = Arc::downgrade(&self.0);
+
+        // The lock is held until the handle is stored, so that a future
+        // ending right away still finds its entry to remove
+        let mut map = self.0.map.lock().unwrap();
+        let handle = spawn(async move {
+            fut.await;
+            if let Some(inner) = inner.upgrade() {
+                inner.map.lock().unwrap().remove(&id);
+            }
+        });
+        map.insert(id, handle);
+        id
+    }
+
+    pub(crate) fn remove(&self, id: u64) -> bool {
+        if let Some(handle) = self.0.map.lock().unwrap().remove(&id) {
+            handle.abort();
+            true
+        } else {
+            false
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.map.lock().unwrap().len()
+    }
+}
+
+impl Drop for SubscriptionMapInner {
+    fn drop(&mut self) {
+        for handle in self.map.get_mut().unwrap().values() {
+            handle.abort();
+        }
+    }
+}
+
+/// Subscription map of the Lua state, shared by all modules running
+/// background tasks
+pub(crate) fn get_subscription_map(lua: &Lua) -> SubscriptionMap {
+    if let Some(subscriptions) = lua.app_data_ref::<SubscriptionMap>() {
+        return subscriptions.clone();
+    }
+
+    let subscriptions = SubscriptionMap::new();
+    lua.set_app_data(subscriptions.clone());
+    subscriptions
+}
+
+/// Handle of a subscription
+pub(crate) struct Subscription {
+    pub(crate) id: u64,
+    pub(crate) subscriptions: SubscriptionMap,
+}
+
+impl UserData for Subscription {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_lua, this| Ok(this.id));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("cancel", |_lua, this, ()| {
+            Ok(this.subscriptions.remove(this.id))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_finished_subscription_is_removed() {
+        let subscriptions = SubscriptionMap::new();
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let finished = subscriptions.add(async {});
+        let running = subscriptions.add(async {
+            let _ = rx.await;
+        });
+
+        tokio::task::yield_now().await;
+        assert_eq!(subscriptions.len(), 1);
+        assert!(!subscriptions.remove(finished));
+
+        assert!(subscriptions.remove(running));
+        assert_eq!(subscriptions.len(), 0);
+        drop(tx);
+    }
+}
@@ -1,30 +1,80 @@
+//! `libs.timer`: one-shot, interval and cron timers.
+//!
+//! `timeout`, `interval` and `schedule` return ids for `cancel`.
+//! `cron("0 7 * * 1-5", callback, { name, persist, utc }?)` runs a callback on
+//! a five-field cron schedule in local time, or UTC with `utc = true`, and
+//! `next(id_or_expression)` returns the next fire time as ISO 8601.
+//!
+//! With `persist = true` a named schedule is saved in the data directory, so
+//! that `restore(name, callback)` can re-arm it after a restart. Cancelling a
+//! persistent timer forgets it.
+
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
     sync::{
         Arc, Mutex,
         atomic::{AtomicU64, Ordering},
     },
 };
 
-use chrono::Utc;
-use mlua::{Error, Function, Lua, RegistryKey, Result, Table};
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat, Utc};
+use mlua::{Error, Function, Lua, RegistryKey, Result, Table, Value};
+use serde::{Deserialize, Serialize};
 use tokio::{
     task::{JoinHandle, spawn},
     time::{self, Duration},
 };
+use uniremote_core::RemoteContext;
+
+use crate::{
+    callback::{dispatch, dispatch_wait},
+    cron::Schedule,
+};
+
+/// File name of the persisted cron schedules inside a remote's data directory
+pub const TIMERS_FILE: &str = "timers.json";
+
+/// Longest single sleep of a cron timer, so that wall clock changes and
+/// system suspend delay it by at most this much
+const MAX_CRON_SLEEP: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 struct TimerMap(Arc<TimerMapInner>);
 
 struct TimerMapInner {
     map: Mutex<HashMap<u64, JoinHandle<()>>>,
+    crons: Mutex<HashMap<u64, CronTimer>>,
     counter: AtomicU64,
 }
 
+#[derive(Clone)]
+struct CronTimer {
+    schedule: Schedule,
+    utc: bool,
+    name: Option<String>,
+    persist: bool,
+}
+
+impl CronTimer {
+    fn next_fire(&self) -> Option<DateTime<FixedOffset>> {
+        next_fire(&self.schedule, self.utc)
+    }
+}
+
+/// A cron schedule saved in the timers file
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedCron {
+    cron: String,
+    #[serde(default)]
+    utc: bool,
+}
+
 impl TimerMap {
     fn new() -> Self {
         Self(Arc::new(TimerMapInner {
             map: Mutex::new(HashMap::new()),
+            crons: Mutex::new(HashMap::new()),
             counter: AtomicU64::new(1),
         }))
     }
@@ -36,6 +86,7 @@ impl TimerMap {
     }
 
     fn remove_timer(&self, id: u64) -> bool {
+        self.0.crons.lock().unwrap().remove(&id);
         if let Some(handle) = self.0.map.lock().unwrap().remove(&id) {
             handle.abort();
             true
@@ -43,6 +94,22 @@ impl TimerMap {
             false
         }
     }
+
+    fn set_cron(&self, id: u64, cron: CronTimer) {
+        self.0.crons.lock().unwrap().insert(id, cron);
+    }
+
+    fn get_cron(&self, id: u64) -> Option<CronTimer> {
+        self.0.crons.lock().unwrap().get(&id).cloned()
+    }
+
+    fn find_cron(&self, name: &str) -> Option<u64> {
+        let crons = self.0.crons.lock().unwrap();
+        crons
+            .iter()
+            .find(|(_, cron)| cron.name.as_deref() == Some(name))
+            .map(|(id, _)| *id)
+    }
 }
 
 fn get_timer_map(lua: &Lua) -> TimerMap {
@@ -66,9 +133,9 @@ fn timeout(lua: &Lua, (callback, time_ms): (Function, u64)) -> Result<u64> {
 
         // Try to upgrade the weak reference
         if let Some(lua) = weak_lua.try_upgrade() {
-            // Execute the callback
+            // Queue the callback on the worker
             if let Ok(callback) = lua.registry_value::<Function>(&registry_key)
-                && let Err(err) = callback.call::<()>(())
+                && let Err(err) = dispatch(&lua, "timer callback", callback, ()).await
             {
                 tracing::error!("timer callback error: {err}");
             }
@@ -103,9 +170,10 @@ fn interval(lua: &Lua, (callback, time_ms): (Function, u64)) -> Result<u64> {
                 break;
             };
 
-            // Execute the callback
+            // Run the callback on the worker and wait for it, so that a
+            // failing callback stops the interval
             if let Ok(callback) = lua.registry_value::<Function>(&registry_key)
-                && let Err(err) = callback.call::<()>(())
+                && let Err(err) = dispatch_wait(&lua, "timer callback", callback, ()).await
             {
                 tracing::error!("timer callback error: {err}");
                 break;
@@ -153,9 +221,9 @@ fn schedule(lua: &Lua, (callback, iso_time): (Function, String)) -> Result<u64>
 
         // Try to upgrade the weak reference
         if let Some(lua) = weak_lua.try_upgrade() {
-            // Execute the callback
+            // Queue the callback on the worker
             if let Ok(callback) = lua.registry_value::<Function>(&registry_key)
-                && let Err(err) = callback.call::<()>(())
+                && let Err(err) = dispatch(&lua, "timer callback", callback, ()).await
             {
                 tracing::error!("timer callback error: {err}");
             }
@@ -169,9 +237,218 @@ fn schedule(lua: &Lua, (callback, iso_time): (Function, String)) -> Result<u64>
     Ok(timer_id)
 }
 
+fn next_fire(schedule: &Schedule, utc: bool) -> Option<DateTime<FixedOffset>> {
+    if utc {
+        schedule
+            .next_after(&Utc::now())
+            .map(|time| time.fixed_offset())
+    } else {
+        schedule
+            .next_after(&Local::now())
+            .map(|time| time.fixed_offset())
+    }
+}
+
+/// Sleep until a wall clock time, checking the clock in short steps
+async fn sleep_until(target: DateTime<FixedOffset>) {
+    while let Ok(remaining) = target.signed_duration_since(Utc::now()).to_std()
+        && !remaining.is_zero()
+    {
+        time::sleep(remaining.min(MAX_CRON_SLEEP)).await;
+    }
+}
+
+fn parse_schedule(expression: &str) -> Result<Schedule> {
+    expression
+        .parse()
+        .map_err(|error| Error::runtime(format!("{error:#}")))
+}
+
+fn get_timers_file(lua: &Lua) -> Result<PathBuf> {
+    // Only states created by the loader have a remote context, others (e.g.
+    // in tests) get an error instead of a panic
+    lua.app_data_ref::<RemoteContext>()
+        .map(|ctx| ctx.data_dir.join(TIMERS_FILE))
+        .ok_or_else(|| Error::runtime("timer persistence is not available"))
+}
+
+fn read_saved_crons(lua: &Lua) -> Result<BTreeMap<String, SavedCron>> {
+    let path = get_timers_file(lua)?;
+    if !path.is_file() {
+        return Ok(BTreeMap::new());
+    }
+
+    let content = std::fs::read(&path)
+        .map_err(|error| Error::runtime(format!("failed to read timers file: {error}")))?;
+    serde_json::from_slice(&content)
+        .map_err(|error| Error::runtime(format!("failed to parse timers file: {error}")))
+}
+
+/// Whether any cron timers are persisted for the remote of the state
+pub(crate) fn has_saved_crons(lua: &Lua) -> bool {
+    read_saved_crons(lua).is_ok_and(|crons| !crons.is_empty())
+}
+
+fn update_saved_crons(
+    lua: &Lua,
+    change: impl FnOnce(&mut BTreeMap<String, SavedCron>),
+) -> Result<()> {
+    let path = get_timers_file(lua)?;
+    let mut crons = read_saved_crons(lua)?;
+    change(&mut crons);
+
+    let content = serde_json::to_vec_pretty(&crons).map_err(Error::external)?;
+    crate::data::write_atomic(&path, &content)
+        .map_err(|error| Error::runtime(format!("failed to write timers file: {error:#}")))
+}
+
+/// Spawn the task of a cron timer, replacing any cron timer with the same name
+fn arm_cron(lua: &Lua, callback: Function, cron: CronTimer) -> Result<u64> {
+    let timer_map = get_timer_map(lua);
+
+    if let Some(name) = &cron.name
+        && let Some(existing) = timer_map.find_cron(name)
+    {
+        timer_map.remove_timer(existing);
+    }
+
+    // Create a registry key to keep the function alive
+    let registry_key: RegistryKey = lua.create_registry_value(callback)?;
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    let task_cron = cron.clone();
+    let timer_id = timer_map.add_timer(async move {
+        while let Some(next) = task_cron.next_fire() {
+            sleep_until(next).await;
+
+            // Try to upgrade the weak reference
+            let Some(lua) = weak_lua.try_upgrade() else {
+                return;
+            };
+
+            // Unlike intervals, a failing callback doesn't stop the schedule,
+            // so that one bad run doesn't silently disable an alarm
+            if let Ok(callback) = lua.registry_value::<Function>(&registry_key)
+                && let Err(err) = dispatch(&lua, "cron callback", callback, ()).await
+            {
+                tracing::error!("cron callback error: {err}");
+            }
+        }
+
+        // Clean up the registry key when the schedule has no more times
+        if let Some(lua) = weak_lua.try_upgrade() {
+            let _ = lua.remove_registry_value(registry_key);
+        }
+    });
+
+    tracing::info!(
+        "created cron timer with id: {timer_id}, schedule: {}",
+        cron.schedule
+    );
+    timer_map.set_cron(timer_id, cron);
+    Ok(timer_id)
+}
+
+fn cron(
+    lua: &Lua,
+    (expression, callback, options): (String, Function, Option<Table>),
+) -> Result<u64> {
+    let schedule = parse_schedule(&expression)?;
+
+    let (name, persist, utc) = match &options {
+        Some(options) => (
+            options.get::<Option<String>>("name")?,
+            options.get::<Option<bool>>("persist")?.unwrap_or(false),
+            options.get::<Option<bool>>("utc")?.unwrap_or(false),
+        ),
+        None => (None, false, false),
+    };
+
+    if persist {
+        let Some(name) = &name else {
+            return Err(Error::runtime("persistent cron timers require a name"));
+        };
+        update_saved_crons(lua, |crons| {
+            crons.insert(
+                name.clone(),
+                SavedCron {
+                    cron: schedule.to_string(),
+                    utc,
+                },
+            );
+        })?;
+    }
+
+    arm_cron(
+        lua,
+        callback,
+        CronTimer {
+            schedule,
+            utc,
+            name,
+            persist,
+        },
+    )
+}
+
+/// Re-arm a persisted cron timer, returning `nil` if none is saved under `name`
+fn restore(lua: &Lua, (name, callback): (String, Function)) -> Result<Option<u64>> {
+    let Some(saved) = read_saved_crons(lua)?.remove(&name) else {
+        return Ok(None);
+    };
+
+    let cron = CronTimer {
+        schedule: parse_schedule(&saved.cron)?,
+        utc: saved.utc,
+        name: Some(name),
+        persist: true,
+    };
+    arm_cron(lua, callback, cron).map(Some)
+}
+
+/// Next fire time of a cron timer id or a cron expression as ISO 8601
+fn next(lua: &Lua, (target, options): (Value, Option<Table>)) -> Result<Option<String>> {
+    let next = match target {
+        Value::Integer(id) => match get_timer_map(lua).get_cron(id as u64) {
+            Some(cron) => cron.next_fire(),
+            None => return Ok(None),
+        },
+        Value::String(expression) => {
+            let schedule = parse_schedule(&expression.to_str()?)?;
+            let utc = match &options {
+                Some(options) => options.get::<Option<bool>>("utc")?.unwrap_or(false),
+                None => false,
+            };
+            next_fire(&schedule, utc)
+        }
+        other => {
+            return Err(Error::runtime(format!(
+                "expected a timer id or a cron expression, got {}",
+                other.type_name()
+            )));
+        }
+    };
+
+    Ok(next.map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true)))
+}
+
 fn cancel(lua: &Lua, timer_id: u64) -> Result<()> {
     let timer_map = get_timer_map(lua);
 
+    // Cancelling a persistent cron timer also forgets it
+    if let Some(CronTimer {
+        name: Some(name),
+        persist: true,
+        ..
+    }) = timer_map.get_cron(timer_id)
+    {
+        update_saved_crons(lua, |crons| {
+            crons.remove(&name);
+        })?;
+    }
+
     if timer_map.remove_timer(timer_id) {
         tracing::info!("cancelled timer with id: {timer_id}");
     } else {
@@ -188,6 +465,9 @@ pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
     module.set("timeout", lua.create_function(timeout)?)?;
     module.set("interval", lua.create_function(interval)?)?;
     module.set("schedule", lua.create_function(schedule)?)?;
+    module.set("cron", lua.create_function(cron)?)?;
+    module.set("restore", lua.create_function(restore)?)?;
+    module.set("next", lua.create_function(next)?)?;
     module.set("cancel", lua.create_function(cancel)?)?;
 
     libs.set("timer", &module)?;
@@ -347,4 +627,103 @@ mod tests {
 
         assert!(result.is_err(), "Past time should fail");
     }
+
+    #[tokio::test]
+    async fn test_cron() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local tmr = require("timer")
+            local id = tmr.cron("0 7 * * 1-5", function() end)
+            assert(id > 0)
+            assert(tmr.next(id):match("^%d%d%d%d%-%d%d%-%d%dT07:00:00"))
+            assert(tmr.next("@daily", { utc = true }):match("T00:00:00Z$"))
+            assert(tmr.next("0 0 30 2 *") == nil)
+            assert(tmr.next(tmr.timeout(function() end, 1000)) == nil)
+
+            -- A named timer replaces the previous one with the same name
+            local first = tmr.cron("* * * * *", function() end, { name = "tick" })
+            local second = tmr.cron("*/5 * * * *", function() end, { name = "tick" })
+            assert(tmr.next(first) == nil and tmr.next(second) ~= nil)
+
+            tmr.cancel(id)
+            assert(tmr.next(id) == nil)
+
+            local ok, err = pcall(tmr.cron, "61 * * * *", function() end)
+            assert(not ok and tostring(err):find("invalid minute field"))
+            ok, err = pcall(tmr.cron, "* * * * *", function() end, { persist = true })
+            assert(not ok and tostring(err):find("require a name"))
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cron_persistence() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let script = temp_dir.path().join("remote.lua");
+        let context = RemoteContext::new(
+            script.clone(),
+            temp_dir.path().to_path_buf(),
+            temp_dir.path().join("data"),
+        );
+
+        // Runs the script at the top level, the way the loader does
+        let run_remote = |code: &str| {
+            std::fs::write(&script, code).unwrap();
+            let state = crate::LuaState::new(
+                temp_dir.path(),
+                temp_dir.path(),
+                crate::LuaLimits::default(),
+                &uniremote_core::Permissions::All,
+            )
+            .unwrap();
+            state.add_state(context.clone());
+            state.exec(&script).unwrap();
+            state
+        };
+
+        let state = run_remote(
+            r#"
+            local tmr = require("timer")
+            assert(tmr.restore("alarm", function() end) == nil)
+            tmr.cron("30 6 * * mon-fri", function() end, { name = "alarm", persist = true })
+        "#,
+        );
+
+        let saved = std::fs::read_to_string(temp_dir.path().join("data").join(TIMERS_FILE));
+        assert!(saved.unwrap().contains("30 6 * * mon-fri"));
+        assert!(state.has_saved_timers());
+
+        // A new state (e.g. after a restart) re-arms the saved schedule
+        let state = run_remote(
+            r#"
+            local tmr = require("timer")
+            local id = tmr.restore("alarm", function() end)
+            assert(id ~= nil)
+            assert(tmr.next(id):match("T06:30:00"))
+
+            tmr.cancel(id)
+            assert(tmr.restore("alarm", function() end) == nil)
+        "#,
+        );
+        assert!(!state.has_saved_timers());
+
+        // States without a remote context cannot persist timers
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        let error = lua
+            .load(r#"require("timer").restore("alarm", function() end)"#)
+            .exec()
+            .unwrap_err();
+        assert!(error.to_string().contains("not available"), "{error}");
+    }
 }
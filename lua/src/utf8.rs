@@ -0,0 +1,150 @@
+//! `libs.utf8`: the standard `utf8` table extended with character-based
+//! `sub`, `upper`, `lower`, `reverse`, `chars`, `insert`, `remove` and
+//! `valid`.
+
+use mlua::{Error, Lua, Result, Table};
+
+fn to_chars(text: &mlua::String) -> Result<Vec<char>> {
+    let text = text
+        .to_str()
+        .map_err(|_| Error::runtime("utf8 error: invalid UTF-8 string"))?;
+    Ok(text.chars().collect())
+}
+
+/// Resolve a 1-based character index the way `string.sub` does, where
+/// negative indices count from the end
+fn resolve_index(index: i64, len: usize) -> i64 {
+    if index < 0 {
+        len as i64 + index + 1
+    } else {
+        index
+    }
+}
+
+/// Return the characters from `i` to `j` (inclusive), with `string.sub` semantics
+fn sub(_: &Lua, (text, i, j): (mlua::String, Option<i64>, Option<i64>)) -> Result<String> {
+    let chars = to_chars(&text)?;
+    let start = resolve_index(i.unwrap_or(1), chars.len()).max(1);
+    let end = resolve_index(j.unwrap_or(-1), chars.len()).min(chars.len() as i64);
+    if start > end {
+        return Ok(String::new());
+    }
+    Ok(chars[start as usize - 1..end as usize].iter().collect())
+}
+
+fn upper(_: &Lua, text: mlua::String) -> Result<String> {
+    Ok(to_chars(&text)?
+        .into_iter()
+        .flat_map(char::to_uppercase)
+        .collect())
+}
+
+fn lower(_: &Lua, text: mlua::String) -> Result<String> {
+    Ok(to_chars(&text)?
+        .into_iter()
+        .flat_map(char::to_lowercase)
+        .collect())
+}
+
+fn reverse(_: &Lua, text: mlua::String) -> Result<String> {
+    Ok(to_chars(&text)?.into_iter().rev().collect())
+}
+
+/// Split a string into a table of its characters
+fn chars(lua: &Lua, text: mlua::String) -> Result<Table> {
+    let chars = to_chars(&text)?;
+    lua.create_sequence_from(chars.into_iter().map(String::from))
+}
+
+/// Insert `value` so that it starts at character `i`; `len + 1` appends
+fn insert(_: &Lua, (text, i, value): (mlua::String, i64, mlua::String)) -> Result<String> {
+    let mut chars = to_chars(&text)?;
+    let index = resolve_index(i, chars.len()).clamp(1, chars.len() as i64 + 1) as usize - 1;
+    chars.splice(index..index, to_chars(&value)?);
+    Ok(chars.into_iter().collect())
+}
+
+/// Remove the characters from `i` to `j` (defaults to `i`)
+fn remove(_: &Lua, (text, i, j): (mlua::String, i64, Option<i64>)) -> Result<String> {
+    let mut chars = to_chars(&text)?;
+    let start = resolve_index(i, chars.len()).max(1);
+    let end = resolve_index(j.unwrap_or(i), chars.len()).min(chars.len() as i64);
+    if start <= end {
+        chars.drain(start as usize - 1..end as usize);
+    }
+    Ok(chars.into_iter().collect())
+}
+
+fn valid(_: &Lua, text: mlua::String) -> Result<bool> {
+    Ok(text.to_str().is_ok())
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+
+    // Keep the standard `utf8` functions (char, codepoint, codes, len, ...)
+    if let Some(builtin) = lua.globals().get::<Option<Table>>("utf8")? {
+        for pair in builtin.pairs::<mlua::Value, mlua::Value>() {
+            let (key, value) = pair?;
+            module.set(key, value)?;
+        }
+    }
+
+    module.set("sub", lua.create_function(sub)?)?;
+    module.set("upper", lua.create_function(upper)?)?;
+    module.set("lower", lua.create_function(lower)?)?;
+    module.set("reverse", lua.create_function(reverse)?)?;
+    module.set("chars", lua.create_function(chars)?)?;
+    module.set("insert", lua.create_function(insert)?)?;
+    module.set("remove", lua.create_function(remove)?)?;
+    module.set("valid", lua.create_function(valid)?)?;
+
+    libs.set("utf8", &module)?;
+    lua.register_module("utf8", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf8() {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+
+        lua.load(
+            r#"
+            local utf8 = require("utf8")
+            local text = "Grüße, Мир"
+            assert(utf8.len(text) == 10)
+            assert(utf8.char(72, 228) == "Hä")
+            assert(utf8.sub(text, 1, 5) == "Grüße")
+            assert(utf8.sub(text, -3) == "Мир")
+            assert(utf8.sub(text, 8, 100) == "Мир")
+            assert(utf8.sub(text, 5, 2) == "")
+            assert(utf8.upper(text) == "GRÜSSE, МИР")
+            assert(utf8.lower("ÄÖÜ Мир") == "äöü мир")
+            assert(utf8.reverse("añb") == "bña")
+
+            local chars = utf8.chars("añ€")
+            assert(#chars == 3 and chars[2] == "ñ" and chars[3] == "€")
+
+            assert(utf8.insert("Grße", 3, "ü") == "Grüße")
+            assert(utf8.insert("ab", 3, "ç") == "abç")
+            assert(utf8.remove(text, 6, 7) == "GrüßeМир")
+            assert(utf8.remove("añb", 2) == "ab")
+            assert(utf8.remove("añb", -1) == "añ")
+
+            assert(utf8.valid(text))
+            assert(not utf8.valid("\xff"))
+            local ok, err = pcall(utf8.upper, "\xff")
+            assert(not ok and tostring(err):find("invalid UTF%-8"))
+        "#,
+        )
+        .exec()
+        .unwrap();
+    }
+}
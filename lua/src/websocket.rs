@@ -0,0 +1,623 @@
+//! `libs.websocket`: WebSocket client, permission `websocket`.
+//!
+//! `connect(url, { headers, reconnect = true, backoff = 1, max_backoff = 30,
+//! on_open, on_message, on_close }?)` connects before returning a socket with
+//! `send(text)`, `send_binary(data)`, `connected()` and
+//! `close(code?, reason?)`. Lost connections are reconnected with exponential
+//! backoff unless `reconnect = false`, and open sockets are closed after
+//! `events.destroy`.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use flume::{Receiver, Sender};
+use futures_util::{SinkExt, StreamExt};
+use mlua::{
+    Error, Function, IntoLuaMulti, Lua, RegistryKey, Result, String as LuaString, Table, UserData,
+    UserDataFields, UserDataMethods, WeakLua,
+};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{
+        Message,
+        client::IntoClientRequest,
+        handshake::client::Request,
+        http::{HeaderName, HeaderValue},
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
+};
+
+use crate::{callback::dispatch, subscription::get_subscription_map};
+
+/// Delay before the first reconnection attempt, doubled after each failure
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Close code reported when the connection is lost without a close frame
+const ABNORMAL_CLOSURE: u16 = 1006;
+
+type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+fn websocket_error(error: impl std::fmt::Display) -> Error {
+    Error::runtime(format!("websocket error: {error}"))
+}
+
+enum Command {
+    Send(Message),
+    Close(Option<CloseFrame>),
+}
+
+/// Open connections of the Lua state, closed when the remote is destroyed
+#[derive(Clone, Default)]
+struct Connections(Arc<ConnectionsInner>);
+
+#[derive(Default)]
+struct ConnectionsInner {
+    map: Mutex<HashMap<u64, Sender<Command>>>,
+    counter: AtomicU64,
+}
+
+impl Connections {
+    fn add(&self, commands: Sender<Command>) -> u64 {
+        let id = self.0.counter.fetch_add(1, Ordering::SeqCst) + 1;
+        self.0.map.lock().unwrap().insert(id, commands);
+        id
+    }
+
+    fn remove(&self, id: u64) {
+        self.0.map.lock().unwrap().remove(&id);
+    }
+}
+
+fn get_connections(lua: &Lua) -> Connections {
+    if let Some(connections) = lua.app_data_ref::<Connections>() {
+        return connections.clone();
+    }
+
+    let connections = Connections::default();
+    lua.set_app_data(connections.clone());
+    connections
+}
+
+/// Close all open connections of the Lua state
+pub(crate) fn close_all(lua: &Lua) {
+    let Some(connections) = lua.app_data_ref::<Connections>() else {
+        return;
+    };
+
+    for (id, commands) in connections.0.map.lock().unwrap().drain() {
+        tracing::info!("closing websocket connection {id}");
+        let _ = commands.send(Command::Close(None));
+    }
+}
+
+/// Where to connect to and how to reconnect
+struct Target {
+    url: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    reconnect: bool,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Target {
+    fn from_options(url: String, options: Option<&Table>) -> Result<Self> {
+        let mut target = Self {
+            url,
+            headers: Vec::new(),
+            reconnect: true,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        };
+        let Some(options) = options else {
+            return Ok(target);
+        };
+
+        if let Some(headers) = options.get::<Option<Table>>("headers")? {
+            for pair in headers.pairs::<String, String>() {
+                let (name, value) = pair?;
+                let name = HeaderName::from_bytes(name.as_bytes()).map_err(websocket_error)?;
+                let value = HeaderValue::from_str(&value).map_err(websocket_error)?;
+                target.headers.push((name, value));
+            }
+        }
+        if let Some(reconnect) = options.get::<Option<bool>>("reconnect")? {
+            target.reconnect = reconnect;
+        }
+        if let Some(backoff) = options.get::<Option<f64>>("backoff")? {
+            target.backoff = Duration::try_from_secs_f64(backoff).map_err(websocket_error)?;
+        }
+        if let Some(max_backoff) = options.get::<Option<f64>>("max_backoff")? {
+            target.max_backoff =
+                Duration::try_from_secs_f64(max_backoff).map_err(websocket_error)?;
+        }
+        Ok(target)
+    }
+
+    fn request(&self) -> Result<Request> {
+        let mut request = self
+            .url
+            .as_str()
+            .into_client_request()
+            .map_err(websocket_error)?;
+        for (name, value) in &self.headers {
+            request.headers_mut().insert(name.clone(), value.clone());
+        }
+        Ok(request)
+    }
+
+    async fn connect(&self) -> Result<Stream> {
+        let (stream, _) = connect_async(self.request()?)
+            .await
+            .map_err(websocket_error)?;
+        Ok(stream)
+    }
+}
+
+/// Callbacks given in the options of `libs.websocket.connect`
+struct Callbacks {
+    open: Option<RegistryKey>,
+    message: Option<RegistryKey>,
+    close: Option<RegistryKey>,
+}
+
+impl Callbacks {
+    fn from_options(lua: &Lua, options: Option<&Table>) -> Result<Self> {
+        let callback = |name: &str| -> Result<Option<RegistryKey>> {
+            let Some(options) = options else {
+                return Ok(None);
+            };
+            options
+                .get::<Option<Function>>(name)?
+                .map(|callback| lua.create_registry_value(callback))
+                .transpose()
+        };
+
+        Ok(Self {
+            open: callback("on_open")?,
+            message: callback("on_message")?,
+            close: callback("on_close")?,
+        })
+    }
+
+    /// Queue the callback on the worker with the arguments `args` builds,
+    /// returning `false` once the Lua state is gone
+    async fn call<A: IntoLuaMulti>(
+        weak_lua: &WeakLua,
+        key: Option<&RegistryKey>,
+        event: &str,
+        args: impl FnOnce(&Lua) -> Result<A>,
+    ) -> bool {
+        let Some(lua) = weak_lua.try_upgrade() else {
+            return false;
+        };
+        let Some(key) = key else {
+            return true;
+        };
+
+        let name = format!("websocket {event} callback");
+        let result = async {
+            let callback = lua.registry_value::<Function>(key)?;
+            dispatch(&lua, &name, callback, args(&lua)?).await
+        }
+        .await;
+        if let Err(error) = result {
+            tracing::error!("websocket {event} callback error: {error}");
+        }
+        true
+    }
+
+    fn remove(self, weak_lua: &WeakLua) {
+        if let Some(lua) = weak_lua.try_upgrade() {
+            for key in [self.open, self.message, self.close].into_iter().flatten() {
+                let _ = lua.remove_registry_value(key);
+            }
+        }
+    }
+}
+
+/// How a connection ended
+enum Closed {
+    /// Closed by the script or the remote's lifecycle
+    Locally,
+    /// Closed by the server or lost, with the close code and reason
+    Remotely(u16, String),
+}
+
+/// Forward commands to the server and messages to the callbacks until the
+/// connection is closed
+async fn session(
+    stream: &mut Stream,
+    commands: &Receiver<Command>,
+    callbacks: &Callbacks,
+    weak_lua: &WeakLua,
+) -> Closed {
+    let mut close_frame = None;
+    loop {
+        tokio::select! {
+            command = commands.recv_async() => match command {
+                Ok(Command::Send(message)) => {
+                    if let Err(error) = stream.send(message).await {
+                        return Closed::Remotely(ABNORMAL_CLOSURE, error.to_string());
+                    }
+                }
+                Ok(Command::Close(frame)) => {
+                    let _ = stream.close(frame).await;
+                    return Closed::Locally;
+                }
+                Err(_) => {
+                    let _ = stream.close(None).await;
+                    return Closed::Locally;
+                }
+            },
+            message = stream.next() => {
+                let alive = match message {
+                    Some(Ok(Message::Text(text))) => {
+                        Callbacks::call(weak_lua, callbacks.message.as_ref(), "message", |_| {
+                            Ok((text.as_str().to_string(), false))
+                        })
+                        .await
+                    }
+                    Some(Ok(Message::Binary(data))) => {
+                        Callbacks::call(weak_lua, callbacks.message.as_ref(), "message", |lua| {
+                            Ok((lua.create_string(&data)?, true))
+                        })
+                        .await
+                    }
+                    // The reply is sent while reading on until the stream ends
+                    Some(Ok(Message::Close(frame))) => {
+                        close_frame = Some(match frame {
+                            Some(frame) => (u16::from(frame.code), frame.reason.to_string()),
+                            None => (u16::from(CloseCode::Status), String::new()),
+                        });
+                        true
+                    }
+                    Some(Ok(_)) => true,
+                    Some(Err(error)) => {
+                        let (code, reason) =
+                            close_frame.unwrap_or((ABNORMAL_CLOSURE, error.to_string()));
+                        return Closed::Remotely(code, reason);
+                    }
+                    None => {
+                        let (code, reason) =
+                            close_frame.unwrap_or((ABNORMAL_CLOSURE, String::new()));
+                        return Closed::Remotely(code, reason);
+                    }
+                };
+                if !alive {
+                    return Closed::Locally;
+                }
+            }
+        }
+    }
+}
+
+/// Reconnect with exponential backoff, giving up if the connection is closed
+/// in the meantime
+async fn reconnect(target: &Target, commands: &Receiver<Command>) -> Option<Stream> {
+    let mut backoff = target.backoff;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            command = commands.recv_async() => match command {
+                Ok(Command::Send(_)) => {
+                    tracing::warn!("dropping websocket message to {} while reconnecting", target.url);
+                    continue;
+                }
+                Ok(Command::Close(_)) | Err(_) => return None,
+            },
+        }
+
+        match target.connect().await {
+            Ok(stream) => return Some(stream),
+            Err(error) => {
+                tracing::warn!("failed to reconnect to {}: {error}", target.url);
+                backoff = (backoff * 2).min(target.max_backoff);
+            }
+        }
+    }
+}
+
+/// State shared between a connection's handle and its task
+struct Shared {
+    connected: AtomicBool,
+}
+
+async fn run(
+    mut stream: Stream,
+    target: Target,
+    shared: Arc<Shared>,
+    commands: Receiver<Command>,
+    callbacks: Callbacks,
+    weak_lua: WeakLua,
+) {
+    loop {
+        shared.connected.store(true, Ordering::SeqCst);
+        if !Callbacks::call(&weak_lua, callbacks.open.as_ref(), "open", |_| Ok(())).await {
+            break;
+        }
+
+        let closed = session(&mut stream, &commands, &callbacks, &weak_lua).await;
+        shared.connected.store(false, Ordering::SeqCst);
+        let Closed::Remotely(code, reason) = closed else {
+            break;
+        };
+
+        tracing::info!(
+            "websocket connection to {} closed: {code} {reason}",
+            target.url
+        );
+        if !Callbacks::call(&weak_lua, callbacks.close.as_ref(), "close", |_| {
+            Ok((code, reason))
+        })
+        .await
+            || !target.reconnect
+        {
+            break;
+        }
+
+        match reconnect(&target, &commands).await {
+            Some(reconnected) => stream = reconnected,
+            None => break,
+        }
+    }
+
+    callbacks.remove(&weak_lua);
+}
+
+/// Handle of a connection returned by `libs.websocket.connect`
+struct WebSocket {
+    id: u64,
+    shared: Arc<Shared>,
+    commands: Sender<Command>,
+    connections: Connections,
+}
+
+impl WebSocket {
+    fn send(&self, message: Message) -> Result<()> {
+        if !self.shared.connected.load(Ordering::SeqCst) {
+            return Err(Error::runtime("websocket is not connected"));
+        }
+        self.commands
+            .send(Command::Send(message))
+            .map_err(|_| Error::runtime("websocket is closed"))
+    }
+}
+
+impl UserData for WebSocket {
+    fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("id", |_, this| Ok(this.id));
+    }
+
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("send", |_, this, text: String| {
+            this.send(Message::text(text))
+        });
+
+        methods.add_method("send_binary", |_, this, data: LuaString| {
+            this.send(Message::binary(data.as_bytes().to_vec()))
+        });
+
+        methods.add_method("connected", |_, this, ()| {
+            Ok(this.shared.connected.load(Ordering::SeqCst))
+        });
+
+        methods.add_method(
+            "close",
+            |_, this, (code, reason): (Option<u16>, Option<String>)| {
+                let frame = CloseFrame {
+                    code: CloseCode::from(code.unwrap_or(u16::from(CloseCode::Normal))),
+                    reason: reason.unwrap_or_default().into(),
+                };
+                this.connections.remove(this.id);
+                this.shared.connected.store(false, Ordering::SeqCst);
+                let _ = this.commands.send(Command::Close(Some(frame)));
+                Ok(())
+            },
+        );
+    }
+}
+
+async fn connect(lua: Lua, (url, options): (String, Option<Table>)) -> Result<WebSocket> {
+    let target = Target::from_options(url, options.as_ref())?;
+    let stream = target.connect().await?;
+    let callbacks = Callbacks::from_options(&lua, options.as_ref())?;
+
+    let (commands, receiver) = flume::unbounded();
+    let connections = get_connections(&lua);
+    let id = connections.add(commands.clone());
+    let shared = Arc::new(Shared {
+        connected: AtomicBool::new(true),
+    });
+
+    tracing::info!("connected websocket {id} to {}", target.url);
+    let weak_lua = lua.weak();
+    let task_shared = shared.clone();
+    let task_connections = connections.clone();
+    get_subscription_map(&lua).add(async move {
+        run(stream, target, task_shared, receiver, callbacks, weak_lua).await;
+        task_connections.remove(id);
+    });
+
+    Ok(WebSocket {
+        id,
+        shared,
+        commands,
+        connections,
+    })
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("connect", lua.create_async_function(connect)?)?;
+
+    libs.set("websocket", &module)?;
+    lua.register_module("websocket", module)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{
+        accept_async, accept_hdr_async,
+        tungstenite::handshake::server::{
+            ErrorResponse, Request as ServerRequest, Response as ServerResponse,
+        },
+    };
+
+    use super::*;
+
+    fn create_lua() -> Lua {
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua
+    }
+
+    async fn wait_for(lua: &Lua, condition: &str) -> bool {
+        for _ in 0..100 {
+            if lua.load(condition).eval::<bool>().unwrap() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn check_token(
+        request: &ServerRequest,
+        response: ServerResponse,
+    ) -> std::result::Result<ServerResponse, ErrorResponse> {
+        assert_eq!(request.headers()["Authorization"], "Bearer secret");
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Echo server checking the token, which drops the first connection
+        // after the first message to make the client reconnect
+        let server = tokio::spawn(async move {
+            for connection in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut stream = accept_hdr_async(socket, check_token).await.unwrap();
+
+                while let Some(message) = stream.next().await {
+                    let message = message.unwrap();
+                    if message.is_close() {
+                        break;
+                    }
+                    stream.send(message).await.unwrap();
+                    if connection == 0 {
+                        stream
+                            .close(Some(CloseFrame {
+                                code: CloseCode::Away,
+                                reason: "restarting".into(),
+                            }))
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+        });
+
+        let lua = create_lua();
+        lua.globals().set("port", port).unwrap();
+        lua.load(
+            r#"
+            opened, messages, closed = 0, {}, nil
+            socket = libs.websocket.connect("ws://127.0.0.1:" .. port, {
+                headers = { Authorization = "Bearer secret" },
+                backoff = 0.05,
+                on_open = function() opened = opened + 1 end,
+                on_message = function(data, binary)
+                    table.insert(messages, { data = data, binary = binary })
+                end,
+                on_close = function(code, reason) closed = { code = code, reason = reason } end,
+            })
+            socket:send("hello")
+        "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        assert!(wait_for(&lua, "return opened == 2 and socket:connected()").await);
+        lua.load(
+            r#"
+            assert(messages[1].data == "hello" and not messages[1].binary)
+            assert(closed.code == 1001 and closed.reason == "restarting")
+            socket:send_binary("\0\1\2")
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        assert!(wait_for(&lua, "return #messages == 2").await);
+        lua.load(
+            r#"
+            assert(messages[2].data == "\0\1\2" and messages[2].binary)
+            socket:close()
+            assert(not socket:connected())
+            assert(not pcall(socket.send, socket, "closed"))
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_all() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = accept_async(socket).await.unwrap();
+            stream.next().await.unwrap().unwrap()
+        });
+
+        let lua = create_lua();
+        lua.load(format!(
+            r#"socket = libs.websocket.connect("ws://127.0.0.1:{port}")"#
+        ))
+        .exec_async()
+        .await
+        .unwrap();
+
+        close_all(&lua);
+        let message = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(message.is_close());
+        assert!(wait_for(&lua, "return not socket:connected()").await);
+    }
+
+    #[tokio::test]
+    async fn test_connect_error() {
+        let lua = create_lua();
+        let error = lua
+            .load(r#"libs.websocket.connect("ws://127.0.0.1:1")"#)
+            .exec_async()
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("websocket error"), "{error}");
+    }
+}
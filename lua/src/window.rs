@@ -0,0 +1,671 @@
+//! `libs.window`: X11 window management through EWMH, permission `window`.
+//!
+//! `list()` and `active()` return `{ id, title, class, instance, pid,
+//! desktop, x, y, width, height, active, minimized, maximized }`. `focus`,
+//! `minimize`, `maximize(id, enable?)`, `close` and
+//! `move(id, { x, y, width, height })` only accept managed windows.
+//! `events.windowchanged(window)` fires when the active window changes.
+
+use std::{os::fd::AsRawFd, sync::Arc};
+
+use mlua::{Error, Function, Lua, LuaSerdeExt, Result, Table, Value};
+use serde::Serialize;
+use tokio::io::unix::AsyncFd;
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        Event,
+        xproto::{
+            AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConnectionExt, EventMask,
+            Window,
+        },
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{callback::dispatch, subscription::get_subscription_map};
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        UTF8_STRING,
+        WM_CHANGE_STATE,
+        _NET_CLIENT_LIST,
+        _NET_ACTIVE_WINDOW,
+        _NET_CLOSE_WINDOW,
+        _NET_MOVERESIZE_WINDOW,
+        _NET_WM_NAME,
+        _NET_WM_PID,
+        _NET_WM_DESKTOP,
+        _NET_WM_STATE,
+        _NET_WM_STATE_HIDDEN,
+        _NET_WM_STATE_MAXIMIZED_VERT,
+        _NET_WM_STATE_MAXIMIZED_HORZ,
+    }
+}
+
+/// `_NET_WM_DESKTOP` of windows shown on all desktops
+const ALL_DESKTOPS: u32 = 0xFFFFFFFF;
+
+/// `WM_CHANGE_STATE` value asking the window manager to iconify a window
+const ICONIC_STATE: u32 = 3;
+
+/// Source indication of requests sent on behalf of the user, like pagers do
+const SOURCE_PAGER: u32 = 2;
+
+/// `_NET_WM_STATE` actions
+const STATE_REMOVE: u32 = 0;
+const STATE_ADD: u32 = 1;
+
+/// Window as returned to Lua
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub title: String,
+    pub class: String,
+    pub instance: String,
+    pub pid: Option<u32>,
+    /// Desktop index, `-1` for windows shown on all desktops
+    pub desktop: Option<i64>,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub active: bool,
+    pub minimized: bool,
+    pub maximized: bool,
+}
+
+/// Connection to an X server managed by an EWMH compliant window manager
+struct Ewmh {
+    conn: RustConnection,
+    root: Window,
+    atoms: Atoms,
+}
+
+fn x11_error(error: impl std::fmt::Display) -> Error {
+    Error::runtime(format!("x11 error: {error}"))
+}
+
+/// Split `WM_CLASS` into its instance and class names
+fn parse_wm_class(value: &[u8]) -> (String, String) {
+    let mut parts = value
+        .split(|&byte| byte == 0)
+        .map(|part| String::from_utf8_lossy(part).into_owned());
+    let instance = parts.next().unwrap_or_default();
+    let class = parts.next().unwrap_or_default();
+    (instance, class)
+}
+
+fn desktop_index(desktop: u32) -> i64 {
+    if desktop == ALL_DESKTOPS {
+        -1
+    } else {
+        desktop as i64
+    }
+}
+
+impl Ewmh {
+    fn connect(display: Option<&str>) -> Result<Self> {
+        let (conn, screen) = x11rb::connect(display).map_err(x11_error)?;
+        let root = conn.setup().roots[screen].root;
+        let atoms = Atoms::new(&conn)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+        Ok(Self { conn, root, atoms })
+    }
+
+    fn property(&self, window: Window, property: u32, kind: impl Into<u32>) -> Result<Vec<u8>> {
+        let reply = self
+            .conn
+            .get_property(false, window, property, kind, 0, u32::MAX)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+        Ok(reply.value)
+    }
+
+    fn property32(&self, window: Window, property: u32) -> Result<Vec<u32>> {
+        let reply = self
+            .conn
+            .get_property(false, window, property, AtomEnum::ANY, 0, u32::MAX)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+        Ok(reply
+            .value32()
+            .map(|values| values.collect())
+            .unwrap_or_default())
+    }
+
+    fn client_list(&self) -> Result<Vec<Window>> {
+        self.property32(self.root, self.atoms._NET_CLIENT_LIST)
+    }
+
+    fn active_window(&self) -> Result<Option<Window>> {
+        let active = self.property32(self.root, self.atoms._NET_ACTIVE_WINDOW)?;
+        Ok(active.first().copied().filter(|&window| window != 0))
+    }
+
+    fn title(&self, window: Window) -> Result<String> {
+        let mut title = self.property(window, self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING)?;
+        if title.is_empty() {
+            title = self.property(window, AtomEnum::WM_NAME.into(), AtomEnum::STRING)?;
+        }
+        Ok(String::from_utf8_lossy(&title).into_owned())
+    }
+
+    fn info(&self, window: Window, active: Option<Window>) -> Result<WindowInfo> {
+        let title = self.title(window)?;
+        let (instance, class) =
+            parse_wm_class(&self.property(window, AtomEnum::WM_CLASS.into(), AtomEnum::STRING)?);
+        let pid = self
+            .property32(window, self.atoms._NET_WM_PID)?
+            .first()
+            .copied();
+        let desktop = self
+            .property32(window, self.atoms._NET_WM_DESKTOP)?
+            .first()
+            .copied()
+            .map(desktop_index);
+        let state = self.property32(window, self.atoms._NET_WM_STATE)?;
+
+        let geometry = self
+            .conn
+            .get_geometry(window)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+        // Window managers reparent windows into frames, so the position of the
+        // geometry is relative to the frame rather than the root window
+        let position = self
+            .conn
+            .translate_coordinates(window, self.root, 0, 0)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+
+        Ok(WindowInfo {
+            id: window,
+            title,
+            class,
+            instance,
+            pid,
+            desktop,
+            x: position.dst_x.into(),
+            y: position.dst_y.into(),
+            width: geometry.width.into(),
+            height: geometry.height.into(),
+            active: active == Some(window),
+            minimized: state.contains(&self.atoms._NET_WM_STATE_HIDDEN),
+            maximized: state.contains(&self.atoms._NET_WM_STATE_MAXIMIZED_VERT)
+                && state.contains(&self.atoms._NET_WM_STATE_MAXIMIZED_HORZ),
+        })
+    }
+
+    fn windows(&self) -> Result<Vec<WindowInfo>> {
+        let active = self.active_window()?;
+        self.client_list()?
+            .into_iter()
+            .map(|window| self.info(window, active))
+            .collect()
+    }
+
+    /// Ask the window manager to change a window, as described by EWMH
+    fn request(&self, window: Window, kind: u32, data: [u32; 5]) -> Result<()> {
+        let event = ClientMessageEvent::new(32, window, kind, data);
+        self.conn
+            .send_event(
+                false,
+                self.root,
+                EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
+                event,
+            )
+            .map_err(x11_error)?;
+        self.conn.flush().map_err(x11_error)
+    }
+
+    /// Make sure a window is managed, so requests can't target arbitrary
+    /// windows of other clients
+    fn managed(&self, window: Window) -> Result<Window> {
+        if self.client_list()?.contains(&window) {
+            Ok(window)
+        } else {
+            Err(Error::runtime(format!("window {window} not found")))
+        }
+    }
+}
+
+fn get_ewmh(lua: &Lua) -> Result<Arc<Ewmh>> {
+    if let Some(ewmh) = lua.app_data_ref::<Arc<Ewmh>>() {
+        return Ok(ewmh.clone());
+    }
+
+    let ewmh = Arc::new(Ewmh::connect(None)?);
+    lua.set_app_data(ewmh.clone());
+    Ok(ewmh)
+}
+
+fn list(lua: &Lua, (): ()) -> Result<Value> {
+    lua.to_value(&get_ewmh(lua)?.windows()?)
+}
+
+fn active(lua: &Lua, (): ()) -> Result<Value> {
+    let ewmh = get_ewmh(lua)?;
+    match ewmh.active_window()? {
+        Some(window) => lua.to_value(&ewmh.info(window, Some(window))?),
+        None => Ok(Value::Nil),
+    }
+}
+
+fn focus(lua: &Lua, window: Window) -> Result<()> {
+    let ewmh = get_ewmh(lua)?;
+    let window = ewmh.managed(window)?;
+    let current = ewmh.active_window()?.unwrap_or_default();
+    ewmh.request(
+        window,
+        ewmh.atoms._NET_ACTIVE_WINDOW,
+        [SOURCE_PAGER, x11rb::CURRENT_TIME, current, 0, 0],
+    )
+}
+
+fn minimize(lua: &Lua, window: Window) -> Result<()> {
+    let ewmh = get_ewmh(lua)?;
+    let window = ewmh.managed(window)?;
+    ewmh.request(
+        window,
+        ewmh.atoms.WM_CHANGE_STATE,
+        [ICONIC_STATE, 0, 0, 0, 0],
+    )
+}
+
+fn maximize(lua: &Lua, (window, enable): (Window, Option<bool>)) -> Result<()> {
+    let ewmh = get_ewmh(lua)?;
+    let window = ewmh.managed(window)?;
+    let action = if enable.unwrap_or(true) {
+        STATE_ADD
+    } else {
+        STATE_REMOVE
+    };
+    ewmh.request(
+        window,
+        ewmh.atoms._NET_WM_STATE,
+        [
+            action,
+            ewmh.atoms._NET_WM_STATE_MAXIMIZED_VERT,
+            ewmh.atoms._NET_WM_STATE_MAXIMIZED_HORZ,
+            SOURCE_PAGER,
+            0,
+        ],
+    )
+}
+
+fn close(lua: &Lua, window: Window) -> Result<()> {
+    let ewmh = get_ewmh(lua)?;
+    let window = ewmh.managed(window)?;
+    ewmh.request(
+        window,
+        ewmh.atoms._NET_CLOSE_WINDOW,
+        [x11rb::CURRENT_TIME, SOURCE_PAGER, 0, 0, 0],
+    )
+}
+
+/// Move and resize a window, leaving out fields keeps their current value
+fn move_window(lua: &Lua, (window, geometry): (Window, Table)) -> Result<()> {
+    let ewmh = get_ewmh(lua)?;
+    let window = ewmh.managed(window)?;
+
+    // Bits 8 to 11 tell which of x, y, width and height are set
+    let mut flags = SOURCE_PAGER << 12;
+    let mut data = [0; 5];
+    for (index, field) in ["x", "y", "width", "height"].into_iter().enumerate() {
+        if let Some(value) = geometry.get::<Option<i32>>(field)? {
+            flags |= 1 << (8 + index);
+            data[index + 1] = value as u32;
+        }
+    }
+    // Keep the window's own gravity
+    data[0] = flags;
+
+    ewmh.request(window, ewmh.atoms._NET_MOVERESIZE_WINDOW, data)
+}
+
+/// Call `events.windowchanged` with the new active window, or `nil`,
+/// whenever the active window changes.
+///
+/// Uses its own connection as it has to wait for events of the root window.
+fn watch_active_window(lua: &Lua, display: Option<&str>) -> Result<()> {
+    let ewmh = Ewmh::connect(display)?;
+    ewmh.conn
+        .change_window_attributes(
+            ewmh.root,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+        )
+        .map_err(x11_error)?;
+    ewmh.conn.flush().map_err(x11_error)?;
+
+    let fd = AsyncFd::new(ewmh.conn.stream().as_raw_fd()).map_err(x11_error)?;
+    let mut current = ewmh.active_window()?;
+    let ewmh = Arc::new(ewmh);
+
+    // Create a weak reference to Lua for safe cross-thread access
+    let weak_lua = lua.weak();
+
+    get_subscription_map(lua).add(async move {
+        loop {
+            // Drain events read along with replies before waiting for more
+            let mut changed = false;
+            loop {
+                match ewmh.conn.poll_for_event() {
+                    Ok(Some(Event::PropertyNotify(event)))
+                        if event.atom == ewmh.atoms._NET_ACTIVE_WINDOW =>
+                    {
+                        changed = true;
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => break,
+                    Err(error) => {
+                        tracing::error!("lost connection to the X server: {error}");
+                        return;
+                    }
+                }
+            }
+
+            if changed {
+                // Property requests wait for the X server, keep them off the
+                // runtime threads
+                let result = tokio::task::spawn_blocking({
+                    let ewmh = ewmh.clone();
+                    move || active_window_info(&ewmh)
+                })
+                .await;
+                let (active, info) = match result {
+                    Ok(Ok(active)) => active,
+                    Ok(Err(error)) => {
+                        tracing::error!("failed to get active window: {error}");
+                        return;
+                    }
+                    Err(error) => {
+                        tracing::error!("failed to get active window: {error}");
+                        return;
+                    }
+                };
+
+                if active != current {
+                    current = active;
+                    let Some(lua) = weak_lua.try_upgrade() else {
+                        return;
+                    };
+                    if let Err(error) = trigger_window_changed(&lua, info).await {
+                        tracing::error!("windowchanged event handler error: {error}");
+                    }
+                }
+                // Replies may have come with more events
+                continue;
+            }
+
+            match fd.readable().await {
+                Ok(mut guard) => guard.clear_ready(),
+                Err(error) => {
+                    tracing::error!("failed to wait for X events: {error}");
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// The active window along with its info
+fn active_window_info(ewmh: &Ewmh) -> Result<(Option<Window>, Option<WindowInfo>)> {
+    let active = ewmh.active_window()?;
+    let info = active.and_then(|window| ewmh.info(window, active).ok());
+    Ok((active, info))
+}
+
+async fn trigger_window_changed(lua: &Lua, window: Option<WindowInfo>) -> Result<()> {
+    let Ok(events) = lua.globals().get::<Table>("events") else {
+        return Ok(());
+    };
+    let Some(handler) = events.get::<Option<Function>>("windowchanged")? else {
+        return Ok(());
+    };
+    dispatch(
+        lua,
+        "windowchanged event handler",
+        handler,
+        lua.to_value(&window)?,
+    )
+    .await
+}
+
+pub fn load(lua: &Lua, libs: &Table) -> anyhow::Result<()> {
+    let module = lua.create_table()?;
+    module.set("list", lua.create_function(list)?)?;
+    module.set("active", lua.create_function(active)?)?;
+    module.set("focus", lua.create_function(focus)?)?;
+    module.set("minimize", lua.create_function(minimize)?)?;
+    module.set("maximize", lua.create_function(maximize)?)?;
+    module.set("close", lua.create_function(close)?)?;
+    module.set("move", lua.create_function(move_window)?)?;
+
+    libs.set("window", &module)?;
+    lua.register_module("window", module)?;
+
+    // Without a display there are no window changes to report
+    if std::env::var_os("DISPLAY").is_some()
+        && tokio::runtime::Handle::try_current().is_ok()
+        && let Err(error) = watch_active_window(lua, None)
+    {
+        tracing::warn!("failed to watch active window: {error}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use x11rb::{
+        COPY_DEPTH_FROM_PARENT,
+        protocol::xproto::{CreateWindowAux, PropMode, WindowClass},
+        wrapper::ConnectionExt as _,
+    };
+
+    use super::*;
+
+    /// Private X server, stopped when dropped
+    struct TestDisplay {
+        server: Child,
+        name: String,
+    }
+
+    impl TestDisplay {
+        fn start() -> Option<Self> {
+            let number = 90 + std::process::id() % 100;
+            let name = format!(":{number}");
+            let server = Command::new("Xvfb")
+                .args([name.as_str(), "-nolisten", "tcp"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+
+            let socket = format!("/tmp/.X11-unix/X{number}");
+            for _ in 0..50 {
+                if Path::new(&socket).exists() {
+                    return Some(Self { server, name });
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            None
+        }
+    }
+
+    impl Drop for TestDisplay {
+        fn drop(&mut self) {
+            let _ = self.server.kill();
+            let _ = self.server.wait();
+        }
+    }
+
+    #[test]
+    fn test_parse_wm_class() {
+        assert_eq!(
+            parse_wm_class(b"xterm\0XTerm\0"),
+            ("xterm".to_string(), "XTerm".to_string())
+        );
+        assert_eq!(parse_wm_class(b""), (String::new(), String::new()));
+        assert_eq!(desktop_index(2), 2);
+        assert_eq!(desktop_index(ALL_DESKTOPS), -1);
+    }
+
+    #[tokio::test]
+    async fn test_window_management() {
+        let Some(display) = TestDisplay::start() else {
+            eprintln!("Xvfb is not available, skipping");
+            return;
+        };
+
+        // Play the window manager: create a client window and publish it
+        let wm = Ewmh::connect(Some(&display.name)).unwrap();
+        let window = wm.conn.generate_id().unwrap();
+        wm.conn
+            .create_window(
+                COPY_DEPTH_FROM_PARENT,
+                window,
+                wm.root,
+                10,
+                20,
+                300,
+                200,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                0,
+                &CreateWindowAux::new(),
+            )
+            .unwrap();
+        let utf8 = wm.atoms.UTF8_STRING;
+        wm.conn
+            .change_property8(
+                PropMode::REPLACE,
+                window,
+                wm.atoms._NET_WM_NAME,
+                utf8,
+                "Slides".as_bytes(),
+            )
+            .unwrap();
+        wm.conn
+            .change_property8(
+                PropMode::REPLACE,
+                window,
+                AtomEnum::WM_CLASS,
+                AtomEnum::STRING,
+                b"impress\0LibreOffice\0",
+            )
+            .unwrap();
+        wm.conn
+            .change_property32(
+                PropMode::REPLACE,
+                window,
+                wm.atoms._NET_WM_PID,
+                AtomEnum::CARDINAL,
+                &[42],
+            )
+            .unwrap();
+        wm.conn
+            .change_property32(
+                PropMode::REPLACE,
+                window,
+                wm.atoms._NET_WM_DESKTOP,
+                AtomEnum::CARDINAL,
+                &[1],
+            )
+            .unwrap();
+        wm.conn
+            .change_property32(
+                PropMode::REPLACE,
+                wm.root,
+                wm.atoms._NET_CLIENT_LIST,
+                AtomEnum::WINDOW,
+                &[window],
+            )
+            .unwrap();
+        wm.conn
+            .change_window_attributes(
+                wm.root,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::SUBSTRUCTURE_REDIRECT),
+            )
+            .unwrap();
+        wm.conn.sync().unwrap();
+
+        let lua = Lua::new();
+        let libs = lua.create_table().unwrap();
+        load(&lua, &libs).unwrap();
+        lua.set_app_data(Arc::new(Ewmh::connect(Some(&display.name)).unwrap()));
+        watch_active_window(&lua, Some(&display.name)).unwrap();
+        lua.globals().set("libs", libs).unwrap();
+        lua.globals().set("window_id", window).unwrap();
+
+        lua.load(
+            r#"
+            events = {
+                windowchanged = function(window) changed = window and window.title end,
+            }
+
+            assert(libs.window.active() == nil)
+            local windows = libs.window.list()
+            assert(#windows == 1)
+            local slides = windows[1]
+            assert(slides.id == window_id)
+            assert(slides.title == "Slides")
+            assert(slides.class == "LibreOffice" and slides.instance == "impress")
+            assert(slides.pid == 42 and slides.desktop == 1)
+            assert(slides.x == 10 and slides.y == 20)
+            assert(slides.width == 300 and slides.height == 200)
+
+            libs.window.focus(window_id)
+            assert(not pcall(libs.window.focus, 1))
+        "#,
+        )
+        .exec()
+        .unwrap();
+
+        // The focus request reaches the window manager
+        let event = wm.conn.wait_for_event().unwrap();
+        match event {
+            Event::ClientMessage(event) => {
+                assert_eq!(event.type_, wm.atoms._NET_ACTIVE_WINDOW);
+                assert_eq!(event.window, window);
+            }
+            event => panic!("unexpected event {event:?}"),
+        }
+
+        // Activating the window triggers the event handler
+        wm.conn
+            .change_property32(
+                PropMode::REPLACE,
+                wm.root,
+                wm.atoms._NET_ACTIVE_WINDOW,
+                AtomEnum::WINDOW,
+                &[window],
+            )
+            .unwrap();
+        wm.conn.sync().unwrap();
+
+        let mut changed = None;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            changed = lua.globals().get::<Option<String>>("changed").unwrap();
+            if changed.is_some() {
+                break;
+            }
+        }
+        assert_eq!(changed.as_deref(), Some("Slides"));
+    }
+}
@@ -88,11 +88,91 @@ function handleServerMessage(message) {
         case 'error':
             showNotification('Error', message.message);
             break;
+        case 'notification':
+            showNotification(message.summary || message.app, message.body);
+            break;
+        case 'confirm':
+            handleConfirmMessage(message);
+            break;
+        case 'clipboard':
+            handleClipboardMessage(message);
+            break;
         default:
             console.warn('Unknown message type:', message.type);
     }
 }
 
+// Ask the user to confirm an action (e.g., {"type":"confirm","nonce":"...","message":"Reboot?"})
+function handleConfirmMessage(message) {
+    const confirmed = window.confirm(message.message);
+    if (ws && ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify({ type: 'confirm', nonce: message.nonce, confirmed: confirmed }));
+    }
+}
+
+// Put the desktop clipboard text on this device (e.g., {"type":"clipboard","text":"..."})
+function handleClipboardMessage(message) {
+    document.dispatchEvent(new CustomEvent('uniremote:clipboard', { detail: message.text }));
+
+    // The clipboard API is only available in secure contexts
+    if (navigator.clipboard && window.isSecureContext) {
+        navigator.clipboard.writeText(message.text)
+            .then(() => showNotification('Clipboard', 'Copied desktop clipboard', 2000))
+            .catch(() => showNotification('Clipboard', message.text));
+    } else {
+        showNotification('Clipboard', message.text);
+    }
+}
+
+// Replace the desktop clipboard text, reading this device's clipboard or
+// asking for the text if no text is given
+async function pushClipboard(text) {
+    if (text === undefined && navigator.clipboard && window.isSecureContext) {
+        try {
+            text = await navigator.clipboard.readText();
+        } catch (e) {
+            console.warn('Failed to read clipboard:', e);
+        }
+    }
+    if (text === undefined) {
+        text = window.prompt('Text for the desktop clipboard');
+    }
+    if (typeof text !== 'string' || !ws || ws.readyState !== WebSocket.OPEN) {
+        showNotification('Clipboard', 'Nothing to send to the desktop clipboard');
+        return;
+    }
+    ws.send(JSON.stringify({ type: 'clipboard_set', text: text }));
+    showNotification('Clipboard', 'Sent to desktop clipboard', 2000);
+}
+
+// Request the desktop clipboard text, answered with a 'clipboard' message
+function pullClipboard() {
+    if (ws && ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify({ type: 'clipboard_get' }));
+    } else {
+        showNotification('Clipboard', 'Not connected to the server');
+    }
+}
+
+// Attach the clipboard sync controls, shown when the server syncs the clipboard
+function initializeClipboard() {
+    const push = document.getElementById('clipboard-push');
+    if (push) {
+        push.addEventListener('click', (e) => {
+            e.preventDefault();
+            pushClipboard();
+        });
+    }
+
+    const pull = document.getElementById('clipboard-pull');
+    if (pull) {
+        pull.addEventListener('click', (e) => {
+            e.preventDefault();
+            pullClipboard();
+        });
+    }
+}
+
 // Handle update messages (e.g., {"type":"update","action":"update","args":{"id":"widget-id","text":"new text"}})
 function handleUpdateMessage(message) {
     const args = message.args || {};
@@ -119,7 +199,23 @@ function handleUpdateMessage(message) {
             if (args.checked !== undefined && element.type === 'checkbox') {
                 element.checked = args.checked;
             }
-            
+
+            // Update image source if provided (for images)
+            if (args.image !== undefined && element.tagName === 'IMG') {
+                element.src = args.image;
+            }
+
+            // Update progress if provided (for sliders)
+            const range = element.type === 'range' ? element : element.querySelector('input[type="range"]');
+            if (range) {
+                if (args.progressmax !== undefined) {
+                    range.max = args.progressmax;
+                }
+                if (args.progress !== undefined) {
+                    range.value = args.progress;
+                }
+            }
+
             console.log(`Updated element ${args.id}`);
         } else {
             console.warn(`Element with id '${args.id}' not found`);
@@ -421,6 +517,7 @@ function initializeRemote() {
 // Initialize application
 function initialize() {
     initializeRemote();
+    initializeClipboard();
     connectWebSocket();
 }
 
@@ -25,6 +25,19 @@ fn default_remotes_dir() -> PathBuf {
     dir.canonicalize().unwrap_or(dir)
 }
 
+fn default_data_dir() -> PathBuf {
+    xdg::BaseDirectories::with_prefix("uniremote")
+        .get_data_home()
+        .expect("missing data directory")
+        .join("remotes")
+}
+
+fn default_secrets_dir() -> PathBuf {
+    xdg::BaseDirectories::with_prefix("uniremote")
+        .get_config_home()
+        .expect("missing config directory")
+}
+
 fn canonicalize_path(path: &str) -> Result<PathBuf, String> {
     Path::new(path)
         .canonicalize()
@@ -58,6 +71,20 @@ pub struct Args {
     #[arg(long, default_value_os_t = default_remotes_dir(), value_parser = canonicalize_path)]
     pub remotes: PathBuf,
 
+    /// Directory for per-remote private data
+    ///
+    /// If not specified, uses XDG data directory
+    /// (~/.local/share/uniremote/remotes)
+    #[arg(long, default_value_os_t = default_data_dir())]
+    pub data: PathBuf,
+
+    /// Directory for the encrypted secret store
+    ///
+    /// If not specified, uses XDG config directory
+    /// (~/.config/uniremote)
+    #[arg(long, default_value_os_t = default_secrets_dir())]
+    pub secrets: PathBuf,
+
     /// Maximum memory (in MB) that Lua scripts can use
     ///
     /// Default: 10 MB
@@ -69,6 +96,18 @@ pub struct Args {
     /// Default: 1,000,000 instructions
     #[arg(long, default_value_t = 1_000_000)]
     pub lua_max_instructions: u64,
+
+    /// Show desktop notifications of this machine on connected clients
+    ///
+    /// Monitors `org.freedesktop.Notifications` on the session bus.
+    #[arg(long)]
+    pub mirror_notifications: bool,
+
+    /// Let clients read and replace the text on the desktop clipboard
+    ///
+    /// Uses wl-clipboard on Wayland and xclip on X11.
+    #[arg(long)]
+    pub clipboard_sync: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -2,7 +2,7 @@ use axum::{
     Json,
     body::Body,
     extract::{Path, State},
-    http::StatusCode,
+    http::{StatusCode, header},
     response::{Html, IntoResponse, Redirect, Response},
 };
 use axum_extra::{
@@ -16,7 +16,7 @@ use mediatype::{
 };
 use tokio::fs::File;
 use tokio_util::io::ReaderStream;
-use uniremote_core::{CallActionRequest, RemoteId};
+use uniremote_core::{CallActionRequest, Remote, RemoteId};
 use uniremote_render::{Buffer, RenderHtml};
 
 use crate::{auth::AUTH_COOKIE_NAME, state::AppState};
@@ -71,6 +71,8 @@ fn list_remotes_html(state: &AppState) -> Response {
         html.push_uri(id);
         html.push_str(r#"/icon" alt=""><div>"#);
         html.push_html(&remote.meta.name);
+        html.push_str(r#"</div><div class="remote-permissions">"#);
+        html.push_html(&remote.meta.permissions.to_string());
         html.push_str(r#"</div></a></li>"#);
     }
 
@@ -90,6 +92,7 @@ fn list_remotes_json(state: &AppState) -> Response {
             serde_json::json!({
                 "id": id,
                 "name": remote.meta.name,
+                "permissions": remote.meta.permissions,
             })
         })
         .collect();
@@ -102,17 +105,37 @@ pub async fn get_remote(
     State(state): State<AppState>,
 ) -> Result<Html<String>, StatusCode> {
     let remote = &state.remote(&remote_id)?.remote;
+    let html = render_remote(&remote_id, remote, state.clipboard().is_some());
+    Ok(html.into_html())
+}
 
+/// Remote page, with clipboard sync controls if the server syncs the
+/// clipboard
+fn render_remote(remote_id: &RemoteId, remote: &Remote, clipboard_sync: bool) -> Buffer {
     let mut output = Buffer::with_header();
 
-    output.push_str("<div class=\"backlink\"><a href=\"/\">&larr; Back to remotes</a></div><h1>");
+    output.push_str("<div class=\"backlink\"><a href=\"/\">&larr; Back to remotes</a>");
+    if !remote.schema.is_empty() {
+        output.push_str("<a class=\"settings-link\" href=\"/r/");
+        output.push_uri(remote_id);
+        output.push_str("/settings\">Settings</a>");
+    }
+    output.push_str("<a class=\"data-link\" href=\"/r/");
+    output.push_uri(remote_id);
+    output.push_str("/data\">Data</a>");
+    if clipboard_sync {
+        output.push_str(
+            "<button type=\"button\" class=\"clipboard-button\" id=\"clipboard-pull\">Get clipboard</button>\
+             <button type=\"button\" class=\"clipboard-button\" id=\"clipboard-push\">Send clipboard</button>",
+        );
+    }
+    output.push_str("</div><h1>");
     output.push_html(&remote.meta.name);
     output.push_str("</h1>");
 
     remote.layout.render(&mut output);
     output.add_footer();
-
-    Ok(output.into_html())
+    output
 }
 
 pub async fn call_remote_action(
@@ -146,6 +169,75 @@ pub async fn call_remote_action(
     })))
 }
 
+pub async fn get_remote_data(
+    Path(remote_id): Path<RemoteId>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let token = jar
+        .get(AUTH_COOKIE_NAME)
+        .map(|cookie| cookie.value())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    state.authenticate(token)?;
+
+    let data = state.remote(&remote_id)?.data.entries();
+    Ok(Json(serde_json::json!({
+        "id": remote_id,
+        "data": data,
+    })))
+}
+
+pub async fn get_remote_data_page(
+    Path(remote_id): Path<RemoteId>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Html<String>, StatusCode> {
+    let token = jar
+        .get(AUTH_COOKIE_NAME)
+        .map(|cookie| cookie.value())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    state.authenticate(token)?;
+
+    let loaded = state.remote(&remote_id)?;
+    let html = render_data(&remote_id, &loaded.remote.meta.name, &loaded.data.entries());
+    Ok(html.into_html())
+}
+
+/// Read-only view of the entries a remote stored with `libs.data`
+fn render_data(
+    remote_id: &RemoteId,
+    name: &str,
+    entries: &serde_json::Map<String, serde_json::Value>,
+) -> Buffer {
+    let mut html = Buffer::with_header();
+
+    html.push_str(r#"<div class="backlink"><a href="/r/"#);
+    html.push_uri(remote_id);
+    html.push_str(r#"">&larr; Back to remote</a></div><h1>"#);
+    html.push_html(name);
+    html.push_str(" data</h1>");
+
+    if entries.is_empty() {
+        html.push_str(r#"<div class="data-empty">No stored data</div>"#);
+    } else {
+        html.push_str(r#"<table class="data-table"><tr><th>Key</th><th>Value</th></tr>"#);
+        for (key, value) in entries {
+            let value = serde_json::to_string_pretty(value).unwrap_or_default();
+            html.push_str("<tr><td>");
+            html.push_html(key);
+            html.push_str("</td><td><pre>");
+            html.push_html(&value);
+            html.push_str("</pre></td></tr>");
+        }
+        html.push_str("</table>");
+    }
+
+    html.add_footer();
+    html
+}
+
 pub async fn get_remote_icon(
     Path(remote_id): Path<RemoteId>,
     State(state): State<AppState>,
@@ -191,3 +283,78 @@ pub async fn get_remote_icon(
         .body(body)
         .unwrap())
 }
+
+/// Album art of the player bound with `libs.media.bind`
+pub async fn get_media_art(
+    Path(remote_id): Path<RemoteId>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Response, StatusCode> {
+    let token = jar
+        .get(AUTH_COOKIE_NAME)
+        .map(|cookie| cookie.value())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    state.authenticate(token)?;
+
+    let art = state
+        .remote(&remote_id)?
+        .media_art
+        .load()
+        .await
+        .map_err(|error| {
+            tracing::warn!("failed to load album art of remote {remote_id}: {error:#}");
+            StatusCode::NOT_FOUND
+        })?;
+    let (data, mime_type) = art.ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime_type),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_data_escapes_entries() {
+        let entries = serde_json::json!({
+            "playlist": "<b>Favourites</b>",
+            "channels": [1, 5, 7],
+        });
+        let html = render_data(
+            &RemoteId::from("player"),
+            "Player",
+            entries.as_object().unwrap(),
+        );
+
+        assert!(html.contains("<td>playlist</td>"));
+        assert!(html.contains("&lt;b&gt;Favourites"));
+        assert!(!html.contains("<b>Favourites"));
+        assert!(html.contains("Player data"));
+    }
+
+    #[test]
+    fn test_render_remote_clipboard_controls() {
+        let remote = Remote {
+            path: "remote".into(),
+            meta: serde_json::from_str(r#"{"meta.name": "Player"}"#).unwrap(),
+            layout: Default::default(),
+            schema: Default::default(),
+        };
+        let remote_id = RemoteId::from("player");
+
+        let html = render_remote(&remote_id, &remote, true);
+        assert!(html.contains(r#"id="clipboard-push""#));
+        assert!(html.contains(r#"id="clipboard-pull""#));
+
+        let html = render_remote(&remote_id, &remote, false);
+        assert!(!html.contains("clipboard-"));
+    }
+}
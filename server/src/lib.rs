@@ -17,10 +17,14 @@ use uniremote_loader::LoadedRemote;
 
 mod auth;
 mod handlers;
+mod notifications;
 mod qr;
+mod screen;
+mod settings;
 mod websocket;
 
 pub mod args;
+pub mod logging;
 pub mod state;
 
 pub use crate::args::BindAddress;
@@ -31,6 +35,8 @@ const ASSETS_DIR: &str = "server/assets";
 pub async fn run(
     remotes: HashMap<RemoteId, LoadedRemote>,
     bind_addr: BindAddress,
+    mirror_notifications: bool,
+    clipboard_sync: bool,
 ) -> anyhow::Result<()> {
     let auth_token = AuthToken::generate();
 
@@ -44,7 +50,26 @@ pub async fn run(
 
     print_qr_code(local_addr, &auth_token);
 
-    let state = AppState::new(remotes, auth_token);
+    let clipboard = if clipboard_sync {
+        let clipboard = uniremote_lua::clipboard::Clipboard::detect();
+        if clipboard.is_none() {
+            tracing::warn!("clipboard sync is disabled, no graphical session found");
+        }
+        clipboard
+    } else {
+        None
+    };
+
+    let state = AppState::new(remotes, auth_token, clipboard);
+
+    if mirror_notifications {
+        let sender = state.notifications();
+        tokio::spawn(async move {
+            if let Err(error) = notifications::mirror_notifications(sender).await {
+                tracing::error!("failed to mirror notifications: {error:#}");
+            }
+        });
+    }
 
     let cors = CorsLayer::new()
         .allow_origin(AllowOrigin::exact(origin.parse().unwrap()))
@@ -61,8 +86,16 @@ pub async fn run(
         .route("/login/{token}", get(handlers::login))
         .route("/r/{id}", get(handlers::get_remote))
         .route("/r/{id}/icon", get(handlers::get_remote_icon))
+        .route(
+            "/r/{id}/settings",
+            get(settings::get_settings).post(settings::update_settings),
+        )
+        .route("/r/{id}/data", get(handlers::get_remote_data_page))
+        .route("/r/{id}/media/art", get(handlers::get_media_art))
         .route("/api/r/{id}/call", post(handlers::call_remote_action))
+        .route("/api/r/{id}/data", get(handlers::get_remote_data))
         .route("/api/r/{id}/ws", get(websocket::websocket_handler))
+        .route("/api/r/{id}/screen", get(screen::get_screen))
         .nest_service("/assets", ServeDir::new(ASSETS_DIR))
         .layer(SetResponseHeaderLayer::overriding(
             header::CONTENT_SECURITY_POLICY,
@@ -0,0 +1,74 @@
+use std::io::{self, Write};
+
+use tracing_subscriber::fmt::MakeWriter;
+use uniremote_loader::SecretStore;
+
+/// Set up logging to stdout with the secrets of all remotes masked, so that
+/// no log line leaks a secret whichever module it comes from
+pub fn init(secrets: SecretStore) {
+    tracing_subscriber::fmt()
+        .with_writer(RedactingWriter(secrets))
+        .init();
+}
+
+/// Log writer masking secrets in each formatted event
+struct RedactingWriter(SecretStore);
+
+impl<'a> MakeWriter<'a> for RedactingWriter {
+    type Writer = RedactedEvent;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactedEvent {
+            secrets: self.0.clone(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// A single log event, buffered so that secrets are masked as a whole
+/// before it is written out
+struct RedactedEvent {
+    secrets: SecretStore,
+    buffer: Vec<u8>,
+}
+
+impl RedactedEvent {
+    fn redacted(&self) -> String {
+        self.secrets.redact(&String::from_utf8_lossy(&self.buffer))
+    }
+}
+
+impl Write for RedactedEvent {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RedactedEvent {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            let _ = io::stdout().write_all(self.redacted().as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted_event() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let secrets = SecretStore::open(temp_dir.path()).unwrap();
+        secrets.scoped("media").set("token", "hunter2").unwrap();
+
+        let mut event = RedactingWriter(secrets).make_writer();
+        write!(event, "request failed: Bearer hunter2").unwrap();
+        assert_eq!(event.redacted(), "request failed: Bearer ********");
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::Context;
 use clap::Parser;
 use uniremote_loader::LuaLimits;
 use uniremote_server::args::Args;
@@ -5,20 +6,29 @@ use uniremote_server::args::Args;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
 
     let args = Args::parse();
 
+    let secrets = uniremote_loader::SecretStore::open(&args.secrets)
+        .context("failed to open secret store")?;
+    uniremote_server::logging::init(secrets.clone());
+
     let lua_limits = LuaLimits {
         memory_mb: args.lua_max_mem,
         max_instructions: args.lua_max_instructions,
     };
 
-    let remotes = uniremote_loader::load_remotes(args.remotes, lua_limits)?;
+    let remotes = uniremote_loader::load_remotes(args.remotes, args.data, secrets, lua_limits)?;
 
     tracing::info!("loaded {} remotes", remotes.len());
 
-    uniremote_server::run(remotes, args.bind).await?;
+    uniremote_server::run(
+        remotes,
+        args.bind,
+        args.mirror_notifications,
+        args.clipboard_sync,
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use tokio::sync::broadcast::Sender;
+use uniremote_core::ServerMessage;
+use zbus::{Connection, Message, MessageStream, message::Type, zvariant::OwnedValue};
+
+const NOTIFICATIONS_INTERFACE: &str = "org.freedesktop.Notifications";
+
+/// Match rule for notifications sent by any application on the bus
+const NOTIFY_RULE: &str =
+    "type='method_call',interface='org.freedesktop.Notifications',member='Notify'";
+
+/// Body of an `org.freedesktop.Notifications.Notify` call
+type NotifyBody = (
+    String,
+    u32,
+    String,
+    String,
+    String,
+    Vec<String>,
+    HashMap<String, OwnedValue>,
+    i32,
+);
+
+/// Forward desktop notifications on the session bus to connected clients
+pub async fn mirror_notifications(sender: Sender<ServerMessage>) -> anyhow::Result<()> {
+    let connection = Connection::session()
+        .await
+        .context("failed to connect to session bus")?;
+    monitor_notifications(&connection, sender).await
+}
+
+/// Turn the connection into a bus monitor for `Notify` calls.
+///
+/// A monitor only receives messages and must never send any, so the
+/// connection can't be used for anything else afterwards.
+async fn monitor_notifications(
+    connection: &Connection,
+    sender: Sender<ServerMessage>,
+) -> anyhow::Result<()> {
+    let mut stream = MessageStream::from(connection);
+
+    connection
+        .call_method(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            Some("org.freedesktop.DBus.Monitoring"),
+            "BecomeMonitor",
+            &(vec![NOTIFY_RULE], 0u32),
+        )
+        .await
+        .context("failed to monitor notifications")?;
+
+    tracing::info!("mirroring desktop notifications to clients");
+
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                tracing::warn!("failed to receive notification: {error}");
+                continue;
+            }
+        };
+
+        if let Some(notification) = parse_notification(&message) {
+            tracing::debug!("mirroring notification: {notification:?}");
+            // Sending fails only if no client is connected
+            let _ = sender.send(notification);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_notification(message: &Message) -> Option<ServerMessage> {
+    let header = message.header();
+    if header.message_type() != Type::MethodCall
+        || header.interface()?.as_str() != NOTIFICATIONS_INTERFACE
+        || header.member()?.as_str() != "Notify"
+    {
+        return None;
+    }
+
+    let (app, _, icon, summary, body, ..): NotifyBody = message.body().deserialize().ok()?;
+    Some(ServerMessage::Notification {
+        app,
+        summary,
+        body,
+        icon: (!icon.is_empty()).then_some(icon),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    /// Private session bus, stopped when dropped
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+
+            Some(Self {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+
+        async fn connect(&self) -> Connection {
+            zbus::connection::Builder::address(self.address.as_str())
+                .unwrap()
+                .build()
+                .await
+                .unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitor_notifications() {
+        let Some(bus) = TestBus::start() else {
+            eprintln!("dbus-daemon is not available, skipping");
+            return;
+        };
+
+        let (sender, mut receiver) = broadcast::channel(16);
+        let monitor = bus.connect().await;
+        let task = tokio::spawn(async move { monitor_notifications(&monitor, sender).await });
+
+        // Give the monitor time to register before sending
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = bus.connect().await;
+        let body: NotifyBody = (
+            "mail".to_string(),
+            0,
+            String::new(),
+            "New message".to_string(),
+            "Hello".to_string(),
+            Vec::new(),
+            HashMap::new(),
+            -1,
+        );
+        // Nobody owns the name, but the monitor sees the call anyway
+        let _ = client
+            .call_method(
+                Some("org.freedesktop.Notifications"),
+                "/org/freedesktop/Notifications",
+                Some(NOTIFICATIONS_INTERFACE),
+                "Notify",
+                &body,
+            )
+            .await;
+
+        let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("notification was not mirrored")
+            .unwrap();
+        assert!(matches!(
+            message,
+            ServerMessage::Notification { app, summary, body, icon: None }
+                if app == "mail" && summary == "New message" && body == "Hello"
+        ));
+
+        task.abort();
+    }
+}
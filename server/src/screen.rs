@@ -0,0 +1,182 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use futures_util::stream;
+use serde::Deserialize;
+use uniremote_core::{Permission, RemoteId};
+use uniremote_lua::screen::{DEFAULT_QUALITY, Screen};
+
+use crate::{auth::AUTH_COOKIE_NAME, state::AppState};
+
+const MJPEG_BOUNDARY: &str = "frame";
+const DEFAULT_FPS: u32 = 2;
+const MAX_FPS: u32 = 15;
+
+#[derive(Debug, Deserialize)]
+pub struct ScreenParams {
+    /// Factor between 0 and 1 to shrink the screenshot by
+    scale: Option<f64>,
+    /// JPEG quality from 1 to 100
+    quality: Option<u8>,
+    /// Serve an MJPEG stream instead of a single snapshot
+    #[serde(default)]
+    stream: bool,
+    /// Frames per second of the stream
+    fps: Option<u32>,
+}
+
+impl ScreenParams {
+    fn quality(&self) -> u8 {
+        self.quality.unwrap_or(DEFAULT_QUALITY)
+    }
+
+    fn scale(&self) -> f64 {
+        self.scale.unwrap_or(1.0)
+    }
+}
+
+pub async fn get_screen(
+    Path(remote_id): Path<RemoteId>,
+    State(state): State<AppState>,
+    Query(params): Query<ScreenParams>,
+    jar: CookieJar,
+) -> Result<Response, StatusCode> {
+    let token = jar
+        .get(AUTH_COOKIE_NAME)
+        .map(|cookie| cookie.value())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    state.authenticate(token)?;
+
+    // Only remotes declaring the screen permission may show it, `*` is not
+    // enough to expose the desktop to clients
+    let remote = &state.remote(&remote_id)?.remote;
+    if !remote.meta.permissions.declares(Permission::Screen) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let screen = tokio::task::spawn_blocking(|| Screen::connect(None))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|error| {
+            tracing::error!("failed to open screen: {error:#}");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+    let screen = Arc::new(screen);
+
+    if params.stream {
+        return Ok(stream_screen(screen, params));
+    }
+
+    let jpeg = capture(screen, params.scale(), params.quality())
+        .await
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/jpeg"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        jpeg,
+    )
+        .into_response())
+}
+
+async fn capture(screen: Arc<Screen>, scale: f64, quality: u8) -> Option<Vec<u8>> {
+    let result =
+        tokio::task::spawn_blocking(move || screen.capture(None)?.scale(scale).to_jpeg(quality))
+            .await;
+
+    match result {
+        Ok(Ok(jpeg)) => Some(jpeg),
+        Ok(Err(error)) => {
+            tracing::error!("failed to capture screen: {error:#}");
+            None
+        }
+        Err(error) => {
+            tracing::error!("screen capture task failed: {error}");
+            None
+        }
+    }
+}
+
+/// Frame of an MJPEG stream including its multipart headers
+fn mjpeg_part(jpeg: &[u8]) -> Vec<u8> {
+    let mut part = format!(
+        "--{MJPEG_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        jpeg.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+/// Serve screenshots as `multipart/x-mixed-replace`, which browsers show as
+/// a video in an `img` element
+fn stream_screen(screen: Arc<Screen>, params: ScreenParams) -> Response {
+    let fps = params.fps.unwrap_or(DEFAULT_FPS).clamp(1, MAX_FPS);
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / fps);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    let (scale, quality) = (params.scale(), params.quality());
+    let frames = stream::unfold(
+        (screen, interval),
+        move |(screen, mut interval)| async move {
+            interval.tick().await;
+            let jpeg = capture(screen.clone(), scale, quality).await?;
+            Some((
+                Ok::<_, std::io::Error>(Bytes::from(mjpeg_part(&jpeg))),
+                (screen, interval),
+            ))
+        },
+    );
+
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                format!("multipart/x-mixed-replace; boundary={MJPEG_BOUNDARY}"),
+            ),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        Body::from_stream(frames),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Uri;
+
+    use super::*;
+
+    #[test]
+    fn test_screen_params() {
+        let uri: Uri = "/api/r/desktop/screen".parse().unwrap();
+        let Query(params) = Query::<ScreenParams>::try_from_uri(&uri).unwrap();
+        assert_eq!((params.scale(), params.quality()), (1.0, DEFAULT_QUALITY));
+        assert!(!params.stream);
+
+        let uri: Uri = "/api/r/desktop/screen?scale=0.25&quality=50&stream=true&fps=5"
+            .parse()
+            .unwrap();
+        let Query(params) = Query::<ScreenParams>::try_from_uri(&uri).unwrap();
+        assert_eq!((params.scale(), params.quality()), (0.25, 50));
+        assert!(params.stream);
+        assert_eq!(params.fps, Some(5));
+    }
+
+    #[test]
+    fn test_mjpeg_part() {
+        assert_eq!(
+            mjpeg_part(b"jpeg"),
+            b"--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\njpeg\r\n"
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    Form,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use uniremote_core::{Remote, RemoteId, SettingSpec, SettingType, SettingsSchema};
+use uniremote_render::Buffer;
+
+use uniremote_loader::LoadedRemote;
+
+use crate::{auth::AUTH_COOKIE_NAME, state::AppState};
+
+type FieldErrors = HashMap<String, String>;
+
+/// Submitted settings split by where they are stored
+#[derive(Debug, Default, PartialEq)]
+struct ValidatedSettings {
+    /// Values for the settings file
+    values: HashMap<String, String>,
+    /// Changed secrets for the secret store
+    secrets: HashMap<String, String>,
+}
+
+pub async fn get_settings(
+    Path(remote_id): Path<RemoteId>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Html<String>, StatusCode> {
+    let token = jar
+        .get(AUTH_COOKIE_NAME)
+        .map(|cookie| cookie.value())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    state.authenticate(token)?;
+
+    let loaded = state.remote(&remote_id)?;
+    let remote = &loaded.remote;
+    if remote.schema.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let values = read_settings(remote)?;
+    let stored_secrets = stored_secrets(loaded);
+    let html = render_settings(
+        &remote_id,
+        remote,
+        &values,
+        &stored_secrets,
+        &FieldErrors::new(),
+        None,
+    );
+    Ok(html.into_html())
+}
+
+pub async fn update_settings(
+    Path(remote_id): Path<RemoteId>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Response, StatusCode> {
+    let token = jar
+        .get(AUTH_COOKIE_NAME)
+        .map(|cookie| cookie.value())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    state.authenticate(token)?;
+
+    let loaded = state.remote(&remote_id)?;
+    let remote = &loaded.remote;
+    if remote.schema.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let validated = match validate_settings(&remote.schema, &form) {
+        Ok(validated) => validated,
+        Err(errors) => {
+            let mut shown = read_settings(remote)?;
+            shown.extend(form);
+            let html = render_settings(
+                &remote_id,
+                remote,
+                &shown,
+                &stored_secrets(loaded),
+                &errors,
+                None,
+            );
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, html.into_html()).into_response());
+        }
+    };
+
+    tracing::info!("update settings of remote '{remote_id}'");
+
+    for (key, secret) in &validated.secrets {
+        loaded.secrets.set(key, secret).map_err(|error| {
+            tracing::error!("failed to store secret '{key}' of remote '{remote_id}': {error:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+
+    let mut changed = loaded
+        .worker
+        .update_settings(validated.values.clone())
+        .await
+        .map_err(|error| {
+            tracing::error!("failed to update settings of remote '{remote_id}': {error:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    changed.extend(validated.secrets.into_keys());
+
+    let notice = if changed.is_empty() {
+        "No changes"
+    } else {
+        "Settings saved"
+    };
+    let html = render_settings(
+        &remote_id,
+        remote,
+        &validated.values,
+        &stored_secrets(loaded),
+        &FieldErrors::new(),
+        Some(notice),
+    );
+    Ok(html.into_html().into_response())
+}
+
+fn read_settings(remote: &Remote) -> Result<HashMap<String, String>, StatusCode> {
+    uniremote_loader::read_remote_settings(remote).map_err(|error| {
+        tracing::error!("failed to read settings: {error:#}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Names of the secret settings that have a value in the secret store
+fn stored_secrets(loaded: &LoadedRemote) -> HashSet<String> {
+    loaded
+        .remote
+        .schema
+        .settings
+        .iter()
+        .filter(|spec| spec.kind == SettingType::Secret && loaded.secrets.contains(&spec.key))
+        .map(|spec| spec.key.clone())
+        .collect()
+}
+
+/// Validate submitted form values against the schema.
+///
+/// Returns normalized raw values for all non-secret settings in the schema,
+/// or an error message per invalid field. Unchecked checkboxes are missing
+/// from the form and mean `false`, empty secret fields keep the stored secret.
+fn validate_settings(
+    schema: &SettingsSchema,
+    form: &HashMap<String, String>,
+) -> Result<ValidatedSettings, FieldErrors> {
+    let mut validated = ValidatedSettings::default();
+    let mut errors = FieldErrors::new();
+
+    for spec in &schema.settings {
+        let submitted = form.get(&spec.key).map(String::as_str);
+        let raw = match (spec.kind, submitted) {
+            (SettingType::Bool, submitted) => submitted.is_some().to_string(),
+            (SettingType::Secret, None | Some("")) => continue,
+            (SettingType::Secret, Some(secret)) => {
+                validated
+                    .secrets
+                    .insert(spec.key.clone(), secret.to_string());
+                continue;
+            }
+            (_, submitted) => submitted.unwrap_or_default().to_string(),
+        };
+
+        match spec.parse(&raw) {
+            Ok(serde_json::Value::String(value)) => {
+                validated.values.insert(spec.key.clone(), value);
+            }
+            Ok(value) => {
+                validated.values.insert(spec.key.clone(), value.to_string());
+            }
+            Err(error) => {
+                errors.insert(spec.key.clone(), error.to_string());
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(validated)
+    } else {
+        Err(errors)
+    }
+}
+
+fn render_settings(
+    remote_id: &RemoteId,
+    remote: &Remote,
+    values: &HashMap<String, String>,
+    stored_secrets: &HashSet<String>,
+    errors: &FieldErrors,
+    notice: Option<&str>,
+) -> Buffer {
+    let mut html = Buffer::with_header();
+
+    html.push_str(r#"<div class="backlink"><a href="/r/"#);
+    html.push_uri(remote_id);
+    html.push_str(r#"">&larr; Back to remote</a></div><h1>"#);
+    html.push_html(&remote.meta.name);
+    html.push_str(" settings</h1>");
+
+    if let Some(notice) = notice {
+        html.push_str(r#"<div class="settings-notice">"#);
+        html.push_html(notice);
+        html.push_str("</div>");
+    }
+
+    html.push_str(r#"<form class="settings-form" method="post" action="/r/"#);
+    html.push_uri(remote_id);
+    html.push_str(r#"/settings">"#);
+
+    for spec in &remote.schema.settings {
+        let value = values
+            .get(&spec.key)
+            .cloned()
+            .or_else(|| spec.default.clone())
+            .unwrap_or_default();
+
+        html.push_str(r#"<div class="setting">"#);
+        render_field(&mut html, spec, &value, stored_secrets.contains(&spec.key));
+
+        if let Some(error) = errors.get(&spec.key) {
+            html.push_str(r#"<div class="setting-error">"#);
+            html.push_html(error);
+            html.push_str("</div>");
+        }
+
+        if let Some(help) = &spec.help {
+            html.push_str(r#"<div class="setting-help">"#);
+            html.push_html(help);
+            html.push_str("</div>");
+        }
+
+        html.push_str("</div>");
+    }
+
+    html.push_str(r#"<button type="submit">Save</button></form>"#);
+    html.add_footer();
+    html
+}
+
+fn render_field(html: &mut Buffer, spec: &SettingSpec, value: &str, has_secret: bool) {
+    if spec.kind == SettingType::Bool {
+        let checked = spec.parse(value).ok() == Some(serde_json::Value::Bool(true));
+        html.push_str(r#"<label class="toggle"><input type="checkbox" name=""#);
+        html.push_html(&spec.key);
+        html.push_str(if checked {
+            r#"" checked />"#
+        } else {
+            r#"" />"#
+        });
+        html.push_str("<span>");
+        html.push_html(&spec.label);
+        html.push_str("</span></label>");
+        return;
+    }
+
+    html.push_str(r#"<label for="setting-"#);
+    html.push_html(&spec.key);
+    html.push_str(r#"">"#);
+    html.push_html(&spec.label);
+    html.push_str("</label>");
+
+    if spec.kind == SettingType::Enum {
+        html.push_str(r#"<select class="text" id="setting-"#);
+        html.push_html(&spec.key);
+        html.push_str(r#"" name=""#);
+        html.push_html(&spec.key);
+        html.push_str(r#"">"#);
+        for option in &spec.options {
+            html.push_str(r#"<option value=""#);
+            html.push_html(option);
+            html.push_str(if option == value {
+                r#"" selected>"#
+            } else {
+                r#"">"#
+            });
+            html.push_html(option);
+            html.push_str("</option>");
+        }
+        html.push_str("</select>");
+        return;
+    }
+
+    let input_type = match spec.kind {
+        SettingType::Int => "number",
+        SettingType::Secret => "password",
+        _ => "text",
+    };
+
+    html.push_str(r#"<input class="text" type=""#);
+    html.push_str(input_type);
+    html.push_str(r#"" id="setting-"#);
+    html.push_html(&spec.key);
+    html.push_str(r#"" name=""#);
+    html.push_html(&spec.key);
+
+    // Secrets are never sent back to the client
+    if spec.kind == SettingType::Secret {
+        if has_secret {
+            html.push_str(r#"" placeholder="unchanged"#);
+        }
+    } else {
+        html.push_str(r#"" value=""#);
+        html.push_html(value);
+    }
+    html.push_str(r#"" />"#);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn schema() -> SettingsSchema {
+        SettingsSchema::from_properties(map(&[
+            ("host.type", "string"),
+            ("port.type", "int"),
+            ("muted.type", "bool"),
+            ("mode.type", "enum"),
+            ("mode.options", "fast slow"),
+            ("token.type", "secret"),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        let form = map(&[
+            ("host", "media.local"),
+            ("port", " 8080"),
+            ("mode", "slow"),
+            ("token", ""),
+        ]);
+
+        let validated = validate_settings(&schema(), &form).unwrap();
+        assert_eq!(
+            validated.values,
+            map(&[
+                ("host", "media.local"),
+                ("port", "8080"),
+                ("muted", "false"),
+                ("mode", "slow"),
+            ])
+        );
+        // An empty secret field keeps the stored secret
+        assert!(validated.secrets.is_empty());
+
+        let form = map(&[
+            ("port", "8080"),
+            ("muted", "on"),
+            ("mode", "fast"),
+            ("token", "s3cret"),
+        ]);
+        let validated = validate_settings(&schema(), &form).unwrap();
+        assert_eq!(validated.values["muted"], "true");
+        assert_eq!(validated.secrets, map(&[("token", "s3cret")]));
+        assert!(!validated.values.contains_key("token"));
+    }
+
+    #[test]
+    fn test_validate_settings_errors() {
+        let form = map(&[("port", "eighty"), ("mode", "medium")]);
+
+        let errors = validate_settings(&schema(), &form).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors["port"], "expected an integer");
+        assert_eq!(errors["mode"], "expected one of: fast, slow");
+    }
+
+    #[test]
+    fn test_render_settings_masks_secrets() {
+        let remote = Remote {
+            path: "remote".into(),
+            meta: serde_json::from_str(r#"{"meta.name": "Player"}"#).unwrap(),
+            layout: Default::default(),
+            schema: schema(),
+        };
+        let values = map(&[("token", "s3cret"), ("host", "media.local")]);
+        let stored_secrets = HashSet::from(["token".to_string()]);
+
+        let html = render_settings(
+            &RemoteId::from("player"),
+            &remote,
+            &values,
+            &stored_secrets,
+            &FieldErrors::new(),
+            None,
+        );
+
+        assert!(!html.contains("s3cret"));
+        assert!(html.contains(r#"value="media.local""#));
+        assert!(html.contains(r#"placeholder="unchanged""#));
+    }
+}
@@ -1,19 +1,30 @@
 use std::{collections::HashMap, sync::Arc};
 
 use axum::http::StatusCode;
-use uniremote_core::RemoteId;
+use tokio::sync::broadcast;
+use uniremote_core::{RemoteId, ServerMessage};
 use uniremote_loader::LoadedRemote;
+use uniremote_lua::clipboard::Clipboard;
 
 use crate::auth::AuthToken;
 
+const NOTIFICATIONS_BUFFER_SIZE: usize = 16;
+
 #[derive(Clone)]
 pub(crate) struct AppState(Arc<AppStateInner>);
 
 impl AppState {
-    pub fn new(remotes: HashMap<RemoteId, LoadedRemote>, auth_token: AuthToken) -> Self {
+    pub fn new(
+        remotes: HashMap<RemoteId, LoadedRemote>,
+        auth_token: AuthToken,
+        clipboard: Option<Clipboard>,
+    ) -> Self {
+        let (notifications, _) = broadcast::channel(NOTIFICATIONS_BUFFER_SIZE);
         Self(Arc::new(AppStateInner {
             remotes,
             auth_token,
+            notifications,
+            clipboard,
         }))
     }
 
@@ -25,6 +36,16 @@ impl AppState {
         self.0.auth_token.validate(token)
     }
 
+    /// Sender for messages to all clients regardless of the remote they show
+    pub fn notifications(&self) -> broadcast::Sender<ServerMessage> {
+        self.0.notifications.clone()
+    }
+
+    /// Desktop clipboard shared with clients, if clipboard sync is enabled
+    pub fn clipboard(&self) -> Option<&Clipboard> {
+        self.0.clipboard.as_ref()
+    }
+
     pub fn remotes(&self) -> impl Iterator<Item = (&RemoteId, &LoadedRemote)> {
         self.0.remotes.iter()
     }
@@ -33,4 +54,6 @@ impl AppState {
 struct AppStateInner {
     remotes: HashMap<RemoteId, LoadedRemote>,
     auth_token: AuthToken,
+    notifications: broadcast::Sender<ServerMessage>,
+    clipboard: Option<Clipboard>,
 }
@@ -7,11 +7,14 @@ use axum::{
     response::Response,
 };
 use axum_extra::extract::cookie::CookieJar;
+use flume::{Receiver, Sender};
 use futures_util::{
     sink::SinkExt,
     stream::{SplitSink, SplitStream, StreamExt},
 };
-use uniremote_core::{ClientMessage, RemoteId};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uniremote_core::{ClientMessage, RemoteId, ServerMessage};
+use uniremote_lua::clipboard::Clipboard;
 use uniremote_worker::{LuaWorker, Subscription};
 
 use crate::{AppState, auth::AUTH_COOKIE_NAME};
@@ -33,15 +36,29 @@ pub async fn websocket_handler(
     let remote = state.remote(&remote_id)?;
 
     let worker = remote.worker.clone();
+    let notifications = state.notifications().subscribe();
+    let clipboard = state.clipboard().cloned();
 
-    Ok(ws.on_upgrade(move |socket| handle_websocket(socket, worker)))
+    Ok(ws.on_upgrade(move |socket| handle_websocket(socket, worker, notifications, clipboard)))
 }
 
-async fn handle_websocket(socket: WebSocket, worker: LuaWorker) {
+async fn handle_websocket(
+    socket: WebSocket,
+    worker: LuaWorker,
+    notifications: broadcast::Receiver<ServerMessage>,
+    clipboard: Option<Clipboard>,
+) {
     let (tx, rx) = socket.split();
+    // Replies to this client only
+    let (replies_tx, replies) = flume::unbounded();
 
-    let mut send_task = tokio::spawn(handle_outgoing_messages(tx, worker.subscribe()));
-    let mut recv_task = tokio::spawn(handle_incoming_messages(worker, rx));
+    let mut send_task = tokio::spawn(handle_outgoing_messages(
+        tx,
+        worker.subscribe(),
+        notifications,
+        replies,
+    ));
+    let mut recv_task = tokio::spawn(handle_incoming_messages(worker, clipboard, replies_tx, rx));
 
     // Wait for either task to finish
     tokio::select! {
@@ -53,8 +70,29 @@ async fn handle_websocket(socket: WebSocket, worker: LuaWorker) {
 async fn handle_outgoing_messages(
     mut sender: SplitSink<WebSocket, Message>,
     subscription: Subscription,
+    mut notifications: broadcast::Receiver<ServerMessage>,
+    replies: Receiver<ServerMessage>,
 ) {
-    while let Ok(msg) = subscription.recv().await {
+    loop {
+        let msg = tokio::select! {
+            msg = subscription.recv() => match msg {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+            msg = notifications.recv() => match msg {
+                Ok(msg) => subscription.redact(msg),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("client missed {skipped} notifications");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            msg = replies.recv_async() => match msg {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+        };
+
         let json = match serde_json::to_string(&msg) {
             Ok(json) => json,
             Err(error) => {
@@ -69,7 +107,12 @@ async fn handle_outgoing_messages(
     }
 }
 
-async fn handle_incoming_messages(worker: LuaWorker, mut receiver: SplitStream<WebSocket>) {
+async fn handle_incoming_messages(
+    worker: LuaWorker,
+    clipboard: Option<Clipboard>,
+    replies: Sender<ServerMessage>,
+    mut receiver: SplitStream<WebSocket>,
+) {
     while let Some(msg) = receiver.next().await {
         match msg {
             Ok(Message::Text(text)) => {
@@ -87,6 +130,13 @@ async fn handle_incoming_messages(worker: LuaWorker, mut receiver: SplitStream<W
                             tracing::error!("failed to send action to worker: {error}");
                         }
                     }
+                    ClientMessage::Confirm(response) => worker.confirm(response),
+                    ClientMessage::ClipboardSet { .. } | ClientMessage::ClipboardGet => {
+                        let reply = handle_clipboard_message(clipboard.as_ref(), client_msg).await;
+                        if let Some(reply) = reply {
+                            let _ = replies.send(reply);
+                        }
+                    }
                 }
             }
             Ok(Message::Close(_)) => break,
@@ -98,3 +148,72 @@ async fn handle_incoming_messages(worker: LuaWorker, mut receiver: SplitStream<W
         }
     }
 }
+
+async fn handle_clipboard_message(
+    clipboard: Option<&Clipboard>,
+    message: ClientMessage,
+) -> Option<ServerMessage> {
+    let Some(clipboard) = clipboard else {
+        return Some(ServerMessage::Error {
+            message: "clipboard sync is disabled".to_string(),
+        });
+    };
+
+    let result = match message {
+        ClientMessage::ClipboardSet { text } => clipboard.set_text(&text).await.map(|_| None),
+        ClientMessage::ClipboardGet => clipboard
+            .get_text()
+            .await
+            .map(|text| Some(ServerMessage::Clipboard { text })),
+        _ => Ok(None),
+    };
+
+    result.unwrap_or_else(|error| {
+        tracing::error!("clipboard sync failed: {error:#}");
+        Some(ServerMessage::Error {
+            message: format!("clipboard sync failed: {error}"),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use uniremote_lua::clipboard::Backend;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_clipboard_messages() {
+        let reply = handle_clipboard_message(None, ClientMessage::ClipboardGet).await;
+        assert!(matches!(reply, Some(ServerMessage::Error { .. })));
+
+        // Fake xclip keeping the clipboard in a file next to it
+        let dir = tempfile::TempDir::new().unwrap();
+        let xclip = dir.path().join("xclip");
+        std::fs::write(
+            &xclip,
+            "#!/bin/sh\nfile=\"$(dirname \"$0\")/content\"\n\
+             case \"$*\" in *-in*) cat > \"$file\" ;; *) cat \"$file\" ;; esac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&xclip, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let clipboard = Clipboard::with_programs(Backend::X11, &xclip, &xclip);
+
+        let set = ClientMessage::ClipboardSet {
+            text: "typed on the phone".to_string(),
+        };
+        assert!(
+            handle_clipboard_message(Some(&clipboard), set)
+                .await
+                .is_none()
+        );
+
+        let reply = handle_clipboard_message(Some(&clipboard), ClientMessage::ClipboardGet).await;
+        assert!(matches!(
+            reply,
+            Some(ServerMessage::Clipboard { text }) if text == "typed on the phone"
+        ));
+    }
+}
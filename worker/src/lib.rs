@@ -1,12 +1,18 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering},
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
 use anyhow::anyhow;
 use flume::{Receiver, SendError, Sender};
-use tokio::{sync::Mutex, task::JoinHandle};
-use uniremote_core::{CallActionRequest, ServerMessage};
+use tokio::{
+    sync::{Mutex, oneshot},
+    task::JoinHandle,
+};
+use uniremote_core::{CallActionRequest, ConfirmResponse, ServerMessage};
 use uniremote_lua::LuaState;
 
 mod subscription;
@@ -15,9 +21,17 @@ pub use subscription::Subscription;
 const CHANNEL_BUFFER_SIZE: usize = 100;
 const MAX_SEND_RETRIES: usize = 10;
 
+enum WorkerRequest {
+    CallAction(CallActionRequest),
+    UpdateSettings(
+        HashMap<String, String>,
+        oneshot::Sender<anyhow::Result<Vec<String>>>,
+    ),
+}
+
 struct LuaWorkerInner {
     started: AtomicBool,
-    inbox: Receiver<CallActionRequest>,
+    inbox: Receiver<WorkerRequest>,
     outbox: Receiver<ServerMessage>,
     state: Arc<LuaState>,
     task: Mutex<Option<JoinHandle<()>>>,
@@ -34,16 +48,19 @@ impl Drop for LuaWorkerInner {
 #[derive(Clone)]
 pub struct LuaWorker {
     inner: Arc<LuaWorkerInner>,
-    sender: Sender<CallActionRequest>,
+    sender: Sender<WorkerRequest>,
 }
 
 impl LuaWorker {
+    /// Create the worker of a remote, which starts with the first request.
+    /// Remotes with persisted cron timers are started right away inside a
+    /// tokio runtime, so that their timers fire before any client connects.
     pub fn new(state: LuaState) -> Self {
         let (sender, inbox) = flume::bounded(CHANNEL_BUFFER_SIZE);
         let (outbox_tx, outbox) = flume::bounded(CHANNEL_BUFFER_SIZE);
         state.add_state(outbox_tx.clone());
 
-        Self {
+        let worker = Self {
             inner: Arc::new(LuaWorkerInner {
                 inbox,
                 outbox,
@@ -52,39 +69,92 @@ impl LuaWorker {
                 task: Mutex::new(None),
             }),
             sender,
+        };
+
+        if worker.inner.state.has_saved_timers() && tokio::runtime::Handle::try_current().is_ok() {
+            let task = worker.spawn();
+            worker.inner.task.try_lock().unwrap().replace(task);
         }
+        worker
     }
 
     async fn start(&self) {
-        if self.inner.started.swap(true, Ordering::SeqCst) {
+        if self.inner.started.load(Ordering::SeqCst) {
             return;
         }
 
+        let mut task = self.inner.task.lock().await;
+        if task.is_none() {
+            task.replace(self.spawn());
+        }
+    }
+
+    /// Spawn the task running the remote's actions, settings updates and
+    /// queued callbacks one at a time
+    fn spawn(&self) -> JoinHandle<()> {
+        self.inner.started.store(true, Ordering::SeqCst);
+
         let inbox = self.inner.inbox.clone();
         let state = self.inner.state.clone();
-        let task = tokio::spawn(async move {
-            if let Err(error) = state.trigger_event("create") {
-                tracing::error!("failed to run create event handler: {error}");
+        let callbacks = state.callbacks();
+        tokio::spawn(async move {
+            if let Err(error) = state.trigger_event("create").await {
+                tracing::error!("failed to run create event handler: {error:#}");
             }
 
-            while let Ok(CallActionRequest { action, args }) = inbox.recv_async().await {
-                if let Err(error) = state.call_action(action, args) {
-                    tracing::error!("failed to handle action request: {error:#}");
+            loop {
+                tokio::select! {
+                    request = inbox.recv_async() => match request {
+                        Ok(request) => handle_request(&state, request).await,
+                        Err(_) => break,
+                    },
+                    Ok(callback) = callbacks.recv_async() => state.run_callback(callback).await,
                 }
             }
 
-            if let Err(error) = state.trigger_event("destroy") {
-                tracing::error!("failed to run destroy event handler: {error}");
+            if let Err(error) = state.destroy().await {
+                tracing::error!("failed to run destroy event handler: {error:#}");
             }
-        });
-        self.inner.task.lock().await.replace(task);
+        })
     }
 
     pub fn subscribe(&self) -> Subscription {
         Subscription::new(self.inner.outbox.clone(), self.inner.state.clone())
     }
 
-    pub async fn send(&self, mut request: CallActionRequest) -> anyhow::Result<()> {
+    pub async fn send(&self, request: CallActionRequest) -> anyhow::Result<()> {
+        self.dispatch(WorkerRequest::CallAction(request)).await
+    }
+
+    /// Answer a confirmation request of the remote.
+    ///
+    /// This bypasses the inbox, as the action waiting for the answer keeps the
+    /// worker from handling any other request.
+    pub fn confirm(&self, response: ConfirmResponse) {
+        if !self
+            .inner
+            .state
+            .confirm(&response.nonce, response.confirmed)
+        {
+            tracing::warn!("no pending confirmation for nonce {}", response.nonce);
+        }
+    }
+
+    /// Update the remote's settings and save them to its settings file,
+    /// returning the keys that changed
+    pub async fn update_settings(
+        &self,
+        settings: HashMap<String, String>,
+    ) -> anyhow::Result<Vec<String>> {
+        let (reply, response) = oneshot::channel();
+        self.dispatch(WorkerRequest::UpdateSettings(settings, reply))
+            .await?;
+        response
+            .await
+            .map_err(|_| anyhow!("worker stopped before updating settings"))?
+    }
+
+    async fn dispatch(&self, mut request: WorkerRequest) -> anyhow::Result<()> {
         self.start().await;
 
         for _ in 0..MAX_SEND_RETRIES {
@@ -97,7 +167,20 @@ impl LuaWorker {
             };
         }
 
-        tracing::error!("failed to send action request to worker after {MAX_SEND_RETRIES} retries");
-        Err(anyhow!("failed to send action request to worker"))
+        tracing::error!("failed to send request to worker after {MAX_SEND_RETRIES} retries");
+        Err(anyhow!("failed to send request to worker"))
+    }
+}
+
+async fn handle_request(state: &LuaState, request: WorkerRequest) {
+    match request {
+        WorkerRequest::CallAction(CallActionRequest { action, args }) => {
+            if let Err(error) = state.call_action(action, args).await {
+                tracing::error!("failed to handle action request: {error:#}");
+            }
+        }
+        WorkerRequest::UpdateSettings(settings, reply) => {
+            let _ = reply.send(state.update_settings(settings));
+        }
     }
 }
@@ -11,8 +11,8 @@ pub struct Subscription {
 }
 
 impl Subscription {
-    /// Create a new subscription and trigger focus event if this is the first
-    /// subscription.
+    /// Create a new subscription and queue the focus event if this is the
+    /// first subscription.
     pub(crate) fn new(receiver: Receiver<ServerMessage>, state: Arc<LuaState>) -> Self {
         // Check if this is the first subscription.
         // After cloning, receiver_count will be at least 2 (original + this clone).
@@ -22,7 +22,7 @@ impl Subscription {
             receiver.receiver_count()
         );
         if receiver.receiver_count() == 2
-            && let Err(error) = state.trigger_event("focus")
+            && let Err(error) = state.queue_event("focus")
         {
             tracing::warn!("failed to trigger focus event: {error}");
         }
@@ -30,9 +30,16 @@ impl Subscription {
         Self { receiver, state }
     }
 
-    /// Receive a message from the subscription
+    /// Receive a message from the subscription, with the remote's secrets
+    /// masked
     pub async fn recv(&self) -> Result<ServerMessage, flume::RecvError> {
-        self.receiver.recv_async().await
+        let message = self.receiver.recv_async().await?;
+        Ok(self.redact(message))
+    }
+
+    /// Mask the remote's secrets in a message for the subscribed client
+    pub fn redact(&self, message: ServerMessage) -> ServerMessage {
+        self.state.redact_message(message)
     }
 }
 
@@ -47,7 +54,7 @@ impl Drop for Subscription {
             self.receiver.receiver_count()
         );
         if self.receiver.receiver_count() == 2
-            && let Err(error) = self.state.trigger_event("blur")
+            && let Err(error) = self.state.queue_event("blur")
         {
             tracing::warn!("failed to trigger blur event: {error}");
         }